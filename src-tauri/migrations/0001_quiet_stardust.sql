@@ -0,0 +1 @@
+ALTER TABLE `media_info` ADD `album_artist` text;
\ No newline at end of file
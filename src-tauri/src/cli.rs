@@ -0,0 +1,465 @@
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+use uuid::Uuid;
+
+use crate::mpv::{LoadMode, MpvError, MpvPlayer};
+
+pub const USAGE: &str = "\
+Usage: tauri-media-player [OPTIONS] [FILE|FOLDER|URL]...
+
+Options:
+  --enqueue            Add the files to the end of the playlist
+  --play-next          Insert the files after the current one
+  --start=<time>       Start the first file at <time> (seconds or [[hh:]mm:]ss)
+  --volume=<0-100>     Set the volume
+  --fullscreen         Show the window fullscreen
+  --playlist=<file>    Load a playlist file (M3U, PLS, ...)
+  -h, --help           Show this help
+
+If the player is already running, the files are opened there.";
+
+/// Where the running instance writes the port and secret it listens on, in
+/// the app data directory.
+const INSTANCE_FILE_NAME: &str = "instance";
+
+/// How long a second instance waits for the running one to take its arguments.
+const FORWARD_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// How the files from the command line are added to the playlist.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum OpenMode {
+    /// Replace the playlist and play the first file.
+    #[default]
+    Replace,
+    Enqueue,
+    PlayNext,
+}
+
+/// The parsed command line of a launch, also sent to the running instance.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LaunchArgs {
+    /// Absolute paths (files or folders, which mpv expands) and URLs.
+    pub inputs: Vec<String>,
+    pub mode: OpenMode,
+    pub start: Option<f64>,
+    pub volume: Option<f64>,
+    pub fullscreen: bool,
+    pub playlist: Option<String>,
+    pub help: bool,
+}
+
+impl LaunchArgs {
+    /// Parses the arguments after the program name. Relative paths are made
+    /// absolute here, since the running instance may have another working directory.
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self, String> {
+        let cwd = std::env::current_dir().unwrap_or_default();
+        let mut parsed = LaunchArgs::default();
+        let mut only_inputs = false;
+
+        for arg in args {
+            if only_inputs || !arg.starts_with("--") && arg != "-h" {
+                parsed.inputs.push(resolve_input(&arg, &cwd));
+                continue;
+            }
+
+            let (name, value) = match arg.split_once('=') {
+                Some((name, value)) => (name, Some(value)),
+                None => (arg.as_str(), None),
+            };
+            let required = || value.ok_or_else(|| format!("{} needs a value", name));
+
+            match name {
+                "--" => only_inputs = true,
+                "-h" | "--help" => parsed.help = true,
+                "--enqueue" => parsed.mode = OpenMode::Enqueue,
+                "--play-next" => parsed.mode = OpenMode::PlayNext,
+                "--fullscreen" => parsed.fullscreen = true,
+                "--start" => {
+                    let value = required()?;
+                    parsed.start =
+                        Some(parse_time(value).ok_or_else(|| format!("Invalid time: {}", value))?);
+                }
+                "--volume" => {
+                    let value = required()?;
+                    parsed.volume = Some(
+                        value
+                            .parse::<f64>()
+                            .ok()
+                            .filter(|v| *v >= 0.0)
+                            .ok_or_else(|| format!("Invalid volume: {}", value))?,
+                    );
+                }
+                "--playlist" => parsed.playlist = Some(resolve_input(required()?, &cwd)),
+                _ => return Err(format!("Unknown option: {}", name)),
+            }
+        }
+
+        Ok(parsed)
+    }
+
+    /// Opens the inputs in `player`. Window options such as `fullscreen` are left to the caller.
+    pub fn apply(&self, player: &MpvPlayer) -> Result<(), MpvError> {
+        if let Some(volume) = self.volume {
+            player.set_volume(volume)?;
+        }
+
+        // The playlist goes after the files, so with `PlayNext`, which inserts
+        // back to front, it is loaded first
+        if self.mode == OpenMode::PlayNext {
+            self.load_playlist(player)?;
+            self.load_inputs(player)
+        } else {
+            self.load_inputs(player)?;
+            self.load_playlist(player)
+        }
+    }
+
+    fn load_playlist(&self, player: &MpvPlayer) -> Result<(), MpvError> {
+        let Some(playlist) = &self.playlist else {
+            return Ok(());
+        };
+        let flag = match self.mode {
+            OpenMode::Replace if self.inputs.is_empty() => "replace",
+            OpenMode::Replace | OpenMode::Enqueue => "append-play",
+            OpenMode::PlayNext => "insert-next-play",
+        };
+        player.command(&["loadlist", playlist, flag])
+    }
+
+    fn load_inputs(&self, player: &MpvPlayer) -> Result<(), MpvError> {
+        let start: Vec<(&str, String)> = self
+            .start
+            .map(|start| vec![("start", format!("{:.3}", start))])
+            .unwrap_or_default();
+
+        match self.mode {
+            OpenMode::Replace | OpenMode::Enqueue => {
+                for (i, input) in self.inputs.iter().enumerate() {
+                    let mode = match (self.mode, i) {
+                        (OpenMode::Replace, 0) => LoadMode::Replace,
+                        (OpenMode::Replace, _) => LoadMode::Append,
+                        _ => LoadMode::AppendPlay,
+                    };
+                    let options = if i == 0 { start.as_slice() } else { &[] };
+                    player.load_file_with_options(input, Some(mode), options)?;
+                }
+            }
+            OpenMode::PlayNext => {
+                // Each one goes right after the current file, so insert them back to front
+                for (i, input) in self.inputs.iter().enumerate().rev() {
+                    let options = if i == 0 { start.as_slice() } else { &[] };
+                    player.load_file_with_options(
+                        input,
+                        Some(LoadMode::InsertNextPlay),
+                        options,
+                    )?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn resolve_input(input: &str, cwd: &Path) -> String {
+    if input.contains("://") {
+        return input.to_string();
+    }
+    let path = Path::new(input);
+    if path.is_absolute() {
+        input.to_string()
+    } else {
+        cwd.join(path).to_string_lossy().into_owned()
+    }
+}
+
+/// Parses `90`, `1:30`, `1:02:03` or `90.5` into seconds.
+fn parse_time(value: &str) -> Option<f64> {
+    let parts: Vec<&str> = value.split(':').collect();
+    if parts.len() > 3 {
+        return None;
+    }
+
+    let mut seconds = 0.0;
+    for part in parts {
+        let part: f64 = part.parse().ok()?;
+        if part < 0.0 {
+            return None;
+        }
+        seconds = seconds * 60.0 + part;
+    }
+    Some(seconds)
+}
+
+/// Sends `args` to an already running instance that announced itself in
+/// `app_data_dir`. Returns `false` if there is none, in which case this
+/// process should start the app.
+pub fn forward_to_running_instance(app_data_dir: &Path, args: &LaunchArgs) -> bool {
+    let Ok(contents) = std::fs::read_to_string(app_data_dir.join(INSTANCE_FILE_NAME)) else {
+        return false;
+    };
+    let Some((port, secret)) = contents
+        .trim()
+        .split_once(' ')
+        .and_then(|(port, secret)| Some((port.parse::<u16>().ok()?, secret)))
+    else {
+        return false;
+    };
+
+    let forward = || -> std::io::Result<bool> {
+        let mut stream =
+            TcpStream::connect_timeout(&(Ipv4Addr::LOCALHOST, port).into(), FORWARD_TIMEOUT)?;
+        stream.set_read_timeout(Some(FORWARD_TIMEOUT))?;
+        writeln!(stream, "{}", secret)?;
+        writeln!(stream, "{}", serde_json::to_string(args)?)?;
+
+        let mut reply = String::new();
+        BufReader::new(stream).read_line(&mut reply)?;
+        Ok(reply.trim() == "ok")
+    };
+
+    // A stale file from an instance that crashed; this process takes over
+    forward().unwrap_or(false)
+}
+
+/// The instance file written by `InstanceListener::bind`.
+pub struct InstanceFile {
+    path: PathBuf,
+    contents: String,
+}
+
+impl InstanceFile {
+    /// Only the owner may read it; the secret is what keeps other local users
+    /// from opening files in this player.
+    fn create(path: PathBuf, contents: String) -> std::io::Result<Self> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        // A file left by an earlier run may have other permissions
+        match std::fs::remove_file(&path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+            _ => (),
+        }
+
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        options.open(&path)?.write_all(contents.as_bytes())?;
+
+        Ok(Self { path, contents })
+    }
+
+    /// Deletes the file on shutdown, unless another instance has replaced it since.
+    pub fn remove(&self) {
+        if std::fs::read_to_string(&self.path).is_ok_and(|contents| contents == self.contents) {
+            if let Err(e) = std::fs::remove_file(&self.path) {
+                eprintln!("Failed to remove {}: {}", self.path.display(), e);
+            }
+        }
+    }
+}
+
+/// Receives the arguments of later launches, see `forward_to_running_instance`.
+///
+/// Only processes that can read the instance file in the user's app data
+/// directory know the secret, so other local users can't open files in this player.
+pub struct InstanceListener {
+    listener: TcpListener,
+    secret: String,
+    file: InstanceFile,
+}
+
+impl InstanceListener {
+    /// Listens on a free loopback port and announces it in the instance file
+    /// in `app_data_dir`.
+    pub fn bind(app_data_dir: &Path) -> std::io::Result<Self> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+        let secret = Uuid::new_v4().to_simple().to_string();
+        let port = listener.local_addr()?.port();
+        let file = InstanceFile::create(
+            app_data_dir.join(INSTANCE_FILE_NAME),
+            format!("{} {}", port, secret),
+        )?;
+        Ok(Self {
+            listener,
+            secret,
+            file,
+        })
+    }
+
+    /// Calls `on_launch` with every forwarded launch, on a separate thread.
+    /// The returned file should be removed when the app exits.
+    pub fn listen(self, on_launch: impl Fn(LaunchArgs) + Send + 'static) -> InstanceFile {
+        let InstanceListener {
+            listener,
+            secret,
+            file,
+        } = self;
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(stream) = stream else {
+                    continue;
+                };
+                match receive(stream, &secret) {
+                    Ok(Some(args)) => on_launch(args),
+                    Ok(None) => (),
+                    Err(e) => eprintln!("Failed to receive forwarded arguments: {}", e),
+                }
+            }
+        });
+        file
+    }
+}
+
+fn receive(mut stream: TcpStream, expected_secret: &str) -> std::io::Result<Option<LaunchArgs>> {
+    stream.set_read_timeout(Some(FORWARD_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+
+    let mut secret = String::new();
+    reader.read_line(&mut secret)?;
+    if secret.trim() != expected_secret {
+        return Ok(None);
+    }
+
+    let mut args = String::new();
+    reader.read_line(&mut args)?;
+    let args = serde_json::from_str(&args)?;
+    writeln!(stream, "ok")?;
+    Ok(Some(args))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    fn parse(args: &[&str]) -> Result<LaunchArgs, String> {
+        LaunchArgs::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn parses_times() {
+        assert_eq!(parse_time("90"), Some(90.0));
+        assert_eq!(parse_time("90.5"), Some(90.5));
+        assert_eq!(parse_time("1:30"), Some(90.0));
+        assert_eq!(parse_time("1:02:03"), Some(3723.0));
+        assert_eq!(parse_time("1:2:3:4"), None);
+        assert_eq!(parse_time("-5"), None);
+        assert_eq!(parse_time("1:"), None);
+        assert_eq!(parse_time("abc"), None);
+    }
+
+    #[test]
+    fn parses_options_and_inputs() {
+        let args = parse(&[
+            "--enqueue",
+            "--start=1:30",
+            "--volume=50",
+            "--fullscreen",
+            "/music/a.flac",
+            "https://example.com/stream",
+        ])
+        .unwrap();
+        assert_eq!(args.mode, OpenMode::Enqueue);
+        assert_eq!(args.start, Some(90.0));
+        assert_eq!(args.volume, Some(50.0));
+        assert!(args.fullscreen);
+        assert_eq!(args.inputs, ["/music/a.flac", "https://example.com/stream"]);
+
+        assert!(parse(&["-h"]).unwrap().help);
+        assert_eq!(parse(&["--play-next"]).unwrap().mode, OpenMode::PlayNext);
+    }
+
+    #[test]
+    fn makes_relative_paths_absolute() {
+        let cwd = std::env::current_dir().unwrap();
+        let args = parse(&["a.flac", "--playlist=list.m3u"]).unwrap();
+        assert_eq!(args.inputs, [cwd.join("a.flac").to_string_lossy()]);
+        assert_eq!(
+            args.playlist.as_deref(),
+            Some(cwd.join("list.m3u").to_string_lossy().as_ref())
+        );
+    }
+
+    #[test]
+    fn takes_everything_after_a_double_dash_as_inputs() {
+        let args = parse(&["--", "/music/--enqueue"]).unwrap();
+        assert_eq!(args.mode, OpenMode::Replace);
+        assert_eq!(args.inputs, ["/music/--enqueue"]);
+    }
+
+    #[test]
+    fn rejects_invalid_options() {
+        assert!(parse(&["--start"]).is_err());
+        assert!(parse(&["--start=soon"]).is_err());
+        assert!(parse(&["--volume=-1"]).is_err());
+        assert!(parse(&["--shuffle"]).is_err());
+    }
+
+    #[test]
+    fn forwards_launches_to_the_running_instance() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(!forward_to_running_instance(
+            dir.path(),
+            &LaunchArgs::default()
+        ));
+
+        let (sender, receiver) = mpsc::channel();
+        let listener = InstanceListener::bind(dir.path()).unwrap();
+        let file = listener.listen(move |args| sender.send(args).unwrap());
+
+        let args = parse(&["--play-next", "/music/a.flac"]).unwrap();
+        assert!(forward_to_running_instance(dir.path(), &args));
+        let received = receiver.recv_timeout(FORWARD_TIMEOUT).unwrap();
+        assert_eq!(received.mode, OpenMode::PlayNext);
+        assert_eq!(received.inputs, ["/music/a.flac"]);
+
+        file.remove();
+        assert!(!dir.path().join(INSTANCE_FILE_NAME).exists());
+        assert!(!forward_to_running_instance(dir.path(), &args));
+    }
+
+    #[test]
+    fn ignores_launches_with_the_wrong_secret() {
+        let dir = tempfile::tempdir().unwrap();
+        let (sender, receiver) = mpsc::channel();
+        let _file = InstanceListener::bind(dir.path())
+            .unwrap()
+            .listen(move |args| sender.send(args).unwrap());
+
+        let path = dir.path().join(INSTANCE_FILE_NAME);
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let (port, _) = contents.split_once(' ').unwrap();
+        std::fs::write(&path, format!("{} guessed", port)).unwrap();
+
+        assert!(!forward_to_running_instance(
+            dir.path(),
+            &LaunchArgs::default()
+        ));
+        assert!(receiver.recv_timeout(Duration::from_millis(200)).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn only_the_owner_can_read_the_instance_file() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(INSTANCE_FILE_NAME);
+        std::fs::write(&path, "left by an earlier run").unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+
+        let listener = InstanceListener::bind(dir.path()).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        // Another instance took over the file since
+        std::fs::write(&path, "1234 other").unwrap();
+        listener.file.remove();
+        assert!(path.exists());
+    }
+}
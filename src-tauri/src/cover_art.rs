@@ -0,0 +1,993 @@
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::webp::WebPEncoder;
+use image::{DynamicImage, ImageError};
+use lofty::picture::{Picture, PictureType};
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+
+use crate::media_probe;
+use crate::metadata;
+use crate::mpv::MpvError;
+
+/// Directory in the app cache directory that holds one directory of images per
+/// version of a media file.
+pub const CACHE_DIR_NAME: &str = "covers";
+
+/// Scheme of the protocol that serves the images.
+pub const PROTOCOL: &str = "cover";
+
+/// Sizes are rounded up to a multiple of this, so that slightly different
+/// layouts share their variants.
+const SIZE_STEP: u32 = 32;
+const MAX_SIZE: u32 = 2048;
+const JPEG_QUALITY: u8 = 85;
+
+const SOURCE_FILE_NAME: &str = "source.json";
+const ORIGINAL_FILE_NAME: &str = "original";
+/// Prefix of the scratch directories video frames are decoded to.
+const FRAME_DIR_PREFIX: &str = ".frame-";
+
+/// The cache is trimmed to this size, least recently used files first...
+const MAX_CACHE_BYTES: u64 = 256 * 1024 * 1024;
+/// ...and files not used for this long are dropped.
+const MAX_CACHE_AGE: Duration = Duration::from_secs(60 * 24 * 60 * 60);
+/// The cache is trimmed at startup and after extracting this many covers.
+const EVICT_INTERVAL: usize = 200;
+
+/// Names of sidecar images, in order of preference, without extension. A `*`
+/// matches any suffix; `<basename>` is the media file's name.
+const SIDECAR_NAMES: &[&str] = &["cover", "folder", "front", "albumart*", "<basename>"];
+const SIDECAR_EXTENSIONS: &[&str] = &["jpg", "jpeg", "png", "webp"];
+
+#[derive(Error, Debug)]
+pub enum CoverError {
+    #[error("Failed to access cover cache: {0}")]
+    IoError(#[from] std::io::Error),
+
+    #[error("Failed to resize cover: {0}")]
+    ImageError(#[from] ImageError),
+
+    #[error("Failed to extract video frame: {0}")]
+    MpvError(#[from] MpvError),
+
+    #[error("Unknown cover: {0}")]
+    UnknownKey(String),
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum CoverFormat {
+    Jpeg,
+    Webp,
+}
+
+impl CoverFormat {
+    /// As in the `format` parameter of cover URLs.
+    fn name(self) -> &'static str {
+        match self {
+            CoverFormat::Jpeg => "jpeg",
+            CoverFormat::Webp => "webp",
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            CoverFormat::Jpeg => "jpg",
+            CoverFormat::Webp => "webp",
+        }
+    }
+
+    fn mime_type(self) -> &'static str {
+        match self {
+            CoverFormat::Jpeg => "image/jpeg",
+            CoverFormat::Webp => "image/webp",
+        }
+    }
+}
+
+/// A version of a cover to serve: the image as found, or re-encoded.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Variant {
+    Original,
+    /// Scaled down so its longest side is at most `size`.
+    Resized {
+        size: u32,
+        format: CoverFormat,
+    },
+}
+
+impl Variant {
+    /// From the `size` and `format` parameters. Without a size, the image
+    /// keeps its own, up to `MAX_SIZE`; without a format it is a JPEG.
+    fn new(size: Option<u32>, format: Option<CoverFormat>) -> Self {
+        if size.is_none() && format.is_none() {
+            return Variant::Original;
+        }
+        let size = size
+            .unwrap_or(MAX_SIZE)
+            .div_ceil(SIZE_STEP)
+            .saturating_mul(SIZE_STEP)
+            .min(MAX_SIZE);
+        Variant::Resized {
+            size,
+            format: format.unwrap_or(CoverFormat::Jpeg),
+        }
+    }
+
+    fn file_name(self) -> String {
+        match self {
+            Variant::Original => ORIGINAL_FILE_NAME.to_string(),
+            Variant::Resized { size, format } => format!("{}.{}", size, format.extension()),
+        }
+    }
+
+    /// The parameters to add to the URL of the cover.
+    fn query(self) -> String {
+        match self {
+            Variant::Original => String::new(),
+            Variant::Resized { size, format } => {
+                format!("&size={}&format={}", size, format.name())
+            }
+        }
+    }
+}
+
+/// Where a cover was found.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum CoverSource {
+    /// A picture in the file's tags, e.g. `CoverFront`.
+    Embedded { picture_type: String },
+    /// An image in the file's folder.
+    Sidecar { path: PathBuf },
+    /// The first frame of the video, or its attached picture.
+    VideoFrame,
+}
+
+#[derive(Debug, Clone)]
+pub struct ResolvedCover {
+    pub source: CoverSource,
+    pub data: Vec<u8>,
+    pub mime_type: String,
+    /// `None` if the image can't be decoded.
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+}
+
+/// A resolved cover as the webview gets it: a URL to load the image from,
+/// rather than its bytes.
+#[derive(Serialize, Debug, Clone)]
+pub struct CoverInfo {
+    /// See `CoverArtCache::url`; of the image as found.
+    pub url: String,
+    pub source: CoverSource,
+    pub mime_type: String,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+}
+
+/// An image to send back through the protocol.
+pub struct CoverImage {
+    pub data: Vec<u8>,
+    pub mime_type: String,
+}
+
+/// What the cache directory of a media file was extracted from.
+#[derive(Serialize, Deserialize)]
+struct Source {
+    path: PathBuf,
+    /// See `file_version`.
+    version: u64,
+    mime_type: Option<String>,
+    /// `None` if the file has no cover.
+    cover: Option<CoverSource>,
+}
+
+/// Serves the cover art of media files (see `resolve`) as plain images, so the
+/// webview can show them with `<img>` instead of receiving the bytes over IPC.
+///
+/// Each file gets a URL from `url`, e.g.
+/// `cover://localhost/<key>?v=<version>&size=<px>&format=webp`. The art is
+/// resolved once per file version into `<key>-<version>` in the cache directory,
+/// and each variant is resized once next to it. Files are written under a
+/// temporary name and renamed, so concurrent requests never see them half
+/// written. The cache is limited by `MAX_CACHE_BYTES` and `MAX_CACHE_AGE`.
+pub struct CoverArtCache {
+    lib_path: String,
+    cache_dir: PathBuf,
+    /// Files that URLs were handed out for, by key. Keys from earlier runs are
+    /// found through the source file in their directory.
+    sources: Mutex<HashMap<String, PathBuf>>,
+    /// Variants being made in the background for `serve`.
+    pending: Mutex<HashSet<PathBuf>>,
+    extracted: AtomicUsize,
+}
+
+impl CoverArtCache {
+    /// `lib_path` is the libmpv used to decode video frames.
+    pub fn new(lib_path: &str, cache_dir: PathBuf) -> Arc<Self> {
+        let cache = Arc::new(Self {
+            lib_path: lib_path.to_string(),
+            cache_dir,
+            sources: Mutex::new(HashMap::new()),
+            pending: Mutex::new(HashSet::new()),
+            extracted: AtomicUsize::new(0),
+        });
+
+        let cache_ref = Arc::downgrade(&cache);
+        thread::spawn(move || {
+            if let Some(cache) = cache_ref.upgrade() {
+                cache.evict();
+            }
+        });
+        cache
+    }
+
+    /// The URL of the cover of `path`, scaled down to `size` (the longest side
+    /// in pixels) and in `format`; with neither, the image as found. The cover
+    /// is extracted and resized first, which may take a while, so that loading
+    /// the URL only reads the cache.
+    ///
+    /// The URL changes when the file or its sidecar image is modified, so
+    /// responses can be cached forever.
+    pub fn url(
+        &self,
+        path: &str,
+        size: Option<u32>,
+        format: Option<CoverFormat>,
+    ) -> Result<String, CoverError> {
+        let version = file_version(Path::new(path))?;
+        let key = cache_key(path);
+        self.sources
+            .lock()
+            .unwrap()
+            .insert(key.clone(), PathBuf::from(path));
+
+        let variant = Variant::new(size, format);
+        if self.extract(&key, version)?.is_some() {
+            render(&self.version_dir(&key, version), variant)?;
+        }
+        Ok(format!(
+            "{}?v={}{}",
+            protocol_url(&key),
+            version,
+            variant.query()
+        ))
+    }
+
+    /// Handles a request, given the path and query of its URL. Returns `None`
+    /// if the file has no cover.
+    ///
+    /// Only reads the cache, since it runs on the webview's thread: anything
+    /// missing (e.g. evicted since `url`) is made in the background, and `None`
+    /// returned meanwhile.
+    pub fn serve(
+        self: &Arc<Self>,
+        url_path: &str,
+        query: &str,
+    ) -> Result<Option<CoverImage>, CoverError> {
+        let key = url_path.trim_matches('/');
+        if key.is_empty() || !key.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(CoverError::UnknownKey(key.to_string()));
+        }
+
+        let mut version = None;
+        let mut size = None;
+        let mut format = None;
+        for (name, value) in query.split('&').filter_map(|pair| pair.split_once('=')) {
+            match name {
+                "v" => version = value.parse::<u64>().ok(),
+                "size" => size = value.parse::<u32>().ok().filter(|size| *size > 0),
+                "format" if value == "jpeg" => format = Some(CoverFormat::Jpeg),
+                "format" if value == "webp" => format = Some(CoverFormat::Webp),
+                _ => (),
+            }
+        }
+        let version = version.ok_or_else(|| CoverError::UnknownKey(key.to_string()))?;
+        let variant = Variant::new(size, format);
+
+        let dir = self.version_dir(key, version);
+        if let Some(source) = read_source(&dir) {
+            if source.cover.is_none() {
+                return Ok(None);
+            }
+            match fs::read(dir.join(variant.file_name())) {
+                Ok(data) => {
+                    let mime_type = match variant {
+                        Variant::Original => source
+                            .mime_type
+                            .unwrap_or_else(|| "application/octet-stream".to_string()),
+                        Variant::Resized { format, .. } => format.mime_type().to_string(),
+                    };
+                    return Ok(Some(CoverImage { data, mime_type }));
+                }
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                Err(_) => (),
+            }
+        }
+
+        self.prepare_in_background(key, version, variant);
+        Ok(None)
+    }
+
+    /// Finds the cover of `path`: the front cover in its tags, then an image in
+    /// its folder, then any other embedded picture, then the first video frame.
+    /// Goes through the cache, like `url`.
+    pub fn resolve(&self, path: &Path) -> Result<Option<ResolvedCover>, CoverError> {
+        let version = file_version(path)?;
+        let key = cache_key(&path.to_string_lossy());
+        self.sources
+            .lock()
+            .unwrap()
+            .insert(key.clone(), path.to_path_buf());
+
+        let Some(source) = self.extract(&key, version)? else {
+            return Ok(None);
+        };
+        let data = fs::read(self.version_dir(&key, version).join(ORIGINAL_FILE_NAME))?;
+        Ok(source
+            .cover
+            .map(|cover| resolved(cover, data, source.mime_type)))
+    }
+
+    /// Like `resolve`, but with the URL of the cover instead of its data.
+    pub fn info(&self, path: &str) -> Result<Option<CoverInfo>, CoverError> {
+        let Some(cover) = self.resolve(Path::new(path))? else {
+            return Ok(None);
+        };
+        Ok(Some(CoverInfo {
+            url: self.url(path, None, None)?,
+            source: cover.source,
+            mime_type: cover.mime_type,
+            width: cover.width,
+            height: cover.height,
+        }))
+    }
+
+    /// Extracts the cover and makes `variant` on another thread, unless that
+    /// is already under way.
+    fn prepare_in_background(self: &Arc<Self>, key: &str, version: u64, variant: Variant) {
+        let dir = self.version_dir(key, version);
+        let variant_path = dir.join(variant.file_name());
+        if !self.pending.lock().unwrap().insert(variant_path.clone()) {
+            return;
+        }
+
+        let cache = self.clone();
+        let key = key.to_string();
+        thread::spawn(move || {
+            let result = cache
+                .extract(&key, version)
+                .and_then(|source| match source {
+                    Some(_) => render(&dir, variant),
+                    None => Ok(()),
+                });
+            if let Err(e) = result {
+                eprintln!("Failed to prepare cover: {}", e);
+            }
+            cache.pending.lock().unwrap().remove(&variant_path);
+        });
+    }
+
+    /// Resolves the cover of the file with `key` into the directory of
+    /// `version`, unless it is there. Returns `None` if the file has
+    /// no cover, or if it changed since.
+    fn extract(&self, key: &str, version: u64) -> Result<Option<Source>, CoverError> {
+        let dir = self.version_dir(key, version);
+        let source_file = dir.join(SOURCE_FILE_NAME);
+        if let Some(source) = read_source(&dir) {
+            // Marks it as recently used for `evict`
+            let _ = fs::File::options()
+                .append(true)
+                .open(&source_file)
+                .and_then(|file| file.set_modified(SystemTime::now()));
+            return Ok(source.cover.is_some().then_some(source));
+        }
+
+        let known_path = self.sources.lock().unwrap().get(key).cloned();
+        let path = match known_path {
+            Some(path) => path,
+            None => self
+                .versions(key)
+                .into_iter()
+                .find_map(|(_, dir)| read_source(&dir))
+                .map(|source| source.path)
+                .ok_or_else(|| CoverError::UnknownKey(key.to_string()))?,
+        };
+        if file_version(&path)? != version {
+            return Ok(None);
+        }
+
+        fs::create_dir_all(&dir)?;
+        let frame_dir = self.frame_dir();
+        let cover = resolve_cover(&self.lib_path, &path, &frame_dir);
+        let _ = fs::remove_dir_all(&frame_dir);
+        let cover = cover?;
+        let source = Source {
+            path,
+            version,
+            mime_type: cover.as_ref().map(|cover| cover.mime_type.clone()),
+            cover: cover.as_ref().map(|cover| cover.source.clone()),
+        };
+        if let Some(cover) = &cover {
+            write_atomically(&dir.join(ORIGINAL_FILE_NAME), &cover.data)?;
+        }
+        // Written last, and even without a cover, so files without art aren't
+        // searched again
+        write_atomically(
+            &source_file,
+            &serde_json::to_vec(&source).map_err(std::io::Error::from)?,
+        )?;
+
+        // Earlier versions of the file are stale
+        for (other, old_dir) in self.versions(key) {
+            if other != version {
+                let _ = fs::remove_dir_all(old_dir);
+            }
+        }
+        if self.extracted.fetch_add(1, Ordering::Relaxed) % EVICT_INTERVAL == EVICT_INTERVAL - 1 {
+            self.evict();
+        }
+
+        Ok(cover.is_some().then_some(source))
+    }
+
+    /// A scratch directory of its own, so concurrent extractions don't share one.
+    fn frame_dir(&self) -> PathBuf {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        self.cache_dir
+            .join(format!("{}{}-{}", FRAME_DIR_PREFIX, std::process::id(), id))
+    }
+
+    fn version_dir(&self, key: &str, version: u64) -> PathBuf {
+        self.cache_dir.join(format!("{}-{}", key, version))
+    }
+
+    /// The cached versions of the file with `key`.
+    fn versions(&self, key: &str) -> Vec<(u64, PathBuf)> {
+        let prefix = format!("{}-", key);
+        let Ok(entries) = fs::read_dir(&self.cache_dir) else {
+            return Vec::new();
+        };
+        entries
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                let version = name.strip_prefix(&prefix)?.parse().ok()?;
+                Some((version, entry.path()))
+            })
+            .collect()
+    }
+
+    fn evict(&self) {
+        if let Err(e) = trim_cache(&self.cache_dir, MAX_CACHE_BYTES, MAX_CACHE_AGE) {
+            eprintln!("Failed to clean up cover cache: {}", e);
+        }
+    }
+}
+
+fn read_source(dir: &Path) -> Option<Source> {
+    fs::read(dir.join(SOURCE_FILE_NAME))
+        .ok()
+        .and_then(|data| serde_json::from_slice(&data).ok())
+}
+
+/// Makes `variant` from the original in `dir`, unless it is there.
+fn render(dir: &Path, variant: Variant) -> Result<(), CoverError> {
+    let Variant::Resized { size, format } = variant else {
+        return Ok(());
+    };
+    let path = dir.join(variant.file_name());
+    if path.exists() {
+        return Ok(());
+    }
+    let data = resize(&fs::read(dir.join(ORIGINAL_FILE_NAME))?, size, format)?;
+    write_atomically(&path, &data)?;
+    Ok(())
+}
+
+/// Writes `data` under a temporary name next to `path`, then renames it.
+fn write_atomically(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let temp_path = path.with_file_name(format!(".{}.{}-{}.tmp", name, std::process::id(), id));
+
+    fs::write(&temp_path, data)
+        .and_then(|()| fs::rename(&temp_path, path))
+        .inspect_err(|_| {
+            let _ = fs::remove_file(&temp_path);
+        })
+}
+
+/// Removes files not used for `max_age`, then the least recently used ones
+/// until the rest fit in `max_bytes`. A file's last use is the modification
+/// time of its source file.
+fn trim_cache(cache_dir: &Path, max_bytes: u64, max_age: Duration) -> std::io::Result<()> {
+    let mut versions = Vec::new();
+    for entry in fs::read_dir(cache_dir)? {
+        let path = entry?.path();
+        if !path.is_dir() {
+            continue;
+        }
+        let last_used = fs::metadata(path.join(SOURCE_FILE_NAME))
+            .or_else(|_| fs::metadata(&path))
+            .and_then(|metadata| metadata.modified())
+            .unwrap_or(UNIX_EPOCH);
+        let size: u64 = fs::read_dir(&path)?
+            .filter_map(|entry| entry.ok()?.metadata().ok())
+            .map(|metadata| metadata.len())
+            .sum();
+        versions.push((last_used, size, path));
+    }
+
+    versions.sort_by_key(|(last_used, _, _)| std::cmp::Reverse(*last_used));
+    let now = SystemTime::now();
+    let mut total = 0;
+    for (last_used, size, path) in versions {
+        total += size;
+        let expired = now.duration_since(last_used).unwrap_or_default() > max_age;
+        if expired || total > max_bytes {
+            fs::remove_dir_all(&path)?;
+            total -= size;
+        }
+    }
+    Ok(())
+}
+
+/// See `CoverArtCache::resolve`. `frame_dir` is a scratch directory for decoding video.
+pub fn resolve_cover(
+    lib_path: &str,
+    path: &Path,
+    frame_dir: &Path,
+) -> Result<Option<ResolvedCover>, CoverError> {
+    // Videos that lofty can't read simply have no embedded pictures
+    let pictures = metadata::read_pictures(path);
+    let is_audio = pictures.is_ok() && !metadata::may_hold_video(path);
+    let pictures = pictures.unwrap_or_default();
+    let embedded = |picture: &Picture| {
+        resolved(
+            CoverSource::Embedded {
+                picture_type: format!("{:?}", picture.pic_type()),
+            },
+            picture.data().to_vec(),
+            picture.mime_type().map(|m| m.to_string()),
+        )
+    };
+
+    if let Some(front) = pictures
+        .iter()
+        .find(|p| p.pic_type() == PictureType::CoverFront)
+    {
+        return Ok(Some(embedded(front)));
+    }
+    if let Some(sidecar) = find_sidecar(path) {
+        let data = fs::read(&sidecar)?;
+        let mime_type = mime_type_for_extension(&sidecar);
+        return Ok(Some(resolved(
+            CoverSource::Sidecar { path: sidecar },
+            data,
+            mime_type,
+        )));
+    }
+    if let Some(picture) = pictures.first() {
+        return Ok(Some(embedded(picture)));
+    }
+
+    if is_audio {
+        return Ok(None);
+    }
+    let frame = media_probe::extract_first_frame(lib_path, &path.to_string_lossy(), frame_dir)?;
+    Ok(frame.map(|data| {
+        resolved(
+            CoverSource::VideoFrame,
+            data,
+            Some("image/jpeg".to_string()),
+        )
+    }))
+}
+
+fn resolved(source: CoverSource, data: Vec<u8>, mime_type: Option<String>) -> ResolvedCover {
+    let dimensions = image::io::Reader::new(Cursor::new(&data))
+        .with_guessed_format()
+        .ok()
+        .and_then(|reader| reader.into_dimensions().ok());
+    ResolvedCover {
+        source,
+        mime_type: mime_type.unwrap_or_else(|| "application/octet-stream".to_string()),
+        width: dimensions.map(|(width, _)| width),
+        height: dimensions.map(|(_, height)| height),
+        data,
+    }
+}
+
+/// The first image in the folder of `path` matching `SIDECAR_NAMES`, compared
+/// without case.
+fn find_sidecar(path: &Path) -> Option<PathBuf> {
+    let folder = path.parent()?;
+    let basename = path.file_stem()?.to_string_lossy().to_lowercase();
+    let images: Vec<(String, PathBuf)> = fs::read_dir(folder)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter_map(|image| {
+            let extension = image.extension()?.to_string_lossy().to_lowercase();
+            if !SIDECAR_EXTENSIONS.contains(&extension.as_str()) {
+                return None;
+            }
+            let stem = image.file_stem()?.to_string_lossy().to_lowercase();
+            Some((stem, image))
+        })
+        .collect();
+
+    SIDECAR_NAMES.iter().find_map(|name| {
+        let mut candidates: Vec<&(String, PathBuf)> = images
+            .iter()
+            .filter(|(stem, _)| match name.strip_suffix('*') {
+                Some(prefix) => stem.starts_with(prefix),
+                None if *name == "<basename>" => *stem == basename,
+                None => stem == name,
+            })
+            .collect();
+        // e.g. `AlbumArt_{...}_Large` before `AlbumArtSmall`, then by extension preference
+        candidates.sort_by_key(|(stem, image)| {
+            let extension = image
+                .extension()
+                .map(|ext| ext.to_string_lossy().to_lowercase())
+                .unwrap_or_default();
+            (
+                !stem.ends_with("large"),
+                SIDECAR_EXTENSIONS.iter().position(|ext| *ext == extension),
+            )
+        });
+        candidates.first().map(|(_, image)| image.clone())
+    })
+}
+
+fn mime_type_for_extension(path: &Path) -> Option<String> {
+    let extension = path.extension()?.to_string_lossy().to_lowercase();
+    let mime_type = match extension.as_str() {
+        "jpg" | "jpeg" => "image/jpeg",
+        "png" => "image/png",
+        "webp" => "image/webp",
+        _ => return None,
+    };
+    Some(mime_type.to_string())
+}
+
+/// Scales `data` down so its longest side is at most `size`; smaller images
+/// are only re-encoded.
+fn resize(data: &[u8], size: u32, format: CoverFormat) -> Result<Vec<u8>, ImageError> {
+    let image = image::load_from_memory(data)?;
+    let image = if image.width() > size || image.height() > size {
+        image.thumbnail(size, size)
+    } else {
+        image
+    };
+
+    let mut encoded = Cursor::new(Vec::new());
+    match format {
+        // JPEG has no alpha channel
+        CoverFormat::Jpeg => JpegEncoder::new_with_quality(&mut encoded, JPEG_QUALITY)
+            .encode_image(&DynamicImage::ImageRgb8(image.to_rgb8()))?,
+        CoverFormat::Webp => {
+            let image = image.to_rgba8();
+            WebPEncoder::new_lossless(&mut encoded).encode(
+                image.as_raw(),
+                image.width(),
+                image.height(),
+                image::ColorType::Rgba8,
+            )?
+        }
+    }
+    Ok(encoded.into_inner())
+}
+
+fn modified_secs(path: &Path) -> std::io::Result<u64> {
+    Ok(fs::metadata(path)?
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0))
+}
+
+/// Identifies what the cover of `path` is resolved from: its modification
+/// time, and the path and modification time of its sidecar image, so adding,
+/// replacing or editing e.g. `cover.jpg` or `folder.png` makes a new version.
+fn file_version(path: &Path) -> std::io::Result<u64> {
+    let modified = modified_secs(path)?;
+    let sidecar = find_sidecar(path);
+    let sidecar_modified = sidecar
+        .as_deref()
+        .and_then(|sidecar| modified_secs(sidecar).ok());
+    let mut hasher = DefaultHasher::new();
+    (modified, sidecar, sidecar_modified).hash(&mut hasher);
+    Ok(hasher.finish())
+}
+
+fn cache_key(path: &str) -> String {
+    let mut hasher = DefaultHasher::new();
+    path.hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
+}
+
+/// URL of a cover, in the form the webview loads custom protocols.
+fn protocol_url(key: &str) -> String {
+    if cfg!(windows) {
+        format!("https://{}.localhost/{}", PROTOCOL, key)
+    } else {
+        format!("{}://localhost/{}", PROTOCOL, key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mpv::test_support;
+
+    /// A folder with a song and, if `cover` is set, a 300x200 `cover.png` next to it.
+    fn album(cover: bool) -> (tempfile::TempDir, String) {
+        let dir = tempfile::tempdir().unwrap();
+        let song = dir.path().join("song.wav");
+        fs::write(&song, test_support::sine_wav(8_000, 0, 800)).unwrap();
+        if cover {
+            image::RgbImage::from_pixel(300, 200, image::Rgb([200, 40, 40]))
+                .save(dir.path().join("cover.png"))
+                .unwrap();
+        }
+        (dir, song.to_string_lossy().into_owned())
+    }
+
+    /// Requests `url` the way the protocol handler does.
+    fn serve(cache: &Arc<CoverArtCache>, url: &str) -> Option<CoverImage> {
+        let url = url.split_once("localhost/").unwrap().1;
+        let (url_path, query) = url.split_once('?').unwrap();
+        cache.serve(url_path, query).unwrap()
+    }
+
+    fn dimensions(image: &CoverImage) -> (u32, u32) {
+        let image = image::load_from_memory(&image.data).unwrap();
+        (image.width(), image.height())
+    }
+
+    #[test]
+    fn rounds_sizes_and_defaults_to_jpeg() {
+        assert_eq!(Variant::new(None, None), Variant::Original);
+        assert_eq!(
+            Variant::new(Some(100), None),
+            Variant::Resized {
+                size: 128,
+                format: CoverFormat::Jpeg
+            }
+        );
+        assert_eq!(
+            Variant::new(None, Some(CoverFormat::Webp)),
+            Variant::Resized {
+                size: MAX_SIZE,
+                format: CoverFormat::Webp
+            }
+        );
+        assert_eq!(
+            Variant::new(Some(100_000), None).query(),
+            "&size=2048&format=jpeg"
+        );
+        assert_eq!(Variant::new(None, None).query(), "");
+    }
+
+    #[test]
+    fn serves_the_variants_it_made() {
+        let (dir, song) = album(true);
+        let cache = CoverArtCache::new("", dir.path().join("cache"));
+
+        let url = cache.url(&song, Some(64), Some(CoverFormat::Webp)).unwrap();
+        assert!(url.ends_with("&size=64&format=webp"));
+        let image = serve(&cache, &url).unwrap();
+        assert_eq!(image.mime_type, "image/webp");
+        assert_eq!(dimensions(&image), (64, 43));
+
+        let url = cache.url(&song, None, None).unwrap();
+        let image = serve(&cache, &url).unwrap();
+        assert_eq!(image.mime_type, "image/png");
+        assert_eq!(dimensions(&image), (300, 200));
+
+        // Keeps its own size
+        let url = cache.url(&song, None, Some(CoverFormat::Webp)).unwrap();
+        assert_eq!(dimensions(&serve(&cache, &url).unwrap()), (300, 200));
+    }
+
+    #[test]
+    fn makes_missing_variants_in_the_background() {
+        let (dir, song) = album(true);
+        let cache = CoverArtCache::new("", dir.path().join("cache"));
+        let url = cache.url(&song, None, None).unwrap();
+
+        let url = format!("{}&size=32", url);
+        assert!(serve(&cache, &url).is_none());
+        let image = (0..100)
+            .find_map(|_| {
+                thread::sleep(Duration::from_millis(50));
+                serve(&cache, &url)
+            })
+            .unwrap();
+        assert_eq!(dimensions(&image), (32, 21));
+    }
+
+    #[test]
+    fn remembers_files_without_a_cover() {
+        let (dir, song) = album(false);
+        let cache = CoverArtCache::new("", dir.path().join("cache"));
+
+        let url = cache.url(&song, Some(64), None).unwrap();
+        assert!(serve(&cache, &url).is_none());
+        assert!(cache.pending.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn replaces_the_covers_of_modified_files() {
+        let (dir, song) = album(true);
+        let cache = CoverArtCache::new("", dir.path().join("cache"));
+        let key = cache_key(&song);
+        let old_url = cache.url(&song, Some(64), None).unwrap();
+
+        fs::File::options()
+            .append(true)
+            .open(&song)
+            .unwrap()
+            .set_modified(SystemTime::now() + Duration::from_secs(10))
+            .unwrap();
+        let new_url = cache.url(&song, Some(64), None).unwrap();
+
+        assert_ne!(old_url, new_url);
+        assert_eq!(cache.versions(&key).len(), 1);
+        assert!(serve(&cache, &new_url).is_some());
+        // Can't be made any more
+        assert!(serve(&cache, &old_url).is_none());
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(cache.versions(&key).len(), 1);
+    }
+
+    #[test]
+    fn resolves_covers_through_the_cache() {
+        let (dir, song) = album(true);
+        let cache = CoverArtCache::new("", dir.path().join("cache"));
+
+        let cover = cache.resolve(Path::new(&song)).unwrap().unwrap();
+        assert_eq!(
+            cover.source,
+            CoverSource::Sidecar {
+                path: dir.path().join("cover.png")
+            }
+        );
+        assert_eq!((cover.width, cover.height), (Some(300), Some(200)));
+        assert_eq!(cover.mime_type, "image/png");
+
+        let cached = cache.resolve(Path::new(&song)).unwrap().unwrap();
+        assert_eq!(cached.data, cover.data);
+        assert_eq!(cache.extracted.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn replaces_the_cover_when_the_sidecar_changes() {
+        let (dir, song) = album(true);
+        let cache = CoverArtCache::new("", dir.path().join("cache"));
+        let cover = dir.path().join("cover.png");
+        let first_url = cache.url(&song, None, None).unwrap();
+
+        fs::File::options()
+            .append(true)
+            .open(&cover)
+            .unwrap()
+            .set_modified(SystemTime::now() + Duration::from_secs(10))
+            .unwrap();
+        let edited_url = cache.url(&song, None, None).unwrap();
+        assert_ne!(edited_url, first_url);
+
+        fs::remove_file(&cover).unwrap();
+        image::RgbImage::from_pixel(100, 50, image::Rgb([40, 40, 200]))
+            .save(dir.path().join("folder.png"))
+            .unwrap();
+        let resolved = cache.resolve(Path::new(&song)).unwrap().unwrap();
+        assert_eq!(
+            resolved.source,
+            CoverSource::Sidecar {
+                path: dir.path().join("folder.png")
+            }
+        );
+        assert_eq!((resolved.width, resolved.height), (Some(100), Some(50)));
+        let folder_url = cache.url(&song, None, None).unwrap();
+        assert_ne!(folder_url, edited_url);
+        assert_eq!(dimensions(&serve(&cache, &folder_url).unwrap()), (100, 50));
+
+        fs::remove_file(dir.path().join("folder.png")).unwrap();
+        assert!(cache.resolve(Path::new(&song)).unwrap().is_none());
+    }
+
+    #[test]
+    fn reports_the_url_instead_of_the_image() {
+        let (dir, song) = album(true);
+        let cache = CoverArtCache::new("", dir.path().join("cache"));
+
+        let info = cache.info(&song).unwrap().unwrap();
+        assert_eq!(info.url, cache.url(&song, None, None).unwrap());
+        assert_eq!((info.width, info.height), (Some(300), Some(200)));
+        assert_eq!(info.mime_type, "image/png");
+        assert_eq!(dimensions(&serve(&cache, &info.url).unwrap()), (300, 200));
+
+        let json = serde_json::to_value(&info).unwrap();
+        assert!(json.get("data").is_none());
+        assert_eq!(json["source"]["kind"], "sidecar");
+
+        let (dir, song) = album(false);
+        let cache = CoverArtCache::new("", dir.path().join("cache"));
+        assert!(cache.info(&song).unwrap().is_none());
+    }
+
+    #[test]
+    fn resolves_the_same_file_concurrently() {
+        let (dir, song) = album(true);
+        let cache = CoverArtCache::new("", dir.path().join("cache"));
+
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let (cache, song) = (cache.clone(), song.clone());
+                thread::spawn(move || cache.resolve(Path::new(&song)).unwrap().unwrap())
+            })
+            .collect();
+        for thread in threads {
+            assert_eq!(thread.join().unwrap().width, Some(300));
+        }
+        assert_eq!(cache.versions(&cache_key(&song)).len(), 1);
+    }
+
+    #[test]
+    fn decodes_frames_in_separate_directories() {
+        let cache = CoverArtCache::new("", PathBuf::from("cache"));
+        assert_ne!(cache.frame_dir(), cache.frame_dir());
+    }
+
+    #[test]
+    fn writes_through_a_temporary_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("original");
+        write_atomically(&path, b"old").unwrap();
+        write_atomically(&path, b"new").unwrap();
+
+        assert_eq!(fs::read(&path).unwrap(), b"new");
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn evicts_old_and_least_recently_used_covers() {
+        let dir = tempfile::tempdir().unwrap();
+        let day = Duration::from_secs(24 * 60 * 60);
+        for (name, ago) in [("a-1", 70), ("b-1", 3), ("c-1", 2), ("d-1", 1)] {
+            let version_dir = dir.path().join(name);
+            fs::create_dir_all(&version_dir).unwrap();
+            fs::write(version_dir.join(ORIGINAL_FILE_NAME), vec![0; 100]).unwrap();
+            fs::File::create(version_dir.join(SOURCE_FILE_NAME))
+                .unwrap()
+                .set_modified(SystemTime::now() - day * ago)
+                .unwrap();
+        }
+
+        trim_cache(dir.path(), 250, day * 60).unwrap();
+
+        let mut left: Vec<String> = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        left.sort();
+        assert_eq!(left, ["c-1", "d-1"]);
+    }
+}
@@ -0,0 +1,34 @@
+use crate::cover_art::{CoverArtCache, CoverFormat, CoverInfo};
+
+use std::sync::Arc;
+use tauri::State;
+
+/// URL of the cover of `path`, for `<img src>`: scaled down to `size` pixels
+/// and as WebP or JPEG (the default) if either is given, else the image as
+/// found. Resolves once the image is in the cache.
+#[tauri::command]
+pub async fn cover_get_url(
+    covers: State<'_, Arc<CoverArtCache>>,
+    path: String,
+    size: Option<u32>,
+    format: Option<CoverFormat>,
+) -> Result<String, String> {
+    let covers = covers.inner().clone();
+    tauri::async_runtime::spawn_blocking(move || covers.url(&path, size, format))
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| e.to_string())
+}
+
+/// The URL of the cover of `path` with where it was found, or `None` if it has none.
+#[tauri::command]
+pub async fn cover_resolve(
+    covers: State<'_, Arc<CoverArtCache>>,
+    path: String,
+) -> Result<Option<CoverInfo>, String> {
+    let covers = covers.inner().clone();
+    tauri::async_runtime::spawn_blocking(move || covers.info(&path))
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| e.to_string())
+}
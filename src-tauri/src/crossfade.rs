@@ -0,0 +1,993 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::f64::consts::FRAC_PI_2;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::mpv::{LoadMode, MpvError, MpvPlayer};
+use crate::player_handle::PlayerHandle;
+use crate::scanner;
+
+/// How often the controller checks the remaining time and updates the fade.
+const TICK_INTERVAL: Duration = Duration::from_millis(50);
+
+pub const MIN_CROSSFADE_SECS: f64 = 1.0;
+pub const MAX_CROSSFADE_SECS: f64 = 12.0;
+
+/// Shape of the volume ramps. Gains are amplitudes in `[0, 1]`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FadeCurve {
+    Linear,
+    /// Constant perceived loudness across the fade (`cos`/`sin` ramps).
+    EqualPower,
+    /// Smoothstep; slow start and end, fast middle.
+    SCurve,
+    /// Quadratic; the incoming track stays quiet for longer.
+    Exponential,
+}
+
+impl FadeCurve {
+    /// Returns `(outgoing, incoming)` gains at fade progress `t` in `[0, 1]`.
+    pub fn gains(&self, t: f64) -> (f64, f64) {
+        let t = t.clamp(0.0, 1.0);
+        match self {
+            FadeCurve::Linear => (1.0 - t, t),
+            FadeCurve::EqualPower => ((t * FRAC_PI_2).cos(), (t * FRAC_PI_2).sin()),
+            FadeCurve::SCurve => {
+                let incoming = t * t * (3.0 - 2.0 * t);
+                (1.0 - incoming, incoming)
+            }
+            FadeCurve::Exponential => ((1.0 - t) * (1.0 - t), t * t),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CrossfadeConfig {
+    /// Length of the fade in seconds, clamped to `MIN_CROSSFADE_SECS..=MAX_CROSSFADE_SECS`.
+    pub duration_secs: f64,
+    pub curve: FadeCurve,
+}
+
+impl CrossfadeConfig {
+    fn clamped(self) -> Self {
+        CrossfadeConfig {
+            duration_secs: self
+                .duration_secs
+                .clamp(MIN_CROSSFADE_SECS, MAX_CROSSFADE_SECS),
+            ..self
+        }
+    }
+}
+
+impl Default for CrossfadeConfig {
+    fn default() -> Self {
+        CrossfadeConfig {
+            duration_secs: 5.0,
+            curve: FadeCurve::EqualPower,
+        }
+    }
+}
+
+/// Snapshot of the controller reported to the frontend.
+#[derive(Debug, Clone, Serialize)]
+pub struct CrossfadeState {
+    pub playlist_pos: Option<usize>,
+    pub playlist_count: usize,
+    pub fading: bool,
+    pub paused: bool,
+    pub volume: f64,
+}
+
+pub type PositionCallback = Box<dyn Fn(Option<usize>) + Send + 'static>;
+pub type DeckCallback = Box<dyn Fn(&Arc<MpvPlayer>) + Send + 'static>;
+
+struct Fade {
+    /// The fade started when the outgoing track had this many seconds left.
+    length: f64,
+}
+
+struct State {
+    config: CrossfadeConfig,
+    playlist: Vec<String>,
+    position: Option<usize>,
+    /// Set when `position` changes, until it is reported.
+    position_changed: bool,
+    /// Index into `decks` of the deck the current track plays on.
+    active: usize,
+    /// Playlist index loaded (paused) in the idle deck, ready to fade in.
+    preloaded: Option<usize>,
+    fade: Option<Fade>,
+    /// Whether the active deck has left idle since its file was loaded. Loading is
+    /// asynchronous, so until then an idle deck doesn't mean the track ended.
+    active_started: bool,
+    volume: f64,
+    paused: bool,
+}
+
+impl State {
+    fn new(config: CrossfadeConfig, volume: f64) -> Self {
+        State {
+            config: config.clamped(),
+            playlist: Vec::new(),
+            position: None,
+            position_changed: false,
+            active: 0,
+            preloaded: None,
+            fade: None,
+            active_started: false,
+            volume,
+            paused: false,
+        }
+    }
+}
+
+/// What the active deck reported on a tick.
+struct DeckStatus {
+    idle: bool,
+    /// Position and duration of its file, once loaded far enough to know them.
+    times: Option<(f64, f64)>,
+}
+
+/// What a tick does.
+#[derive(Debug, PartialEq)]
+enum Step {
+    Wait,
+    /// The current track ended without a fade; start `next` right away.
+    Cut(usize),
+    /// The last entry ended.
+    End,
+    /// Start fading in the preloaded entry, `remaining` seconds before the end.
+    StartFade {
+        length: f64,
+        remaining: f64,
+    },
+    /// Update the gains of the running fade.
+    Fade {
+        remaining: f64,
+    },
+    /// The outgoing track is over; the incoming deck becomes the active one.
+    FinishFade,
+}
+
+impl Step {
+    /// `next` is the entry after the current one, if any.
+    fn of(state: &State, next: Option<usize>, deck: DeckStatus) -> Step {
+        if state.position.is_none() || state.paused {
+            return Step::Wait;
+        }
+
+        // The current track ended before (or without) a fade, e.g. the next
+        // entry was not preloaded in time; fall back to a hard cut.
+        if deck.idle {
+            return match (&state.fade, next) {
+                _ if !state.active_started => Step::Wait,
+                (Some(_), _) => Step::FinishFade,
+                (None, Some(next)) => Step::Cut(next),
+                (None, None) => Step::End,
+            };
+        }
+
+        let Some((time_pos, duration)) = deck.times else {
+            return Step::Wait;
+        };
+        let remaining = (duration - time_pos).max(0.0);
+
+        match &state.fade {
+            None => {
+                // Short tracks get a shorter fade so it never covers more than half of them
+                let length = state.config.duration_secs.min(duration / 2.0);
+                match next {
+                    Some(next) if state.preloaded == Some(next) && remaining <= length => {
+                        Step::StartFade { length, remaining }
+                    }
+                    _ => Step::Wait,
+                }
+            }
+            Some(fade) if remaining <= TICK_INTERVAL.as_secs_f64() || fade.length <= 0.0 => {
+                Step::FinishFade
+            }
+            Some(_) => Step::Fade { remaining },
+        }
+    }
+}
+
+/// The entry after `position` in a playlist of `count` entries.
+fn next_index(count: usize, position: usize, loops: bool) -> Option<usize> {
+    if position + 1 < count {
+        Some(position + 1)
+    } else if count > 0 && loops {
+        Some(0)
+    } else {
+        None
+    }
+}
+
+/// The deck `index` plays on when started: the idle deck if it is preloaded
+/// there, else the active one, except that video always moves to the primary deck.
+fn deck_for_entry(state: &State, index: usize, is_video: bool) -> usize {
+    if state.preloaded == Some(index) {
+        1 - state.active
+    } else if is_video {
+        0
+    } else {
+        state.active
+    }
+}
+
+/// Crossfades between playlist entries using two mpv instances ("decks").
+///
+/// The controller owns the logical playlist; each deck only ever holds a single
+/// file. While one deck plays, the next entry is loaded paused in the other.
+/// When the current track gets within the fade length of its end, the idle deck
+/// starts and both volumes are ramped, after which the decks swap roles.
+///
+/// Only the primary deck renders into the window, so video entries always play
+/// there; an entry the secondary deck can't play is cut to instead of faded in.
+/// Like mpv, the playlist only wraps around when `loop-playlist` is set on the
+/// primary deck.
+pub struct CrossfadeController {
+    decks: [Arc<MpvPlayer>; 2],
+    state: Mutex<State>,
+    running: AtomicBool,
+    on_position_change: Mutex<Option<PositionCallback>>,
+    on_deck_change: Mutex<Option<DeckCallback>>,
+}
+
+impl CrossfadeController {
+    /// `primary` is the deck playback starts on; both players must already be initialized.
+    pub fn new(
+        primary: Arc<MpvPlayer>,
+        secondary: Arc<MpvPlayer>,
+        config: CrossfadeConfig,
+    ) -> Arc<Self> {
+        let volume = primary.get_volume().unwrap_or(100.0);
+
+        let controller = Arc::new(Self {
+            decks: [primary, secondary],
+            state: Mutex::new(State::new(config, volume)),
+            running: AtomicBool::new(true),
+            on_position_change: Mutex::new(None),
+            on_deck_change: Mutex::new(None),
+        });
+
+        controller.start_ticking();
+        controller
+    }
+
+    /// Ticks until `shutdown` is called or the controller is dropped.
+    fn start_ticking(self: &Arc<Self>) {
+        let controller = Arc::downgrade(self);
+        thread::spawn(move || {
+            while let Some(controller) = controller.upgrade() {
+                if !controller.running.load(Ordering::Relaxed) {
+                    break;
+                }
+                if let Err(e) = controller.update(|state| controller.tick(state)) {
+                    eprintln!("Crossfade tick failed: {}", e);
+                }
+                drop(controller);
+                thread::sleep(TICK_INTERVAL);
+            }
+        });
+    }
+
+    /// Runs `f` on the locked state, then reports the position and deck changes
+    /// it made once the lock is released, so the callbacks may call back in.
+    fn update<T>(&self, f: impl FnOnce(&mut State) -> Result<T, MpvError>) -> Result<T, MpvError> {
+        let mut state = self.state.lock().unwrap();
+        let active = state.active;
+        let result = f(&mut state);
+
+        let position = std::mem::take(&mut state.position_changed).then_some(state.position);
+        let deck = (state.active != active).then(|| self.decks[state.active].clone());
+        drop(state);
+
+        if let Some(position) = position {
+            if let Some(callback) = self.on_position_change.lock().unwrap().as_ref() {
+                callback(position);
+            }
+        }
+        if let Some(deck) = deck {
+            if let Some(callback) = self.on_deck_change.lock().unwrap().as_ref() {
+                callback(&deck);
+            }
+        }
+
+        result
+    }
+
+    /// Whether `player` is one of the two decks.
+    pub fn has_deck(&self, player: &Arc<MpvPlayer>) -> bool {
+        self.decks.iter().any(|deck| Arc::ptr_eq(deck, player))
+    }
+
+    /// The deck passed as `secondary` to `new`.
+    pub fn secondary_deck(&self) -> &Arc<MpvPlayer> {
+        &self.decks[1]
+    }
+
+    /// The deck the current track plays on.
+    pub fn active_deck(&self) -> Arc<MpvPlayer> {
+        self.decks[self.state.lock().unwrap().active].clone()
+    }
+
+    pub fn shutdown(&self) {
+        self.running.store(false, Ordering::Relaxed);
+        for deck in &self.decks {
+            let _ = deck.stop();
+        }
+    }
+
+    pub fn set_position_callback(&self, callback: impl Fn(Option<usize>) + Send + 'static) {
+        *self.on_position_change.lock().unwrap() = Some(Box::new(callback));
+    }
+
+    /// `callback` gets the deck playback moved to whenever the decks swap roles.
+    pub fn set_deck_callback(&self, callback: impl Fn(&Arc<MpvPlayer>) + Send + 'static) {
+        *self.on_deck_change.lock().unwrap() = Some(Box::new(callback));
+    }
+
+    pub fn config(&self) -> CrossfadeConfig {
+        self.state.lock().unwrap().config.clone()
+    }
+
+    pub fn set_config(&self, config: CrossfadeConfig) {
+        self.state.lock().unwrap().config = config.clamped();
+    }
+
+    pub fn state(&self) -> CrossfadeState {
+        let state = self.state.lock().unwrap();
+        CrossfadeState {
+            playlist_pos: state.position,
+            playlist_count: state.playlist.len(),
+            fading: state.fade.is_some(),
+            paused: state.paused,
+            volume: state.volume,
+        }
+    }
+
+    /// Replaces the playlist and starts playing `start` (if any).
+    pub fn set_playlist(&self, paths: Vec<String>, start: Option<usize>) -> Result<(), MpvError> {
+        self.update(|state| {
+            self.cancel_fade(state)?;
+            state.playlist = paths;
+            state.preloaded = None;
+
+            match start {
+                Some(index) => self.start_entry(state, index),
+                None => {
+                    for deck in &self.decks {
+                        deck.stop()?;
+                    }
+                    Self::set_position(state, None);
+                    Ok(())
+                }
+            }
+        })
+    }
+
+    pub fn get_playlist_pos(&self) -> Option<usize> {
+        self.state.lock().unwrap().position
+    }
+
+    /// Jumps to `index` immediately (a manual skip is never crossfaded).
+    pub fn set_playlist_pos(&self, index: usize) -> Result<(), MpvError> {
+        self.update(|state| {
+            self.cancel_fade(state)?;
+            self.start_entry(state, index)
+        })
+    }
+
+    /// Does nothing on the last entry, unless the playlist loops.
+    pub fn playlist_next(&self) -> Result<(), MpvError> {
+        self.update(|state| {
+            let next = match state.position {
+                Some(position) => self.next_index(state, position),
+                None => (!state.playlist.is_empty()).then_some(0),
+            };
+            let Some(next) = next else {
+                return Ok(());
+            };
+            self.cancel_fade(state)?;
+            self.start_entry(state, next)
+        })
+    }
+
+    /// Does nothing on the first entry, unless the playlist loops.
+    pub fn playlist_prev(&self) -> Result<(), MpvError> {
+        self.update(|state| {
+            let count = state.playlist.len();
+            let prev = match state.position {
+                Some(0) if self.loops_playlist() => count.checked_sub(1),
+                Some(0) => None,
+                Some(position) => Some(position - 1),
+                None => (count > 0).then_some(0),
+            };
+            let Some(prev) = prev else {
+                return Ok(());
+            };
+            self.cancel_fade(state)?;
+            self.start_entry(state, prev)
+        })
+    }
+
+    /// The entry after `position`, wrapping around if the playlist loops.
+    fn next_index(&self, state: &State, position: usize) -> Option<usize> {
+        let count = state.playlist.len();
+        // Only ask the deck when it matters
+        next_index(
+            count,
+            position,
+            position + 1 >= count && self.loops_playlist(),
+        )
+    }
+
+    /// Whether `loop-playlist` is set (to `inf`, `force` or a count) on the
+    /// primary deck, where the regular playlist commands would set it.
+    fn loops_playlist(&self) -> bool {
+        match self.decks[0].get_property_json("loop-playlist") {
+            Ok(Value::Bool(looping)) => looping,
+            Ok(Value::String(value)) => value != "no",
+            Ok(Value::Number(count)) => count.as_i64() != Some(1),
+            _ => false,
+        }
+    }
+
+    /// Seeks the current track. Seeking during a fade aborts it; the fade
+    /// starts again once the track gets close enough to its end.
+    pub fn seek(&self, position: f64) -> Result<(), MpvError> {
+        self.update(|state| {
+            self.cancel_fade(state)?;
+            self.decks[state.active].seek(position)
+        })
+    }
+
+    pub fn play(&self) -> Result<(), MpvError> {
+        self.update(|state| {
+            state.paused = false;
+            self.decks[state.active].play()?;
+            if state.fade.is_some() {
+                self.decks[1 - state.active].play()?;
+            }
+            Ok(())
+        })
+    }
+
+    pub fn pause(&self) -> Result<(), MpvError> {
+        self.update(|state| {
+            state.paused = true;
+            self.decks[state.active].pause()?;
+            if state.fade.is_some() {
+                self.decks[1 - state.active].pause()?;
+            }
+            Ok(())
+        })
+    }
+
+    pub fn stop(&self) -> Result<(), MpvError> {
+        self.update(|state| {
+            state.fade = None;
+            state.preloaded = None;
+            for deck in &self.decks {
+                deck.stop()?;
+            }
+            Self::set_position(state, None);
+            Ok(())
+        })
+    }
+
+    pub fn get_volume(&self) -> f64 {
+        self.state.lock().unwrap().volume
+    }
+
+    pub fn set_volume(&self, volume: f64) -> Result<(), MpvError> {
+        self.update(|state| {
+            state.volume = volume;
+            if state.fade.is_none() {
+                self.decks[state.active].set_volume(volume)?;
+            }
+            // During a fade the next tick applies the new master volume to both decks
+            Ok(())
+        })
+    }
+
+    fn tick(&self, state: &mut State) -> Result<(), MpvError> {
+        let Some(position) = state.position else {
+            return Ok(());
+        };
+        if state.paused {
+            return Ok(());
+        }
+
+        let active = &self.decks[state.active];
+        let idle = active.is_idle()?;
+        let times = match (idle, active.get_position(), active.get_duration()) {
+            (false, Ok(time_pos), Ok(duration)) => Some((time_pos, duration)),
+            _ => None,
+        };
+        if !idle {
+            state.active_started = true;
+        }
+
+        let next = self.next_index(state, position);
+        match Step::of(state, next, DeckStatus { idle, times }) {
+            Step::Wait => Ok(()),
+            Step::Cut(next) => self.start_entry(state, next),
+            Step::End => {
+                Self::set_position(state, None);
+                Ok(())
+            }
+            Step::StartFade { length, remaining } => {
+                let incoming = &self.decks[1 - state.active];
+                incoming.set_volume(0.0)?;
+                incoming.play()?;
+                state.fade = Some(Fade { length });
+                self.apply_gains(state, remaining)
+            }
+            Step::Fade { remaining } => self.apply_gains(state, remaining),
+            Step::FinishFade => self.finish_fade(state),
+        }
+    }
+
+    /// Sets the deck volumes for a fade with `remaining` seconds left on the outgoing track.
+    /// Progress is derived from the outgoing track's position, so pauses keep the
+    /// fade frozen where it was.
+    fn apply_gains(&self, state: &State, remaining: f64) -> Result<(), MpvError> {
+        let Some(fade) = &state.fade else {
+            return Ok(());
+        };
+        let t = 1.0 - remaining / fade.length;
+        let (outgoing, incoming) = state.config.curve.gains(t);
+
+        self.decks[state.active].set_volume(Self::mpv_volume(state.volume, outgoing))?;
+        self.decks[1 - state.active].set_volume(Self::mpv_volume(state.volume, incoming))
+    }
+
+    /// mpv's volume is cubic (`gain = (volume / 100)^3`), so an amplitude gain
+    /// needs its cube root to come out as intended.
+    fn mpv_volume(master: f64, gain: f64) -> f64 {
+        master * gain.max(0.0).cbrt()
+    }
+
+    fn finish_fade(&self, state: &mut State) -> Result<(), MpvError> {
+        let outgoing = state.active;
+        state.active = 1 - outgoing;
+        state.fade = None;
+
+        self.decks[outgoing].stop()?;
+        self.decks[state.active].set_volume(state.volume)?;
+        state.active_started = false;
+
+        let next = state.preloaded.take();
+        Self::set_position(state, next);
+        self.preload_next(state)
+    }
+
+    /// Stops an ongoing fade and rewinds the incoming track so it can fade in again later.
+    fn cancel_fade(&self, state: &mut State) -> Result<(), MpvError> {
+        if state.fade.take().is_none() {
+            return Ok(());
+        }
+
+        let incoming = &self.decks[1 - state.active];
+        incoming.pause()?;
+        incoming.seek(0.0)?;
+        incoming.set_volume(0.0)?;
+        self.decks[state.active].set_volume(state.volume)
+    }
+
+    /// Starts playing `index` right away on the active deck, or switches to the
+    /// idle deck if that entry is already preloaded there.
+    fn start_entry(&self, state: &mut State, index: usize) -> Result<(), MpvError> {
+        let Some(path) = state.playlist.get(index).cloned() else {
+            return Err(MpvError::invalid_argument(
+                "playlist-pos",
+                format!("Playlist position {} out of range", index),
+            ));
+        };
+
+        let deck = deck_for_entry(state, index, scanner::is_video_file(Path::new(&path)));
+        if deck != state.active {
+            self.decks[state.active].stop()?;
+            state.active = deck;
+        }
+        if state.preloaded == Some(index) {
+            state.preloaded = None;
+        } else {
+            self.decks[state.active].load_file(&path, Some(LoadMode::Replace))?;
+        }
+
+        state.active_started = false;
+
+        let active = &self.decks[state.active];
+        active.set_volume(state.volume)?;
+        if state.paused {
+            active.pause()?;
+        } else {
+            active.play()?;
+        }
+
+        Self::set_position(state, Some(index));
+        self.preload_next(state)
+    }
+
+    /// Loads the entry after the current one, paused and muted, in the idle deck.
+    /// Video isn't preloaded in the secondary deck, which has no window.
+    fn preload_next(&self, state: &mut State) -> Result<(), MpvError> {
+        let idle_index = 1 - state.active;
+        let idle = &self.decks[idle_index];
+        let next = state
+            .position
+            .and_then(|position| self.next_index(state, position))
+            // A looping playlist of one entry just restarts it
+            .filter(|&next| Some(next) != state.position)
+            .and_then(|next| state.playlist.get(next).map(|path| (next, path)))
+            .filter(|(_, path)| idle_index == 0 || !scanner::is_video_file(Path::new(path)));
+
+        match next {
+            Some((next, path)) => {
+                idle.pause()?;
+                idle.set_volume(0.0)?;
+                idle.load_file(path, Some(LoadMode::Replace))?;
+                state.preloaded = Some(next);
+            }
+            None => {
+                idle.stop()?;
+                state.preloaded = None;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reported by `update` once the state is unlocked.
+    fn set_position(state: &mut State, position: Option<usize>) {
+        state.position = position;
+        state.position_changed = true;
+    }
+}
+
+impl Drop for CrossfadeController {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+    }
+}
+
+type SharedPositionCallback = Arc<dyn Fn(Option<usize>) + Send + Sync + 'static>;
+type SharedDeckCallback = Arc<dyn Fn(&Arc<MpvPlayer>) + Send + Sync + 'static>;
+
+/// Owns the crossfade controller, which is created on first use since it
+/// needs a second mpv core, and the config while there is none.
+pub struct CrossfadeHandle {
+    lib_path: String,
+    player: PlayerHandle,
+    config: Mutex<CrossfadeConfig>,
+    controller: Mutex<Option<Arc<CrossfadeController>>>,
+    on_position_change: SharedPositionCallback,
+    on_deck_change: SharedDeckCallback,
+}
+
+impl CrossfadeHandle {
+    /// `on_deck_change` gets the deck playback moved to, including the main
+    /// player when a controller that left it is released.
+    pub fn new(
+        player: PlayerHandle,
+        lib_path: &str,
+        on_position_change: impl Fn(Option<usize>) + Send + Sync + 'static,
+        on_deck_change: impl Fn(&Arc<MpvPlayer>) + Send + Sync + 'static,
+    ) -> Self {
+        CrossfadeHandle {
+            lib_path: lib_path.to_string(),
+            player,
+            config: Mutex::new(CrossfadeConfig::default()),
+            controller: Mutex::new(None),
+            on_position_change: Arc::new(on_position_change),
+            on_deck_change: Arc::new(on_deck_change),
+        }
+    }
+
+    pub fn config(&self) -> CrossfadeConfig {
+        self.config.lock().unwrap().clone()
+    }
+
+    pub fn set_config(&self, config: CrossfadeConfig) {
+        let config = config.clamped();
+        if let Some(controller) = self.current() {
+            controller.set_config(config.clone());
+        }
+        *self.config.lock().unwrap() = config;
+    }
+
+    /// The state of the controller, or an empty one if there is none yet.
+    pub fn state(&self) -> CrossfadeState {
+        match self.current() {
+            Some(controller) => controller.state(),
+            None => CrossfadeState {
+                playlist_pos: None,
+                playlist_count: 0,
+                fading: false,
+                paused: false,
+                volume: self
+                    .player
+                    .get()
+                    .and_then(|player| player.get_volume())
+                    .unwrap_or(100.0),
+            },
+        }
+    }
+
+    /// The controller, if there is one for the running main player.
+    fn current(&self) -> Option<Arc<CrossfadeController>> {
+        let primary = self.player.get().ok()?;
+        self.controller
+            .lock()
+            .unwrap()
+            .clone()
+            .filter(|controller| controller.has_deck(&primary))
+    }
+
+    /// The controller, created if there is none for the running main player.
+    pub fn controller(&self) -> Result<Arc<CrossfadeController>, MpvError> {
+        let primary = self.player.get()?;
+
+        let mut current = self.controller.lock().unwrap();
+        if let Some(controller) = current.as_ref() {
+            if controller.has_deck(&primary) {
+                return Ok(controller.clone());
+            }
+        }
+        // The main player was re-created since; its old core is gone
+        if let Some(controller) = current.take() {
+            self.release(&controller);
+        }
+
+        let controller =
+            CrossfadeController::new(primary, self.create_secondary_deck()?, self.config());
+        let on_position_change = self.on_position_change.clone();
+        controller.set_position_callback(move |position| on_position_change(position));
+        let on_deck_change = self.on_deck_change.clone();
+        controller.set_deck_callback(move |deck| on_deck_change(deck));
+
+        *current = Some(controller.clone());
+        Ok(controller)
+    }
+
+    /// Stops crossfade playback on both decks and releases the controller.
+    pub fn disable(&self) {
+        let controller = self.controller.lock().unwrap().take();
+        if let Some(controller) = controller {
+            self.release(&controller);
+        }
+    }
+
+    /// Creates the second, audio-only deck. It has no window, so the controller
+    /// keeps video entries on the main player.
+    fn create_secondary_deck(&self) -> Result<Arc<MpvPlayer>, MpvError> {
+        let deck = MpvPlayer::new(&self.lib_path)?;
+        deck.set_option("vid", "no")?;
+        deck.set_option("force-window", "no")?;
+        deck.initialize()?;
+        Ok(deck)
+    }
+
+    /// Stops the controller and shuts down the secondary deck, which nothing
+    /// else uses, after moving back to the main player if it played last.
+    fn release(&self, controller: &CrossfadeController) {
+        controller.shutdown();
+
+        if let Ok(primary) = self.player.get() {
+            if controller.has_deck(&primary) && !Arc::ptr_eq(&controller.active_deck(), &primary) {
+                (self.on_deck_change)(&primary);
+            }
+        }
+
+        if let Err(e) = controller.secondary_deck().quit() {
+            eprintln!("Failed to shut down crossfade deck: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CURVES: [FadeCurve; 4] = [
+        FadeCurve::Linear,
+        FadeCurve::EqualPower,
+        FadeCurve::SCurve,
+        FadeCurve::Exponential,
+    ];
+
+    fn assert_gains(actual: (f64, f64), expected: (f64, f64)) {
+        assert!(
+            (actual.0 - expected.0).abs() < 1e-9 && (actual.1 - expected.1).abs() < 1e-9,
+            "{:?} != {:?}",
+            actual,
+            expected
+        );
+    }
+
+    /// Playing the first of `count` entries, the second preloaded.
+    fn playing(count: usize) -> State {
+        let mut state = State::new(CrossfadeConfig::default(), 100.0);
+        state.playlist = (0..count).map(|i| format!("{}.flac", i)).collect();
+        state.position = Some(0);
+        state.preloaded = (count > 1).then_some(1);
+        state.active_started = true;
+        state
+    }
+
+    fn at(time_pos: f64, duration: f64) -> DeckStatus {
+        DeckStatus {
+            idle: false,
+            times: Some((time_pos, duration)),
+        }
+    }
+
+    fn ended() -> DeckStatus {
+        DeckStatus {
+            idle: true,
+            times: None,
+        }
+    }
+
+    #[test]
+    fn curves_go_from_the_outgoing_to_the_incoming_track() {
+        for curve in CURVES {
+            assert_gains(curve.gains(0.0), (1.0, 0.0));
+            assert_gains(curve.gains(1.0), (0.0, 1.0));
+            // Progress out of range is clamped
+            assert_gains(curve.gains(-0.5), (1.0, 0.0));
+            assert_gains(curve.gains(1.5), (0.0, 1.0));
+
+            let mut previous = curve.gains(0.0);
+            for step in 1..=20 {
+                let gains = curve.gains(step as f64 / 20.0);
+                assert!(
+                    gains.0 <= previous.0 && gains.1 >= previous.1,
+                    "{:?}",
+                    curve
+                );
+                previous = gains;
+            }
+        }
+    }
+
+    #[test]
+    fn curves_have_their_shape_halfway() {
+        assert_gains(FadeCurve::Linear.gains(0.5), (0.5, 0.5));
+        assert_gains(FadeCurve::SCurve.gains(0.5), (0.5, 0.5));
+        assert_gains(FadeCurve::Exponential.gains(0.5), (0.25, 0.25));
+        assert_gains(FadeCurve::SCurve.gains(0.25), (0.84375, 0.15625));
+
+        for step in 0..=10 {
+            let (outgoing, incoming) = FadeCurve::EqualPower.gains(step as f64 / 10.0);
+            assert!((outgoing * outgoing + incoming * incoming - 1.0).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn clamps_the_fade_length() {
+        let config = |duration_secs| CrossfadeConfig {
+            duration_secs,
+            curve: FadeCurve::Linear,
+        };
+        assert_eq!(config(0.0).clamped().duration_secs, MIN_CROSSFADE_SECS);
+        assert_eq!(config(60.0).clamped().duration_secs, MAX_CROSSFADE_SECS);
+        assert_eq!(config(3.0).clamped().duration_secs, 3.0);
+    }
+
+    #[test]
+    fn finds_the_next_entry() {
+        assert_eq!(next_index(3, 0, false), Some(1));
+        assert_eq!(next_index(3, 2, false), None);
+        assert_eq!(next_index(3, 2, true), Some(0));
+        assert_eq!(next_index(0, 0, true), None);
+    }
+
+    #[test]
+    fn waits_until_the_fade_length_before_the_end() {
+        let state = playing(2);
+
+        assert_eq!(Step::of(&state, Some(1), at(10.0, 200.0)), Step::Wait);
+        assert_eq!(
+            Step::of(&state, Some(1), at(196.0, 200.0)),
+            Step::StartFade {
+                length: 5.0,
+                remaining: 4.0
+            }
+        );
+        // Nothing to fade into
+        assert_eq!(Step::of(&state, None, at(196.0, 200.0)), Step::Wait);
+    }
+
+    #[test]
+    fn does_not_fade_into_an_entry_that_is_not_preloaded() {
+        let mut state = playing(3);
+        state.preloaded = None;
+        assert_eq!(Step::of(&state, Some(1), at(199.0, 200.0)), Step::Wait);
+
+        // It is cut to once the track ended instead
+        assert_eq!(Step::of(&state, Some(1), ended()), Step::Cut(1));
+    }
+
+    #[test]
+    fn fades_short_tracks_over_half_their_length() {
+        let state = playing(2);
+
+        assert_eq!(Step::of(&state, Some(1), at(2.0, 6.0)), Step::Wait);
+        assert_eq!(
+            Step::of(&state, Some(1), at(3.5, 6.0)),
+            Step::StartFade {
+                length: 3.0,
+                remaining: 2.5
+            }
+        );
+    }
+
+    #[test]
+    fn finishes_the_fade_at_the_end_of_the_outgoing_track() {
+        let mut state = playing(2);
+        state.fade = Some(Fade { length: 5.0 });
+
+        assert_eq!(
+            Step::of(&state, Some(1), at(197.0, 200.0)),
+            Step::Fade { remaining: 3.0 }
+        );
+        assert_eq!(
+            Step::of(&state, Some(1), at(199.99, 200.0)),
+            Step::FinishFade
+        );
+        assert_eq!(Step::of(&state, Some(1), ended()), Step::FinishFade);
+    }
+
+    #[test]
+    fn ends_after_the_last_entry() {
+        let state = playing(1);
+        assert_eq!(Step::of(&state, None, ended()), Step::End);
+    }
+
+    #[test]
+    fn waits_while_paused_stopped_or_loading() {
+        let mut state = playing(2);
+        state.active_started = false;
+        // Loading is asynchronous, so the deck is still idle
+        assert_eq!(Step::of(&state, Some(1), ended()), Step::Wait);
+        state.active_started = true;
+        assert_eq!(
+            Step::of(
+                &state,
+                Some(1),
+                DeckStatus {
+                    idle: false,
+                    times: None
+                }
+            ),
+            Step::Wait
+        );
+
+        state.paused = true;
+        assert_eq!(Step::of(&state, Some(1), ended()), Step::Wait);
+        state.paused = false;
+        state.position = None;
+        assert_eq!(Step::of(&state, Some(1), ended()), Step::Wait);
+    }
+
+    #[test]
+    fn switches_decks_only_for_preloaded_entries_and_video() {
+        let mut state = playing(3);
+        assert_eq!(deck_for_entry(&state, 1, false), 1);
+        assert_eq!(deck_for_entry(&state, 2, false), 0);
+
+        state.active = 1;
+        state.preloaded = Some(2);
+        assert_eq!(deck_for_entry(&state, 2, false), 0);
+        assert_eq!(deck_for_entry(&state, 0, false), 1);
+        // Only the primary deck has a window
+        assert_eq!(deck_for_entry(&state, 0, true), 0);
+    }
+}
@@ -0,0 +1,99 @@
+use crate::crossfade::{CrossfadeConfig, CrossfadeHandle, CrossfadeState};
+use crate::mpv::MpvError;
+
+use tauri::State;
+
+/*
+ * While crossfading, the controller owns the playlist: the frontend should use
+ * these commands instead of the `mpv_*` playlist and transport commands.
+ */
+
+#[derive(Clone, serde::Serialize)]
+pub struct CrossfadePositionPayload {
+    pub playlist_pos: Option<usize>,
+}
+
+#[tauri::command]
+pub fn crossfade_get_config(crossfade: State<'_, CrossfadeHandle>) -> CrossfadeConfig {
+    crossfade.config()
+}
+
+#[tauri::command]
+pub fn crossfade_set_config(crossfade: State<'_, CrossfadeHandle>, config: CrossfadeConfig) {
+    crossfade.set_config(config);
+}
+
+#[tauri::command]
+pub fn crossfade_get_state(crossfade: State<'_, CrossfadeHandle>) -> CrossfadeState {
+    crossfade.state()
+}
+
+#[tauri::command]
+pub fn crossfade_set_playlist(
+    crossfade: State<'_, CrossfadeHandle>,
+    paths: Vec<String>,
+    start: Option<usize>,
+) -> Result<(), MpvError> {
+    crossfade.controller()?.set_playlist(paths, start)
+}
+
+#[tauri::command]
+pub fn crossfade_get_playlist_pos(crossfade: State<'_, CrossfadeHandle>) -> Option<usize> {
+    crossfade.state().playlist_pos
+}
+
+#[tauri::command]
+pub fn crossfade_set_playlist_pos(
+    crossfade: State<'_, CrossfadeHandle>,
+    pos: usize,
+) -> Result<(), MpvError> {
+    crossfade.controller()?.set_playlist_pos(pos)
+}
+
+#[tauri::command]
+pub fn crossfade_playlist_next(crossfade: State<'_, CrossfadeHandle>) -> Result<(), MpvError> {
+    crossfade.controller()?.playlist_next()
+}
+
+#[tauri::command]
+pub fn crossfade_playlist_prev(crossfade: State<'_, CrossfadeHandle>) -> Result<(), MpvError> {
+    crossfade.controller()?.playlist_prev()
+}
+
+#[tauri::command]
+pub fn crossfade_seek(
+    crossfade: State<'_, CrossfadeHandle>,
+    position: f64,
+) -> Result<(), MpvError> {
+    crossfade.controller()?.seek(position)
+}
+
+#[tauri::command]
+pub fn crossfade_play(crossfade: State<'_, CrossfadeHandle>) -> Result<(), MpvError> {
+    crossfade.controller()?.play()
+}
+
+#[tauri::command]
+pub fn crossfade_pause(crossfade: State<'_, CrossfadeHandle>) -> Result<(), MpvError> {
+    crossfade.controller()?.pause()
+}
+
+#[tauri::command]
+pub fn crossfade_stop(crossfade: State<'_, CrossfadeHandle>) -> Result<(), MpvError> {
+    crossfade.controller()?.stop()
+}
+
+#[tauri::command]
+pub fn crossfade_set_volume(
+    crossfade: State<'_, CrossfadeHandle>,
+    volume: f64,
+) -> Result<(), MpvError> {
+    crossfade.controller()?.set_volume(volume)
+}
+
+/// Stops crossfade playback on both decks and releases the controller, handing
+/// the main player back to the regular `mpv_*` commands.
+#[tauri::command]
+pub fn crossfade_disable(crossfade: State<'_, CrossfadeHandle>) {
+    crossfade.disable();
+}
@@ -0,0 +1,532 @@
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use thiserror::Error;
+
+/// CUE sheet timestamps are `MM:SS:FF` where `FF` is a CD frame (1/75 s).
+const FRAMES_PER_SECOND: f64 = 75.0;
+
+/// Folders whose sheets are kept parsed; the cache is emptied past this.
+const MAX_CACHED_DIRS: usize = 256;
+
+/// The sheets of each folder `find_cue_sheet` looked in, so scanning a folder
+/// doesn't parse all of its sheets again for every file.
+static SHEET_CACHE: Lazy<Mutex<HashMap<PathBuf, DirSheets>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// The `.cue` files of a folder as of its modification time. A sheet is parsed
+/// again when its own modification time changes.
+#[derive(Clone)]
+struct DirSheets {
+    modified: Option<SystemTime>,
+    sheets: Vec<CachedSheet>,
+}
+
+#[derive(Clone)]
+struct CachedSheet {
+    path: PathBuf,
+    parsed: bool,
+    modified: Option<SystemTime>,
+    /// `None` if it couldn't be read or parsed.
+    sheet: Option<Arc<CueSheet>>,
+}
+
+#[derive(Error, Debug)]
+pub enum CueError {
+    #[error("Failed to read cue sheet: {0}")]
+    IoError(#[from] std::io::Error),
+
+    #[error("Invalid cue sheet at line {line}: {message}")]
+    ParseError { line: usize, message: String },
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CueSheet {
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    pub songwriter: Option<String>,
+    pub genre: Option<String>,
+    pub date: Option<String>,
+    pub files: Vec<CueFile>,
+}
+
+#[derive(Debug, Clone)]
+pub struct CueFile {
+    /// Path of the referenced media file, resolved against the sheet's directory.
+    pub path: PathBuf,
+    pub file_type: Option<String>,
+    pub tracks: Vec<CueTrack>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CueTrack {
+    pub number: u32,
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    pub songwriter: Option<String>,
+    pub isrc: Option<String>,
+    /// `INDEX 00` (start of the pregap) in seconds, if present.
+    pub pregap: Option<f64>,
+    /// `INDEX 01` (start of the track proper) in seconds.
+    pub start: f64,
+}
+
+/// A track described by a CUE sheet; a time range within a single media file.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VirtualTrack {
+    pub path: String,
+    pub track: u32,
+    pub total_tracks: u32,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub start: f64,
+    /// End of the track in seconds; `None` means it runs to the end of the file.
+    pub end: Option<f64>,
+    pub duration: Option<f64>,
+}
+
+impl CueSheet {
+    /// Reads and parses a `.cue` file. Sheets are commonly not UTF-8, so
+    /// anything that fails to decode is read as Latin-1 instead.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self, CueError> {
+        let path = path.as_ref();
+        let bytes = fs::read(path)?;
+        let text = match String::from_utf8(bytes) {
+            Ok(text) => text,
+            Err(e) => e.into_bytes().iter().map(|&b| b as char).collect(),
+        };
+
+        let base_dir = path.parent().unwrap_or_else(|| Path::new(""));
+        Self::parse(&text, base_dir)
+    }
+
+    /// Parses the contents of a cue sheet. `FILE` entries are resolved relative to `base_dir`.
+    pub fn parse(text: &str, base_dir: &Path) -> Result<Self, CueError> {
+        let mut sheet = CueSheet::default();
+        let mut current_track: Option<CueTrack> = None;
+
+        let text = text.trim_start_matches('\u{feff}');
+
+        for (i, line) in text.lines().enumerate() {
+            let line_no = i + 1;
+            let tokens = tokenize(line);
+            let Some(keyword) = tokens.first() else {
+                continue;
+            };
+            let arg = tokens.get(1).cloned();
+
+            match keyword.to_ascii_uppercase().as_str() {
+                "REM" => {
+                    let value = tokens.get(2..).map(|rest| rest.join(" "));
+                    match arg.map(|a| a.to_ascii_uppercase()).as_deref() {
+                        Some("GENRE") => sheet.genre = value,
+                        Some("DATE") => sheet.date = value,
+                        _ => {}
+                    }
+                }
+                "FILE" => {
+                    Self::finish_track(&mut sheet, &mut current_track);
+                    let name =
+                        arg.ok_or_else(|| parse_error(line_no, "FILE without a file name"))?;
+                    sheet.files.push(CueFile {
+                        path: resolve_file(base_dir, &name),
+                        file_type: tokens.get(2).cloned(),
+                        tracks: Vec::new(),
+                    });
+                }
+                "TRACK" => {
+                    Self::finish_track(&mut sheet, &mut current_track);
+                    if sheet.files.is_empty() {
+                        return Err(parse_error(line_no, "TRACK before any FILE"));
+                    }
+                    let number = arg
+                        .and_then(|n| n.parse::<u32>().ok())
+                        .ok_or_else(|| parse_error(line_no, "TRACK without a valid number"))?;
+                    current_track = Some(CueTrack {
+                        number,
+                        ..Default::default()
+                    });
+                }
+                "INDEX" => {
+                    let track = current_track
+                        .as_mut()
+                        .ok_or_else(|| parse_error(line_no, "INDEX outside of a TRACK"))?;
+                    let index = arg.and_then(|n| n.parse::<u32>().ok());
+                    let time = tokens
+                        .get(2)
+                        .and_then(|t| parse_timestamp(t))
+                        .ok_or_else(|| {
+                            parse_error(line_no, "INDEX without a valid MM:SS:FF time")
+                        })?;
+                    match index {
+                        Some(0) => track.pregap = Some(time),
+                        Some(1) => track.start = time,
+                        _ => {}
+                    }
+                }
+                "TITLE" | "PERFORMER" | "SONGWRITER" | "ISRC" => {
+                    let keyword = keyword.to_ascii_uppercase();
+                    match current_track.as_mut() {
+                        Some(track) => match keyword.as_str() {
+                            "TITLE" => track.title = arg,
+                            "PERFORMER" => track.performer = arg,
+                            "SONGWRITER" => track.songwriter = arg,
+                            _ => track.isrc = arg,
+                        },
+                        None => match keyword.as_str() {
+                            "TITLE" => sheet.title = arg,
+                            "PERFORMER" => sheet.performer = arg,
+                            "SONGWRITER" => sheet.songwriter = arg,
+                            _ => {}
+                        },
+                    }
+                }
+                _ => {} // CATALOG, FLAGS, PREGAP, POSTGAP, CDTEXTFILE, ...
+            }
+        }
+
+        Self::finish_track(&mut sheet, &mut current_track);
+        Ok(sheet)
+    }
+
+    fn finish_track(sheet: &mut CueSheet, track: &mut Option<CueTrack>) {
+        if let (Some(track), Some(file)) = (track.take(), sheet.files.last_mut()) {
+            file.tracks.push(track);
+        }
+    }
+
+    /// Total number of tracks across all files of the sheet.
+    pub fn track_count(&self) -> usize {
+        self.files.iter().map(|f| f.tracks.len()).sum()
+    }
+
+    /// Whether any `FILE` entry of this sheet refers to `media_path`.
+    pub fn references(&self, media_path: &Path) -> bool {
+        self.files.iter().any(|f| same_file(&f.path, media_path))
+    }
+
+    /// Flattens the sheet into virtual tracks. A track ends where the next track
+    /// in the same file starts; the last track of each file has no `end`.
+    pub fn virtual_tracks(&self) -> Vec<VirtualTrack> {
+        let total_tracks = self.track_count() as u32;
+        let mut virtual_tracks = Vec::with_capacity(total_tracks as usize);
+
+        for file in &self.files {
+            for (i, track) in file.tracks.iter().enumerate() {
+                let end = file.tracks.get(i + 1).map(|next| next.start);
+                virtual_tracks.push(VirtualTrack {
+                    path: file.path.to_string_lossy().into_owned(),
+                    track: track.number,
+                    total_tracks,
+                    title: track.title.clone(),
+                    artist: track.performer.clone().or_else(|| self.performer.clone()),
+                    album: self.title.clone(),
+                    start: track.start,
+                    end,
+                    duration: end.map(|end| end - track.start),
+                });
+            }
+        }
+
+        virtual_tracks
+    }
+
+    /// Virtual tracks that belong to `media_path`, with the open end of the last
+    /// track closed off at `file_duration`.
+    pub fn virtual_tracks_for(&self, media_path: &Path, file_duration: f64) -> Vec<VirtualTrack> {
+        self.virtual_tracks()
+            .into_iter()
+            .filter(|t| same_file(Path::new(&t.path), media_path))
+            .map(|mut t| {
+                if t.end.is_none() && file_duration > t.start {
+                    t.end = Some(file_duration);
+                    t.duration = Some(file_duration - t.start);
+                }
+                t
+            })
+            .collect()
+    }
+}
+
+/// Looks for a cue sheet describing `media_path`: `<stem>.cue` or `<file name>.cue`
+/// next to the file first, then any other sheet in the same folder that references it.
+pub fn find_cue_sheet(media_path: &Path) -> Option<CueSheet> {
+    let dir = media_path.parent()?;
+    let file_name = media_path.file_name()?.to_string_lossy().into_owned();
+    let preferred = [
+        media_path.with_extension("cue"),
+        dir.join(format!("{}.cue", file_name)),
+    ];
+
+    // Copied out, so that folders are listed and sheets parsed without the lock
+    let dir_modified = modified(dir);
+    let cached = SHEET_CACHE
+        .lock()
+        .unwrap()
+        .get(dir)
+        .filter(|cached| cached.modified == dir_modified)
+        .cloned();
+    let mut cached = cached.unwrap_or_else(|| DirSheets {
+        modified: dir_modified,
+        sheets: list_cue_files(dir)
+            .into_iter()
+            .map(|path| CachedSheet {
+                path,
+                parsed: false,
+                modified: None,
+                sheet: None,
+            })
+            .collect(),
+    });
+
+    cached.sheets.sort_by_key(|c| {
+        preferred
+            .iter()
+            .position(|p| *p == c.path)
+            .unwrap_or(preferred.len())
+    });
+    let found = cached.sheets.iter_mut().find_map(|cached| {
+        let sheet_modified = modified(&cached.path);
+        if !cached.parsed || cached.modified != sheet_modified {
+            cached.parsed = true;
+            cached.modified = sheet_modified;
+            cached.sheet = CueSheet::from_path(&cached.path).ok().map(Arc::new);
+        }
+        cached
+            .sheet
+            .as_ref()
+            .filter(|sheet| sheet.references(media_path))
+            .map(|sheet| CueSheet::clone(sheet))
+    });
+
+    let mut cache = SHEET_CACHE.lock().unwrap();
+    if cache.len() >= MAX_CACHED_DIRS && !cache.contains_key(dir) {
+        cache.clear();
+    }
+    cache.insert(dir.to_path_buf(), cached);
+    found
+}
+
+fn list_cue_files(dir: &Path) -> Vec<PathBuf> {
+    fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .filter_map(|e| e.ok())
+                .map(|e| e.path())
+                .filter(|p| is_cue_file(p) && p.is_file())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+pub fn is_cue_file(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("cue"))
+}
+
+/// Parses `MM:SS:FF` into seconds.
+fn parse_timestamp(timestamp: &str) -> Option<f64> {
+    let mut parts = timestamp.split(':').map(|p| p.trim().parse::<u32>().ok());
+    let minutes = parts.next()??;
+    let seconds = parts.next()??;
+    let frames = parts.next()??;
+    if parts.next().is_some() || seconds >= 60 || frames >= FRAMES_PER_SECOND as u32 {
+        return None;
+    }
+
+    Some(minutes as f64 * 60.0 + seconds as f64 + frames as f64 / FRAMES_PER_SECOND)
+}
+
+/// Splits a cue sheet line into whitespace separated tokens, keeping quoted strings together.
+fn tokenize(line: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut chars = line.trim().chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '"' {
+            chars.next();
+            tokens.push(chars.by_ref().take_while(|&c| c != '"').collect());
+        } else {
+            let mut token = String::new();
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() {
+                    break;
+                }
+                token.push(c);
+                chars.next();
+            }
+            tokens.push(token);
+        }
+    }
+
+    tokens
+}
+
+/// Resolves a `FILE` entry. Rippers often leave the original name in the sheet
+/// (e.g. `album.wav`) after the audio is transcoded, so fall back to a file
+/// with the same stem in the same folder.
+fn resolve_file(base_dir: &Path, name: &str) -> PathBuf {
+    let path = base_dir.join(name);
+    if path.exists() {
+        return path;
+    }
+
+    let stem = path.file_stem().map(|s| s.to_os_string());
+    let sibling = fs::read_dir(base_dir).ok().and_then(|entries| {
+        entries.filter_map(|e| e.ok()).map(|e| e.path()).find(|p| {
+            !is_cue_file(p) && p.is_file() && p.file_stem().map(|s| s.to_os_string()) == stem
+        })
+    });
+
+    sibling.unwrap_or(path)
+}
+
+fn same_file(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+fn parse_error(line: usize, message: &str) -> CueError {
+    CueError::ParseError {
+        line,
+        message: message.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHEET: &str = r#"REM GENRE "Progressive Rock"
+REM DATE 1973
+PERFORMER "Pink Floyd"
+TITLE "The Dark Side of the Moon"
+FILE "album.flac" WAVE
+  TRACK 01 AUDIO
+    TITLE "Speak to Me"
+    INDEX 01 00:00:00
+  TRACK 02 AUDIO
+    TITLE "Breathe"
+    PERFORMER "David Gilmour"
+    INDEX 00 01:05:00
+    INDEX 01 01:07:37
+  TRACK 03 AUDIO
+    TITLE "On the Run"
+    INDEX 01 03:55:60
+"#;
+
+    #[test]
+    fn parses_album_and_tracks() {
+        // Sheets often start with a byte order mark
+        let sheet = CueSheet::parse(&format!("\u{feff}{}", SHEET), Path::new("/music")).unwrap();
+        assert_eq!(sheet.title.as_deref(), Some("The Dark Side of the Moon"));
+        assert_eq!(sheet.performer.as_deref(), Some("Pink Floyd"));
+        assert_eq!(sheet.genre.as_deref(), Some("Progressive Rock"));
+        assert_eq!(sheet.date.as_deref(), Some("1973"));
+        assert_eq!(sheet.files.len(), 1);
+        assert_eq!(sheet.files[0].path, Path::new("/music/album.flac"));
+        assert_eq!(sheet.files[0].file_type.as_deref(), Some("WAVE"));
+
+        let tracks = &sheet.files[0].tracks;
+        assert_eq!(tracks.len(), 3);
+        assert_eq!(tracks[1].number, 2);
+        assert_eq!(tracks[1].title.as_deref(), Some("Breathe"));
+        assert_eq!(tracks[1].pregap, Some(65.0));
+        assert!((tracks[1].start - (67.0 + 37.0 / 75.0)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn virtual_tracks_end_where_the_next_starts() {
+        let sheet = CueSheet::parse(SHEET, Path::new("/music")).unwrap();
+        let tracks = sheet.virtual_tracks();
+        assert_eq!(tracks.len(), 3);
+        assert!(tracks.iter().all(|t| t.total_tracks == 3));
+        assert_eq!(tracks[0].end, Some(tracks[1].start));
+        assert_eq!(tracks[0].artist.as_deref(), Some("Pink Floyd"));
+        assert_eq!(tracks[1].artist.as_deref(), Some("David Gilmour"));
+        assert_eq!(tracks[2].end, None);
+        assert_eq!(tracks[2].duration, None);
+
+        let closed = sheet.virtual_tracks_for(Path::new("/music/album.flac"), 300.0);
+        assert_eq!(closed[2].end, Some(300.0));
+        assert!((closed[2].duration.unwrap() - (300.0 - closed[2].start)).abs() < 1e-9);
+        assert!(sheet
+            .virtual_tracks_for(Path::new("/music/other.flac"), 300.0)
+            .is_empty());
+    }
+
+    #[test]
+    fn parses_timestamps() {
+        assert_eq!(parse_timestamp("00:00:00"), Some(0.0));
+        assert_eq!(parse_timestamp("02:30:75"), None);
+        assert_eq!(parse_timestamp("02:60:00"), None);
+        assert_eq!(parse_timestamp("02:30"), None);
+        assert_eq!(parse_timestamp("00:01:00:00"), None);
+        assert_eq!(parse_timestamp("120:00:15"), Some(7200.2));
+    }
+
+    #[test]
+    fn tokenizes_quoted_strings() {
+        assert_eq!(
+            tokenize(r#"  FILE "my album.flac" WAVE"#),
+            ["FILE", "my album.flac", "WAVE"]
+        );
+        assert_eq!(tokenize(r#"TITLE """#), ["TITLE", ""]);
+        assert!(tokenize("   ").is_empty());
+    }
+
+    #[test]
+    fn reports_the_line_of_errors() {
+        let error = CueSheet::parse("TITLE x\nTRACK 01 AUDIO", Path::new("")).unwrap_err();
+        assert!(matches!(error, CueError::ParseError { line: 2, .. }));
+
+        let error =
+            CueSheet::parse("FILE a.wav WAVE\nINDEX 01 00:00:00", Path::new("")).unwrap_err();
+        assert!(matches!(error, CueError::ParseError { line: 2, .. }));
+    }
+
+    #[test]
+    fn reads_latin1_sheets_and_finds_the_audio_by_stem() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("album.flac"), b"").unwrap();
+        fs::write(
+            dir.path().join("album.cue"),
+            b"TITLE \"Caf\xe9\"\nFILE \"album.wav\" WAVE\nTRACK 01 AUDIO\nINDEX 01 00:00:00\n",
+        )
+        .unwrap();
+
+        let sheet = CueSheet::from_path(dir.path().join("album.cue")).unwrap();
+        assert_eq!(sheet.title.as_deref(), Some("Caf\u{e9}"));
+        assert_eq!(sheet.files[0].path, dir.path().join("album.flac"));
+    }
+
+    #[test]
+    fn finds_sheets_that_reference_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let media = dir.path().join("disc 1.flac");
+        fs::write(&media, b"").unwrap();
+        fs::write(dir.path().join("notes.cue"), "garbage\nTRACK 01 AUDIO").unwrap();
+
+        fs::write(
+            dir.path().join("Whole Album.cue"),
+            "FILE \"disc 1.flac\" WAVE\nTRACK 01 AUDIO\nINDEX 01 00:00:00\n",
+        )
+        .unwrap();
+        let sheet = find_cue_sheet(&media).unwrap();
+        assert!(sheet.references(&media));
+        assert!(find_cue_sheet(&dir.path().join("disc 2.flac")).is_none());
+    }
+}
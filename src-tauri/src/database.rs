@@ -0,0 +1,81 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePool, SqlitePoolOptions};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Name of the database file in the app data directory. The webview opens the
+/// same file through `db_execute` (see `src/db/database.ts`).
+pub const DB_FILE_NAME: &str = "app.db";
+
+/// Opens a connection pool to the app database, creating the file if needed.
+///
+/// Tables used by the webview are created by the Drizzle migrations; tables
+/// owned by the Rust side are created by their stores with `CREATE TABLE IF NOT EXISTS`.
+pub async fn connect(db_path: &Path) -> Result<SqlitePool, sqlx::Error> {
+    if let Some(dir) = db_path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+
+    let options = SqliteConnectOptions::new()
+        .filename(db_path)
+        .create_if_missing(true)
+        // The webview writes to the same file from its own connections
+        .busy_timeout(Duration::from_secs(5));
+
+    let pool = SqlitePoolOptions::new()
+        .max_connections(4)
+        .connect_with(options)
+        .await?;
+
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS app_setting (
+            key TEXT PRIMARY KEY NOT NULL,
+            value TEXT NOT NULL
+        )",
+    )
+    .execute(&pool)
+    .await?;
+
+    Ok(pool)
+}
+
+/// Reads a JSON encoded setting. Missing or undecodable settings are `None`.
+pub async fn get_setting<T: DeserializeOwned>(
+    pool: &SqlitePool,
+    key: &str,
+) -> Result<Option<T>, sqlx::Error> {
+    let value: Option<String> = sqlx::query_scalar("SELECT value FROM app_setting WHERE key = ?")
+        .bind(key)
+        .fetch_optional(pool)
+        .await?;
+
+    Ok(value.and_then(|v| serde_json::from_str(&v).ok()))
+}
+
+pub async fn set_setting<T: Serialize>(
+    pool: &SqlitePool,
+    key: &str,
+    value: &T,
+) -> Result<(), sqlx::Error> {
+    let value = serde_json::to_string(value).map_err(|e| sqlx::Error::Protocol(e.to_string()))?;
+
+    sqlx::query(
+        "INSERT INTO app_setting (key, value) VALUES (?, ?)
+         ON CONFLICT(key) DO UPDATE SET value = excluded.value",
+    )
+    .bind(key)
+    .bind(value)
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Current time as seconds since the Unix epoch, the timestamp format of Rust-owned tables.
+pub fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
@@ -0,0 +1,215 @@
+use serde::Serialize;
+
+use crate::metadata::{self, SimplifiedMetadata};
+
+/// A transition between two playlist entries that mpv cannot play gaplessly
+/// (with `gapless-audio=weak`) because the audio output has to be reconfigured.
+#[derive(Serialize, Debug, Clone)]
+pub struct GaplessBreak {
+    /// Index of the entry that starts after the break.
+    pub index: usize,
+    pub from: String,
+    pub to: String,
+    pub changes: Vec<FormatChange>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct FormatChange {
+    pub field: String,
+    pub from: Option<u32>,
+    pub to: Option<u32>,
+}
+
+/// Compares the audio format of each pair of consecutive entries in `paths`.
+/// Files whose metadata can't be read are skipped rather than reported.
+pub async fn detect_gapless_breaks(paths: &[String]) -> Vec<GaplessBreak> {
+    let mut breaks = Vec::new();
+    let mut previous: Option<(&String, SimplifiedMetadata)> = None;
+
+    for (index, path) in paths.iter().enumerate() {
+        let current = match metadata::parse_metadata(path).await {
+            Ok(metadata) => metadata,
+            Err(_) => {
+                previous = None;
+                continue;
+            }
+        };
+
+        if let Some((previous_path, previous_metadata)) = &previous {
+            let changes = format_changes(previous_metadata, &current);
+            if !changes.is_empty() {
+                breaks.push(GaplessBreak {
+                    index,
+                    from: previous_path.to_string(),
+                    to: path.clone(),
+                    changes,
+                });
+            }
+        }
+
+        previous = Some((path, current));
+    }
+
+    breaks
+}
+
+fn format_changes(from: &SimplifiedMetadata, to: &SimplifiedMetadata) -> Vec<FormatChange> {
+    let fields = [
+        ("sample_rate", from.sample_rate, to.sample_rate),
+        (
+            "channels",
+            from.channels.map(u32::from),
+            to.channels.map(u32::from),
+        ),
+        (
+            "bit_depth",
+            from.bit_depth.map(u32::from),
+            to.bit_depth.map(u32::from),
+        ),
+    ];
+
+    fields
+        .into_iter()
+        // Unknown values (e.g. bit depth of lossy formats) can't be compared
+        .filter(|(_, from, to)| from.is_some() && to.is_some() && from != to)
+        .map(|(field, from, to)| FormatChange {
+            field: field.to_string(),
+            from,
+            to,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mpv::test_support::{self, SINE_AMPLITUDE, SINE_FREQUENCY};
+    use crate::mpv::{LoadMode, MpvEventId, PlaybackContinuity};
+    use std::f64::consts::TAU;
+    use std::fs;
+    use std::path::Path;
+    use std::sync::mpsc;
+    use std::time::{Duration, Instant};
+
+    fn write_sine(path: &Path, sample_rate: u32, offset: usize, count: usize) {
+        fs::write(path, test_support::sine_wav(sample_rate, offset, count)).unwrap();
+    }
+
+    fn detect(paths: &[String]) -> Vec<GaplessBreak> {
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(detect_gapless_breaks(paths))
+    }
+
+    #[test]
+    fn reports_sample_rate_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let paths: Vec<String> = [("a.wav", 44_100), ("b.wav", 44_100), ("c.wav", 48_000)]
+            .iter()
+            .map(|(name, rate)| {
+                let path = dir.path().join(name);
+                write_sine(&path, *rate, 0, 4410);
+                path.to_string_lossy().into_owned()
+            })
+            .collect();
+
+        let breaks = detect(&paths);
+        assert_eq!(breaks.len(), 1);
+        assert_eq!(breaks[0].index, 2);
+        assert_eq!(breaks[0].from, paths[1]);
+        assert_eq!(breaks[0].changes.len(), 1);
+        assert_eq!(breaks[0].changes[0].field, "sample_rate");
+        assert_eq!(breaks[0].changes[0].from, Some(44_100));
+        assert_eq!(breaks[0].changes[0].to, Some(48_000));
+    }
+
+    #[test]
+    fn plays_consecutive_tracks_without_a_gap() {
+        let Some(player) = test_support::player() else {
+            return;
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let rate = 44_100;
+        let count = rate as usize / 2;
+        let paths: Vec<String> = (0..3)
+            .map(|i| {
+                let path = dir.path().join(format!("{}.wav", i));
+                write_sine(&path, rate, i * count, count);
+                path.to_string_lossy().into_owned()
+            })
+            .collect();
+        assert!(detect(&paths).is_empty());
+
+        // Raw samples in the input format, written as fast as they are decoded
+        let output = dir.path().join("output.pcm");
+        for (name, value) in [
+            ("config", "no"),
+            ("load-scripts", "no"),
+            ("vo", "null"),
+            ("ao", "pcm"),
+            ("ao-pcm-waveheader", "no"),
+            ("ao-pcm-append", "yes"),
+            ("audio-format", "s16"),
+            ("audio-samplerate", "44100"),
+            ("audio-channels", "mono"),
+            ("idle", "no"),
+        ] {
+            player.set_option(name, value).unwrap();
+        }
+        player
+            .set_option("ao-pcm-file", &output.to_string_lossy())
+            .unwrap();
+        player.initialize().unwrap();
+        player
+            .set_playback_continuity(&PlaybackContinuity::default())
+            .unwrap();
+
+        let (sender, receiver) = mpsc::channel();
+        player
+            .register_event_callback(MpvEventId::Shutdown, move |_| {
+                let _ = sender.send(());
+            })
+            .unwrap();
+        player
+            .load_file(&paths[0], Some(LoadMode::Replace))
+            .unwrap();
+        for path in &paths[1..] {
+            player.load_file(path, Some(LoadMode::Append)).unwrap();
+        }
+        // Without `idle` the core shuts down after the last entry
+        receiver.recv_timeout(Duration::from_secs(30)).unwrap();
+        drop(player);
+
+        // The output is closed once the core is destroyed, on whichever thread
+        // drops it last
+        let expected = paths.len() * count * 2;
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let mut pcm = Vec::new();
+        while Instant::now() < deadline {
+            pcm = fs::read(&output).unwrap_or_default();
+            if pcm.len() >= expected {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        let samples: Vec<i16> = pcm
+            .chunks_exact(2)
+            .map(|b| i16::from_le_bytes([b[0], b[1]]))
+            .collect();
+
+        // A gap shows up as inserted silence, a restarted wave or missing samples
+        let missing = (paths.len() * count).abs_diff(samples.len());
+        assert!(missing <= rate as usize / 1000, "{} samples off", missing);
+        let max_step = SINE_AMPLITUDE * TAU * SINE_FREQUENCY / rate as f64 + 2.0;
+        for (i, pair) in samples.windows(2).enumerate() {
+            let step = (pair[1] as f64 - pair[0] as f64).abs();
+            assert!(
+                step <= max_step,
+                "discontinuity at sample {}: {} -> {}",
+                i + 1,
+                pair[0],
+                pair[1]
+            );
+        }
+    }
+}
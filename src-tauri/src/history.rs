@@ -0,0 +1,599 @@
+use serde::Serialize;
+use sqlx::SqlitePool;
+use std::sync::{Arc, Mutex};
+use tokio::runtime::Handle;
+
+use crate::database;
+use crate::mpv::{EndFileReason, MpvError, MpvEventId, MpvFormat, MpvPlayer};
+use crate::player_handle::FollowedPlayer;
+
+/// A position jump larger than this between two `time-pos` updates is a seek,
+/// not playback, and is not counted as listened time.
+const MAX_POSITION_STEP_SECS: f64 = 2.0;
+
+/// A play that was stopped before reaching this much of the track (or
+/// `SKIP_MAX_SECS`, whichever is shorter) counts as skipped.
+const SKIP_FRACTION: f64 = 0.5;
+const SKIP_MAX_SECS: f64 = 240.0;
+
+/// One play of a file, as stored in `play_history`.
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct HistoryEntry {
+    pub id: i64,
+    pub path: String,
+    /// Unix timestamp (seconds).
+    pub started_at: i64,
+    pub listened_secs: f64,
+    pub duration: Option<f64>,
+    pub skipped: bool,
+    pub completed: bool,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct TrackPlayCount {
+    pub path: String,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub plays: i64,
+    pub listened_secs: f64,
+}
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct ArtistPlayCount {
+    pub artist: String,
+    pub plays: i64,
+    pub listened_secs: f64,
+}
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct AlbumPlayCount {
+    pub album: String,
+    pub artist: Option<String>,
+    pub plays: i64,
+    pub listened_secs: f64,
+}
+
+/// Play history persisted in the `play_history` table of the app database.
+/// Titles, artists and albums come from the `media_info` cache.
+///
+/// Time windows are `[since, until)` in Unix seconds; `None` leaves that side open.
+/// Skipped plays are excluded from the "most played" counts.
+pub struct HistoryStore {
+    pool: SqlitePool,
+}
+
+impl HistoryStore {
+    pub async fn new(pool: SqlitePool) -> Result<Self, sqlx::Error> {
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS play_history (
+                id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+                path TEXT NOT NULL,
+                started_at INTEGER NOT NULL,
+                listened_secs REAL NOT NULL DEFAULT 0,
+                duration REAL,
+                skipped INTEGER NOT NULL DEFAULT 0,
+                completed INTEGER NOT NULL DEFAULT 0
+            )",
+        )
+        .execute(&pool)
+        .await?;
+
+        sqlx::query(
+            "CREATE INDEX IF NOT EXISTS play_history_started_at_idx ON play_history (started_at)",
+        )
+        .execute(&pool)
+        .await?;
+
+        sqlx::query("CREATE INDEX IF NOT EXISTS play_history_path_idx ON play_history (path)")
+            .execute(&pool)
+            .await?;
+
+        Ok(Self { pool })
+    }
+
+    pub async fn record(&self, play: &PlayRecord) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "INSERT INTO play_history (path, started_at, listened_secs, duration, skipped, completed)
+             VALUES (?, ?, ?, ?, ?, ?)",
+        )
+        .bind(&play.path)
+        .bind(play.started_at)
+        .bind(play.listened_secs)
+        .bind(play.duration)
+        .bind(play.skipped)
+        .bind(play.completed)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn recently_played(&self, limit: i64) -> Result<Vec<HistoryEntry>, sqlx::Error> {
+        sqlx::query_as::<_, HistoryEntry>(
+            "SELECT h.id, h.path, h.started_at, h.listened_secs, h.duration, h.skipped, h.completed,
+                    m.title, m.artist, m.album
+             FROM play_history h
+             LEFT JOIN media_info m ON m.path = h.path
+             ORDER BY h.started_at DESC, h.id DESC
+             LIMIT ?",
+        )
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    pub async fn most_played_tracks(
+        &self,
+        since: Option<i64>,
+        until: Option<i64>,
+        limit: i64,
+    ) -> Result<Vec<TrackPlayCount>, sqlx::Error> {
+        sqlx::query_as::<_, TrackPlayCount>(
+            "SELECT h.path, m.title, m.artist, m.album,
+                    COUNT(*) AS plays, SUM(h.listened_secs) AS listened_secs
+             FROM play_history h
+             LEFT JOIN media_info m ON m.path = h.path
+             WHERE h.skipped = 0 AND h.started_at >= ? AND h.started_at < ?
+             GROUP BY h.path
+             ORDER BY plays DESC, listened_secs DESC
+             LIMIT ?",
+        )
+        .bind(since.unwrap_or(i64::MIN))
+        .bind(until.unwrap_or(i64::MAX))
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    pub async fn most_played_artists(
+        &self,
+        since: Option<i64>,
+        until: Option<i64>,
+        limit: i64,
+    ) -> Result<Vec<ArtistPlayCount>, sqlx::Error> {
+        sqlx::query_as::<_, ArtistPlayCount>(
+            "SELECT m.artist AS artist,
+                    COUNT(*) AS plays, SUM(h.listened_secs) AS listened_secs
+             FROM play_history h
+             JOIN media_info m ON m.path = h.path
+             WHERE h.skipped = 0 AND h.started_at >= ? AND h.started_at < ?
+                   AND m.artist IS NOT NULL
+             GROUP BY m.artist
+             ORDER BY plays DESC, listened_secs DESC
+             LIMIT ?",
+        )
+        .bind(since.unwrap_or(i64::MIN))
+        .bind(until.unwrap_or(i64::MAX))
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    /// Albums are told apart by their album artist (or artist, if it isn't
+    /// tagged), so same-named albums by different artists count separately.
+    pub async fn most_played_albums(
+        &self,
+        since: Option<i64>,
+        until: Option<i64>,
+        limit: i64,
+    ) -> Result<Vec<AlbumPlayCount>, sqlx::Error> {
+        sqlx::query_as::<_, AlbumPlayCount>(
+            "SELECT m.album AS album, COALESCE(m.album_artist, m.artist) AS artist,
+                    COUNT(*) AS plays, SUM(h.listened_secs) AS listened_secs
+             FROM play_history h
+             JOIN media_info m ON m.path = h.path
+             WHERE h.skipped = 0 AND h.started_at >= ? AND h.started_at < ?
+                   AND m.album IS NOT NULL
+             GROUP BY m.album, COALESCE(m.album_artist, m.artist)
+             ORDER BY plays DESC, listened_secs DESC
+             LIMIT ?",
+        )
+        .bind(since.unwrap_or(i64::MIN))
+        .bind(until.unwrap_or(i64::MAX))
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    /// Total seconds listened, skipped plays included.
+    pub async fn total_listening_time(
+        &self,
+        since: Option<i64>,
+        until: Option<i64>,
+    ) -> Result<f64, sqlx::Error> {
+        sqlx::query_scalar(
+            "SELECT COALESCE(SUM(listened_secs), 0.0) FROM play_history
+             WHERE started_at >= ? AND started_at < ?",
+        )
+        .bind(since.unwrap_or(i64::MIN))
+        .bind(until.unwrap_or(i64::MAX))
+        .fetch_one(&self.pool)
+        .await
+    }
+}
+
+/// A finished play, ready to be written to `play_history`.
+#[derive(Debug, Clone)]
+pub struct PlayRecord {
+    pub path: String,
+    pub started_at: i64,
+    pub listened_secs: f64,
+    pub duration: Option<f64>,
+    pub skipped: bool,
+    pub completed: bool,
+}
+
+/// The play in progress.
+struct Session {
+    /// Only known once the file has loaded.
+    path: Option<String>,
+    started_at: i64,
+    listened_secs: f64,
+    duration: Option<f64>,
+    last_position: Option<f64>,
+}
+
+impl Session {
+    fn finish(self, reason: Option<EndFileReason>) -> Option<PlayRecord> {
+        let completed = reason == Some(EndFileReason::Eof);
+        let skip_threshold = self
+            .duration
+            .map_or(SKIP_MAX_SECS, |d| (d * SKIP_FRACTION).min(SKIP_MAX_SECS));
+
+        Some(PlayRecord {
+            path: self.path?,
+            started_at: self.started_at,
+            listened_secs: self.listened_secs,
+            duration: self.duration,
+            skipped: !completed
+                && reason == Some(EndFileReason::Stop)
+                && self.listened_secs < skip_threshold,
+            completed,
+        })
+    }
+}
+
+/// Records a history entry for every file played, from mpv's `StartFile`,
+/// `EndFile` and `time-pos` events.
+pub struct HistoryTracker {
+    store: Arc<HistoryStore>,
+    runtime: Handle,
+    session: Mutex<Option<Session>>,
+    followed: FollowedPlayer,
+}
+
+impl HistoryTracker {
+    pub fn new(store: Arc<HistoryStore>, runtime: Handle) -> Arc<Self> {
+        Arc::new(Self {
+            store,
+            runtime,
+            session: Mutex::new(None),
+            followed: FollowedPlayer::default(),
+        })
+    }
+
+    /// Hooks the tracker into `player`'s events and follows it from now on.
+    /// Called again for every new core and whenever a crossfade moves playback
+    /// to the other deck; a file already playing there starts a new play.
+    pub fn attach(self: &Arc<Self>, player: &Arc<MpvPlayer>) -> Result<(), MpvError> {
+        let new = self.followed.follow(player);
+
+        // A play still open on the previously followed player ended with it
+        self.finish(Some(EndFileReason::Quit), false);
+        if !player.is_idle().unwrap_or(true) {
+            *self.session.lock().unwrap() = Some(Session {
+                path: player.get_path().ok(),
+                started_at: database::unix_now(),
+                listened_secs: 0.0,
+                duration: player.get_duration().ok(),
+                last_position: player.get_position().ok(),
+            });
+        }
+
+        if !new {
+            return Ok(());
+        }
+
+        let (t, p) = (self.clone(), Arc::downgrade(player));
+        player.register_event_callback(MpvEventId::StartFile, move |_| {
+            if !t.followed.is_followed(&p) {
+                return;
+            }
+            *t.session.lock().unwrap() = Some(Session {
+                path: None,
+                started_at: database::unix_now(),
+                listened_secs: 0.0,
+                duration: None,
+                last_position: None,
+            });
+        })?;
+
+        let t = self.clone();
+        let player_ref = Arc::downgrade(player);
+        player.register_event_callback(MpvEventId::FileLoaded, move |_| {
+            if !t.followed.is_followed(&player_ref) {
+                return;
+            }
+            let Some(player) = player_ref.upgrade() else {
+                return;
+            };
+            let path = player.get_path().ok();
+            let duration = player.get_duration().ok();
+            if let Some(session) = t.session.lock().unwrap().as_mut() {
+                session.path = path;
+                session.duration = duration;
+            }
+        })?;
+
+        let (t, p) = (self.clone(), Arc::downgrade(player));
+        player.register_event_callback(MpvEventId::Seek, move |_| {
+            if !t.followed.is_followed(&p) {
+                return;
+            }
+            if let Some(session) = t.session.lock().unwrap().as_mut() {
+                session.last_position = None;
+            }
+        })?;
+
+        let (t, p) = (self.clone(), Arc::downgrade(player));
+        player.on_property_change("time-pos", MpvFormat::Double, move |value| {
+            let Some(position) = value.as_f64().filter(|_| t.followed.is_followed(&p)) else {
+                return;
+            };
+            if let Some(session) = t.session.lock().unwrap().as_mut() {
+                if let Some(last) = session.last_position {
+                    let step = position - last;
+                    if step > 0.0 && step <= MAX_POSITION_STEP_SECS {
+                        session.listened_secs += step;
+                    }
+                }
+                session.last_position = Some(position);
+            }
+        })?;
+
+        let (t, p) = (self.clone(), Arc::downgrade(player));
+        player.register_event_callback(MpvEventId::EndFile, move |event| {
+            if !t.followed.is_followed(&p) {
+                return;
+            }
+            let reason = event.end_file_reason();
+            // Files that failed to load were never played
+            if reason == Some(EndFileReason::Error) {
+                t.session.lock().unwrap().take();
+                return;
+            }
+            t.finish(reason, false);
+        })?;
+
+        Ok(())
+    }
+
+    fn finish(&self, reason: Option<EndFileReason>, wait: bool) {
+        let Some(record) = self
+            .session
+            .lock()
+            .unwrap()
+            .take()
+            .and_then(|session| session.finish(reason))
+        else {
+            return;
+        };
+
+        let store = self.store.clone();
+        let write = async move {
+            if let Err(e) = store.record(&record).await {
+                eprintln!("Failed to record play of {}: {}", record.path, e);
+            }
+        };
+
+        if wait {
+            self.runtime.block_on(write);
+        } else {
+            self.runtime.spawn(write);
+        }
+    }
+
+    /// Records the play in progress and waits for the write. Called on app exit.
+    pub fn flush_now(&self) {
+        self.finish(Some(EndFileReason::Quit), true);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::runtime::Runtime;
+
+    const MIGRATIONS: [&str; 2] = [
+        include_str!("../migrations/0000_secret_lucky_pierre.sql"),
+        include_str!("../migrations/0001_quiet_stardust.sql"),
+    ];
+
+    fn session(listened_secs: f64, duration: Option<f64>) -> Session {
+        Session {
+            path: Some("a.flac".to_string()),
+            started_at: 1000,
+            listened_secs,
+            duration,
+            last_position: None,
+        }
+    }
+
+    fn play(path: &str, started_at: i64, listened_secs: f64, skipped: bool) -> PlayRecord {
+        PlayRecord {
+            path: path.to_string(),
+            started_at,
+            listened_secs,
+            duration: Some(200.0),
+            skipped,
+            completed: !skipped,
+        }
+    }
+
+    async fn store(dir: &std::path::Path) -> HistoryStore {
+        let pool = database::connect(&dir.join("app.db")).await.unwrap();
+        for migration in MIGRATIONS {
+            for statement in migration.split("--> statement-breakpoint") {
+                sqlx::query(statement).execute(&pool).await.unwrap();
+            }
+        }
+
+        let media = [
+            ("a.flac", "Intro", "Band", None, "Greatest Hits"),
+            (
+                "b.flac",
+                "Outro",
+                "Band feat. Guest",
+                Some("Band"),
+                "Greatest Hits",
+            ),
+            ("c.flac", "Other", "Other Band", None, "Greatest Hits"),
+        ];
+        for (path, title, artist, album_artist, album) in media {
+            sqlx::query(
+                "INSERT INTO media_info (path, title, artist, album_artist, album)
+                 VALUES (?, ?, ?, ?, ?)",
+            )
+            .bind(path)
+            .bind(title)
+            .bind(artist)
+            .bind(album_artist)
+            .bind(album)
+            .execute(&pool)
+            .await
+            .unwrap();
+        }
+
+        HistoryStore::new(pool).await.unwrap()
+    }
+
+    #[test]
+    fn counts_plays_stopped_early_as_skipped() {
+        let stopped = Some(EndFileReason::Stop);
+
+        let record = session(99.0, Some(200.0)).finish(stopped).unwrap();
+        assert!(record.skipped && !record.completed);
+        assert!(!session(100.0, Some(200.0)).finish(stopped).unwrap().skipped);
+        // The threshold is capped for long tracks
+        assert!(
+            !session(240.0, Some(3600.0))
+                .finish(stopped)
+                .unwrap()
+                .skipped
+        );
+        assert!(session(239.0, None).finish(stopped).unwrap().skipped);
+    }
+
+    #[test]
+    fn only_counts_stops_as_skips() {
+        let record = session(5.0, Some(200.0))
+            .finish(Some(EndFileReason::Eof))
+            .unwrap();
+        assert!(record.completed && !record.skipped);
+
+        let record = session(5.0, Some(200.0))
+            .finish(Some(EndFileReason::Quit))
+            .unwrap();
+        assert!(!record.completed && !record.skipped);
+    }
+
+    #[test]
+    fn records_nothing_for_files_that_never_loaded() {
+        let session = Session {
+            path: None,
+            ..session(5.0, None)
+        };
+        assert!(session.finish(Some(EndFileReason::Eof)).is_none());
+    }
+
+    #[test]
+    fn counts_plays_per_track_artist_and_album() {
+        let dir = tempfile::tempdir().unwrap();
+        Runtime::new().unwrap().block_on(async {
+            let store = store(dir.path()).await;
+            for record in [
+                play("a.flac", 100, 200.0, false),
+                play("a.flac", 200, 150.0, false),
+                play("a.flac", 300, 10.0, true),
+                play("b.flac", 400, 200.0, false),
+                play("c.flac", 500, 200.0, false),
+            ] {
+                store.record(&record).await.unwrap();
+            }
+
+            let tracks = store.most_played_tracks(None, None, 10).await.unwrap();
+            assert_eq!(tracks[0].path, "a.flac");
+            assert_eq!(tracks[0].title.as_deref(), Some("Intro"));
+            // Skipped plays are left out of the counts
+            assert_eq!(tracks[0].plays, 2);
+            assert_eq!(tracks[0].listened_secs, 350.0);
+
+            let artists = store.most_played_artists(None, None, 10).await.unwrap();
+            let artists: Vec<_> = artists
+                .iter()
+                .map(|a| (a.artist.as_str(), a.plays))
+                .collect();
+            assert_eq!(artists[0], ("Band", 2));
+            assert_eq!(artists.len(), 3);
+
+            // The same album name by another artist is another album
+            let albums = store.most_played_albums(None, None, 10).await.unwrap();
+            let albums: Vec<_> = albums
+                .iter()
+                .map(|a| (a.album.as_str(), a.artist.as_deref(), a.plays))
+                .collect();
+            assert_eq!(
+                albums,
+                [
+                    ("Greatest Hits", Some("Band"), 3),
+                    ("Greatest Hits", Some("Other Band"), 1)
+                ]
+            );
+
+            let recent = store.recently_played(2).await.unwrap();
+            let recent: Vec<_> = recent.iter().map(|e| e.path.as_str()).collect();
+            assert_eq!(recent, ["c.flac", "b.flac"]);
+        });
+    }
+
+    #[test]
+    fn limits_statistics_to_the_time_window() {
+        let dir = tempfile::tempdir().unwrap();
+        Runtime::new().unwrap().block_on(async {
+            let store = store(dir.path()).await;
+            for record in [
+                play("a.flac", 100, 60.0, false),
+                play("b.flac", 200, 30.0, true),
+                play("c.flac", 300, 45.0, false),
+            ] {
+                store.record(&record).await.unwrap();
+            }
+
+            // Skipped plays still count as listening time
+            assert_eq!(store.total_listening_time(None, None).await.unwrap(), 135.0);
+            assert_eq!(
+                store.total_listening_time(Some(200), None).await.unwrap(),
+                75.0
+            );
+            assert_eq!(
+                store.total_listening_time(None, Some(300)).await.unwrap(),
+                90.0
+            );
+            assert_eq!(
+                store.total_listening_time(Some(400), None).await.unwrap(),
+                0.0
+            );
+
+            let tracks = store
+                .most_played_tracks(Some(100), Some(300), 10)
+                .await
+                .unwrap();
+            let tracks: Vec<_> = tracks.iter().map(|t| t.path.as_str()).collect();
+            assert_eq!(tracks, ["a.flac"]);
+        });
+    }
+}
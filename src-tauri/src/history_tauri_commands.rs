@@ -0,0 +1,71 @@
+use crate::history::{AlbumPlayCount, ArtistPlayCount, HistoryEntry, HistoryStore, TrackPlayCount};
+
+use std::sync::Arc;
+use tauri::State;
+
+/*
+ * Time windows (`since`/`until`) are Unix timestamps in seconds; leave either
+ * out for an open-ended window, e.g. `since` = Jan 1st for a yearly summary.
+ */
+
+#[tauri::command]
+pub async fn history_recently_played(
+    store: State<'_, Arc<HistoryStore>>,
+    limit: i64,
+) -> Result<Vec<HistoryEntry>, String> {
+    store
+        .recently_played(limit)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub async fn history_most_played_tracks(
+    store: State<'_, Arc<HistoryStore>>,
+    since: Option<i64>,
+    until: Option<i64>,
+    limit: i64,
+) -> Result<Vec<TrackPlayCount>, String> {
+    store
+        .most_played_tracks(since, until, limit)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub async fn history_most_played_artists(
+    store: State<'_, Arc<HistoryStore>>,
+    since: Option<i64>,
+    until: Option<i64>,
+    limit: i64,
+) -> Result<Vec<ArtistPlayCount>, String> {
+    store
+        .most_played_artists(since, until, limit)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub async fn history_most_played_albums(
+    store: State<'_, Arc<HistoryStore>>,
+    since: Option<i64>,
+    until: Option<i64>,
+    limit: i64,
+) -> Result<Vec<AlbumPlayCount>, String> {
+    store
+        .most_played_albums(since, until, limit)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub async fn history_total_listening_time(
+    store: State<'_, Arc<HistoryStore>>,
+    since: Option<i64>,
+    until: Option<i64>,
+) -> Result<f64, String> {
+    store
+        .total_listening_time(since, until)
+        .await
+        .map_err(|e| e.to_string())
+}
@@ -0,0 +1,583 @@
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use std::path::{Path, PathBuf};
+
+use crate::database;
+
+/// Key of the IPC settings in the `app_setting` table.
+pub const CONFIG_KEY: &str = "ipc";
+
+/// Socket file name in the app data directory, used when no path is configured.
+pub const DEFAULT_SOCKET_NAME: &str = "mpv.sock";
+
+/// Settings of the JSON IPC server. Read on startup, so changes apply on the next start.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct IpcConfig {
+    pub enabled: bool,
+    /// Defaults to `DEFAULT_SOCKET_NAME` in the app data directory.
+    pub socket_path: Option<PathBuf>,
+}
+
+impl IpcConfig {
+    pub async fn load(pool: &SqlitePool) -> Result<Self, sqlx::Error> {
+        Ok(database::get_setting(pool, CONFIG_KEY)
+            .await?
+            .unwrap_or_default())
+    }
+
+    pub async fn save(&self, pool: &SqlitePool) -> Result<(), sqlx::Error> {
+        database::set_setting(pool, CONFIG_KEY, self).await
+    }
+
+    /// The configured socket path, or the default one in `app_data_dir`.
+    pub fn socket_path(&self, app_data_dir: &Path) -> PathBuf {
+        self.socket_path
+            .clone()
+            .unwrap_or_else(|| app_data_dir.join(DEFAULT_SOCKET_NAME))
+    }
+}
+
+#[cfg(unix)]
+pub use server::IpcServer;
+
+/// A server for mpv's JSON IPC protocol (see <https://mpv.io/manual/stable/#json-ipc>),
+/// so tools written for `--input-ipc-server` can control the embedded player.
+///
+/// Like mpv's own server it gives full access to the player; only processes
+/// that can reach the socket file can connect.
+#[cfg(unix)]
+mod server {
+    use serde_json::{json, Value};
+    use std::collections::HashMap;
+    use std::io::{self, BufRead, BufReader, Write};
+    use std::os::unix::fs::FileTypeExt;
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::path::{Path, PathBuf};
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::{Arc, Mutex, Weak};
+    use std::thread;
+
+    use crate::mpv::{EndFileReason, MpvError, MpvEvent, MpvEventId, MpvPlayer, PlaylistEntry};
+    use crate::player_handle::PlayerHandle;
+
+    /// Commands that change the playlist. The app is told about them, since its
+    /// own playlist no longer matches mpv's afterwards.
+    const PLAYLIST_COMMANDS: &[&str] = &[
+        "loadfile",
+        "loadlist",
+        "playlist-clear",
+        "playlist-move",
+        "playlist-remove",
+        "playlist-shuffle",
+        "playlist-unshuffle",
+    ];
+
+    /// Events sent to every client, with the name mpv uses for them.
+    const BROADCAST_EVENTS: [(MpvEventId, &str); 8] = [
+        (MpvEventId::StartFile, "start-file"),
+        (MpvEventId::EndFile, "end-file"),
+        (MpvEventId::FileLoaded, "file-loaded"),
+        (MpvEventId::Seek, "seek"),
+        (MpvEventId::PlaybackRestart, "playback-restart"),
+        (MpvEventId::VideoReconfig, "video-reconfig"),
+        (MpvEventId::AudioReconfig, "audio-reconfig"),
+        (MpvEventId::Shutdown, "shutdown"),
+    ];
+
+    type PlaylistCallback = Box<dyn Fn(&[PlaylistEntry]) + Send + Sync + 'static>;
+
+    struct Client {
+        id: u64,
+        writer: Mutex<UnixStream>,
+        /// Observer id -> property name, from `observe_property`.
+        observed: Mutex<HashMap<i64, String>>,
+    }
+
+    impl Client {
+        fn send(&self, message: &Value) -> io::Result<()> {
+            let mut writer = self.writer.lock().unwrap();
+            writeln!(writer, "{}", message)
+        }
+    }
+
+    pub struct IpcServer {
+        handle: PlayerHandle,
+        path: PathBuf,
+        clients: Mutex<Vec<Arc<Client>>>,
+        next_client_id: AtomicU64,
+        on_playlist_change: PlaylistCallback,
+    }
+
+    impl IpcServer {
+        /// Listens on `path`, replacing a stale socket file left by an earlier run.
+        /// `on_playlist_change` is called with mpv's playlist after a client changed it.
+        pub fn start(
+            handle: PlayerHandle,
+            path: &Path,
+            on_playlist_change: impl Fn(&[PlaylistEntry]) + Send + Sync + 'static,
+        ) -> io::Result<Arc<Self>> {
+            remove_stale_socket(path)?;
+            let listener = UnixListener::bind(path)?;
+
+            let server = Arc::new(Self {
+                handle: handle.clone(),
+                path: path.to_path_buf(),
+                clients: Mutex::new(Vec::new()),
+                next_client_id: AtomicU64::new(0),
+                on_playlist_change: Box::new(on_playlist_change),
+            });
+
+            let server_ref = Arc::downgrade(&server);
+            handle
+                .on_create(move |player| match server_ref.upgrade() {
+                    Some(server) => server.attach(player),
+                    None => Ok(()),
+                })
+                .map_err(|e| io::Error::other(e.to_string()))?;
+
+            let server_ref = Arc::downgrade(&server);
+            thread::spawn(move || Self::accept(server_ref, listener));
+
+            Ok(server)
+        }
+
+        fn accept(server: Weak<Self>, listener: UnixListener) {
+            for stream in listener.incoming() {
+                let Some(server) = server.upgrade() else {
+                    break;
+                };
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        eprintln!("Failed to accept IPC connection: {}", e);
+                        continue;
+                    }
+                };
+
+                let reader = match stream.try_clone() {
+                    Ok(reader) => reader,
+                    Err(e) => {
+                        eprintln!("Failed to accept IPC connection: {}", e);
+                        continue;
+                    }
+                };
+
+                let client = Arc::new(Client {
+                    id: server.next_client_id.fetch_add(1, Ordering::Relaxed),
+                    writer: Mutex::new(stream),
+                    observed: Mutex::new(HashMap::new()),
+                });
+                server.clients.lock().unwrap().push(client.clone());
+
+                thread::spawn(move || {
+                    server.serve(&client, BufReader::new(reader));
+                    server.remove_client(&client);
+                });
+            }
+        }
+
+        /// Answers the client's requests until it disconnects.
+        fn serve(&self, client: &Client, reader: BufReader<UnixStream>) {
+            for line in reader.lines() {
+                let Ok(line) = line else {
+                    break;
+                };
+                if line.trim().is_empty() {
+                    continue;
+                }
+
+                let (reply, observed) = self.handle_request(client, &line);
+                if client.send(&reply).is_err() {
+                    break;
+                }
+
+                // mpv reports the current value of a newly observed property right away
+                if let Some((id, name)) = observed {
+                    if let Ok(player) = self.handle.get() {
+                        let _ = client.send(&property_change(id, &name, &player));
+                    }
+                }
+            }
+        }
+
+        fn remove_client(&self, client: &Client) {
+            self.clients
+                .lock()
+                .unwrap()
+                .retain(|other| other.id != client.id);
+
+            let names: Vec<String> = client
+                .observed
+                .lock()
+                .unwrap()
+                .drain()
+                .map(|(_, name)| name)
+                .collect();
+            for name in names {
+                self.unobserve_unused(&name);
+            }
+        }
+
+        /// Stops observing `name` in mpv once no client observes it anymore.
+        fn unobserve_unused(&self, name: &str) {
+            let observed = self
+                .clients
+                .lock()
+                .unwrap()
+                .iter()
+                .any(|client| client.observed.lock().unwrap().values().any(|n| n == name));
+            if observed {
+                return;
+            }
+            if let Ok(player) = self.handle.get() {
+                if let Err(e) = player.unobserve(name) {
+                    eprintln!("Failed to unobserve {}: {}", name, e);
+                }
+            }
+        }
+
+        /// Returns the reply, plus the observer added by an `observe_property` request.
+        fn handle_request(&self, client: &Client, line: &str) -> (Value, Option<(i64, String)>) {
+            let request: Value = match serde_json::from_str(line) {
+                Ok(request) => request,
+                Err(_) => return (json!({ "error": "invalid parameter" }), None),
+            };
+            let request_id = request.get("request_id").cloned().unwrap_or(json!(0));
+            let args = request
+                .get("command")
+                .and_then(Value::as_array)
+                .cloned()
+                .unwrap_or_default();
+
+            let mut observed = None;
+            let result = self.run(client, &args, &mut observed);
+
+            let mut reply = json!({ "request_id": request_id });
+            match result {
+                Ok(data) => {
+                    reply["error"] = json!("success");
+                    if !data.is_null() {
+                        reply["data"] = data;
+                    }
+                }
+                Err(e) => reply["error"] = json!(e.message()),
+            }
+            (reply, observed)
+        }
+
+        fn run(
+            &self,
+            client: &Client,
+            args: &[Value],
+            observed: &mut Option<(i64, String)>,
+        ) -> Result<Value, MpvError> {
+            let player = self.handle.get()?;
+            let name = args.first().and_then(Value::as_str).unwrap_or_default();
+            let arg_str = |i: usize| args.get(i).and_then(Value::as_str).ok_or_else(invalid);
+
+            match name {
+                "get_property" => player.get_property_json(arg_str(1)?),
+                "get_property_string" => {
+                    player
+                        .get_property_json(arg_str(1)?)
+                        .map(|value| match value {
+                            Value::String(_) => value,
+                            other => Value::String(other.to_string()),
+                        })
+                }
+                "set_property" => {
+                    let value = args.get(2).ok_or_else(invalid)?;
+                    player.set_property_json(arg_str(1)?, value)?;
+                    Ok(Value::Null)
+                }
+                "set_property_string" => {
+                    player.set_property_json(arg_str(1)?, &json!(arg_str(2)?))?;
+                    Ok(Value::Null)
+                }
+                "observe_property" => {
+                    let id = args.get(1).and_then(Value::as_i64).ok_or_else(invalid)?;
+                    let name = arg_str(2)?.to_string();
+                    player.observe(&name)?;
+                    client.observed.lock().unwrap().insert(id, name.clone());
+                    *observed = Some((id, name));
+                    Ok(Value::Null)
+                }
+                "unobserve_property" => {
+                    let id = args.get(1).and_then(Value::as_i64).ok_or_else(invalid)?;
+                    let name = client.observed.lock().unwrap().remove(&id);
+                    if let Some(name) = name {
+                        self.unobserve_unused(&name);
+                    }
+                    Ok(Value::Null)
+                }
+                "client_name" => Ok(json!(format!("ipc-{}", client.id))),
+                "" => Err(invalid()),
+                _ => {
+                    let args: Vec<String> = args
+                        .iter()
+                        .map(|arg| match arg {
+                            Value::String(s) => s.clone(),
+                            other => other.to_string(),
+                        })
+                        .collect();
+                    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+                    player.command(&args)?;
+
+                    if PLAYLIST_COMMANDS.contains(&name) {
+                        (self.on_playlist_change)(&player.get_playlist().unwrap_or_default());
+                    }
+                    Ok(Value::Null)
+                }
+            }
+        }
+
+        /// Forwards `player`'s events to the clients. Called for every new core.
+        fn attach(self: &Arc<Self>, player: &Arc<MpvPlayer>) -> Result<(), MpvError> {
+            for (event_id, name) in BROADCAST_EVENTS {
+                let server = Arc::downgrade(self);
+                player.register_event_callback(event_id, move |event| {
+                    if let Some(server) = server.upgrade() {
+                        server.broadcast(&event_message(name, event));
+                    }
+                })?;
+            }
+
+            let server = Arc::downgrade(self);
+            let player_ref = Arc::downgrade(player);
+            player.register_event_callback(MpvEventId::PropertyChange, move |event| {
+                let (Some(server), Some(player)) = (server.upgrade(), player_ref.upgrade()) else {
+                    return;
+                };
+                if let Some((name, _)) = event.property_change() {
+                    server.send_property_change(&player, &name);
+                }
+            })?;
+
+            // Observations made on the previous core
+            let names: Vec<String> = self
+                .clients
+                .lock()
+                .unwrap()
+                .iter()
+                .flat_map(|client| {
+                    client
+                        .observed
+                        .lock()
+                        .unwrap()
+                        .values()
+                        .cloned()
+                        .collect::<Vec<_>>()
+                })
+                .collect();
+            for name in names {
+                player.observe(&name)?;
+            }
+
+            Ok(())
+        }
+
+        fn broadcast(&self, message: &Value) {
+            let clients = self.clients.lock().unwrap().clone();
+            for client in clients {
+                let _ = client.send(message);
+            }
+        }
+
+        fn send_property_change(&self, player: &MpvPlayer, name: &str) {
+            let clients = self.clients.lock().unwrap().clone();
+            for client in clients {
+                let ids: Vec<i64> = client
+                    .observed
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .filter(|(_, observed)| observed.as_str() == name)
+                    .map(|(id, _)| *id)
+                    .collect();
+                for id in ids {
+                    let _ = client.send(&property_change(id, name, player));
+                }
+            }
+        }
+    }
+
+    impl Drop for IpcServer {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+
+    /// Removes the socket file left by a run that didn't shut down cleanly.
+    /// Anything that isn't a socket, or one a server still answers on, is
+    /// left alone and makes binding fail.
+    fn remove_stale_socket(path: &Path) -> io::Result<()> {
+        let Ok(metadata) = std::fs::symlink_metadata(path) else {
+            return Ok(());
+        };
+        if metadata.file_type().is_socket() && UnixStream::connect(path).is_err() {
+            std::fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+
+    fn invalid() -> MpvError {
+        MpvError::invalid_argument("", "invalid parameter")
+    }
+
+    fn property_change(id: i64, name: &str, player: &MpvPlayer) -> Value {
+        let mut message = json!({ "event": "property-change", "id": id, "name": name });
+        // Unavailable properties are reported without `data`, like mpv does
+        if let Ok(value) = player.get_property_json(name) {
+            message["data"] = value;
+        }
+        message
+    }
+
+    fn event_message(name: &str, event: &MpvEvent) -> Value {
+        let mut message = json!({ "event": name });
+        if let Some(reason) = event.end_file_reason() {
+            message["reason"] = json!(match reason {
+                EndFileReason::Eof => "eof",
+                EndFileReason::Stop => "stop",
+                EndFileReason::Quit => "quit",
+                EndFileReason::Error => "error",
+                EndFileReason::Redirect => "redirect",
+            });
+        }
+        message
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::IpcServer;
+    use crate::mpv::test_support;
+    use crate::player_handle::PlayerHandle;
+    use serde_json::{json, Value};
+    use std::io::{BufRead, BufReader, Write};
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::path::Path;
+    use std::time::Duration;
+
+    struct TestClient {
+        writer: UnixStream,
+        reader: BufReader<UnixStream>,
+    }
+
+    impl TestClient {
+        fn connect(path: &Path) -> Self {
+            let writer = UnixStream::connect(path).unwrap();
+            writer
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            let reader = BufReader::new(writer.try_clone().unwrap());
+            TestClient { writer, reader }
+        }
+
+        fn send(&mut self, request: &str) {
+            writeln!(self.writer, "{}", request).unwrap();
+        }
+
+        fn receive(&mut self) -> Value {
+            let mut line = String::new();
+            self.reader.read_line(&mut line).unwrap();
+            serde_json::from_str(&line).unwrap()
+        }
+
+        /// The next reply, skipping events.
+        fn reply(&mut self) -> Value {
+            loop {
+                let message = self.receive();
+                if message.get("event").is_none() {
+                    return message;
+                }
+            }
+        }
+    }
+
+    fn start(handle: &PlayerHandle, path: &Path) -> std::io::Result<std::sync::Arc<IpcServer>> {
+        IpcServer::start(handle.clone(), path, |_| ())
+    }
+
+    fn handle() -> PlayerHandle {
+        PlayerHandle::new(&test_support::lib_path(), None)
+    }
+
+    #[test]
+    fn replaces_a_stale_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("mpv.sock");
+        drop(UnixListener::bind(&path).unwrap());
+
+        let _server = start(&handle(), &path).unwrap();
+        TestClient::connect(&path);
+    }
+
+    #[test]
+    fn leaves_other_files_alone() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let file = dir.path().join("notes.txt");
+        std::fs::write(&file, "keep me").unwrap();
+        assert!(start(&handle(), &file).is_err());
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "keep me");
+
+        // Another instance still listening
+        let socket = dir.path().join("mpv.sock");
+        let _listener = UnixListener::bind(&socket).unwrap();
+        assert!(start(&handle(), &socket).is_err());
+        assert!(UnixStream::connect(&socket).is_ok());
+    }
+
+    #[test]
+    fn answers_requests_with_their_id() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("mpv.sock");
+        let _server = start(&handle(), &path).unwrap();
+        let mut client = TestClient::connect(&path);
+
+        client.send("not json");
+        assert_eq!(client.reply()["error"], "invalid parameter");
+
+        // There is no core yet
+        client.send(r#"{ "command": ["get_property", "pause"], "request_id": 7 }"#);
+        let reply = client.reply();
+        assert_eq!(reply["request_id"], 7);
+        assert_ne!(reply["error"], "success");
+    }
+
+    #[test]
+    fn stops_sending_changes_of_unobserved_properties() {
+        if test_support::player().is_none() {
+            return;
+        }
+        let handle = handle();
+        handle
+            .recreate(Some(vec![
+                ("vo".to_string(), "null".to_string()),
+                ("ao".to_string(), "null".to_string()),
+            ]))
+            .unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("mpv.sock");
+        let _server = start(&handle, &path).unwrap();
+        let mut client = TestClient::connect(&path);
+
+        client.send(r#"{ "command": ["observe_property", 1, "volume"] }"#);
+        assert_eq!(client.reply()["error"], "success");
+        let change = client.receive();
+        assert_eq!(change["event"], "property-change");
+        assert_eq!(change["name"], "volume");
+
+        client.send(r#"{ "command": ["unobserve_property", 1] }"#);
+        assert_eq!(client.reply()["error"], "success");
+        client.send(r#"{ "command": ["set_property", "volume", 50] }"#);
+        assert_eq!(client.reply()["error"], "success");
+        client.send(r#"{ "command": ["get_property", "volume"], "request_id": 2 }"#);
+
+        // Nothing else may arrive between the replies
+        let reply = client.receive();
+        assert_eq!(reply["request_id"], 2);
+        assert_eq!(reply["data"], json!(50.0));
+    }
+}
@@ -0,0 +1,15 @@
+use crate::ipc_server::IpcConfig;
+
+use sqlx::SqlitePool;
+use tauri::State;
+
+#[tauri::command]
+pub async fn ipc_get_config(pool: State<'_, SqlitePool>) -> Result<IpcConfig, String> {
+    IpcConfig::load(&pool).await.map_err(|e| e.to_string())
+}
+
+/// Saves the IPC settings; the server is started or moved on the next start of the app.
+#[tauri::command]
+pub async fn ipc_set_config(pool: State<'_, SqlitePool>, config: IpcConfig) -> Result<(), String> {
+    config.save(&pool).await.map_err(|e| e.to_string())
+}
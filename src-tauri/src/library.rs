@@ -0,0 +1,1174 @@
+use notify::event::{ModifyKind, RenameMode};
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use serde::{Deserialize, Serialize};
+use sqlx::{Sqlite, SqlitePool, Transaction};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf, MAIN_SEPARATOR};
+use std::sync::atomic::AtomicBool;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tokio::runtime::Handle;
+
+use crate::database;
+use crate::media_probe::ProbePool;
+use crate::metadata::{self, SimplifiedMetadata};
+use crate::scanner::{self, FileStamp};
+
+/// A file is indexed once it hasn't changed for this long, so a file being
+/// copied is read once it's complete.
+const SETTLE_DELAY: Duration = Duration::from_secs(2);
+
+#[derive(Error, Debug)]
+pub enum LibraryError {
+    #[error("Failed to access library database: {0}")]
+    DatabaseError(#[from] sqlx::Error),
+
+    #[error("Failed to watch folder: {0}")]
+    WatchError(#[from] notify::Error),
+
+    #[error("'{0}' is not a folder")]
+    NotAFolder(String),
+
+    #[error("The library indexer has stopped")]
+    IndexerStopped,
+}
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct WatchedFolder {
+    pub id: i64,
+    pub path: String,
+    /// Unix timestamp (seconds).
+    pub added_at: i64,
+}
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct LibraryArtist {
+    pub id: i64,
+    pub name: String,
+    pub albums: i64,
+    pub tracks: i64,
+}
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct LibraryAlbum {
+    pub id: i64,
+    pub title: String,
+    pub artist_id: Option<i64>,
+    /// The album artist, or the artist of the tracks if they don't name one.
+    pub artist: Option<String>,
+    pub year: Option<i64>,
+    pub tracks: i64,
+    pub duration: f64,
+    /// A track of the album, to get its cover from.
+    pub cover_path: String,
+}
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct LibraryGenre {
+    pub name: String,
+    pub tracks: i64,
+}
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct LibraryYear {
+    pub year: i64,
+    pub tracks: i64,
+}
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct LibraryFolder {
+    pub id: i64,
+    pub path: String,
+    pub tracks: i64,
+}
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct LibraryTrack {
+    pub id: i64,
+    pub path: String,
+    pub folder_id: i64,
+    pub title: String,
+    pub artist_id: Option<i64>,
+    pub artist: Option<String>,
+    pub album_id: Option<i64>,
+    pub album: Option<String>,
+    pub track: Option<i64>,
+    pub disc: Option<i64>,
+    pub genre: Option<String>,
+    pub year: Option<i64>,
+    pub duration: f64,
+    pub is_video: bool,
+}
+
+/// Narrows `LibraryStore::tracks`. Unset fields match every track.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct TrackFilter {
+    pub artist_id: Option<i64>,
+    pub album_id: Option<i64>,
+    pub genre: Option<String>,
+    pub year: Option<i64>,
+    pub folder_id: Option<i64>,
+}
+
+/// Sent after the index changed, with the paths of the affected tracks.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct LibraryChange {
+    pub indexed: Vec<String>,
+    pub removed: Vec<String>,
+}
+
+impl LibraryChange {
+    fn is_empty(&self) -> bool {
+        self.indexed.is_empty() && self.removed.is_empty()
+    }
+}
+
+/// `path` with a trailing separator, to match the paths inside it by prefix.
+fn dir_prefix(path: &str) -> String {
+    if path.ends_with(MAIN_SEPARATOR) {
+        path.to_string()
+    } else {
+        format!("{}{}", path, MAIN_SEPARATOR)
+    }
+}
+
+/// The media library in the `library_*` tables of the app database: tracks,
+/// with their artists, albums and folders in tables of their own, and the
+/// watched folders they were found in.
+pub struct LibraryStore {
+    pool: SqlitePool,
+}
+
+impl LibraryStore {
+    pub async fn new(pool: SqlitePool) -> Result<Self, sqlx::Error> {
+        for statement in [
+            "CREATE TABLE IF NOT EXISTS library_watched_folder (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                path TEXT UNIQUE NOT NULL,
+                added_at INTEGER NOT NULL
+            )",
+            "CREATE TABLE IF NOT EXISTS library_folder (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                path TEXT UNIQUE NOT NULL
+            )",
+            "CREATE TABLE IF NOT EXISTS library_artist (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                name TEXT UNIQUE NOT NULL COLLATE NOCASE
+            )",
+            "CREATE TABLE IF NOT EXISTS library_album (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                title TEXT NOT NULL COLLATE NOCASE,
+                artist_id INTEGER REFERENCES library_artist(id) ON DELETE SET NULL,
+                year INTEGER
+            )",
+            "CREATE TABLE IF NOT EXISTS library_track (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                path TEXT UNIQUE NOT NULL,
+                folder_id INTEGER NOT NULL REFERENCES library_folder(id) ON DELETE CASCADE,
+                artist_id INTEGER REFERENCES library_artist(id) ON DELETE SET NULL,
+                album_id INTEGER REFERENCES library_album(id) ON DELETE SET NULL,
+                title TEXT NOT NULL,
+                track INTEGER,
+                disc INTEGER,
+                genre TEXT,
+                year INTEGER,
+                duration REAL NOT NULL,
+                is_video INTEGER NOT NULL,
+                modified INTEGER NOT NULL,
+                size INTEGER NOT NULL
+            )",
+            "CREATE INDEX IF NOT EXISTS library_track_artist ON library_track(artist_id)",
+            "CREATE INDEX IF NOT EXISTS library_track_album ON library_track(album_id)",
+            "CREATE INDEX IF NOT EXISTS library_track_folder ON library_track(folder_id)",
+            "CREATE INDEX IF NOT EXISTS library_album_artist ON library_album(artist_id)",
+        ] {
+            sqlx::query(statement).execute(&pool).await?;
+        }
+
+        Ok(Self { pool })
+    }
+
+    pub async fn watched_folders(&self) -> Result<Vec<WatchedFolder>, sqlx::Error> {
+        sqlx::query_as::<_, WatchedFolder>("SELECT * FROM library_watched_folder ORDER BY path")
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    async fn add_watched_folder(&self, path: &str) -> Result<WatchedFolder, sqlx::Error> {
+        sqlx::query(
+            "INSERT INTO library_watched_folder (path, added_at) VALUES (?, ?)
+             ON CONFLICT(path) DO NOTHING",
+        )
+        .bind(path)
+        .bind(database::unix_now())
+        .execute(&self.pool)
+        .await?;
+
+        sqlx::query_as::<_, WatchedFolder>("SELECT * FROM library_watched_folder WHERE path = ?")
+            .bind(path)
+            .fetch_one(&self.pool)
+            .await
+    }
+
+    async fn remove_watched_folder(&self, path: &str) -> Result<(), sqlx::Error> {
+        sqlx::query("DELETE FROM library_watched_folder WHERE path = ?")
+            .bind(path)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Stamps of the tracks in `dir` and its subfolders, by path.
+    async fn stamps_under(&self, dir: &str) -> Result<HashMap<String, FileStamp>, sqlx::Error> {
+        let prefix = dir_prefix(dir);
+        let rows: Vec<(String, i64, i64)> = sqlx::query_as(
+            "SELECT path, modified, size FROM library_track WHERE substr(path, 1, ?) = ?",
+        )
+        .bind(prefix.chars().count() as i64)
+        .bind(&prefix)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|(path, modified, size)| (path, FileStamp { modified, size }))
+            .collect())
+    }
+
+    async fn stamp(&self, path: &str) -> Result<Option<FileStamp>, sqlx::Error> {
+        let row: Option<(i64, i64)> =
+            sqlx::query_as("SELECT modified, size FROM library_track WHERE path = ?")
+                .bind(path)
+                .fetch_optional(&self.pool)
+                .await?;
+        Ok(row.map(|(modified, size)| FileStamp { modified, size }))
+    }
+
+    /// Adds or updates the track at `path`, with its folder, artist and album.
+    async fn save_track(
+        &self,
+        path: &str,
+        stamp: FileStamp,
+        metadata: &SimplifiedMetadata,
+    ) -> Result<(), sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+
+        let folder = Path::new(path)
+            .parent()
+            .map(|folder| folder.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let folder_id = get_or_insert_folder(&mut tx, &folder).await?;
+
+        let artist = metadata
+            .artist
+            .as_deref()
+            .map(str::trim)
+            .filter(|name| !name.is_empty());
+        let artist_id = match artist {
+            Some(name) => Some(get_or_insert_artist(&mut tx, name).await?),
+            None => None,
+        };
+
+        let album_artist_id = match metadata.album_artist() {
+            Some(name) => Some(get_or_insert_artist(&mut tx, name).await?),
+            None => artist_id,
+        };
+        let album = metadata
+            .album
+            .as_deref()
+            .map(str::trim)
+            .filter(|title| !title.is_empty());
+        let album_id = match album {
+            Some(title) => {
+                Some(get_or_insert_album(&mut tx, title, album_artist_id, metadata.year).await?)
+            }
+            None => None,
+        };
+
+        let title = metadata.title.clone().unwrap_or_else(|| {
+            Path::new(path)
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_else(|| path.to_string())
+        });
+
+        sqlx::query(
+            "INSERT INTO library_track (path, folder_id, artist_id, album_id, title, track, disc,
+                genre, year, duration, is_video, modified, size)
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+             ON CONFLICT(path) DO UPDATE SET
+                folder_id = excluded.folder_id,
+                artist_id = excluded.artist_id,
+                album_id = excluded.album_id,
+                title = excluded.title,
+                track = excluded.track,
+                disc = excluded.disc,
+                genre = excluded.genre,
+                year = excluded.year,
+                duration = excluded.duration,
+                is_video = excluded.is_video,
+                modified = excluded.modified,
+                size = excluded.size",
+        )
+        .bind(path)
+        .bind(folder_id)
+        .bind(artist_id)
+        .bind(album_id)
+        .bind(title)
+        .bind(metadata.track)
+        .bind(metadata.disc)
+        .bind(&metadata.genre)
+        .bind(metadata.year)
+        .bind(metadata.duration)
+        .bind(metadata.is_video)
+        .bind(stamp.modified)
+        .bind(stamp.size)
+        .execute(&mut tx)
+        .await?;
+
+        tx.commit().await
+    }
+
+    /// Removes the track at `path`. Returns whether there was such a track.
+    ///
+    /// Removals and renames of files on disk are applied to `media_info` as well,
+    /// which keeps the search index (see `search.rs`) free of missing files.
+    async fn remove_track(&self, path: &str) -> Result<bool, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("DELETE FROM media_info WHERE path = ?")
+            .bind(path)
+            .execute(&mut tx)
+            .await?;
+        let result = sqlx::query("DELETE FROM library_track WHERE path = ?")
+            .bind(path)
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Removes the tracks in `dir` and its subfolders, except those in the
+    /// folders of `keep`, and returns their paths. Their `media_info` rows go
+    /// too if `deleted`, i.e. the folder is gone from disk.
+    async fn remove_under(
+        &self,
+        dir: &str,
+        keep: &[String],
+        deleted: bool,
+    ) -> Result<Vec<String>, sqlx::Error> {
+        let prefixes: Vec<(i64, String)> = std::iter::once(dir)
+            .chain(keep.iter().map(String::as_str))
+            .map(dir_prefix)
+            .map(|prefix| (prefix.chars().count() as i64, prefix))
+            .collect();
+        let condition = format!(
+            "substr(path, 1, ?) = ?{}",
+            " AND substr(path, 1, ?) != ?".repeat(keep.len())
+        );
+
+        let mut tx = self.pool.begin().await?;
+        if deleted {
+            let sql = format!("DELETE FROM media_info WHERE {}", condition);
+            let mut query = sqlx::query(&sql);
+            for (length, prefix) in &prefixes {
+                query = query.bind(length).bind(prefix);
+            }
+            query.execute(&mut tx).await?;
+        }
+        let sql = format!(
+            "DELETE FROM library_track WHERE {} RETURNING path",
+            condition
+        );
+        let mut query = sqlx::query_scalar(&sql);
+        for (length, prefix) in &prefixes {
+            query = query.bind(length).bind(prefix);
+        }
+        let paths = query.fetch_all(&mut tx).await?;
+        tx.commit().await?;
+        Ok(paths)
+    }
+
+    /// Moves the track at `from` to `to`, keeping its metadata. Returns whether
+    /// there was such a track.
+    async fn rename_track(&self, from: &str, to: &str) -> Result<bool, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        let folder = Path::new(to)
+            .parent()
+            .map(|folder| folder.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let folder_id = get_or_insert_folder(&mut tx, &folder).await?;
+        sqlx::query("DELETE FROM media_info WHERE path = ?")
+            .bind(to)
+            .execute(&mut tx)
+            .await?;
+        sqlx::query("UPDATE media_info SET path = ? WHERE path = ?")
+            .bind(to)
+            .bind(from)
+            .execute(&mut tx)
+            .await?;
+        let result = sqlx::query("UPDATE library_track SET path = ?, folder_id = ? WHERE path = ?")
+            .bind(to)
+            .bind(folder_id)
+            .bind(from)
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Moves the tracks and folders in `from` and its subfolders into `to`, and
+    /// returns the new paths of the tracks.
+    async fn rename_under(&self, from: &str, to: &str) -> Result<Vec<String>, sqlx::Error> {
+        let (from_prefix, to_prefix) = (dir_prefix(from), dir_prefix(to));
+        let length = from_prefix.chars().count() as i64;
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query("UPDATE library_folder SET path = ? WHERE path = ?")
+            .bind(to)
+            .bind(from)
+            .execute(&mut tx)
+            .await?;
+        sqlx::query(
+            "UPDATE library_folder SET path = ? || substr(path, ? + 1)
+             WHERE substr(path, 1, ?) = ?",
+        )
+        .bind(&to_prefix)
+        .bind(length)
+        .bind(length)
+        .bind(&from_prefix)
+        .execute(&mut tx)
+        .await?;
+        sqlx::query("DELETE FROM media_info WHERE substr(path, 1, ?) = ?")
+            .bind(to_prefix.chars().count() as i64)
+            .bind(&to_prefix)
+            .execute(&mut tx)
+            .await?;
+        sqlx::query(
+            "UPDATE media_info SET path = ? || substr(path, ? + 1)
+             WHERE substr(path, 1, ?) = ?",
+        )
+        .bind(&to_prefix)
+        .bind(length)
+        .bind(length)
+        .bind(&from_prefix)
+        .execute(&mut tx)
+        .await?;
+        let paths = sqlx::query_scalar(
+            "UPDATE library_track SET path = ? || substr(path, ? + 1)
+             WHERE substr(path, 1, ?) = ? RETURNING path",
+        )
+        .bind(&to_prefix)
+        .bind(length)
+        .bind(length)
+        .bind(&from_prefix)
+        .fetch_all(&mut tx)
+        .await?;
+
+        tx.commit().await?;
+        Ok(paths)
+    }
+
+    /// Deletes folders, albums and artists no track refers to anymore.
+    async fn prune(&self) -> Result<(), sqlx::Error> {
+        for statement in [
+            "DELETE FROM library_folder
+             WHERE id NOT IN (SELECT folder_id FROM library_track)",
+            "DELETE FROM library_album
+             WHERE id NOT IN (SELECT album_id FROM library_track WHERE album_id IS NOT NULL)",
+            "DELETE FROM library_artist
+             WHERE id NOT IN (SELECT artist_id FROM library_track WHERE artist_id IS NOT NULL)
+               AND id NOT IN (SELECT artist_id FROM library_album WHERE artist_id IS NOT NULL)",
+        ] {
+            sqlx::query(statement).execute(&self.pool).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn artists(&self) -> Result<Vec<LibraryArtist>, sqlx::Error> {
+        sqlx::query_as::<_, LibraryArtist>(
+            "SELECT library_artist.id, library_artist.name,
+                (SELECT COUNT(*) FROM library_album
+                 WHERE library_album.artist_id = library_artist.id) AS albums,
+                (SELECT COUNT(*) FROM library_track
+                 WHERE library_track.artist_id = library_artist.id) AS tracks
+             FROM library_artist
+             ORDER BY library_artist.name",
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    /// All albums, or those of `artist_id` (as album artist, or as artist of
+    /// one of the tracks).
+    pub async fn albums(&self, artist_id: Option<i64>) -> Result<Vec<LibraryAlbum>, sqlx::Error> {
+        sqlx::query_as::<_, LibraryAlbum>(
+            "SELECT library_album.id, library_album.title, library_album.artist_id,
+                library_artist.name AS artist, library_album.year,
+                COUNT(library_track.id) AS tracks,
+                COALESCE(SUM(library_track.duration), 0.0) AS duration,
+                MIN(library_track.path) AS cover_path
+             FROM library_album
+             JOIN library_track ON library_track.album_id = library_album.id
+             LEFT JOIN library_artist ON library_artist.id = library_album.artist_id
+             WHERE ?1 IS NULL OR library_album.artist_id = ?1 OR library_album.id IN (
+                SELECT album_id FROM library_track WHERE artist_id = ?1
+             )
+             GROUP BY library_album.id
+             ORDER BY library_album.year IS NULL, library_album.year, library_album.title",
+        )
+        .bind(artist_id)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    pub async fn genres(&self) -> Result<Vec<LibraryGenre>, sqlx::Error> {
+        sqlx::query_as::<_, LibraryGenre>(
+            "SELECT genre AS name, COUNT(*) AS tracks
+             FROM library_track
+             WHERE genre IS NOT NULL AND genre != ''
+             GROUP BY genre COLLATE NOCASE
+             ORDER BY genre COLLATE NOCASE",
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    pub async fn years(&self) -> Result<Vec<LibraryYear>, sqlx::Error> {
+        sqlx::query_as::<_, LibraryYear>(
+            "SELECT year, COUNT(*) AS tracks
+             FROM library_track
+             WHERE year IS NOT NULL
+             GROUP BY year
+             ORDER BY year DESC",
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    pub async fn folders(&self) -> Result<Vec<LibraryFolder>, sqlx::Error> {
+        sqlx::query_as::<_, LibraryFolder>(
+            "SELECT library_folder.id, library_folder.path, COUNT(library_track.id) AS tracks
+             FROM library_folder
+             JOIN library_track ON library_track.folder_id = library_folder.id
+             GROUP BY library_folder.id
+             ORDER BY library_folder.path",
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    /// Tracks matching `filter`, in album order.
+    pub async fn tracks(&self, filter: &TrackFilter) -> Result<Vec<LibraryTrack>, sqlx::Error> {
+        sqlx::query_as::<_, LibraryTrack>(
+            "SELECT library_track.id, library_track.path, library_track.folder_id,
+                library_track.title, library_track.artist_id, library_artist.name AS artist,
+                library_track.album_id, library_album.title AS album, library_track.track,
+                library_track.disc, library_track.genre, library_track.year,
+                library_track.duration, library_track.is_video
+             FROM library_track
+             LEFT JOIN library_artist ON library_artist.id = library_track.artist_id
+             LEFT JOIN library_album ON library_album.id = library_track.album_id
+             WHERE (?1 IS NULL OR library_track.artist_id = ?1 OR library_album.artist_id = ?1)
+               AND (?2 IS NULL OR library_track.album_id = ?2)
+               AND (?3 IS NULL OR library_track.genre = ?3 COLLATE NOCASE)
+               AND (?4 IS NULL OR library_track.year = ?4)
+               AND (?5 IS NULL OR library_track.folder_id = ?5)
+             ORDER BY library_album.title, library_track.disc, library_track.track,
+                library_track.path",
+        )
+        .bind(filter.artist_id)
+        .bind(filter.album_id)
+        .bind(&filter.genre)
+        .bind(filter.year)
+        .bind(filter.folder_id)
+        .fetch_all(&self.pool)
+        .await
+    }
+}
+
+async fn get_or_insert_folder(
+    tx: &mut Transaction<'_, Sqlite>,
+    path: &str,
+) -> Result<i64, sqlx::Error> {
+    sqlx::query("INSERT INTO library_folder (path) VALUES (?) ON CONFLICT(path) DO NOTHING")
+        .bind(path)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query_scalar("SELECT id FROM library_folder WHERE path = ?")
+        .bind(path)
+        .fetch_one(&mut *tx)
+        .await
+}
+
+async fn get_or_insert_artist(
+    tx: &mut Transaction<'_, Sqlite>,
+    name: &str,
+) -> Result<i64, sqlx::Error> {
+    sqlx::query("INSERT INTO library_artist (name) VALUES (?) ON CONFLICT(name) DO NOTHING")
+        .bind(name)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query_scalar("SELECT id FROM library_artist WHERE name = ?")
+        .bind(name)
+        .fetch_one(&mut *tx)
+        .await
+}
+
+/// Albums are told apart by title and album artist. The year is taken from the
+/// first track that has one.
+async fn get_or_insert_album(
+    tx: &mut Transaction<'_, Sqlite>,
+    title: &str,
+    artist_id: Option<i64>,
+    year: Option<u32>,
+) -> Result<i64, sqlx::Error> {
+    let existing: Option<i64> =
+        sqlx::query_scalar("SELECT id FROM library_album WHERE title = ? AND artist_id IS ?")
+            .bind(title)
+            .bind(artist_id)
+            .fetch_optional(&mut *tx)
+            .await?;
+    if let Some(id) = existing {
+        sqlx::query("UPDATE library_album SET year = COALESCE(year, ?) WHERE id = ?")
+            .bind(year)
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        return Ok(id);
+    }
+
+    let result = sqlx::query("INSERT INTO library_album (title, artist_id, year) VALUES (?, ?, ?)")
+        .bind(title)
+        .bind(artist_id)
+        .bind(year)
+        .execute(&mut *tx)
+        .await?;
+    Ok(result.last_insert_rowid())
+}
+
+/// The paths `paths` had before their folder was renamed from `from` to `to`.
+fn old_paths(paths: &[String], from: &str, to: &str) -> Vec<String> {
+    paths
+        .iter()
+        .filter_map(|path| Some(format!("{}{}", from, path.strip_prefix(to)?)))
+        .collect()
+}
+
+/// Work for the indexer thread.
+enum Job {
+    /// Compare a folder with the index, and index or remove what changed.
+    Rescan(PathBuf),
+    /// Stop indexing a watched folder and forget its tracks, except those of
+    /// the watched folders inside it in `keep`.
+    Forget {
+        dir: PathBuf,
+        keep: Vec<PathBuf>,
+    },
+    Watch(notify::Result<Event>),
+}
+
+/// Keeps the library in sync with the watched folders: rescans them on start and
+/// on request, and follows adds, renames and deletes through a filesystem watcher
+/// while the app runs.
+///
+/// All changes go through one indexer thread, so rescans and watcher events
+/// never write the same rows at once. Files are only read again when their size
+/// or modification time changed.
+pub struct Library {
+    store: Arc<LibraryStore>,
+    jobs: Mutex<mpsc::Sender<Job>>,
+    watcher: Mutex<RecommendedWatcher>,
+}
+
+impl Library {
+    /// Starts the indexer and watches the saved folders. `probes` are used for
+    /// files lofty can't read; `on_change` is called from the indexer thread.
+    pub fn start(
+        store: Arc<LibraryStore>,
+        probes: Arc<ProbePool>,
+        runtime: Handle,
+        on_change: impl Fn(LibraryChange) + Send + 'static,
+    ) -> Result<Arc<Self>, LibraryError> {
+        let (sender, receiver) = mpsc::channel();
+        let events = sender.clone();
+        let watcher = notify::recommended_watcher(move |event| {
+            // Fails only once the indexer is gone
+            let _ = events.send(Job::Watch(event));
+        })?;
+
+        let indexer = Indexer {
+            store: store.clone(),
+            probes,
+            runtime: runtime.clone(),
+            on_change: Box::new(on_change),
+            pending: HashMap::new(),
+        };
+        std::thread::Builder::new()
+            .name("library-indexer".to_string())
+            .spawn(move || indexer.run(receiver))
+            .map_err(|_| LibraryError::IndexerStopped)?;
+
+        let library = Arc::new(Self {
+            store,
+            jobs: Mutex::new(sender),
+            watcher: Mutex::new(watcher),
+        });
+        for folder in runtime.block_on(library.store.watched_folders())? {
+            // A missing folder (e.g. an unplugged drive) keeps its tracks until
+            // it is back or removed from the library
+            if let Err(e) = library.watch(Path::new(&folder.path)) {
+                eprintln!("Failed to watch {}: {}", folder.path, e);
+                continue;
+            }
+            library.send(Job::Rescan(PathBuf::from(folder.path)))?;
+        }
+        Ok(library)
+    }
+
+    pub fn store(&self) -> &Arc<LibraryStore> {
+        &self.store
+    }
+
+    /// Adds `path` to the watched folders and indexes it.
+    pub async fn add_folder(&self, path: &str) -> Result<WatchedFolder, LibraryError> {
+        if !Path::new(path).is_dir() {
+            return Err(LibraryError::NotAFolder(path.to_string()));
+        }
+        let folder = self.store.add_watched_folder(path).await?;
+        self.watch(Path::new(path))?;
+        self.send(Job::Rescan(PathBuf::from(path)))?;
+        Ok(folder)
+    }
+
+    /// Stops watching `path` and removes its tracks from the library, except
+    /// those another watched folder still covers.
+    pub async fn remove_folder(&self, path: &str) -> Result<(), LibraryError> {
+        self.store.remove_watched_folder(path).await?;
+        let removed = Path::new(path);
+        let roots: Vec<PathBuf> = self
+            .store
+            .watched_folders()
+            .await?
+            .into_iter()
+            .map(|folder| PathBuf::from(folder.path))
+            .collect();
+        // Still watched and indexed through the folder it is in
+        if roots.iter().any(|root| removed.starts_with(root)) {
+            return Ok(());
+        }
+
+        if let Err(e) = self.watcher.lock().unwrap().unwatch(removed) {
+            eprintln!("Failed to unwatch {}: {}", path, e);
+        }
+        // Unwatching drops the watches of the folders inside it too
+        let keep: Vec<PathBuf> = roots
+            .into_iter()
+            .filter(|root| root.starts_with(removed))
+            .collect();
+        for root in &keep {
+            if let Err(e) = self.watch(root) {
+                eprintln!("Failed to watch {}: {}", root.display(), e);
+            }
+        }
+        self.send(Job::Forget {
+            dir: removed.to_path_buf(),
+            keep,
+        })
+    }
+
+    /// Rescans `path`, or every watched folder if it's not given.
+    pub async fn rescan(&self, path: Option<&str>) -> Result<(), LibraryError> {
+        let paths = match path {
+            Some(path) => vec![path.to_string()],
+            None => self
+                .store
+                .watched_folders()
+                .await?
+                .into_iter()
+                .map(|folder| folder.path)
+                .collect(),
+        };
+        for path in paths {
+            self.send(Job::Rescan(PathBuf::from(path)))?;
+        }
+        Ok(())
+    }
+
+    fn watch(&self, path: &Path) -> Result<(), LibraryError> {
+        self.watcher
+            .lock()
+            .unwrap()
+            .watch(path, RecursiveMode::Recursive)?;
+        Ok(())
+    }
+
+    fn send(&self, job: Job) -> Result<(), LibraryError> {
+        self.jobs
+            .lock()
+            .unwrap()
+            .send(job)
+            .map_err(|_| LibraryError::IndexerStopped)
+    }
+}
+
+struct Indexer {
+    store: Arc<LibraryStore>,
+    probes: Arc<ProbePool>,
+    runtime: Handle,
+    on_change: Box<dyn Fn(LibraryChange) + Send>,
+    /// Files changed on disk, and when they last did.
+    pending: HashMap<PathBuf, Instant>,
+}
+
+impl Indexer {
+    fn run(mut self, jobs: mpsc::Receiver<Job>) {
+        loop {
+            let job = if self.pending.is_empty() {
+                match jobs.recv() {
+                    Ok(job) => Some(job),
+                    Err(_) => return,
+                }
+            } else {
+                match jobs.recv_timeout(SETTLE_DELAY / 4) {
+                    Ok(job) => Some(job),
+                    Err(RecvTimeoutError::Timeout) => None,
+                    Err(RecvTimeoutError::Disconnected) => return,
+                }
+            };
+
+            let mut change = LibraryChange::default();
+            match job {
+                Some(Job::Rescan(path)) => self.rescan(&path, &mut change),
+                Some(Job::Forget { dir, keep }) => self.forget(&dir, &keep, false, &mut change),
+                Some(Job::Watch(Ok(event))) => self.on_event(event, &mut change),
+                Some(Job::Watch(Err(e))) => eprintln!("Failed to watch library: {}", e),
+                None => {}
+            }
+            self.index_settled(&mut change);
+
+            if !change.is_empty() {
+                if let Err(e) = self.runtime.block_on(self.store.prune()) {
+                    eprintln!("Failed to prune library: {}", e);
+                }
+                (self.on_change)(change);
+            }
+        }
+    }
+
+    fn rescan(&mut self, dir: &Path, change: &mut LibraryChange) {
+        let dir_str = dir.to_string_lossy();
+        let mut known = match self.runtime.block_on(self.store.stamps_under(&dir_str)) {
+            Ok(known) => known,
+            Err(e) => {
+                eprintln!("Failed to read library: {}", e);
+                return;
+            }
+        };
+
+        let files =
+            scanner::collect_media_files(&[dir_str.into_owned()], true, &AtomicBool::new(false));
+        for file in files {
+            let path = file.to_string_lossy().into_owned();
+            let stamp = FileStamp::read(&file).ok();
+            if known
+                .remove(&path)
+                .is_some_and(|known| Some(known) == stamp)
+            {
+                continue;
+            }
+            self.index(&file, change);
+        }
+
+        // Whatever is left is gone from disk
+        for path in known.into_keys() {
+            self.remove(Path::new(&path), change);
+        }
+    }
+
+    fn forget(&mut self, dir: &Path, keep: &[PathBuf], deleted: bool, change: &mut LibraryChange) {
+        self.pending.retain(|path, _| {
+            !path.starts_with(dir) || keep.iter().any(|kept| path.starts_with(kept))
+        });
+        let keep: Vec<String> = keep
+            .iter()
+            .map(|kept| kept.to_string_lossy().into_owned())
+            .collect();
+        match self.runtime.block_on(
+            self.store
+                .remove_under(&dir.to_string_lossy(), &keep, deleted),
+        ) {
+            Ok(paths) => change.removed.extend(paths),
+            Err(e) => eprintln!("Failed to remove {} from library: {}", dir.display(), e),
+        }
+    }
+
+    fn on_event(&mut self, event: Event, change: &mut LibraryChange) {
+        match event.kind {
+            EventKind::Modify(ModifyKind::Name(RenameMode::Both)) if event.paths.len() == 2 => {
+                self.rename(&event.paths[0], &event.paths[1], change);
+            }
+            EventKind::Modify(ModifyKind::Name(RenameMode::From)) | EventKind::Remove(_) => {
+                for path in &event.paths {
+                    self.remove(path, change);
+                }
+            }
+            EventKind::Create(_) | EventKind::Modify(ModifyKind::Name(_)) | EventKind::Any => {
+                for path in event.paths {
+                    if path.is_dir() {
+                        self.rescan(&path, change);
+                    } else if path.exists() {
+                        self.pending.insert(path, Instant::now());
+                    } else {
+                        // e.g. `RenameMode::Any` for the old name
+                        self.remove(&path, change);
+                    }
+                }
+            }
+            // A folder's content or metadata changes when its entries do, which
+            // come with events of their own
+            EventKind::Modify(_) => {
+                for path in event.paths {
+                    if path.is_file() {
+                        self.pending.insert(path, Instant::now());
+                    }
+                }
+            }
+            EventKind::Access(_) | EventKind::Other => {}
+        }
+    }
+
+    fn rename(&mut self, from: &Path, to: &Path, change: &mut LibraryChange) {
+        self.pending.remove(from);
+        let (from_str, to_str) = (from.to_string_lossy(), to.to_string_lossy());
+
+        if to.is_dir() {
+            match self
+                .runtime
+                .block_on(self.store.rename_under(&from_str, &to_str))
+            {
+                Ok(paths) => {
+                    change.removed.extend(old_paths(&paths, &from_str, &to_str));
+                    change.indexed.extend(paths);
+                }
+                Err(e) => eprintln!("Failed to rename {} in library: {}", from.display(), e),
+            }
+            // Picks up files that weren't indexed under the old name
+            self.rescan(to, change);
+            return;
+        }
+
+        if !scanner::is_media_file(to) {
+            self.remove(from, change);
+            return;
+        }
+        match self
+            .runtime
+            .block_on(self.store.rename_track(&from_str, &to_str))
+        {
+            Ok(true) => {
+                change.removed.push(from_str.into_owned());
+                change.indexed.push(to_str.into_owned());
+            }
+            Ok(false) => {
+                self.pending.insert(to.to_path_buf(), Instant::now());
+            }
+            Err(e) => eprintln!("Failed to rename {} in library: {}", from.display(), e),
+        }
+    }
+
+    fn remove(&mut self, path: &Path, change: &mut LibraryChange) {
+        self.pending.remove(path);
+        let path_str = path.to_string_lossy();
+        // Either a track or a whole folder
+        match self.runtime.block_on(self.store.remove_track(&path_str)) {
+            Ok(true) => change.removed.push(path_str.into_owned()),
+            Ok(false) => self.forget(path, &[], true, change),
+            Err(e) => eprintln!("Failed to remove {} from library: {}", path.display(), e),
+        }
+    }
+
+    /// Indexes the changed files that haven't changed again for `SETTLE_DELAY`.
+    fn index_settled(&mut self, change: &mut LibraryChange) {
+        let settled: Vec<PathBuf> = self
+            .pending
+            .iter()
+            .filter(|(_, changed)| changed.elapsed() >= SETTLE_DELAY)
+            .map(|(path, _)| path.clone())
+            .collect();
+        for path in settled {
+            self.pending.remove(&path);
+            if !scanner::is_media_file(&path) {
+                continue;
+            }
+            let Ok(stamp) = FileStamp::read(&path) else {
+                continue;
+            };
+            let known = self
+                .runtime
+                .block_on(self.store.stamp(&path.to_string_lossy()))
+                .ok()
+                .flatten();
+            if known != Some(stamp) {
+                self.index(&path, change);
+            }
+        }
+    }
+
+    fn index(&mut self, path: &Path, change: &mut LibraryChange) {
+        let path_str = path.to_string_lossy().into_owned();
+        let stamp = match FileStamp::read(path) {
+            Ok(stamp) => stamp,
+            Err(e) => {
+                eprintln!("Failed to read {}: {}", path_str, e);
+                return;
+            }
+        };
+        let metadata = self.runtime.block_on(async {
+            metadata::parse_media_info(&self.probes, &path_str)
+                .await
+                .map_err(|e| e.to_string())
+        });
+        let metadata = match metadata {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                eprintln!("Failed to read metadata of {}: {}", path_str, e);
+                return;
+            }
+        };
+        match self
+            .runtime
+            .block_on(self.store.save_track(&path_str, stamp, &metadata))
+        {
+            Ok(()) => change.indexed.push(path_str),
+            Err(e) => eprintln!("Failed to add {} to library: {}", path_str, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mpv::test_support;
+    use notify::event::{CreateKind, MetadataKind};
+    use std::fs;
+    use tokio::runtime::Runtime;
+
+    fn store(runtime: &Runtime, dir: &Path) -> Arc<LibraryStore> {
+        runtime.block_on(async {
+            let pool = database::connect(&dir.join("app.db")).await.unwrap();
+            Arc::new(LibraryStore::new(pool).await.unwrap())
+        })
+    }
+
+    fn indexer(runtime: &Runtime, store: &Arc<LibraryStore>) -> Indexer {
+        Indexer {
+            store: store.clone(),
+            probes: ProbePool::new(&test_support::lib_path()),
+            runtime: runtime.handle().clone(),
+            on_change: Box::new(|_| {}),
+            pending: HashMap::new(),
+        }
+    }
+
+    fn write_wav(path: &Path) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, test_support::sine_wav(8_000, 0, 800)).unwrap();
+    }
+
+    fn track_paths(runtime: &Runtime, store: &LibraryStore) -> Vec<String> {
+        let mut paths: Vec<String> = runtime
+            .block_on(store.tracks(&TrackFilter::default()))
+            .unwrap()
+            .into_iter()
+            .map(|track| track.path)
+            .collect();
+        paths.sort();
+        paths
+    }
+
+    #[test]
+    fn forgetting_a_folder_keeps_the_watched_folders_inside_it() {
+        let runtime = Runtime::new().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let music = dir.path().join("music");
+        let outer = music.join("a.wav");
+        let inner = music.join("live").join("b.wav");
+        write_wav(&outer);
+        write_wav(&inner);
+        let store = store(&runtime, dir.path());
+        let mut indexer = indexer(&runtime, &store);
+
+        let mut change = LibraryChange::default();
+        indexer.rescan(&music, &mut change);
+        assert_eq!(change.indexed.len(), 2);
+
+        let mut change = LibraryChange::default();
+        indexer.forget(&music, &[music.join("live")], false, &mut change);
+        assert_eq!(change.removed, vec![outer.to_string_lossy().into_owned()]);
+        assert_eq!(
+            track_paths(&runtime, &store),
+            vec![inner.to_string_lossy().into_owned()]
+        );
+    }
+
+    #[test]
+    fn removing_a_folder_inside_another_keeps_its_tracks() {
+        let runtime = Runtime::new().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let music = dir.path().join("music");
+        let live = music.join("live");
+        write_wav(&music.join("a.wav"));
+        write_wav(&live.join("b.wav"));
+        let store = store(&runtime, dir.path());
+        let (sender, changes) = mpsc::channel();
+        let library = Library::start(
+            store.clone(),
+            ProbePool::new(""),
+            runtime.handle().clone(),
+            move |change| {
+                let _ = sender.send(change);
+            },
+        )
+        .unwrap();
+
+        runtime
+            .block_on(library.add_folder(&music.to_string_lossy()))
+            .unwrap();
+        changes.recv_timeout(Duration::from_secs(10)).unwrap();
+        runtime
+            .block_on(library.add_folder(&live.to_string_lossy()))
+            .unwrap();
+        runtime
+            .block_on(library.remove_folder(&live.to_string_lossy()))
+            .unwrap();
+
+        assert_eq!(track_paths(&runtime, &store).len(), 2);
+        let folders = runtime.block_on(store.watched_folders()).unwrap();
+        assert_eq!(folders.len(), 1);
+        assert_eq!(folders[0].path, music.to_string_lossy());
+    }
+
+    #[test]
+    fn only_folder_creations_and_renames_rescan_a_folder() {
+        let runtime = Runtime::new().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let music = dir.path().join("music");
+        write_wav(&music.join("a.wav"));
+        let store = store(&runtime, dir.path());
+        let mut indexer = indexer(&runtime, &store);
+
+        let mut change = LibraryChange::default();
+        let touched = Event::new(EventKind::Modify(ModifyKind::Metadata(MetadataKind::Any)))
+            .add_path(music.clone());
+        indexer.on_event(touched, &mut change);
+        assert!(change.is_empty());
+        assert!(indexer.pending.is_empty());
+
+        let created = Event::new(EventKind::Create(CreateKind::Folder)).add_path(music.clone());
+        indexer.on_event(created, &mut change);
+        assert_eq!(change.indexed.len(), 1);
+    }
+}
@@ -0,0 +1,103 @@
+use crate::library::{
+    Library, LibraryAlbum, LibraryArtist, LibraryFolder, LibraryGenre, LibraryTrack, LibraryYear,
+    TrackFilter, WatchedFolder,
+};
+
+use std::sync::Arc;
+use tauri::State;
+
+#[tauri::command]
+pub async fn library_get_folders(
+    library: State<'_, Arc<Library>>,
+) -> Result<Vec<WatchedFolder>, String> {
+    library
+        .store()
+        .watched_folders()
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// Watches `path` and indexes it in the background; `library-changed` events
+/// follow as tracks are added.
+#[tauri::command]
+pub async fn library_add_folder(
+    library: State<'_, Arc<Library>>,
+    path: String,
+) -> Result<WatchedFolder, String> {
+    library.add_folder(&path).await.map_err(|e| e.to_string())
+}
+
+/// Stops watching `path` and removes its tracks from the library.
+#[tauri::command]
+pub async fn library_remove_folder(
+    library: State<'_, Arc<Library>>,
+    path: String,
+) -> Result<(), String> {
+    library
+        .remove_folder(&path)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// Rescans `path`, or every watched folder if no path is given. Only files
+/// whose size or modification time changed are read again.
+#[tauri::command]
+pub async fn library_rescan(
+    library: State<'_, Arc<Library>>,
+    path: Option<String>,
+) -> Result<(), String> {
+    library
+        .rescan(path.as_deref())
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub async fn library_artists(
+    library: State<'_, Arc<Library>>,
+) -> Result<Vec<LibraryArtist>, String> {
+    library.store().artists().await.map_err(|e| e.to_string())
+}
+
+/// All albums, or those of `artist_id`.
+#[tauri::command]
+pub async fn library_albums(
+    library: State<'_, Arc<Library>>,
+    artist_id: Option<i64>,
+) -> Result<Vec<LibraryAlbum>, String> {
+    library
+        .store()
+        .albums(artist_id)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub async fn library_genres(library: State<'_, Arc<Library>>) -> Result<Vec<LibraryGenre>, String> {
+    library.store().genres().await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub async fn library_years(library: State<'_, Arc<Library>>) -> Result<Vec<LibraryYear>, String> {
+    library.store().years().await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub async fn library_folders(
+    library: State<'_, Arc<Library>>,
+) -> Result<Vec<LibraryFolder>, String> {
+    library.store().folders().await.map_err(|e| e.to_string())
+}
+
+/// Tracks matching `filter` (by artist, album, genre, year or folder).
+#[tauri::command]
+pub async fn library_tracks(
+    library: State<'_, Arc<Library>>,
+    filter: Option<TrackFilter>,
+) -> Result<Vec<LibraryTrack>, String> {
+    library
+        .store()
+        .tracks(&filter.unwrap_or_default())
+        .await
+        .map_err(|e| e.to_string())
+}
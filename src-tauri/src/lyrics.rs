@@ -0,0 +1,736 @@
+use lofty::config::ParseOptions;
+use lofty::error::LoftyError;
+use lofty::file::{AudioFile, FileType, TaggedFileExt};
+use lofty::id3::v2::{Frame, Id3v2Tag, SynchronizedTextFrame, TimestampFormat};
+use lofty::probe::Probe;
+use lofty::tag::ItemKey;
+use serde::Serialize;
+use sqlx::SqlitePool;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use thiserror::Error;
+use tokio::runtime::Handle;
+
+use crate::mpv::{MpvError, MpvEventId, MpvFormat, MpvPlayer};
+use crate::player_handle::FollowedPlayer;
+
+const SIDECAR_EXTENSION: &str = "lrc";
+
+#[derive(Error, Debug)]
+pub enum LyricsError {
+    #[error("Failed to read lyrics: {0}")]
+    IoError(#[from] std::io::Error),
+
+    #[error("Failed to read tags: {0}")]
+    LoftyError(#[from] LoftyError),
+}
+
+/// A word of an enhanced LRC line, or a syllable of a SYLT frame.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct LyricWord {
+    /// In seconds.
+    pub start: f64,
+    pub text: String,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct LyricLine {
+    /// In seconds. Always 0 for unsynchronized lyrics.
+    pub start: f64,
+    /// The start of the next line, if any.
+    pub end: Option<f64>,
+    pub text: String,
+    /// Empty unless the lyrics have word-level timing.
+    pub words: Vec<LyricWord>,
+}
+
+/// Where lyrics were found.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum LyricsSource {
+    /// A `.lrc` file next to the media file.
+    Sidecar { path: PathBuf },
+    /// A SYLT frame.
+    SynchronizedFrame,
+    /// The lyrics field of the tags (USLT, Vorbis `LYRICS`, `©lyr`, ...), which
+    /// may hold LRC.
+    Tag,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Lyrics {
+    pub source: LyricsSource,
+    pub synced: bool,
+    pub lines: Vec<LyricLine>,
+    /// The per-track offset set by the user, in milliseconds. Positive values show
+    /// lines earlier. Offsets from LRC `[offset:]` tags are already applied to
+    /// the line times.
+    pub offset_ms: i64,
+}
+
+impl Lyrics {
+    fn new(source: LyricsSource, lines: Vec<LyricLine>, synced: bool) -> Self {
+        Self {
+            source,
+            synced,
+            lines,
+            offset_ms: 0,
+        }
+    }
+
+    /// Index of the line being sung at `position` (in seconds), if the lyrics
+    /// are synchronized and the first line has started.
+    pub fn line_at(&self, position: f64) -> Option<usize> {
+        if !self.synced {
+            return None;
+        }
+        let position = position + self.offset_ms as f64 / 1000.0;
+        self.lines
+            .partition_point(|line| line.start <= position)
+            .checked_sub(1)
+    }
+}
+
+/// Reads the lyrics of `path`: a sidecar `.lrc` first, then a SYLT frame, then
+/// the lyrics tag. Unsynchronized lyrics are only returned if nothing timed is
+/// found.
+pub fn load_lyrics(path: &Path) -> Result<Option<Lyrics>, LyricsError> {
+    if let Some(sidecar) = find_sidecar(path) {
+        let bytes = fs::read(&sidecar)?;
+        let text = String::from_utf8_lossy(&bytes);
+        let (lines, synced) = parse_text(&text);
+        if !lines.is_empty() {
+            return Ok(Some(Lyrics::new(
+                LyricsSource::Sidecar { path: sidecar },
+                lines,
+                synced,
+            )));
+        }
+    }
+
+    let file_type = Probe::open(path)?.guess_file_type()?.file_type();
+    if let Some(lines) = file_type.and_then(|t| read_sylt(path, t).transpose()) {
+        let lines = lines?;
+        if !lines.is_empty() {
+            return Ok(Some(Lyrics::new(
+                LyricsSource::SynchronizedFrame,
+                lines,
+                true,
+            )));
+        }
+    }
+
+    let tagged = lofty::read_from_path(path)?;
+    let text = tagged
+        .primary_tag()
+        .into_iter()
+        .chain(tagged.tags())
+        .find_map(|tag| tag.get_string(&ItemKey::Lyrics));
+    Ok(text.and_then(|text| {
+        let (lines, synced) = parse_text(text);
+        (!lines.is_empty()).then(|| Lyrics::new(LyricsSource::Tag, lines, synced))
+    }))
+}
+
+/// `<stem>.lrc` in the folder of `path`, ignoring case.
+fn find_sidecar(path: &Path) -> Option<PathBuf> {
+    let folder = path.parent()?;
+    let stem = path.file_stem()?.to_string_lossy().to_lowercase();
+    fs::read_dir(folder)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .find(|candidate| {
+            candidate
+                .extension()
+                .is_some_and(|extension| extension.eq_ignore_ascii_case(SIDECAR_EXTENSION))
+                && candidate
+                    .file_stem()
+                    .is_some_and(|s| s.to_string_lossy().to_lowercase() == stem)
+                && candidate.is_file()
+        })
+}
+
+/// Reads the SYLT lyrics of formats carrying ID3v2. The generic tag drops SYLT,
+/// so the concrete file has to be read.
+fn read_sylt(path: &Path, file_type: FileType) -> Result<Option<Vec<LyricLine>>, LyricsError> {
+    fn id3v2<F: AudioFile>(
+        path: &Path,
+        tag: impl FnOnce(&F) -> Option<&Id3v2Tag>,
+    ) -> Result<Option<Vec<LyricLine>>, LyricsError> {
+        let audio = F::read_from(&mut File::open(path)?, ParseOptions::new())?;
+        Ok(tag(&audio).and_then(sylt_lines))
+    }
+
+    match file_type {
+        FileType::Mpeg => id3v2::<lofty::mpeg::MpegFile>(path, |f| f.id3v2()),
+        FileType::Aac => id3v2::<lofty::aac::AacFile>(path, |f| f.id3v2()),
+        FileType::Aiff => id3v2::<lofty::iff::aiff::AiffFile>(path, |f| f.id3v2()),
+        FileType::Wav => id3v2::<lofty::iff::wav::WavFile>(path, |f| f.id3v2()),
+        _ => Ok(None),
+    }
+}
+
+/// The first SYLT frame holding lyrics with millisecond timestamps. Entries
+/// starting with a line break begin a new line; the others are syllables of the
+/// current one. Frames without any line break hold one line per entry.
+fn sylt_lines(tag: &Id3v2Tag) -> Option<Vec<LyricLine>> {
+    let frame = tag.into_iter().find_map(|frame| match frame {
+        Frame::Binary(binary) if frame.id().as_str() == "SYLT" => {
+            SynchronizedTextFrame::parse(&binary.data, binary.flags())
+                .ok()
+                .filter(|sylt| sylt.timestamp_format == TimestampFormat::MS)
+        }
+        _ => None,
+    })?;
+
+    let per_word = frame
+        .content
+        .iter()
+        .any(|(_, text)| text.starts_with(['\n', '\r']));
+    let mut lines: Vec<LyricLine> = Vec::new();
+    for (timestamp, text) in &frame.content {
+        let start = *timestamp as f64 / 1000.0;
+        let new_line = !per_word || text.starts_with(['\n', '\r']) || lines.is_empty();
+        let text = text.trim_start_matches(['\n', '\r']);
+        if new_line {
+            lines.push(LyricLine {
+                start,
+                end: None,
+                text: String::new(),
+                words: Vec::new(),
+            });
+        }
+        let line = lines.last_mut()?;
+        line.text.push_str(text);
+        if per_word {
+            line.words.push(LyricWord {
+                start,
+                text: text.to_string(),
+            });
+        }
+    }
+    Some(finish_lines(lines))
+}
+
+/// Parses LRC if `text` has any timed line, and returns its lines otherwise.
+/// The flag tells whether the lines are timed.
+pub fn parse_text(text: &str) -> (Vec<LyricLine>, bool) {
+    let synced = parse_lrc(text);
+    if !synced.is_empty() {
+        return (synced, true);
+    }
+
+    let lines = text
+        .trim_start_matches('\u{feff}')
+        .trim()
+        .lines()
+        .map(|line| LyricLine {
+            start: 0.0,
+            end: None,
+            text: line.trim().to_string(),
+            words: Vec::new(),
+        })
+        .collect();
+    (lines, false)
+}
+
+/// Parses LRC, including lines with several timestamps (`[00:12.00][01:30.00]`),
+/// the `[offset:]` tag and enhanced LRC word times (`<00:12.50>`). Untimed lines
+/// and other ID tags are skipped.
+pub fn parse_lrc(text: &str) -> Vec<LyricLine> {
+    let mut offset_ms = 0i64;
+    let mut timed: Vec<(f64, f64, &str)> = Vec::new();
+
+    for raw in text.trim_start_matches('\u{feff}').lines() {
+        let mut rest = raw.trim();
+        let mut times = Vec::new();
+        while let Some(tag) = rest.strip_prefix('[') {
+            let Some(close) = tag.find(']') else {
+                break;
+            };
+            let (content, after) = (&tag[..close], &tag[close + 1..]);
+            if let Some(time) = parse_timestamp(content) {
+                times.push(time);
+            } else if let Some((key, value)) = content.split_once(':') {
+                if key.trim().eq_ignore_ascii_case("offset") {
+                    offset_ms = value.trim().parse().unwrap_or(offset_ms);
+                }
+            }
+            rest = after;
+        }
+        // Word times are written for the first occurrence of a repeated line
+        if let Some(&first) = times.first() {
+            timed.extend(times.into_iter().map(|time| (time, time - first, rest)));
+        }
+    }
+
+    // A positive offset shows the lines earlier
+    let shift = |time: f64| (time - offset_ms as f64 / 1000.0).max(0.0);
+    let mut lines: Vec<LyricLine> = timed
+        .into_iter()
+        .map(|(start, delay, content)| {
+            let start = shift(start);
+            let words = parse_words(content, start, |time| shift(time + delay));
+            let text = if words.is_empty() {
+                content.trim().to_string()
+            } else {
+                words.iter().map(|w| w.text.as_str()).collect::<String>()
+            };
+            LyricLine {
+                start,
+                end: None,
+                text: text.trim().to_string(),
+                words,
+            }
+        })
+        .collect();
+    lines.sort_by(|a, b| a.start.total_cmp(&b.start));
+    finish_lines(lines)
+}
+
+/// Splits the content of an enhanced LRC line at its `<mm:ss.xx>` marks. Text
+/// before the first mark starts with the line.
+fn parse_words(content: &str, line_start: f64, shift: impl Fn(f64) -> f64) -> Vec<LyricWord> {
+    if !content.contains('<') {
+        return Vec::new();
+    }
+
+    let mut words = Vec::new();
+    let mut start = line_start;
+    let mut rest = content;
+    let mut timed = false;
+    loop {
+        let mark = rest.find('<').and_then(|open| {
+            let close = open + rest[open..].find('>')?;
+            Some((open, close, parse_timestamp(&rest[open + 1..close])?))
+        });
+        let text = match mark {
+            Some((open, _, _)) => &rest[..open],
+            None => rest,
+        };
+        if !text.trim().is_empty() {
+            words.push(LyricWord {
+                start,
+                text: text.to_string(),
+            });
+        }
+        let Some((_, close, time)) = mark else {
+            break;
+        };
+        timed = true;
+        start = shift(time);
+        rest = &rest[close + 1..];
+    }
+
+    if timed {
+        words
+    } else {
+        Vec::new()
+    }
+}
+
+/// `mm:ss`, `mm:ss.xx` or `mm:ss:xx`, in seconds.
+fn parse_timestamp(s: &str) -> Option<f64> {
+    let (minutes, seconds) = s.trim().split_once(':')?;
+    let minutes: u32 = minutes.parse().ok()?;
+    let seconds: f64 = match seconds.split_once(':') {
+        Some((whole, fraction)) => format!("{}.{}", whole, fraction).parse().ok()?,
+        None => seconds.parse().ok()?,
+    };
+    (0.0..60.0)
+        .contains(&seconds)
+        .then_some(minutes as f64 * 60.0 + seconds)
+}
+
+/// Ends each line where the next one starts.
+fn finish_lines(mut lines: Vec<LyricLine>) -> Vec<LyricLine> {
+    let starts: Vec<f64> = lines.iter().skip(1).map(|line| line.start).collect();
+    for (line, next) in lines.iter_mut().zip(starts) {
+        line.end = Some(next);
+    }
+    lines
+}
+
+/// Per-track lyric offsets persisted in the `lyrics_offset` table of the app database.
+pub struct LyricsStore {
+    pool: SqlitePool,
+}
+
+impl LyricsStore {
+    pub async fn new(pool: SqlitePool) -> Result<Self, sqlx::Error> {
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS lyrics_offset (
+                path TEXT PRIMARY KEY NOT NULL,
+                offset_ms INTEGER NOT NULL
+            )",
+        )
+        .execute(&pool)
+        .await?;
+
+        Ok(Self { pool })
+    }
+
+    pub async fn get_offset(&self, path: &str) -> Result<i64, sqlx::Error> {
+        let offset: Option<i64> =
+            sqlx::query_scalar("SELECT offset_ms FROM lyrics_offset WHERE path = ?")
+                .bind(path)
+                .fetch_optional(&self.pool)
+                .await?;
+        Ok(offset.unwrap_or(0))
+    }
+
+    /// Saves the offset of `path`. An offset of 0 is forgotten.
+    pub async fn set_offset(&self, path: &str, offset_ms: i64) -> Result<(), sqlx::Error> {
+        if offset_ms == 0 {
+            sqlx::query("DELETE FROM lyrics_offset WHERE path = ?")
+                .bind(path)
+                .execute(&self.pool)
+                .await?;
+        } else {
+            sqlx::query(
+                "INSERT INTO lyrics_offset (path, offset_ms) VALUES (?, ?)
+                 ON CONFLICT(path) DO UPDATE SET offset_ms = excluded.offset_ms",
+            )
+            .bind(path)
+            .bind(offset_ms)
+            .execute(&self.pool)
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Reads the lyrics of `path` along with its saved offset.
+    pub async fn load(&self, path: &str) -> Result<Option<Lyrics>, String> {
+        let file_path = PathBuf::from(path);
+        let lyrics = tokio::task::spawn_blocking(move || load_lyrics(&file_path))
+            .await
+            .map_err(|e| e.to_string())?
+            .map_err(|e| e.to_string())?;
+        let Some(mut lyrics) = lyrics else {
+            return Ok(None);
+        };
+        lyrics.offset_ms = self.get_offset(path).await.map_err(|e| e.to_string())?;
+        Ok(Some(lyrics))
+    }
+}
+
+/// Sent when the active line of the playing file changes.
+#[derive(Serialize, Debug, Clone)]
+pub struct LyricsLineChange {
+    pub path: String,
+    /// `None` before the first line, or once the file has ended.
+    pub index: Option<usize>,
+    pub line: Option<LyricLine>,
+}
+
+struct CurrentLyrics {
+    path: String,
+    lyrics: Option<Lyrics>,
+    position: f64,
+    index: Option<usize>,
+}
+
+/// Follows the playback position and reports the active lyric line.
+pub struct LyricsTracker {
+    store: Arc<LyricsStore>,
+    runtime: Handle,
+    current: Mutex<Option<CurrentLyrics>>,
+    on_line: Box<dyn Fn(LyricsLineChange) + Send + Sync>,
+    followed: FollowedPlayer,
+}
+
+impl LyricsTracker {
+    /// `runtime` loads the lyrics, since the callbacks are called from mpv's
+    /// event thread.
+    pub fn new(
+        store: Arc<LyricsStore>,
+        runtime: Handle,
+        on_line: impl Fn(LyricsLineChange) + Send + Sync + 'static,
+    ) -> Arc<Self> {
+        Arc::new(Self {
+            store,
+            runtime,
+            current: Mutex::new(None),
+            on_line: Box::new(on_line),
+            followed: FollowedPlayer::default(),
+        })
+    }
+
+    /// Hooks the tracker into `player`'s events and follows it from now on.
+    /// Called again for every new core and whenever a crossfade moves playback
+    /// to the other deck, whose file is picked up if it is already playing.
+    pub fn attach(self: &Arc<Self>, player: &Arc<MpvPlayer>) -> Result<(), MpvError> {
+        let new = self.followed.follow(player);
+
+        self.on_end_file();
+        if !player.is_idle().unwrap_or(true) {
+            if let Ok(path) = player.get_path() {
+                self.on_file_loaded(path);
+                let position = player.get_position().unwrap_or(0.0);
+                self.update(|current| current.position = position);
+            }
+        }
+
+        if !new {
+            return Ok(());
+        }
+
+        // Weak, since the player keeps its callbacks alive
+        let (t, p) = (Arc::downgrade(self), Arc::downgrade(player));
+        player.register_event_callback(MpvEventId::FileLoaded, move |_| {
+            let Some(t) = t.upgrade().filter(|t| t.followed.is_followed(&p)) else {
+                return;
+            };
+            if let Some(player) = p.upgrade() {
+                if let Ok(path) = player.get_path() {
+                    t.on_file_loaded(path);
+                }
+            }
+        })?;
+
+        let (t, p) = (Arc::downgrade(self), Arc::downgrade(player));
+        player.register_event_callback(MpvEventId::EndFile, move |_| {
+            if let Some(t) = t.upgrade().filter(|t| t.followed.is_followed(&p)) {
+                t.on_end_file()
+            }
+        })?;
+
+        let (t, p) = (Arc::downgrade(self), Arc::downgrade(player));
+        player.on_property_change("time-pos", MpvFormat::Double, move |value| {
+            let Some(t) = t.upgrade().filter(|t| t.followed.is_followed(&p)) else {
+                return;
+            };
+            if let Some(position) = value.as_f64() {
+                t.update(|current| current.position = position);
+            }
+        })?;
+
+        Ok(())
+    }
+
+    fn on_file_loaded(self: &Arc<Self>, path: String) {
+        *self.current.lock().unwrap() = Some(CurrentLyrics {
+            path: path.clone(),
+            lyrics: None,
+            position: 0.0,
+            index: None,
+        });
+
+        let t = self.clone();
+        self.runtime.spawn(async move {
+            let lyrics = match t.store.load(&path).await {
+                Ok(lyrics) => lyrics,
+                Err(e) => {
+                    eprintln!("Failed to load lyrics of {}: {}", path, e);
+                    return;
+                }
+            };
+            t.update_path(&path, |current| current.lyrics = lyrics);
+        });
+    }
+
+    fn on_end_file(&self) {
+        let Some(current) = self.current.lock().unwrap().take() else {
+            return;
+        };
+        if current.index.is_some() {
+            (self.on_line)(LyricsLineChange {
+                path: current.path,
+                index: None,
+                line: None,
+            });
+        }
+    }
+
+    /// Changes the offset of `path` if it is playing. Doesn't save it.
+    pub fn set_offset(&self, path: &str, offset_ms: i64) {
+        self.update_path(path, |current| {
+            if let Some(lyrics) = current.lyrics.as_mut() {
+                lyrics.offset_ms = offset_ms;
+            }
+        });
+    }
+
+    /// The lyrics of the playing file, once loaded.
+    pub fn current(&self) -> Option<Lyrics> {
+        self.current
+            .lock()
+            .unwrap()
+            .as_ref()
+            .and_then(|current| current.lyrics.clone())
+    }
+
+    fn update_path(&self, path: &str, f: impl FnOnce(&mut CurrentLyrics)) {
+        self.update(|current| {
+            if current.path == path {
+                f(current);
+            }
+        });
+    }
+
+    /// Applies `f`, then reports the active line if it changed.
+    fn update(&self, f: impl FnOnce(&mut CurrentLyrics)) {
+        let change = {
+            let mut current = self.current.lock().unwrap();
+            let Some(current) = current.as_mut() else {
+                return;
+            };
+            f(current);
+
+            let lyrics = current.lyrics.as_ref();
+            let index = lyrics.and_then(|lyrics| lyrics.line_at(current.position));
+            if index == current.index {
+                return;
+            }
+            current.index = index;
+            LyricsLineChange {
+                path: current.path.clone(),
+                index,
+                line: index.and_then(|i| lyrics?.lines.get(i).cloned()),
+            }
+        };
+        (self.on_line)(change);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lofty::id3::v2::{BinaryFrame, FrameId, SyncTextContentType};
+    use lofty::TextEncoding;
+
+    fn starts(lines: &[LyricLine]) -> Vec<f64> {
+        lines.iter().map(|line| line.start).collect()
+    }
+
+    fn sylt_tag(format: TimestampFormat, content: &[(u32, &str)]) -> Id3v2Tag {
+        let frame = SynchronizedTextFrame::new(
+            TextEncoding::UTF8,
+            *b"eng",
+            format,
+            SyncTextContentType::Lyrics,
+            None,
+            content
+                .iter()
+                .map(|(time, text)| (*time, text.to_string()))
+                .collect(),
+        );
+        let mut tag = Id3v2Tag::new();
+        tag.insert(Frame::Binary(BinaryFrame::new(
+            FrameId::new("SYLT").unwrap(),
+            frame.as_bytes().unwrap(),
+        )));
+        tag
+    }
+
+    #[test]
+    fn parses_timestamps() {
+        assert_eq!(parse_timestamp("01:02.50"), Some(62.5));
+        assert_eq!(parse_timestamp("01:02:50"), Some(62.5));
+        assert_eq!(parse_timestamp(" 1:02 "), Some(62.0));
+        assert_eq!(parse_timestamp("120:00.00"), Some(7200.0));
+        assert_eq!(parse_timestamp("00:60.00"), None);
+        assert_eq!(parse_timestamp("ar:Artist"), None);
+        assert_eq!(parse_timestamp("12.50"), None);
+        assert_eq!(parse_timestamp(""), None);
+    }
+
+    #[test]
+    fn parses_timed_lines_in_order() {
+        let lines = parse_lrc(
+            "\u{feff}[ar:Artist]\n[ti:Title]\n[00:12.00]Second\n\
+             [00:05.50][01:00.00] Chorus \nNot timed\n[00:20.00]\n",
+        );
+
+        assert_eq!(starts(&lines), [5.5, 12.0, 20.0, 60.0]);
+        let texts: Vec<&str> = lines.iter().map(|line| line.text.as_str()).collect();
+        assert_eq!(texts, ["Chorus", "Second", "", "Chorus"]);
+        let ends: Vec<Option<f64>> = lines.iter().map(|line| line.end).collect();
+        assert_eq!(ends, [Some(12.0), Some(20.0), Some(60.0), None]);
+        assert!(lines.iter().all(|line| line.words.is_empty()));
+    }
+
+    #[test]
+    fn applies_the_offset_tag() {
+        let lines = parse_lrc("[offset:+500]\n[00:00.20]First\n[00:10.00]Second");
+        assert_eq!(starts(&lines), [0.0, 9.5]);
+
+        let lines = parse_lrc("[offset:-1000]\n[00:10.00]Later");
+        assert_eq!(starts(&lines), [11.0]);
+    }
+
+    #[test]
+    fn parses_word_times() {
+        let lines = parse_lrc("[00:10.00]<00:10.00>Hel<00:10.50>lo <00:11.00>world");
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0].text, "Hello world");
+        let words: Vec<(f64, &str)> = lines[0]
+            .words
+            .iter()
+            .map(|word| (word.start, word.text.as_str()))
+            .collect();
+        assert_eq!(words, [(10.0, "Hel"), (10.5, "lo "), (11.0, "world")]);
+    }
+
+    #[test]
+    fn moves_word_times_with_repeated_lines() {
+        let lines = parse_lrc("[00:10.00][00:30.00]Oh <00:11.00>yeah");
+        assert_eq!(starts(&lines), [10.0, 30.0]);
+        assert_eq!(lines[1].words[0].start, 30.0);
+        assert_eq!(lines[1].words[1].start, 31.0);
+        assert_eq!(lines[1].text, "Oh yeah");
+    }
+
+    #[test]
+    fn falls_back_to_plain_text() {
+        let (lines, synced) = parse_text("First line\n  Second line  \n");
+        assert!(!synced);
+        let texts: Vec<&str> = lines.iter().map(|line| line.text.as_str()).collect();
+        assert_eq!(texts, ["First line", "Second line"]);
+        assert!(parse_lrc("No [00:10.00] times at the start").is_empty());
+    }
+
+    #[test]
+    fn reads_one_line_per_sylt_entry() {
+        let tag = sylt_tag(
+            TimestampFormat::MS,
+            &[(1000, "First line"), (4500, "Second line")],
+        );
+        let lines = sylt_lines(&tag).unwrap();
+
+        assert_eq!(starts(&lines), [1.0, 4.5]);
+        assert_eq!(lines[0].text, "First line");
+        assert_eq!(lines[0].end, Some(4.5));
+        assert!(lines[1].words.is_empty());
+    }
+
+    #[test]
+    fn reads_sylt_syllables_into_lines() {
+        let tag = sylt_tag(
+            TimestampFormat::MS,
+            &[
+                (1000, "Hel"),
+                (1500, "lo "),
+                (2000, "world"),
+                (5000, "\nNext"),
+                (5500, " one"),
+            ],
+        );
+        let lines = sylt_lines(&tag).unwrap();
+
+        assert_eq!(starts(&lines), [1.0, 5.0]);
+        assert_eq!(lines[0].text, "Hello world");
+        assert_eq!(lines[1].text, "Next one");
+        let words: Vec<f64> = lines[0].words.iter().map(|word| word.start).collect();
+        assert_eq!(words, [1.0, 1.5, 2.0]);
+    }
+
+    #[test]
+    fn skips_sylt_frames_timed_in_frames() {
+        let tag = sylt_tag(TimestampFormat::MPEG, &[(100, "Line")]);
+        assert!(sylt_lines(&tag).is_none());
+        assert!(sylt_lines(&Id3v2Tag::new()).is_none());
+    }
+}
@@ -0,0 +1,44 @@
+use crate::lyrics::{Lyrics, LyricsStore, LyricsTracker};
+
+use std::sync::Arc;
+use tauri::State;
+
+/// The lyrics of `path` with its saved offset, or `None` if it has none.
+#[tauri::command]
+pub async fn lyrics_get(
+    store: State<'_, Arc<LyricsStore>>,
+    path: String,
+) -> Result<Option<Lyrics>, String> {
+    store.load(&path).await
+}
+
+/// The lyrics of the playing file, once loaded.
+#[tauri::command]
+pub fn lyrics_get_current(tracker: State<'_, Arc<LyricsTracker>>) -> Option<Lyrics> {
+    tracker.current()
+}
+
+#[tauri::command]
+pub async fn lyrics_get_offset(
+    store: State<'_, Arc<LyricsStore>>,
+    path: String,
+) -> Result<i64, String> {
+    store.get_offset(&path).await.map_err(|e| e.to_string())
+}
+
+/// Saves the lyric offset of `path`, in milliseconds. Positive values show lines
+/// earlier. Takes effect right away if `path` is playing.
+#[tauri::command]
+pub async fn lyrics_set_offset(
+    store: State<'_, Arc<LyricsStore>>,
+    tracker: State<'_, Arc<LyricsTracker>>,
+    path: String,
+    offset_ms: i64,
+) -> Result<(), String> {
+    store
+        .set_offset(&path, offset_ms)
+        .await
+        .map_err(|e| e.to_string())?;
+    tracker.set_offset(&path, offset_ms);
+    Ok(())
+}
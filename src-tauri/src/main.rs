@@ -1,23 +1,86 @@
 // Prevents additional console window on Windows in release, DO NOT REMOVE!!
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
+mod cli;
+mod cover_art;
+mod cover_art_tauri_commands;
+mod crossfade;
+mod crossfade_tauri_commands;
+mod cue;
+mod database;
+mod gapless;
+mod history;
+mod history_tauri_commands;
+mod ipc_server;
+mod ipc_tauri_commands;
+mod library;
+mod library_tauri_commands;
+mod lyrics;
+mod lyrics_tauri_commands;
+mod media_probe;
 mod metadata;
+#[cfg(target_os = "linux")]
+mod mpris;
 mod mpv;
+mod mpv_properties;
 mod mpv_tauri_commands;
+mod player_handle;
+mod player_state;
+mod remote_control;
+mod remote_control_tauri_commands;
+mod resume;
+mod resume_tauri_commands;
+mod scanner;
+mod scanner_tauri_commands;
+mod search;
+mod search_tauri_commands;
+mod sleep_timer;
+mod sleep_timer_tauri_commands;
+mod stream_status;
+mod tag_editor;
+mod tag_editor_tauri_commands;
+mod thumbnails;
+mod thumbnails_tauri_commands;
+#[cfg(windows)]
 mod winapi_abstraction;
 
 use std::path::Path;
+use std::sync::Arc;
 
 use tauri::{Manager, Runtime};
+#[cfg(windows)]
 use winapi::shared::windef::HWND;
 
+#[cfg(windows)]
 use winapi_abstraction::*;
 
 use sqlx::{Column, Connection, Row, SqliteConnection, TypeInfo, ValueRef};
 
+use cover_art::CoverArtCache;
+use crossfade::CrossfadeHandle;
+use crossfade_tauri_commands::CrossfadePositionPayload;
+use history::{HistoryStore, HistoryTracker};
+use ipc_server::IpcConfig;
+use library::{Library, LibraryStore};
+use lyrics::{LyricsStore, LyricsTracker};
+use media_probe::ProbePool;
+use mpv::{MpvError, MpvPlayer};
+use mpv_properties::MpvAllowlist;
+use player_handle::PlayerHandle;
+use player_state::PlayerStateTracker;
+use remote_control::{RemoteControl, RemoteStore};
+use resume::{ResumeStore, ResumeTracker};
+use scanner::{ScanCache, Scanner};
+use search::SearchIndex;
+use sleep_timer::SleepTimer;
+use thumbnails::ThumbnailGenerator;
+
 #[tauri::command]
-async fn get_media_info(path: String) -> Result<metadata::SimplifiedMetadata, String> {
-    match metadata::parse_metadata(&path).await {
+async fn get_media_info(
+    probes: tauri::State<'_, Arc<ProbePool>>,
+    path: String,
+) -> Result<metadata::SimplifiedMetadata, String> {
+    match metadata::parse_media_info(probes.inner(), &path).await {
         Ok(metadata) => Ok(metadata),
         Err(e) => Err(e.to_string()),
     }
@@ -102,9 +165,438 @@ async fn db_execute(
     Ok(result)
 }
 
+/// Opens what was passed on the command line, at startup or forwarded by a later launch.
+fn open_launch_args(app: &tauri::AppHandle, args: &cli::LaunchArgs) {
+    if let Some(window) = app.get_window("container") {
+        if args.fullscreen {
+            window
+                .set_fullscreen(true)
+                .unwrap_or_else(|e| eprintln!("Failed to set fullscreen: {}", e));
+        }
+    }
+
+    let result = app
+        .state::<PlayerHandle>()
+        .get()
+        .and_then(|player| args.apply(&player).map(|_| player));
+    match result {
+        // The playlist was changed behind the UI's back
+        Ok(player) if !args.inputs.is_empty() || args.playlist.is_some() => {
+            let playlist = player.get_playlist().unwrap_or_default();
+            app.emit_all("mpv-external-playlist-change", playlist)
+                .unwrap_or_else(|e| eprintln!("Failed to emit event: {}", e));
+        }
+        Ok(_) => (),
+        Err(e) => eprintln!("Failed to open launch arguments: {}", e),
+    }
+}
+
+/// Sets up the Rust-side services that share the app database and follow mpv's events.
+/*
+ * The webviews and mpv's video are embedded in the container window with Win32
+ * APIs. Elsewhere they stay separate windows and mpv opens its own video window.
+ */
+
+#[cfg(windows)]
+fn child_of<'a, R: Runtime>(
+    builder: tauri::WindowBuilder<'a, R>,
+    container: &tauri::Window<R>,
+) -> tauri::WindowBuilder<'a, R> {
+    builder.parent_window(container.hwnd().unwrap())
+}
+
+#[cfg(not(windows))]
+fn child_of<'a, R: Runtime>(
+    builder: tauri::WindowBuilder<'a, R>,
+    _container: &tauri::Window<R>,
+) -> tauri::WindowBuilder<'a, R> {
+    builder
+}
+
+/// Sets all other windows to be child of the container window; ORDER MATTERS!
+#[cfg(windows)]
+fn embed_windows<R: Runtime>(
+    container_win: &tauri::Window<R>,
+    bg_win: &tauri::Window<R>,
+    mpv_win: &tauri::Window<R>,
+    app_win: &tauri::Window<R>,
+) {
+    attach_child_to_parent_area(
+        container_win.hwnd().unwrap().0 as HWND,
+        bg_win.hwnd().unwrap().0 as HWND,
+        0,
+        0,
+        container_win.inner_size().unwrap().width as i32,
+        container_win.inner_size().unwrap().height as i32,
+    );
+
+    attach_child_to_parent_area(
+        container_win.hwnd().unwrap().0 as HWND,
+        mpv_win.hwnd().unwrap().0 as HWND,
+        0,
+        0,
+        0, // don't care; handled by JS MpvWindowProxy
+        0, // don't care; handled by JS MpvWindowProxy
+    );
+
+    attach_child_to_parent_area(
+        container_win.hwnd().unwrap().0 as HWND,
+        app_win.hwnd().unwrap().0 as HWND,
+        0,
+        0,
+        container_win.inner_size().unwrap().width as i32,
+        container_win.inner_size().unwrap().height as i32,
+    );
+}
+
+#[cfg(not(windows))]
+fn embed_windows<R: Runtime>(
+    _container_win: &tauri::Window<R>,
+    _bg_win: &tauri::Window<R>,
+    _mpv_win: &tauri::Window<R>,
+    _app_win: &tauri::Window<R>,
+) {
+}
+
+/// The window mpv renders the video into.
+#[cfg(windows)]
+fn video_window<R: Runtime>(mpv_win: &tauri::Window<R>) -> Option<usize> {
+    Some(mpv_win.hwnd().unwrap().0 as usize)
+}
+
+#[cfg(not(windows))]
+fn video_window<R: Runtime>(_mpv_win: &tauri::Window<R>) -> Option<usize> {
+    None
+}
+
+fn init_services(app: &tauri::App) -> Result<(), Box<dyn std::error::Error>> {
+    let app_data_dir = app
+        .path_resolver()
+        .app_data_dir()
+        .ok_or("Failed to resolve the app data directory")?;
+    let db_path = app_data_dir.join(database::DB_FILE_NAME);
+    let pool = tauri::async_runtime::block_on(database::connect(&db_path))?;
+    let runtime = tauri::async_runtime::handle().inner().clone();
+    let player_handle = app.state::<PlayerHandle>();
+
+    let resume_store = Arc::new(tauri::async_runtime::block_on(ResumeStore::new(
+        pool.clone(),
+    ))?);
+    let resume_tracker = ResumeTracker::new(resume_store.clone(), runtime.clone());
+
+    let history_store = Arc::new(tauri::async_runtime::block_on(HistoryStore::new(
+        pool.clone(),
+    ))?);
+    let history_tracker = HistoryTracker::new(history_store.clone(), runtime.clone());
+
+    let lyrics_store = Arc::new(tauri::async_runtime::block_on(LyricsStore::new(
+        pool.clone(),
+    ))?);
+    let app_handle = app.handle();
+    let lyrics_tracker = LyricsTracker::new(lyrics_store.clone(), runtime.clone(), move |change| {
+        app_handle
+            .emit_all("lyrics-line", change)
+            .unwrap_or_else(|e| eprintln!("Failed to emit event: {}", e));
+    });
+
+    let app_handle = app.handle();
+    let player_state_tracker = PlayerStateTracker::new(move |state| {
+        app_handle
+            .emit_all("mpv-state", state)
+            .unwrap_or_else(|e| eprintln!("Failed to emit event: {}", e));
+    });
+
+    let app_handle = app.handle();
+    let sleep_timer = SleepTimer::new(move || {
+        app_handle
+            .emit_all("sleep-timer-fired", ())
+            .unwrap_or_else(|e| eprintln!("Failed to emit event: {}", e));
+    });
+
+    let app_cache_dir = app
+        .path_resolver()
+        .app_cache_dir()
+        .ok_or("Failed to resolve the app cache directory")?;
+    let cover_art_cache = CoverArtCache::new(
+        mpv_tauri_commands::MPV_LIB_PATH,
+        app_cache_dir.join(cover_art::CACHE_DIR_NAME),
+    );
+
+    let thumbnail_dir = app_cache_dir.join(thumbnails::CACHE_DIR_NAME);
+    let app_handle = app.handle();
+    let thumbnail_generator = ThumbnailGenerator::new(
+        mpv_tauri_commands::MPV_LIB_PATH,
+        thumbnail_dir,
+        move |progress| {
+            app_handle
+                .emit_all("thumbnail-progress", progress)
+                .unwrap_or_else(|e| eprintln!("Failed to emit event: {}", e));
+        },
+    );
+
+    let scan_cache = Arc::new(tauri::async_runtime::block_on(ScanCache::new(
+        pool.clone(),
+    ))?);
+    let probe_pool = ProbePool::new(mpv_tauri_commands::MPV_LIB_PATH);
+    let app_handle = app.handle();
+    let scanner = Scanner::new(
+        probe_pool.clone(),
+        scan_cache,
+        runtime.clone(),
+        move |progress| {
+            app_handle
+                .emit_all("scan-progress", progress)
+                .unwrap_or_else(|e| eprintln!("Failed to emit event: {}", e));
+        },
+    );
+
+    let library_store = Arc::new(tauri::async_runtime::block_on(LibraryStore::new(
+        pool.clone(),
+    ))?);
+    let app_handle = app.handle();
+    let library = match Library::start(
+        library_store,
+        probe_pool.clone(),
+        runtime.clone(),
+        move |change| {
+            app_handle
+                .emit_all("library-changed", change)
+                .unwrap_or_else(|e| eprintln!("Failed to emit event: {}", e));
+        },
+    ) {
+        Ok(library) => Some(library),
+        // e.g. the system ran out of file watches; the player works without it
+        Err(e) => {
+            eprintln!("Failed to start the library: {}", e);
+            None
+        }
+    };
+
+    let search_index = Arc::new(tauri::async_runtime::block_on(SearchIndex::new(
+        pool.clone(),
+    ))?);
+
+    // Follow the player playback is on: every new mpv core, and the crossfade
+    // deck that takes over from it
+    let (resume, history, lyrics, player_state, sleep) = (
+        resume_tracker.clone(),
+        history_tracker.clone(),
+        lyrics_tracker.clone(),
+        player_state_tracker.clone(),
+        sleep_timer.clone(),
+    );
+    let attach_trackers = Arc::new(move |player: &Arc<MpvPlayer>| -> Result<(), MpvError> {
+        resume.attach(player)?;
+        history.attach(player)?;
+        lyrics.attach(player)?;
+        player_state.attach(player)?;
+        sleep.attach(player)
+    });
+
+    // Run again for every new mpv core
+    let app_handle = app.handle();
+    let (attach, thumbnails) = (attach_trackers.clone(), thumbnail_generator.clone());
+    player_handle.on_create(move |player| {
+        attach(player)?;
+        thumbnails.attach(player)?;
+
+        let app_handle = app_handle.clone();
+        stream_status::watch_stream_status(player, move |status| {
+            app_handle
+                .emit_all("mpv-stream-status", status)
+                .unwrap_or_else(|e| eprintln!("Failed to emit event: {}", e));
+        })
+    })?;
+
+    let app_handle = app.handle();
+    let crossfade = CrossfadeHandle::new(
+        player_handle.inner().clone(),
+        mpv_tauri_commands::MPV_LIB_PATH,
+        move |playlist_pos| {
+            app_handle
+                .emit_all(
+                    "crossfade-playlist-pos",
+                    CrossfadePositionPayload { playlist_pos },
+                )
+                .unwrap_or_else(|e| eprintln!("Failed to emit event: {}", e));
+        },
+        move |deck| {
+            if let Err(e) = attach_trackers(deck) {
+                eprintln!("Failed to follow the crossfade deck: {}", e);
+            }
+        },
+    );
+
+    let allowlist_path = app
+        .path_resolver()
+        .app_config_dir()
+        .ok_or("Failed to resolve the app config directory")?
+        .join(mpv_properties::ALLOWLIST_FILE_NAME);
+    let allowlist = MpvAllowlist::load(&allowlist_path)?;
+
+    let ipc_config = tauri::async_runtime::block_on(IpcConfig::load(&pool))?;
+    #[cfg(unix)]
+    if ipc_config.enabled {
+        // Tell the UI when a client edits the playlist, so it rebuilds its own from it
+        let app_handle = app.handle();
+        let socket_path = ipc_config.socket_path(&app_data_dir);
+        match ipc_server::IpcServer::start(
+            player_handle.inner().clone(),
+            &socket_path,
+            move |playlist| {
+                app_handle
+                    .emit_all("mpv-external-playlist-change", playlist)
+                    .unwrap_or_else(|e| eprintln!("Failed to emit event: {}", e));
+            },
+        ) {
+            Ok(server) => {
+                app.manage(server);
+            }
+            // e.g. another instance is using the socket; the player works without it
+            Err(e) => eprintln!(
+                "Failed to start the mpv IPC server on {}: {}",
+                socket_path.display(),
+                e
+            ),
+        }
+    }
+    #[cfg(not(unix))]
+    if ipc_config.enabled {
+        eprintln!("The mpv IPC server is only supported on Unix");
+    }
+
+    let remote_store = Arc::new(tauri::async_runtime::block_on(RemoteStore::new(
+        pool.clone(),
+    ))?);
+    let app_handle = app.handle();
+    let remote_control = RemoteControl::new(
+        remote_store.clone(),
+        player_handle.inner().clone(),
+        player_state_tracker.clone(),
+        runtime.clone(),
+        move |playlist| {
+            app_handle
+                .emit_all("mpv-external-playlist-change", playlist)
+                .unwrap_or_else(|e| eprintln!("Failed to emit event: {}", e));
+        },
+    )?;
+    // Not fatal, e.g. if the port is taken; the settings can still be changed
+    if let Err(e) = remote_control.apply_config() {
+        eprintln!("Failed to start the remote control server: {}", e);
+    }
+
+    // Not fatal: there may be no session bus
+    #[cfg(target_os = "linux")]
+    {
+        let art_dir = app_cache_dir.join(mpris::ART_DIR_NAME);
+        match mpris::MprisServer::start(
+            player_handle.inner().clone(),
+            runtime,
+            cover_art_cache.clone(),
+            art_dir,
+        ) {
+            Ok(server) => {
+                app.manage(server);
+            }
+            Err(e) => eprintln!("Failed to start the MPRIS server: {}", e),
+        }
+    }
+
+    app.manage(pool);
+    app.manage(resume_store);
+    app.manage(resume_tracker);
+    app.manage(history_store);
+    app.manage(history_tracker);
+    app.manage(lyrics_store);
+    app.manage(lyrics_tracker);
+    app.manage(player_state_tracker);
+    app.manage(allowlist);
+    app.manage(remote_store);
+    app.manage(remote_control);
+    app.manage(sleep_timer);
+    app.manage(thumbnail_generator);
+    app.manage(cover_art_cache);
+    app.manage(crossfade);
+    app.manage(probe_pool);
+    app.manage(scanner);
+    if let Some(library) = library {
+        app.manage(library);
+    }
+    app.manage(search_index);
+
+    Ok(())
+}
+
 fn main() {
+    let launch_args = match cli::LaunchArgs::parse(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, cli::USAGE);
+            std::process::exit(2);
+        }
+    };
+    if launch_args.help {
+        println!("{}", cli::USAGE);
+        return;
+    }
+    let context = tauri::generate_context!();
+    // Per user, so other users can't read the instance file or collide with it
+    let app_data_dir = tauri::api::path::app_data_dir(context.config());
+    if let Some(dir) = &app_data_dir {
+        if cli::forward_to_running_instance(dir, &launch_args) {
+            return;
+        }
+    }
+
     tauri::Builder::default()
-        .setup(|app| {
+        // Sprites and indexes of seek bar thumbnails, see `ThumbnailGenerator`
+        .register_uri_scheme_protocol(thumbnails::PROTOCOL, |app, request| {
+            let url_path = request
+                .uri()
+                .split_once("localhost/")
+                .map_or("", |(_, path)| path);
+            let url_path = url_path.split(['?', '#']).next().unwrap_or_default();
+
+            let generator = app.state::<Arc<ThumbnailGenerator>>();
+            match generator.read(url_path) {
+                Some((data, mime_type)) => tauri::http::ResponseBuilder::new()
+                    .mimetype(mime_type)
+                    .header("Access-Control-Allow-Origin", "*")
+                    .body(data),
+                None => tauri::http::ResponseBuilder::new()
+                    .status(404)
+                    .body(Vec::new()),
+            }
+        })
+        // Embedded cover art, resized on request, see `CoverArtCache`
+        .register_uri_scheme_protocol(cover_art::PROTOCOL, |app, request| {
+            let url = request
+                .uri()
+                .split_once("localhost/")
+                .map_or("", |(_, url)| url);
+            let url = url.split('#').next().unwrap_or_default();
+            let (url_path, query) = url.split_once('?').unwrap_or((url, ""));
+
+            let covers = app.state::<Arc<CoverArtCache>>();
+            match covers.serve(url_path, query) {
+                // The URL changes with the file, so the image never goes stale
+                Ok(Some(image)) => tauri::http::ResponseBuilder::new()
+                    .mimetype(&image.mime_type)
+                    .header("Cache-Control", "public, max-age=31536000, immutable")
+                    .header("Access-Control-Allow-Origin", "*")
+                    .body(image.data),
+                Ok(None) => tauri::http::ResponseBuilder::new()
+                    .status(404)
+                    .body(Vec::new()),
+                Err(e) => {
+                    eprintln!("Failed to serve cover: {}", e);
+                    tauri::http::ResponseBuilder::new()
+                        .status(500)
+                        .body(Vec::new())
+                }
+            }
+        })
+        .setup(move |app| {
             let container_win = tauri::WindowBuilder::new(
                 app,
                 "container",
@@ -119,33 +611,37 @@ fn main() {
                 .eval("document.title = 'Tauri Media Player'")
                 .unwrap();
 
-            let mpv_win =
-                tauri::WindowBuilder::new(app, "mpv", tauri::WindowUrl::App("about:blank".into()))
-                    .title("MPV Webview")
-                    .parent_window(container_win.hwnd().unwrap())
-                    .transparent(true)
-                    .build()
-                    .unwrap();
+            let mpv_win = child_of(
+                tauri::WindowBuilder::new(app, "mpv", tauri::WindowUrl::App("about:blank".into())),
+                &container_win,
+            )
+            .title("MPV Webview")
+            .transparent(true)
+            .build()
+            .unwrap();
 
             // Webview for controlled background (to get thumbnail/album art acrylic backdrop)
-            let bg_win =
-                tauri::WindowBuilder::new(app, "bg", tauri::WindowUrl::App("bg.html".into()))
-                    .title("MPV Webview")
-                    .parent_window(container_win.hwnd().unwrap())
-                    .build()
-                    .unwrap();
+            let bg_win = child_of(
+                tauri::WindowBuilder::new(app, "bg", tauri::WindowUrl::App("bg.html".into())),
+                &container_win,
+            )
+            .title("MPV Webview")
+            .build()
+            .unwrap();
         
         
             #[cfg(dev)]
             bg_win.open_devtools();
 
-            let app_win = tauri::WindowBuilder::new(
-                app,
-                "overlay",
-                tauri::WindowUrl::App("index.html".into()),
+            let app_win = child_of(
+                tauri::WindowBuilder::new(
+                    app,
+                    "overlay",
+                    tauri::WindowUrl::App("index.html".into()),
+                ),
+                &container_win,
             )
             .title("Overlay Webview")
-            .parent_window(container_win.hwnd().unwrap())
             .transparent(true)
             .build()
             .unwrap();
@@ -154,33 +650,7 @@ fn main() {
             app_win.open_devtools();
             
 
-            // Set all other windows to be child of the container window; ORDER MATTERS!
-            attach_child_to_parent_area(
-                container_win.hwnd().unwrap().0 as HWND,
-                bg_win.hwnd().unwrap().0 as HWND,
-                0,
-                0,
-                container_win.inner_size().unwrap().width as i32,
-                container_win.inner_size().unwrap().height as i32,
-            );
-
-            attach_child_to_parent_area(
-                container_win.hwnd().unwrap().0 as HWND,
-                mpv_win.hwnd().unwrap().0 as HWND,
-                0,
-                0,
-                0, // don't care; handled by JS MpvWindowProxy
-                0, // don't care; handled by JS MpvWindowProxy
-            );
-
-            attach_child_to_parent_area(
-                container_win.hwnd().unwrap().0 as HWND,
-                app_win.hwnd().unwrap().0 as HWND,
-                0,
-                0,
-                container_win.inner_size().unwrap().width as i32,
-                container_win.inner_size().unwrap().height as i32,
-            );
+            embed_windows(&container_win, &bg_win, &mpv_win, &app_win);
 
             let container_win_ref = container_win.clone();
 
@@ -200,7 +670,36 @@ fn main() {
                 }
             });
 
-            mpv_tauri_commands::init_mpv(mpv_win.hwnd().unwrap().0 as HWND);
+            let player_handle =
+                PlayerHandle::new(mpv_tauri_commands::MPV_LIB_PATH, video_window(&mpv_win));
+            // Not fatal: the UI gets the error from `mpv_check_core` and the `mpv_*` commands
+            if let Err(e) = player_handle.recreate(None) {
+                eprintln!("Failed to start mpv: {}", e);
+            }
+            app.manage(player_handle);
+            init_services(app)?;
+
+            open_launch_args(&app.handle(), &launch_args);
+            let listener = app_data_dir
+                .as_deref()
+                .ok_or_else(|| std::io::Error::other("no app data directory"))
+                .and_then(cli::InstanceListener::bind);
+            match listener {
+                Ok(listener) => {
+                    let app_handle = app.handle();
+                    let instance_file = listener.listen(move |args| {
+                        open_launch_args(&app_handle, &args);
+                        if let Some(window) = app_handle.get_window("container") {
+                            window
+                                .set_focus()
+                                .unwrap_or_else(|e| eprintln!("Failed to focus window: {}", e));
+                        }
+                    });
+                    app.manage(instance_file);
+                }
+                // Not fatal: later launches just open their own window
+                Err(e) => eprintln!("Failed to listen for other instances: {}", e),
+            }
 
             container_win.show().unwrap(); // Init complete, show window
 
@@ -230,11 +729,91 @@ fn main() {
             mpv_tauri_commands::mpv_get_playlist_pos,
             mpv_tauri_commands::mpv_set_playlist_from_paths,
             mpv_tauri_commands::mpv_clear_playlist,
+            mpv_tauri_commands::mpv_load_virtual_track,
+            mpv_tauri_commands::mpv_get_playback_continuity,
+            mpv_tauri_commands::mpv_set_playback_continuity,
+            mpv_tauri_commands::mpv_detect_gapless_breaks,
+            mpv_tauri_commands::mpv_load_stream,
+            mpv_tauri_commands::mpv_get_stream_status,
+            mpv_tauri_commands::mpv_get_state,
+            mpv_tauri_commands::mpv_check_core,
+            mpv_tauri_commands::mpv_recreate,
+            mpv_tauri_commands::mpv_get_properties,
+            mpv_tauri_commands::mpv_set_properties,
+            mpv_tauri_commands::mpv_batch,
+            crossfade_tauri_commands::crossfade_get_config,
+            crossfade_tauri_commands::crossfade_set_config,
+            crossfade_tauri_commands::crossfade_get_state,
+            crossfade_tauri_commands::crossfade_set_playlist,
+            crossfade_tauri_commands::crossfade_get_playlist_pos,
+            crossfade_tauri_commands::crossfade_set_playlist_pos,
+            crossfade_tauri_commands::crossfade_playlist_next,
+            crossfade_tauri_commands::crossfade_playlist_prev,
+            crossfade_tauri_commands::crossfade_seek,
+            crossfade_tauri_commands::crossfade_play,
+            crossfade_tauri_commands::crossfade_pause,
+            crossfade_tauri_commands::crossfade_stop,
+            crossfade_tauri_commands::crossfade_set_volume,
+            crossfade_tauri_commands::crossfade_disable,
+            resume_tauri_commands::resume_get_config,
+            resume_tauri_commands::resume_set_config,
+            resume_tauri_commands::resume_list_positions,
+            resume_tauri_commands::resume_clear_positions,
+            history_tauri_commands::history_recently_played,
+            history_tauri_commands::history_most_played_tracks,
+            history_tauri_commands::history_most_played_artists,
+            history_tauri_commands::history_most_played_albums,
+            history_tauri_commands::history_total_listening_time,
+            ipc_tauri_commands::ipc_get_config,
+            ipc_tauri_commands::ipc_set_config,
+            remote_control_tauri_commands::remote_get_config,
+            remote_control_tauri_commands::remote_set_config,
+            remote_control_tauri_commands::remote_start_pairing,
+            remote_control_tauri_commands::remote_list_devices,
+            remote_control_tauri_commands::remote_remove_device,
+            sleep_timer_tauri_commands::sleep_timer_set,
+            sleep_timer_tauri_commands::sleep_timer_extend,
+            sleep_timer_tauri_commands::sleep_timer_cancel,
+            sleep_timer_tauri_commands::sleep_timer_get,
+            thumbnails_tauri_commands::thumbnails_request,
+            thumbnails_tauri_commands::thumbnails_cancel,
+            tag_editor_tauri_commands::set_media_info,
+            tag_editor_tauri_commands::set_media_info_batch,
+            tag_editor_tauri_commands::tags_from_filename,
+            cover_art_tauri_commands::cover_get_url,
+            cover_art_tauri_commands::cover_resolve,
+            lyrics_tauri_commands::lyrics_get,
+            lyrics_tauri_commands::lyrics_get_current,
+            lyrics_tauri_commands::lyrics_get_offset,
+            lyrics_tauri_commands::lyrics_set_offset,
+            scanner_tauri_commands::scan_paths,
+            scanner_tauri_commands::scan_cancel,
+            library_tauri_commands::library_get_folders,
+            library_tauri_commands::library_add_folder,
+            library_tauri_commands::library_remove_folder,
+            library_tauri_commands::library_rescan,
+            library_tauri_commands::library_artists,
+            library_tauri_commands::library_albums,
+            library_tauri_commands::library_genres,
+            library_tauri_commands::library_years,
+            library_tauri_commands::library_folders,
+            library_tauri_commands::library_tracks,
+            search_tauri_commands::search_library,
+            search_tauri_commands::search_rebuild,
             get_media_info,
             get_pictures,
             set_background,
             db_execute
         ])
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
+        .build(context)
+        .expect("error while running tauri application")
+        .run(|app, event| {
+            if let tauri::RunEvent::Exit = event {
+                app.state::<Arc<ResumeTracker>>().save_now();
+                app.state::<Arc<HistoryTracker>>().flush_now();
+                if let Some(instance_file) = app.try_state::<cli::InstanceFile>() {
+                    instance_file.remove();
+                }
+            }
+        });
 }
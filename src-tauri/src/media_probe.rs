@@ -0,0 +1,382 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fs;
+use std::path::Path;
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
+
+use crate::mpv::{EndFileReason, MpvError, MpvEventId, MpvPlayer};
+
+/// How long to wait for mpv to open the file, e.g. on a slow network share.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// How long to wait for the event thread of a core that failed to start.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum StreamKind {
+    Video,
+    Audio,
+    Subtitle,
+}
+
+/// A stream of the container, as mpv lists it in `track-list`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MediaStream {
+    pub kind: StreamKind,
+    pub codec: Option<String>,
+    pub language: Option<String>,
+    pub title: Option<String>,
+    /// Cover art or a still image, rather than actual video.
+    pub album_art: bool,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub frame_rate: Option<f64>,
+    pub channels: Option<u8>,
+    pub sample_rate: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Chapter {
+    pub title: Option<String>,
+    /// In seconds.
+    pub start: f64,
+}
+
+/// What the demuxer reports about a file, whatever its format.
+#[derive(Debug, Clone)]
+pub struct MediaProbe {
+    /// Demuxer name, e.g. `mkv` or `mov,mp4,m4a,3gp,3g2,mj2`.
+    pub container: Option<String>,
+    /// Title stored in the container itself.
+    pub title: Option<String>,
+    pub duration: Option<f64>,
+    pub streams: Vec<MediaStream>,
+    pub chapters: Vec<Chapter>,
+}
+
+impl MediaProbe {
+    /// Whether the file has a moving picture; cover art and still images don't count.
+    pub fn is_video(&self) -> bool {
+        self.video_stream().is_some()
+    }
+
+    /// The first actual video stream.
+    pub fn video_stream(&self) -> Option<&MediaStream> {
+        self.streams
+            .iter()
+            .find(|s| s.kind == StreamKind::Video && !s.album_art)
+    }
+
+    pub fn audio_stream(&self) -> Option<&MediaStream> {
+        self.streams.iter().find(|s| s.kind == StreamKind::Audio)
+    }
+}
+
+/// A headless mpv core that only demuxes, kept between probes.
+struct ProbeCore {
+    player: Arc<MpvPlayer>,
+    /// `true` once a file is loaded, `false` if it couldn't be.
+    loaded: mpsc::Receiver<bool>,
+}
+
+impl ProbeCore {
+    fn new(lib_path: &str) -> Result<Self, MpvError> {
+        let player = MpvPlayer::new(lib_path)?;
+        match Self::set_up(&player) {
+            Ok(loaded) => Ok(ProbeCore { player, loaded }),
+            Err(e) => {
+                // Without a Shutdown event, if it never started
+                if player.quit().is_err() {
+                    player.stop_event_processing(SHUTDOWN_TIMEOUT);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    fn set_up(player: &MpvPlayer) -> Result<mpsc::Receiver<bool>, MpvError> {
+        // Nothing is decoded or output: the track list comes from the demuxer
+        for (name, value) in [
+            ("config", "no"),
+            ("load-scripts", "no"),
+            ("ytdl", "no"),
+            ("vo", "null"),
+            ("ao", "null"),
+            ("vid", "no"),
+            ("aid", "no"),
+            ("sid", "no"),
+            ("pause", "yes"),
+            ("idle", "yes"),
+        ] {
+            player.set_option(name, value)?;
+        }
+        player.initialize()?;
+
+        let (sender, receiver) = mpsc::channel();
+        let loaded = sender.clone();
+        player.register_event_callback(MpvEventId::FileLoaded, move |_| {
+            let _ = loaded.send(true);
+        })?;
+        // Closing the previous file ends it with `Stop`, which isn't a failure
+        player.register_event_callback(MpvEventId::EndFile, move |event| {
+            if event.end_file_reason() != Some(EndFileReason::Stop) {
+                let _ = sender.send(false);
+            }
+        })?;
+        Ok(receiver)
+    }
+}
+
+/// Headless mpv cores that only demux, kept between probes. Every probe gets
+/// a core of its own, so the scanner's workers and the library indexer don't
+/// wait on each other.
+pub struct ProbePool {
+    lib_path: String,
+    idle: Mutex<Vec<ProbeCore>>,
+    /// Cores beyond this many are shut down once their probe is done.
+    max_idle: usize,
+}
+
+impl ProbePool {
+    pub fn new(lib_path: &str) -> Arc<Self> {
+        Arc::new(Self {
+            lib_path: lib_path.to_string(),
+            idle: Mutex::new(Vec::new()),
+            max_idle: std::thread::available_parallelism()
+                .map(|n| n.get())
+                .unwrap_or(4),
+        })
+    }
+
+    /// Opens `path` in an idle core, or a new one if all are busy, and reads
+    /// the container, streams and chapters. Blocks until the file is opened.
+    pub fn probe(&self, path: &str) -> Result<MediaProbe, MpvError> {
+        let idle = self.idle.lock().unwrap().pop();
+        let core = match idle {
+            Some(core) => core,
+            None => ProbeCore::new(&self.lib_path)?,
+        };
+
+        let result = match core
+            .player
+            .load_file(path, None)
+            .map(|()| core.loaded.recv_timeout(PROBE_TIMEOUT))
+        {
+            Ok(Ok(true)) => read_probe(&core.player),
+            Ok(Ok(false)) => Err(MpvError::load(path, "unrecognized file format")),
+            Ok(Err(_)) => {
+                // Its events may still arrive, and be taken for the next file's
+                if let Err(e) = core.player.quit() {
+                    eprintln!("Failed to shut down media probe: {}", e);
+                }
+                return Err(MpvError::load(path, "timed out"));
+            }
+            Err(e) => Err(e),
+        };
+
+        // Closes the file, so it isn't kept open until the next probe
+        if let Err(e) = core.player.stop() {
+            eprintln!("Failed to stop media probe: {}", e);
+        }
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < self.max_idle {
+            idle.push(core);
+        } else if let Err(e) = core.player.quit() {
+            eprintln!("Failed to shut down media probe: {}", e);
+        }
+        result
+    }
+}
+
+fn read_probe(player: &MpvPlayer) -> Result<MediaProbe, MpvError> {
+    let streams = player
+        .get_property_json("track-list")?
+        .as_array()
+        .map(|tracks| tracks.iter().filter_map(parse_stream).collect())
+        .unwrap_or_default();
+    let chapters = player
+        .get_property_json("chapter-list")
+        .ok()
+        .and_then(|list| list.as_array().cloned())
+        .map(|list| {
+            list.iter()
+                .map(|chapter| Chapter {
+                    title: string(&chapter["title"]),
+                    start: chapter["time"].as_f64().unwrap_or(0.0),
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    Ok(MediaProbe {
+        container: player
+            .get_property_json("file-format")
+            .ok()
+            .as_ref()
+            .and_then(string),
+        title: player
+            .get_property_json("metadata/by-key/title")
+            .ok()
+            .as_ref()
+            .and_then(string),
+        duration: player.get_duration().ok(),
+        streams,
+        chapters,
+    })
+}
+
+/// Decodes the first frame of the video track of `path` into a JPEG in
+/// `out_dir`, and returns its bytes. For files with an attached picture (e.g.
+/// Matroska cover attachments) that is the picture. Returns `None` for files
+/// without video.
+pub fn extract_first_frame(
+    lib_path: &str,
+    path: &str,
+    out_dir: &Path,
+) -> Result<Option<Vec<u8>>, MpvError> {
+    let player = MpvPlayer::new(lib_path)?;
+    let result = extract_first_frame_with(&player, path, out_dir);
+    if let Err(e) = player.quit() {
+        eprintln!("Failed to shut down frame extraction: {}", e);
+    }
+    result
+}
+
+fn extract_first_frame_with(
+    player: &MpvPlayer,
+    path: &str,
+    out_dir: &Path,
+) -> Result<Option<Vec<u8>>, MpvError> {
+    let io_error = |e: std::io::Error| MpvError::load(path, e.to_string());
+    if out_dir.exists() {
+        fs::remove_dir_all(out_dir).map_err(io_error)?;
+    }
+    fs::create_dir_all(out_dir).map_err(io_error)?;
+
+    // Playback stops by itself after the one frame
+    for (name, value) in [
+        ("config", "no"),
+        ("load-scripts", "no"),
+        ("ytdl", "no"),
+        ("vo", "image"),
+        ("vo-image-format", "jpg"),
+        ("ao", "null"),
+        ("aid", "no"),
+        ("sid", "no"),
+        ("hwdec", "no"),
+        ("frames", "1"),
+        ("idle", "yes"),
+    ] {
+        player.set_option(name, value)?;
+    }
+    player.set_option("vo-image-outdir", &out_dir.to_string_lossy())?;
+    player.initialize()?;
+
+    let (sender, receiver) = mpsc::channel();
+    player.register_event_callback(MpvEventId::EndFile, move |_| {
+        let _ = sender.send(());
+    })?;
+    player.load_file(path, None)?;
+    if receiver.recv_timeout(PROBE_TIMEOUT).is_err() {
+        return Err(MpvError::load(path, "timed out"));
+    }
+
+    let frame = fs::read_dir(out_dir)
+        .map_err(io_error)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .find(|path| path.extension().is_some_and(|ext| ext == "jpg"));
+    let data = frame.map(fs::read).transpose().map_err(io_error)?;
+    let _ = fs::remove_dir_all(out_dir);
+    Ok(data)
+}
+
+fn parse_stream(track: &Value) -> Option<MediaStream> {
+    let kind = match track["type"].as_str()? {
+        "video" => StreamKind::Video,
+        "audio" => StreamKind::Audio,
+        "sub" => StreamKind::Subtitle,
+        _ => return None,
+    };
+    // External files (e.g. subtitles mpv picked up next to the file) aren't in the container
+    if track["external"].as_bool() == Some(true) {
+        return None;
+    }
+
+    Some(MediaStream {
+        kind,
+        codec: string(&track["codec"]),
+        language: string(&track["lang"]),
+        title: string(&track["title"]),
+        album_art: track["albumart"].as_bool().unwrap_or(false)
+            || track["image"].as_bool().unwrap_or(false),
+        width: track["demux-w"].as_u64().map(|w| w as u32),
+        height: track["demux-h"].as_u64().map(|h| h as u32),
+        frame_rate: track["demux-fps"].as_f64(),
+        channels: track["demux-channel-count"].as_u64().map(|c| c as u8),
+        sample_rate: track["demux-samplerate"].as_u64().map(|r| r as u32),
+    })
+}
+
+fn string(value: &Value) -> Option<String> {
+    value
+        .as_str()
+        .filter(|s| !s.is_empty())
+        .map(|s| s.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mpv::test_support;
+
+    #[test]
+    fn probes_several_files_on_one_core() {
+        if test_support::player().is_none() {
+            return;
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let audio = dir.path().join("sine.wav");
+        let broken = dir.path().join("broken.wav");
+        fs::write(&audio, test_support::sine_wav(44_100, 0, 44_100)).unwrap();
+        fs::write(&broken, b"not a media file").unwrap();
+        let pool = ProbePool::new(&test_support::lib_path());
+
+        let first = pool.probe(&audio.to_string_lossy()).unwrap();
+        assert!(pool.probe(&broken.to_string_lossy()).is_err());
+        let second = pool.probe(&audio.to_string_lossy()).unwrap();
+        assert_eq!(pool.idle.lock().unwrap().len(), 1);
+
+        for probe in [first, second] {
+            assert!(!probe.is_video());
+            let audio = probe.audio_stream().unwrap();
+            assert_eq!(audio.sample_rate, Some(44_100));
+            assert!((probe.duration.unwrap() - 1.0).abs() < 0.01);
+        }
+    }
+
+    #[test]
+    fn probes_on_a_core_per_caller() {
+        if test_support::player().is_none() {
+            return;
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let audio = dir.path().join("sine.wav");
+        fs::write(&audio, test_support::sine_wav(44_100, 0, 44_100)).unwrap();
+        let pool = ProbePool::new(&test_support::lib_path());
+
+        let threads: Vec<_> = (0..3)
+            .map(|_| {
+                let (pool, audio) = (pool.clone(), audio.to_string_lossy().into_owned());
+                std::thread::spawn(move || pool.probe(&audio))
+            })
+            .collect();
+        for thread in threads {
+            assert!(thread.join().unwrap().unwrap().audio_stream().is_some());
+        }
+        let idle = pool.idle.lock().unwrap().len();
+        assert!((1..=3).contains(&idle));
+    }
+}
@@ -1,11 +1,19 @@
-use lofty::file::{AudioFile, TaggedFileExt};
-use lofty::tag::Accessor;
+use lofty::file::{AudioFile, FileType, TaggedFile, TaggedFileExt};
+use lofty::tag::{Accessor, ItemKey, Tag};
 use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
 use std::path::Path;
+use std::sync::Arc;
 
+use lofty::picture::PictureType;
 use lofty::probe::Probe;
 
-#[derive(Serialize, Deserialize, Debug)]
+use crate::cue::{self, CueError, CueSheet, VirtualTrack};
+use crate::media_probe::{Chapter, MediaStream, ProbePool};
+
+#[derive(Serialize, Deserialize, Debug, Default)]
 pub struct SimplifiedMetadata {
     pub title: Option<String>,
     pub artist: Option<String>,
@@ -16,22 +24,85 @@ pub struct SimplifiedMetadata {
     pub disc: Option<u32>,
     pub total_discs: Option<u32>,
     pub genre: Option<String>,
+    /// All values of the multi-value fields; `artist` and `genre` hold the first one.
+    pub artists: Option<Vec<String>>,
+    pub genres: Option<Vec<String>>,
+    pub album_artists: Option<Vec<String>>,
+    pub composers: Option<Vec<String>>,
+    pub conductors: Option<Vec<String>>,
+    pub comments: Option<Vec<String>>,
+    /// Unsynchronized lyrics.
+    pub lyrics: Option<String>,
+    pub bpm: Option<f64>,
+    pub isrc: Option<String>,
+    pub labels: Option<Vec<String>>,
+    pub catalog_number: Option<String>,
+    /// As written in the tag, e.g. `1977` or `1977-05-25`.
+    pub original_date: Option<String>,
+    pub musicbrainz: Option<MusicBrainzIds>,
+    pub replay_gain: Option<ReplayGain>,
     // pub pictures: Option<Vec<Vec<u8>>>,
     pub duration: f64,
     pub bitrate: Option<u32>,
     pub sample_rate: Option<u32>,
     pub channels: Option<u8>,
     pub bit_depth: Option<u8>,
+    /// Tracks from a CUE sheet (sidecar or embedded) when the file holds a whole album.
+    pub virtual_tracks: Option<Vec<VirtualTrack>>,
+    /// Whether the file has a video stream (not just cover art), from its content.
+    pub is_video: bool,
+    /// The following come from probing the streams; see `parse_media_info`.
+    pub container: Option<String>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub frame_rate: Option<f64>,
+    pub video_codec: Option<String>,
+    pub audio_codec: Option<String>,
+    pub streams: Option<Vec<MediaStream>>,
+    pub chapters: Option<Vec<Chapter>>,
+}
+
+impl SimplifiedMetadata {
+    /// The first album artist, if the tags name one.
+    pub fn album_artist(&self) -> Option<&str> {
+        self.album_artists
+            .as_ref()
+            .and_then(|names| names.first())
+            .map(|name| name.trim())
+            .filter(|name| !name.is_empty())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct MusicBrainzIds {
+    pub recording_id: Option<String>,
+    pub track_id: Option<String>,
+    pub release_id: Option<String>,
+    pub release_group_id: Option<String>,
+    pub work_id: Option<String>,
+    pub artist_ids: Vec<String>,
+    pub release_artist_ids: Vec<String>,
+}
+
+/// Gains in dB, peaks as a fraction of full scale.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy)]
+pub struct ReplayGain {
+    pub track_gain: Option<f64>,
+    pub track_peak: Option<f64>,
+    pub album_gain: Option<f64>,
+    pub album_peak: Option<f64>,
 }
 
 pub async fn parse_metadata(path: &str) -> Result<SimplifiedMetadata, Box<dyn std::error::Error>> {
     let path = Path::new(path);
+    if cue::is_cue_file(path) {
+        return parse_cue_metadata(path).await;
+    }
+
     let tagged_file = Probe::open(path)?.guess_file_type()?.read()?;
 
     let properties = tagged_file.properties();
-    let tag = tagged_file
-        .primary_tag()
-        .or_else(|| tagged_file.first_tag());
+    let tags = tags_in_order(&tagged_file);
 
     let mut metadata = SimplifiedMetadata {
         title: None,
@@ -49,50 +120,697 @@ pub async fn parse_metadata(path: &str) -> Result<SimplifiedMetadata, Box<dyn st
         sample_rate: properties.sample_rate(),
         channels: properties.channels(),
         bit_depth: properties.bit_depth(),
+        virtual_tracks: None,
+        ..Default::default()
     };
 
-    if let Some(tag) = tag {
-        metadata.title = tag.title().map(|cow| cow.into_owned());
-        metadata.artist = tag.artist().map(|cow| cow.into_owned());
-        metadata.album = tag.album().map(|cow| cow.into_owned());
-        metadata.year = tag.year();
-        metadata.track = tag.track();
-        metadata.genre = tag.genre().map(|cow| cow.into_owned());
-        metadata.disc = tag.disk();
-        metadata.total_discs = tag.disk_total();
-        metadata.total_tracks = tag.track_total();
+    // Each field comes from the first tag that has it, e.g. the genre of an
+    // ID3v1 tag if the ID3v2 tag has none
+    metadata.title = tags.iter().find_map(|tag| tag.title()).map(Cow::into_owned);
+    metadata.artist = tags
+        .iter()
+        .find_map(|tag| tag.artist())
+        .map(Cow::into_owned);
+    metadata.album = tags.iter().find_map(|tag| tag.album()).map(Cow::into_owned);
+    metadata.year = tags.iter().find_map(|tag| tag.year());
+    metadata.track = tags.iter().find_map(|tag| tag.track());
+    metadata.genre = tags.iter().find_map(|tag| tag.genre()).map(Cow::into_owned);
+    metadata.disc = tags.iter().find_map(|tag| tag.disk());
+    metadata.total_discs = tags.iter().find_map(|tag| tag.disk_total());
+    metadata.total_tracks = tags.iter().find_map(|tag| tag.track_total());
+    read_extended_tags(&tags, &mut metadata);
+    // APE items hold all values of a field in one, separated by NUL
+    let first = |values: &Option<Vec<String>>| values.as_ref().and_then(|v| v.first().cloned());
+    metadata.artist = first(&metadata.artists).or(metadata.artist);
+    metadata.genre = first(&metadata.genres).or(metadata.genre);
 
-        // let pictures = tag.pictures().iter().map(|p| p.data().to_vec()).collect();
-        // metadata.pictures = Some(pictures);
+    // Prefer a sheet embedded in the tags (FLAC/APE `CUESHEET`) over a sidecar file
+    let embedded_sheet = tags.iter().find_map(|tag| embedded_sheet(tag, path));
+    if let Some(sheet) = embedded_sheet.or_else(|| cue::find_cue_sheet(path)) {
+        let virtual_tracks = sheet.virtual_tracks_for(path, metadata.duration);
+        if !virtual_tracks.is_empty() {
+            metadata.album = metadata.album.or_else(|| sheet.title.clone());
+            metadata.artist = metadata.artist.or_else(|| sheet.performer.clone());
+            metadata.genre = metadata.genre.or_else(|| sheet.genre.clone());
+            metadata.total_tracks = Some(virtual_tracks.len() as u32);
+            metadata.virtual_tracks = Some(virtual_tracks);
+        }
     }
 
     Ok(metadata)
 }
 
+/// The `CUESHEET` item of `tag`, as a sheet describing `path`.
+fn embedded_sheet(tag: &Tag, path: &Path) -> Option<CueSheet> {
+    let text = tag.get_string(&ItemKey::Unknown("CUESHEET".to_string()))?;
+    let mut sheet = CueSheet::parse(text, path.parent().unwrap_or(Path::new(""))).ok()?;
+    // Embedded sheets always describe the file they are embedded in
+    for file in sheet.files.iter_mut() {
+        file.path = path.to_path_buf();
+    }
+    Some(sheet)
+}
+
+/// Whether files like `path` can carry a `CUESHEET` item: formats tagged with
+/// Vorbis comments or APE tags.
+fn may_embed_sheet(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| {
+            ["flac", "ape", "wv", "tta", "mpc"]
+                .iter()
+                .any(|known| ext.eq_ignore_ascii_case(known))
+        })
+}
+
+/// The tracks of `path` if it is a cue sheet or a file described by one, else
+/// `None`. Unlike `parse_metadata`, only reads the tags of formats that can
+/// embed a sheet, and the audio properties of files that have one. Blocking.
+pub fn read_virtual_tracks(path: &Path) -> Result<Option<Vec<VirtualTrack>>, CueError> {
+    if cue::is_cue_file(path) {
+        return Ok(Some(CueSheet::from_path(path)?.virtual_tracks()));
+    }
+
+    let tagged_file = may_embed_sheet(path)
+        .then(|| Probe::open(path).ok()?.guess_file_type().ok()?.read().ok())
+        .flatten();
+    let embedded_sheet = tagged_file.as_ref().and_then(|tagged_file| {
+        tags_in_order(tagged_file)
+            .into_iter()
+            .find_map(|tag| embedded_sheet(tag, path))
+    });
+    let Some(sheet) = embedded_sheet.or_else(|| cue::find_cue_sheet(path)) else {
+        return Ok(None);
+    };
+
+    let duration = match &tagged_file {
+        Some(tagged_file) => tagged_file.properties().duration().as_secs_f64(),
+        None => read_duration(path).unwrap_or(0.0),
+    };
+    let virtual_tracks = sheet.virtual_tracks_for(path, duration);
+    Ok((!virtual_tracks.is_empty()).then_some(virtual_tracks))
+}
+
+/// The tags of `tagged_file`, primary tag first. Otherwise they keep the order
+/// of the file.
+fn tags_in_order(tagged_file: &TaggedFile) -> Vec<&Tag> {
+    let primary_type = tagged_file.primary_tag_type();
+    tagged_file
+        .primary_tag()
+        .into_iter()
+        .chain(
+            tagged_file
+                .tags()
+                .iter()
+                .filter(|tag| tag.tag_type() != primary_type),
+        )
+        .collect()
+}
+
+/// Reads the fields beyond the basic `Accessor` ones, each from the first of
+/// `tags` that has it. Going through `ItemKey` maps the ID3v2, Vorbis, APE and
+/// MP4 names of each field to the same key.
+fn read_extended_tags(tags: &[&Tag], metadata: &mut SimplifiedMetadata) {
+    let strings = |key: ItemKey| -> Option<Vec<String>> {
+        tags.iter().find_map(|tag| {
+            // ID3v2.4 and APE store several values in one item, separated by NUL
+            let values: Vec<String> = tag
+                .get_strings(&key)
+                .flat_map(|value| value.split('\0'))
+                .map(str::trim)
+                .filter(|value| !value.is_empty())
+                .map(String::from)
+                .collect();
+            (!values.is_empty()).then_some(values)
+        })
+    };
+    let string = |key: ItemKey| strings(key).and_then(|values| values.into_iter().next());
+
+    metadata.artists = strings(ItemKey::TrackArtist);
+    metadata.genres = strings(ItemKey::Genre);
+    metadata.album_artists = strings(ItemKey::AlbumArtist);
+    metadata.composers = strings(ItemKey::Composer);
+    metadata.conductors = strings(ItemKey::Conductor);
+    metadata.comments = strings(ItemKey::Comment);
+    metadata.lyrics = string(ItemKey::Lyrics);
+    metadata.bpm = string(ItemKey::Bpm)
+        .or_else(|| string(ItemKey::IntegerBpm))
+        .and_then(|bpm| bpm.parse().ok());
+    metadata.isrc = string(ItemKey::Isrc);
+    metadata.labels = strings(ItemKey::Label);
+    metadata.catalog_number = string(ItemKey::CatalogNumber);
+    metadata.original_date = string(ItemKey::OriginalReleaseDate);
+
+    let musicbrainz = MusicBrainzIds {
+        recording_id: string(ItemKey::MusicBrainzRecordingId),
+        track_id: string(ItemKey::MusicBrainzTrackId),
+        release_id: string(ItemKey::MusicBrainzReleaseId),
+        release_group_id: string(ItemKey::MusicBrainzReleaseGroupId),
+        work_id: string(ItemKey::MusicBrainzWorkId),
+        artist_ids: strings(ItemKey::MusicBrainzArtistId).unwrap_or_default(),
+        release_artist_ids: strings(ItemKey::MusicBrainzReleaseArtistId).unwrap_or_default(),
+    };
+    let has_musicbrainz = musicbrainz.recording_id.is_some()
+        || musicbrainz.track_id.is_some()
+        || musicbrainz.release_id.is_some()
+        || musicbrainz.release_group_id.is_some()
+        || musicbrainz.work_id.is_some()
+        || !musicbrainz.artist_ids.is_empty()
+        || !musicbrainz.release_artist_ids.is_empty();
+    metadata.musicbrainz = has_musicbrainz.then_some(musicbrainz);
+
+    // Written as e.g. `-6.48 dB` and `0.988553`
+    let number = |key: ItemKey| {
+        string(key).and_then(|value| {
+            value
+                .trim_end_matches(|c: char| c.is_ascii_alphabetic() || c.is_whitespace())
+                .parse::<f64>()
+                .ok()
+        })
+    };
+    let replay_gain = ReplayGain {
+        track_gain: number(ItemKey::ReplayGainTrackGain),
+        track_peak: number(ItemKey::ReplayGainTrackPeak),
+        album_gain: number(ItemKey::ReplayGainAlbumGain),
+        album_peak: number(ItemKey::ReplayGainAlbumPeak),
+    };
+    let has_replay_gain = replay_gain.track_gain.is_some()
+        || replay_gain.track_peak.is_some()
+        || replay_gain.album_gain.is_some()
+        || replay_gain.album_peak.is_some();
+    metadata.replay_gain = has_replay_gain.then_some(replay_gain);
+}
+
+/// Metadata for a `.cue` file itself: the audio properties of the first
+/// referenced file, with the sheet's album information and tracks.
+async fn parse_cue_metadata(path: &Path) -> Result<SimplifiedMetadata, Box<dyn std::error::Error>> {
+    let sheet = CueSheet::from_path(path)?;
+    let first_file = sheet
+        .files
+        .first()
+        .ok_or("Cue sheet does not reference any file")?;
+
+    let tagged_file = Probe::open(&first_file.path)?.guess_file_type()?.read()?;
+    let properties = tagged_file.properties();
+
+    let mut virtual_tracks = Vec::with_capacity(sheet.track_count());
+    let mut duration = 0.0;
+    for file in &sheet.files {
+        let file_duration = read_duration(&file.path).unwrap_or(0.0);
+        duration += file_duration;
+        virtual_tracks.extend(sheet.virtual_tracks_for(&file.path, file_duration));
+    }
+
+    Ok(SimplifiedMetadata {
+        title: sheet.title.clone(),
+        artist: sheet.performer.clone(),
+        album: sheet.title.clone(),
+        year: sheet
+            .date
+            .as_deref()
+            .and_then(|d| d.get(..4))
+            .and_then(|y| y.parse().ok()),
+        track: None,
+        total_tracks: Some(virtual_tracks.len() as u32),
+        disc: None,
+        total_discs: None,
+        genre: sheet.genre.clone(),
+        duration,
+        bitrate: properties.audio_bitrate(),
+        sample_rate: properties.sample_rate(),
+        channels: properties.channels(),
+        bit_depth: properties.bit_depth(),
+        virtual_tracks: Some(virtual_tracks),
+        ..Default::default()
+    })
+}
+
+/// Like `parse_metadata`, but also probes the streams with a headless mpv when
+/// lofty can't read the file (e.g. Matroska/WebM) or it has a video track
+/// (MP4), which fills in the container, video and stream fields. Plain audio
+/// files are never probed.
+pub async fn parse_media_info(
+    probes: &Arc<ProbePool>,
+    path: &str,
+) -> Result<SimplifiedMetadata, Box<dyn std::error::Error>> {
+    let metadata = parse_metadata(path).await;
+    let needs_probe = match &metadata {
+        Ok(metadata) => metadata.virtual_tracks.is_none() && may_hold_video(Path::new(path)),
+        Err(_) => true,
+    };
+    if !needs_probe {
+        return metadata;
+    }
+
+    let (probes, probe_path) = (probes.clone(), path.to_string());
+    let probe = tokio::task::spawn_blocking(move || probes.probe(&probe_path)).await?;
+    let (mut metadata, probe) = match (metadata, probe) {
+        (Ok(metadata), Err(e)) => {
+            eprintln!("Failed to probe {}: {}", path, e);
+            return Ok(metadata);
+        }
+        (Err(e), Err(_)) => return Err(e),
+        (metadata, Ok(probe)) => (metadata.unwrap_or_default(), probe),
+    };
+
+    metadata.is_video = probe.is_video();
+    metadata.container = probe.container.clone();
+    metadata.title = metadata.title.or_else(|| probe.title.clone());
+    if metadata.duration <= 0.0 {
+        metadata.duration = probe.duration.unwrap_or(0.0);
+    }
+    if let Some(video) = probe.video_stream() {
+        metadata.width = video.width;
+        metadata.height = video.height;
+        metadata.frame_rate = video.frame_rate;
+        metadata.video_codec = video.codec.clone();
+    }
+    if let Some(audio) = probe.audio_stream() {
+        metadata.audio_codec = audio.codec.clone();
+        metadata.channels = metadata.channels.or(audio.channels);
+        metadata.sample_rate = metadata.sample_rate.or(audio.sample_rate);
+    }
+    metadata.streams = Some(probe.streams);
+    metadata.chapters = Some(probe.chapters);
+
+    Ok(metadata)
+}
+
+/// Whether the content is in a format lofty reads as audio but that holds a
+/// video track. Decided from the file header, not the extension.
+pub fn may_hold_video(path: &Path) -> bool {
+    let is_mp4 = Probe::open(path)
+        .ok()
+        .and_then(|probe| probe.guess_file_type().ok())
+        .is_some_and(|probe| probe.file_type() == Some(FileType::Mp4));
+    // When in doubt, the caller probes
+    is_mp4
+        && File::open(path)
+            .and_then(mp4_has_video_track)
+            .unwrap_or(true)
+}
+
+/// Larger `moov` boxes are only read this far.
+const MAX_MOOV_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Looks for a track with a video handler in the `moov` box, which may come
+/// after the media data.
+fn mp4_has_video_track(mut reader: impl Read + Seek) -> io::Result<bool> {
+    while let Some((kind, size)) = read_box_header(&mut reader)? {
+        if &kind != b"moov" {
+            match size {
+                Some(size) => reader.seek(SeekFrom::Current(size as i64))?,
+                None => break,
+            };
+            continue;
+        }
+
+        let mut moov = Vec::new();
+        reader
+            .take(size.unwrap_or(u64::MAX).min(MAX_MOOV_SIZE))
+            .read_to_end(&mut moov)?;
+        let has_video = mp4_boxes(&moov)
+            .filter(|(kind, _)| kind == b"trak")
+            .filter_map(|(_, trak)| mp4_boxes(trak).find(|(kind, _)| kind == b"mdia"))
+            .filter_map(|(_, mdia)| mp4_boxes(mdia).find(|(kind, _)| kind == b"hdlr"))
+            // Version and flags, then `pre_defined`, then the handler type
+            .any(|(_, hdlr)| hdlr.get(8..12) == Some(b"vide"));
+        return Ok(has_video);
+    }
+    Ok(false)
+}
+
+/// The type of the next box and the size of its content, `None` if it extends
+/// to the end of the file. `None` at the end of the file.
+fn read_box_header(reader: &mut impl Read) -> io::Result<Option<([u8; 4], Option<u64>)>> {
+    let mut header = [0; 8];
+    match reader.read_exact(&mut header) {
+        Ok(()) => (),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let kind = [header[4], header[5], header[6], header[7]];
+    let size = match u32::from_be_bytes([header[0], header[1], header[2], header[3]]) {
+        0 => None,
+        1 => {
+            let mut large_size = [0; 8];
+            reader.read_exact(&mut large_size)?;
+            Some(u64::from_be_bytes(large_size).saturating_sub(16))
+        }
+        size => Some((size as u64).saturating_sub(8)),
+    };
+    Ok(Some((kind, size)))
+}
+
+/// The boxes in `data`, with their content. Stops at the first malformed one.
+fn mp4_boxes(data: &[u8]) -> impl Iterator<Item = ([u8; 4], &[u8])> {
+    let mut rest = data;
+    std::iter::from_fn(move || {
+        let header = rest.get(..8)?;
+        let kind = [header[4], header[5], header[6], header[7]];
+        let (start, end) = match u32::from_be_bytes([header[0], header[1], header[2], header[3]]) {
+            0 => (8, rest.len()),
+            1 => {
+                let large_size: [u8; 8] = rest.get(8..16)?.try_into().ok()?;
+                (16, usize::try_from(u64::from_be_bytes(large_size)).ok()?)
+            }
+            size => (8, size as usize),
+        };
+        let content = rest.get(start..end)?;
+        rest = &rest[end..];
+        Some((kind, content))
+    })
+}
 
-#[derive(Serialize, Deserialize, Debug)]
+fn read_duration(path: &Path) -> Result<f64, Box<dyn std::error::Error>> {
+    let tagged_file = Probe::open(path)?.guess_file_type()?.read()?;
+    Ok(tagged_file.properties().duration().as_secs_f64())
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Picture {
-    data: Vec<u8>,
-    mime_type: String,
+    pub data: Vec<u8>,
+    pub mime_type: String,
 }
 
 pub async fn get_pictures(path: &str) -> Result<Vec<Picture>, Box<dyn std::error::Error>> {
-    let path = Path::new(path);
-    let tagged_file = Probe::open(path)?.guess_file_type()?.read()?;
-    let tag = tagged_file
-        .primary_tag()
-        .or_else(|| tagged_file.first_tag());
-    
-    if let Some(tag) = tag {
-        let pictures = tag.pictures().iter().map(|p| Picture {
+    let pictures = read_pictures(Path::new(path))?
+        .iter()
+        .map(|p| Picture {
             data: p.data().to_vec(),
             mime_type: p.mime_type().map_or_else(
                 || String::from("application/octet-stream"),
-                |mt| mt.to_string()
+                |mt| mt.to_string(),
             ),
-        }).collect();
-        Ok(pictures)
-    } else {
-        Ok(vec![])
+        })
+        .collect();
+    Ok(pictures)
+}
+
+/// The pictures of all tags, primary tag first, with front covers ahead of the
+/// other types. Otherwise they keep the order of the tags.
+pub fn read_pictures(
+    path: &Path,
+) -> Result<Vec<lofty::picture::Picture>, lofty::error::LoftyError> {
+    let tagged_file = Probe::open(path)?.guess_file_type()?.read()?;
+
+    let mut pictures: Vec<lofty::picture::Picture> = Vec::new();
+    for picture in tags_in_order(&tagged_file)
+        .into_iter()
+        .flat_map(|tag| tag.pictures())
+    {
+        // The same art is often in both an ID3v2 and an APE tag
+        if !pictures.iter().any(|p| p.data() == picture.data()) {
+            pictures.push(picture.clone());
+        }
+    }
+    pictures.sort_by_key(|p| p.pic_type() != PictureType::CoverFront);
+    Ok(pictures)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mpv::test_support;
+    use lofty::config::WriteOptions;
+    use lofty::tag::{ItemValue, TagExt, TagItem, TagType};
+    use std::io::Cursor;
+
+    fn mp4_box(kind: &[u8; 4], content: &[u8]) -> Vec<u8> {
+        let mut data = ((content.len() + 8) as u32).to_be_bytes().to_vec();
+        data.extend_from_slice(kind);
+        data.extend_from_slice(content);
+        data
+    }
+
+    fn track(handler: &[u8; 4]) -> Vec<u8> {
+        let mut hdlr = vec![0; 8];
+        hdlr.extend_from_slice(handler);
+        hdlr.extend_from_slice(&[0; 12]);
+        let mdia = [mp4_box(b"mdhd", &[0; 24]), mp4_box(b"hdlr", &hdlr)].concat();
+        mp4_box(
+            b"trak",
+            &[mp4_box(b"tkhd", &[0; 84]), mp4_box(b"mdia", &mdia)].concat(),
+        )
+    }
+
+    fn mp4(tracks: &[Vec<u8>], moov_last: bool) -> Vec<u8> {
+        let ftyp = mp4_box(b"ftyp", b"isom\0\0\0\0isommp41");
+        let moov = mp4_box(b"moov", &tracks.concat());
+        let mdat = mp4_box(b"mdat", &[0; 1000]);
+        if moov_last {
+            [ftyp, mdat, moov].concat()
+        } else {
+            [ftyp, moov, mdat].concat()
+        }
+    }
+
+    #[test]
+    fn finds_video_tracks() {
+        let video = mp4(&[track(b"soun"), track(b"vide")], false);
+        assert!(mp4_has_video_track(Cursor::new(video)).unwrap());
+
+        let after_media_data = mp4(&[track(b"vide")], true);
+        assert!(mp4_has_video_track(Cursor::new(after_media_data)).unwrap());
+    }
+
+    #[test]
+    fn ignores_audio_and_other_tracks() {
+        let audio = mp4(&[track(b"soun"), track(b"text")], true);
+        assert!(!mp4_has_video_track(Cursor::new(audio)).unwrap());
+        assert!(!mp4_has_video_track(Cursor::new(mp4(&[], false))).unwrap());
+    }
+
+    #[test]
+    fn reads_boxes_with_64_bit_sizes() {
+        let mut data = 1u32.to_be_bytes().to_vec();
+        data.extend_from_slice(b"mdat");
+        data.extend_from_slice(&24u64.to_be_bytes());
+        data.extend_from_slice(&[0; 8]);
+        data.extend(mp4_box(b"moov", &track(b"vide")));
+        assert!(mp4_has_video_track(Cursor::new(data.clone())).unwrap());
+
+        let kinds: Vec<[u8; 4]> = mp4_boxes(&data).map(|(kind, _)| kind).collect();
+        assert_eq!(kinds, [*b"mdat", *b"moov"]);
+    }
+
+    #[test]
+    fn stops_at_truncated_boxes() {
+        let mut data = mp4_box(b"free", &[0; 4]);
+        data.extend_from_slice(&100u32.to_be_bytes());
+        data.extend_from_slice(b"trak");
+        assert_eq!(mp4_boxes(&data).count(), 1);
+    }
+
+    #[test]
+    fn only_looks_inside_mp4_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let video = dir.path().join("video.mp4");
+        let audio = dir.path().join("audio.m4a");
+        let other = dir.path().join("other.mp4");
+        std::fs::write(&video, mp4(&[track(b"vide")], true)).unwrap();
+        std::fs::write(&audio, mp4(&[track(b"soun")], true)).unwrap();
+        std::fs::write(&other, b"not an mp4 file").unwrap();
+
+        assert!(may_hold_video(&video));
+        assert!(!may_hold_video(&audio));
+        assert!(!may_hold_video(&other));
+    }
+
+    #[test]
+    fn reads_virtual_tracks_only_from_sheets() {
+        let dir = tempfile::tempdir().unwrap();
+        let album = dir.path().join("album.wav");
+        let single = dir.path().join("single.wav");
+        let sheet = dir.path().join("album.cue");
+        std::fs::write(&album, test_support::sine_wav(8_000, 0, 80_000)).unwrap();
+        std::fs::write(&single, test_support::sine_wav(8_000, 0, 8_000)).unwrap();
+        std::fs::write(
+            &sheet,
+            "FILE \"album.wav\" WAVE\nTRACK 01 AUDIO\nINDEX 01 00:00:00\n\
+             TRACK 02 AUDIO\nINDEX 01 00:04:00\n",
+        )
+        .unwrap();
+
+        let tracks = read_virtual_tracks(&album).unwrap().unwrap();
+        assert_eq!(tracks.len(), 2);
+        assert_eq!(tracks[0].end, Some(4.0));
+        assert!((tracks[1].end.unwrap() - 10.0).abs() < 0.01);
+
+        let tracks = read_virtual_tracks(&sheet).unwrap().unwrap();
+        assert_eq!(tracks.len(), 2);
+        assert_eq!(tracks[1].end, None);
+
+        assert!(read_virtual_tracks(&single).unwrap().is_none());
+    }
+
+    /// A FLAC file with a stream info block and no audio.
+    fn flac() -> Vec<u8> {
+        let mut data = b"fLaC".to_vec();
+        data.extend_from_slice(&[0, 0, 0, 34]);
+        data.extend_from_slice(&[0x10, 0, 0x10, 0, 0, 0, 0, 0, 0, 0]);
+        // 44.1 kHz, stereo, 16 bits, one second
+        let format: u64 = (44_100 << 44) | (1 << 41) | (15 << 36) | 44_100;
+        data.extend_from_slice(&format.to_be_bytes());
+        data.extend_from_slice(&[0; 16]);
+        // Last block, which lofty leaves alone when writing
+        data.extend_from_slice(&[0x81, 0, 0, 4, 0, 0, 0, 0]);
+        data
+    }
+
+    /// Silent 128 kbps MPEG-1 layer 3 frames.
+    fn mp3() -> Vec<u8> {
+        let mut data = Vec::new();
+        for _ in 0..4 {
+            data.extend_from_slice(&[0xFF, 0xFB, 0x90, 0x64]);
+            data.resize(data.len() + 413, 0);
+        }
+        data
+    }
+
+    /// An M4A file with an empty audio track.
+    fn m4a() -> Vec<u8> {
+        let ftyp = mp4_box(b"ftyp", b"M4A \0\0\0\0M4A isom");
+        let mut mvhd = vec![0; 100];
+        mvhd[12..16].copy_from_slice(&1000u32.to_be_bytes());
+        let mut mdhd = vec![0; 24];
+        mdhd[12..16].copy_from_slice(&44_100u32.to_be_bytes());
+        let mut hdlr = vec![0; 8];
+        hdlr.extend_from_slice(b"soun");
+        hdlr.extend_from_slice(&[0; 13]);
+        let mdia = [mp4_box(b"mdhd", &mdhd), mp4_box(b"hdlr", &hdlr)].concat();
+        let trak = mp4_box(
+            b"trak",
+            &[mp4_box(b"tkhd", &[0; 84]), mp4_box(b"mdia", &mdia)].concat(),
+        );
+        let moov = mp4_box(b"moov", &[mp4_box(b"mvhd", &mvhd), trak].concat());
+        [ftyp, moov, mp4_box(b"mdat", &[0; 100])].concat()
+    }
+
+    fn push(tag: &mut Tag, key: ItemKey, values: &[&str]) {
+        // ID3v2.4 frames and APE items hold all values in one, separated by NUL
+        if matches!(tag.tag_type(), TagType::Id3v2 | TagType::Ape) {
+            tag.insert_text(key, values.join("\0"));
+            return;
+        }
+        for value in values {
+            tag.push(TagItem::new(
+                key.clone(),
+                ItemValue::Text(value.to_string()),
+            ));
+        }
+    }
+
+    fn write_tag(path: &Path, tag: &Tag) {
+        tag.save_to_path(path, WriteOptions::default()).unwrap();
+    }
+
+    fn parse(path: &Path) -> SimplifiedMetadata {
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(parse_metadata(&path.to_string_lossy()))
+            .unwrap()
+    }
+
+    #[test]
+    fn reads_multiple_values_and_ids_of_each_tag_format() {
+        let dir = tempfile::tempdir().unwrap();
+        for (name, data, tag_type) in [
+            ("id3v2.mp3", mp3(), TagType::Id3v2),
+            ("vorbis.flac", flac(), TagType::VorbisComments),
+            ("ape.mp3", mp3(), TagType::Ape),
+            ("mp4.m4a", m4a(), TagType::Mp4Ilst),
+        ] {
+            let path = dir.path().join(name);
+            std::fs::write(&path, data).unwrap();
+            let mut tag = Tag::new(tag_type);
+            push(&mut tag, ItemKey::TrackArtist, &["Artist A", "Artist B"]);
+            push(&mut tag, ItemKey::Genre, &["Rock", "Pop"]);
+            push(&mut tag, ItemKey::AlbumArtist, &["Band", "Guest"]);
+            push(&mut tag, ItemKey::MusicBrainzTrackId, &["track-id"]);
+            push(&mut tag, ItemKey::MusicBrainzReleaseId, &["release-id"]);
+            push(
+                &mut tag,
+                ItemKey::MusicBrainzArtistId,
+                &["artist-a", "artist-b"],
+            );
+            push(&mut tag, ItemKey::ReplayGainTrackGain, &["-6.48 dB"]);
+            push(&mut tag, ItemKey::ReplayGainTrackPeak, &["0.988553"]);
+            push(&mut tag, ItemKey::ReplayGainAlbumGain, &["+1.50 dB"]);
+            write_tag(&path, &tag);
+
+            let metadata = parse(&path);
+            let strings = |values: &[&str]| Some(values.iter().map(|v| v.to_string()).collect());
+            assert_eq!(metadata.artist.as_deref(), Some("Artist A"), "{}", name);
+            assert_eq!(
+                metadata.artists,
+                strings(&["Artist A", "Artist B"]),
+                "{}",
+                name
+            );
+            assert_eq!(metadata.genres, strings(&["Rock", "Pop"]), "{}", name);
+            assert_eq!(
+                metadata.album_artists,
+                strings(&["Band", "Guest"]),
+                "{}",
+                name
+            );
+            assert_eq!(metadata.album_artist(), Some("Band"), "{}", name);
+
+            let musicbrainz = metadata.musicbrainz.unwrap();
+            assert_eq!(
+                musicbrainz.track_id.as_deref(),
+                Some("track-id"),
+                "{}",
+                name
+            );
+            assert_eq!(
+                musicbrainz.release_id.as_deref(),
+                Some("release-id"),
+                "{}",
+                name
+            );
+            assert_eq!(musicbrainz.artist_ids, ["artist-a", "artist-b"], "{}", name);
+            assert_eq!(musicbrainz.release_group_id, None, "{}", name);
+
+            let replay_gain = metadata.replay_gain.unwrap();
+            assert_eq!(replay_gain.track_gain, Some(-6.48), "{}", name);
+            assert_eq!(replay_gain.track_peak, Some(0.988553), "{}", name);
+            assert_eq!(replay_gain.album_gain, Some(1.5), "{}", name);
+            assert_eq!(replay_gain.album_peak, None, "{}", name);
+        }
+    }
+
+    #[test]
+    fn merges_the_fields_of_all_tags() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("song.mp3");
+        std::fs::write(&path, mp3()).unwrap();
+
+        let mut id3v2 = Tag::new(TagType::Id3v2);
+        id3v2.set_title("Title".to_string());
+        push(&mut id3v2, ItemKey::TrackArtist, &["Artist A", "Artist B"]);
+        write_tag(&path, &id3v2);
+        // Fields of later tags don't replace those of the primary one
+        let mut id3v1 = Tag::new(TagType::Id3v1);
+        id3v1.set_title("Tit".to_string());
+        id3v1.set_artist("Artist".to_string());
+        id3v1.set_genre("Jazz".to_string());
+        id3v1.insert_text(ItemKey::Year, "1999".to_string());
+        write_tag(&path, &id3v1);
+        let mut ape = Tag::new(TagType::Ape);
+        push(&mut ape, ItemKey::AlbumArtist, &["Band"]);
+        push(&mut ape, ItemKey::ReplayGainAlbumGain, &["-3.00 dB"]);
+        write_tag(&path, &ape);
+
+        let metadata = parse(&path);
+        assert_eq!(metadata.title.as_deref(), Some("Title"));
+        assert_eq!(metadata.artist.as_deref(), Some("Artist A"));
+        assert_eq!(metadata.artists.unwrap(), ["Artist A", "Artist B"]);
+        assert_eq!(metadata.genre.as_deref(), Some("Jazz"));
+        assert_eq!(metadata.genres.unwrap(), ["Jazz"]);
+        assert_eq!(metadata.year, Some(1999));
+        assert_eq!(metadata.album_artists.unwrap(), ["Band"]);
+        assert_eq!(metadata.replay_gain.unwrap().album_gain, Some(-3.0));
     }
 }
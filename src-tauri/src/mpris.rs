@@ -0,0 +1,755 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Weak};
+use std::thread;
+
+use tokio::runtime::Handle;
+use url::Url;
+use zbus::zvariant::{ObjectPath, OwnedValue, Value};
+use zbus::{connection, fdo, interface, Connection, SignalContext};
+
+use crate::cover_art::CoverArtCache;
+use crate::metadata;
+use crate::mpv::{MpvError, MpvEventId, MpvFormat, MpvPlayer, PropertyValue};
+use crate::player_handle::PlayerHandle;
+
+const BUS_NAME: &str = "org.mpris.MediaPlayer2.tauri_media_player";
+const OBJECT_PATH: &str = "/org/mpris/MediaPlayer2";
+const TRACK_PATH_PREFIX: &str = "/org/tauri_media_player/track/";
+const NO_TRACK: &str = "/org/mpris/MediaPlayer2/TrackList/NoTrack";
+
+/// Directory in the app cache directory where cover art is written for `mpris:artUrl`.
+pub const ART_DIR_NAME: &str = "mpris-art";
+
+/// MPRIS uses microseconds for positions and lengths.
+fn to_micros(seconds: f64) -> i64 {
+    (seconds * 1_000_000.0) as i64
+}
+
+fn fdo_error(e: MpvError) -> fdo::Error {
+    fdo::Error::Failed(e.to_string())
+}
+
+/// What `Metadata` reports about the current file.
+#[derive(Debug, Clone, Default)]
+struct TrackMetadata {
+    track_id: String,
+    length: Option<i64>,
+    url: Option<String>,
+    title: Option<String>,
+    artists: Vec<String>,
+    album: Option<String>,
+    track_number: Option<u32>,
+    disc_number: Option<u32>,
+    genres: Vec<String>,
+    art_url: Option<String>,
+}
+
+impl TrackMetadata {
+    /// Reads the tags of the file `player` just loaded. Streams and files
+    /// without tags get mpv's title instead.
+    fn read(
+        player: &MpvPlayer,
+        track_id: String,
+        covers: &CoverArtCache,
+        art_dir: &Path,
+        runtime: &Handle,
+    ) -> Self {
+        let path = player.get_path().ok();
+        let mut track = TrackMetadata {
+            track_id,
+            length: player.get_duration().ok().map(to_micros),
+            url: path.as_deref().and_then(to_url),
+            ..Default::default()
+        };
+
+        let tags = path
+            .as_deref()
+            .and_then(|path| runtime.block_on(metadata::parse_metadata(path)).ok());
+        match tags {
+            Some(tags) => {
+                track.title = tags.title;
+                track.artists = tags.artist.into_iter().collect();
+                track.album = tags.album;
+                track.track_number = tags.track;
+                track.disc_number = tags.disc;
+                track.genres = tags.genre.into_iter().collect();
+            }
+            None => {
+                track.title = player
+                    .get_property_json("media-title")
+                    .ok()
+                    .and_then(|title| title.as_str().map(String::from));
+            }
+        }
+
+        if let Some(path) = path.as_deref() {
+            track.art_url = write_art(path, covers, art_dir);
+        }
+        track
+    }
+
+    fn to_map(&self) -> HashMap<String, OwnedValue> {
+        let mut map = HashMap::new();
+        let mut insert = |key: &str, value: Value<'_>| {
+            if let Ok(value) = OwnedValue::try_from(value) {
+                map.insert(key.to_string(), value);
+            }
+        };
+
+        if let Ok(track_id) = ObjectPath::try_from(self.track_id.as_str()) {
+            insert("mpris:trackid", Value::from(track_id));
+        }
+        if let Some(length) = self.length {
+            insert("mpris:length", Value::from(length));
+        }
+        if let Some(art_url) = &self.art_url {
+            insert("mpris:artUrl", Value::from(art_url.as_str()));
+        }
+        if let Some(url) = &self.url {
+            insert("xesam:url", Value::from(url.as_str()));
+        }
+        if let Some(title) = &self.title {
+            insert("xesam:title", Value::from(title.as_str()));
+        }
+        if !self.artists.is_empty() {
+            insert("xesam:artist", Value::from(self.artists.clone()));
+        }
+        if let Some(album) = &self.album {
+            insert("xesam:album", Value::from(album.as_str()));
+        }
+        if let Some(track_number) = self.track_number {
+            insert("xesam:trackNumber", Value::from(track_number as i32));
+        }
+        if let Some(disc_number) = self.disc_number {
+            insert("xesam:discNumber", Value::from(disc_number as i32));
+        }
+        if !self.genres.is_empty() {
+            insert("xesam:genre", Value::from(self.genres.clone()));
+        }
+        map
+    }
+}
+
+/// `path` as a URL; mpv's path of a stream already is one.
+fn to_url(path: &str) -> Option<String> {
+    if path.contains("://") {
+        return Some(path.to_string());
+    }
+    // Relative paths have no URL
+    Url::from_file_path(path).ok().map(String::from)
+}
+
+/// Writes the cover of `path` to `art_dir` and returns its URL. Desktop
+/// widgets can only load art from a file, not from the bus or `cover://`.
+fn write_art(path: &str, covers: &CoverArtCache, art_dir: &Path) -> Option<String> {
+    // Streams have no cover
+    if path.contains("://") {
+        return None;
+    }
+    let cover = covers.resolve(Path::new(path)).ok()??;
+
+    let extension = match cover.mime_type.as_str() {
+        "image/png" => "png",
+        "image/gif" => "gif",
+        "image/bmp" => "bmp",
+        _ => "jpg",
+    };
+    let mut hasher = DefaultHasher::new();
+    path.hash(&mut hasher);
+    let art_path = art_dir.join(format!("{:016x}.{}", hasher.finish(), extension));
+
+    std::fs::create_dir_all(art_dir).ok()?;
+    std::fs::write(&art_path, &cover.data).ok()?;
+    Url::from_file_path(&art_path).ok().map(String::from)
+}
+
+/// `org.mpris.MediaPlayer2`: identifies the app. Raising and quitting aren't supported.
+struct Root;
+
+#[interface(name = "org.mpris.MediaPlayer2")]
+impl Root {
+    fn raise(&self) {}
+
+    fn quit(&self) {}
+
+    #[zbus(property)]
+    fn can_quit(&self) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn can_raise(&self) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn has_track_list(&self) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn identity(&self) -> &str {
+        "Tauri Media Player"
+    }
+
+    #[zbus(property)]
+    fn supported_uri_schemes(&self) -> Vec<String> {
+        vec!["file".to_string(), "http".to_string(), "https".to_string()]
+    }
+
+    #[zbus(property)]
+    fn supported_mime_types(&self) -> Vec<String> {
+        Vec::new()
+    }
+}
+
+/// `org.mpris.MediaPlayer2.Player`: playback controls, mapped onto the current mpv core.
+struct Player {
+    handle: PlayerHandle,
+    track: Option<TrackMetadata>,
+}
+
+impl Player {
+    fn player(&self) -> fdo::Result<Arc<MpvPlayer>> {
+        self.handle.get().map_err(fdo_error)
+    }
+}
+
+#[interface(name = "org.mpris.MediaPlayer2.Player")]
+impl Player {
+    fn next(&self) -> fdo::Result<()> {
+        self.player()?.playlist_next().map_err(fdo_error)
+    }
+
+    fn previous(&self) -> fdo::Result<()> {
+        self.player()?.playlist_prev().map_err(fdo_error)
+    }
+
+    fn pause(&self) -> fdo::Result<()> {
+        self.player()?.pause().map_err(fdo_error)
+    }
+
+    fn play_pause(&self) -> fdo::Result<()> {
+        let player = self.player()?;
+        if player.is_paused().map_err(fdo_error)? {
+            player.play().map_err(fdo_error)
+        } else {
+            player.pause().map_err(fdo_error)
+        }
+    }
+
+    fn stop(&self) -> fdo::Result<()> {
+        self.player()?.stop().map_err(fdo_error)
+    }
+
+    fn play(&self) -> fdo::Result<()> {
+        self.player()?.play().map_err(fdo_error)
+    }
+
+    /// Seeks by `offset` microseconds; seeking past the end goes to the next track.
+    fn seek(&self, offset: i64) -> fdo::Result<()> {
+        let player = self.player()?;
+        let position = player.get_position().map_err(fdo_error)? + offset as f64 / 1_000_000.0;
+        match player.get_duration() {
+            Ok(duration) if position > duration => player.playlist_next().map_err(fdo_error),
+            _ => player.seek(position.max(0.0)).map_err(fdo_error),
+        }
+    }
+
+    /// Ignored if `track_id` isn't the current track or `position` is out of range, as the spec asks.
+    fn set_position(&self, track_id: ObjectPath<'_>, position: i64) -> fdo::Result<()> {
+        let Some(track) = &self.track else {
+            return Ok(());
+        };
+        if track.track_id != track_id.as_str()
+            || position < 0
+            || track.length.is_some_and(|length| position > length)
+        {
+            return Ok(());
+        }
+        self.player()?
+            .seek(position as f64 / 1_000_000.0)
+            .map_err(fdo_error)
+    }
+
+    fn open_uri(&self, uri: &str) -> fdo::Result<()> {
+        let url = Url::parse(uri).map_err(|e| fdo::Error::InvalidArgs(e.to_string()))?;
+        let location = if url.scheme() == "file" {
+            let path = url
+                .to_file_path()
+                .map_err(|_| fdo::Error::InvalidArgs(format!("Not a local file: {}", uri)))?;
+            path.to_string_lossy().into_owned()
+        } else {
+            uri.to_string()
+        };
+        self.player()?.load_file(&location, None).map_err(fdo_error)
+    }
+
+    #[zbus(signal)]
+    async fn seeked(ctxt: &SignalContext<'_>, position: i64) -> zbus::Result<()>;
+
+    #[zbus(property)]
+    fn playback_status(&self) -> &str {
+        let Ok(player) = self.handle.get() else {
+            return "Stopped";
+        };
+        if player.is_idle().unwrap_or(true) {
+            "Stopped"
+        } else if player.is_paused().unwrap_or(true) {
+            "Paused"
+        } else {
+            "Playing"
+        }
+    }
+
+    #[zbus(property)]
+    fn rate(&self) -> f64 {
+        1.0
+    }
+
+    #[zbus(property)]
+    fn minimum_rate(&self) -> f64 {
+        1.0
+    }
+
+    #[zbus(property)]
+    fn maximum_rate(&self) -> f64 {
+        1.0
+    }
+
+    #[zbus(property)]
+    fn metadata(&self) -> HashMap<String, OwnedValue> {
+        match &self.track {
+            Some(track) => track.to_map(),
+            None => {
+                let mut map = HashMap::new();
+                if let Ok(no_track) = OwnedValue::try_from(Value::from(
+                    ObjectPath::from_static_str_unchecked(NO_TRACK),
+                )) {
+                    map.insert("mpris:trackid".to_string(), no_track);
+                }
+                map
+            }
+        }
+    }
+
+    /// mpv's volume as a fraction of 100%.
+    #[zbus(property)]
+    fn volume(&self) -> f64 {
+        self.handle
+            .get()
+            .and_then(|player| player.get_volume())
+            .map(|volume| volume / 100.0)
+            .unwrap_or(0.0)
+    }
+
+    #[zbus(property)]
+    fn set_volume(&mut self, volume: f64) -> fdo::Result<()> {
+        self.player()?
+            .set_volume(volume.max(0.0) * 100.0)
+            .map_err(fdo_error)
+    }
+
+    /// Not announced through `PropertiesChanged`; clients interpolate it and listen for `Seeked`.
+    #[zbus(property(emits_changed_signal = "false"))]
+    fn position(&self) -> i64 {
+        self.handle
+            .get()
+            .and_then(|player| player.get_position())
+            .map(to_micros)
+            .unwrap_or(0)
+    }
+
+    #[zbus(property)]
+    fn can_go_next(&self) -> bool {
+        true
+    }
+
+    #[zbus(property)]
+    fn can_go_previous(&self) -> bool {
+        true
+    }
+
+    #[zbus(property)]
+    fn can_play(&self) -> bool {
+        true
+    }
+
+    #[zbus(property)]
+    fn can_pause(&self) -> bool {
+        true
+    }
+
+    #[zbus(property)]
+    fn can_seek(&self) -> bool {
+        true
+    }
+
+    #[zbus(property(emits_changed_signal = "const"))]
+    fn can_control(&self) -> bool {
+        true
+    }
+}
+
+/// Publishes the player on the session bus as an MPRIS media player, so it
+/// shows up in desktop media widgets and can be controlled with `playerctl`.
+pub struct MprisServer {
+    connection: Connection,
+    runtime: Handle,
+    covers: Arc<CoverArtCache>,
+    art_dir: PathBuf,
+    /// Bumped whenever a file is loaded or playback stops. Numbers the track ids, which
+    /// must change whenever a file is loaded, and lets metadata read for an
+    /// earlier file be dropped.
+    generation: AtomicU64,
+    /// A seek is in progress; `Seeked` is sent once playback restarts.
+    seeking: AtomicBool,
+}
+
+impl MprisServer {
+    pub fn start(
+        handle: PlayerHandle,
+        runtime: Handle,
+        covers: Arc<CoverArtCache>,
+        art_dir: PathBuf,
+    ) -> Result<Arc<Self>, zbus::Error> {
+        Self::start_on(
+            connection::Builder::session()?,
+            handle,
+            runtime,
+            covers,
+            art_dir,
+        )
+    }
+
+    fn start_on(
+        bus: connection::Builder<'_>,
+        handle: PlayerHandle,
+        runtime: Handle,
+        covers: Arc<CoverArtCache>,
+        art_dir: PathBuf,
+    ) -> Result<Arc<Self>, zbus::Error> {
+        let player = Player {
+            handle: handle.clone(),
+            track: None,
+        };
+        let connection = runtime.block_on(
+            bus.name(BUS_NAME)?
+                .serve_at(OBJECT_PATH, Root)?
+                .serve_at(OBJECT_PATH, player)?
+                .build(),
+        )?;
+
+        let server = Arc::new(Self {
+            connection,
+            runtime,
+            covers,
+            art_dir,
+            generation: AtomicU64::new(0),
+            seeking: AtomicBool::new(false),
+        });
+
+        let server_ref = Arc::downgrade(&server);
+        handle
+            .on_create(move |player| match server_ref.upgrade() {
+                Some(server) => server.attach(player),
+                None => Ok(()),
+            })
+            .map_err(|e| zbus::Error::Failure(e.to_string()))?;
+
+        Ok(server)
+    }
+
+    /// Sends `PropertiesChanged` and `Seeked` for `player`'s events. Called for every new core.
+    fn attach(self: &Arc<Self>, player: &Arc<MpvPlayer>) -> Result<(), MpvError> {
+        // Same formats as `PlayerStateTracker`, which observes these too
+        for name in ["pause", "idle-active"] {
+            let server = Arc::downgrade(self);
+            player.on_property_change(name, MpvFormat::Flag, move |_| {
+                Self::notify(&server, |player, ctxt| async move {
+                    player.get().await.playback_status_changed(&ctxt).await
+                });
+            })?;
+        }
+
+        let server = Arc::downgrade(self);
+        player.on_property_change("volume", MpvFormat::Double, move |_| {
+            Self::notify(&server, |player, ctxt| async move {
+                player.get().await.volume_changed(&ctxt).await
+            });
+        })?;
+
+        let server = Arc::downgrade(self);
+        player.register_event_callback(MpvEventId::Seek, move |_| {
+            if let Some(server) = server.upgrade() {
+                server.seeking.store(true, Ordering::SeqCst);
+            }
+        })?;
+
+        let server = Arc::downgrade(self);
+        let player_ref = Arc::downgrade(player);
+        player.register_event_callback(MpvEventId::PlaybackRestart, move |_| {
+            let seeked = server
+                .upgrade()
+                .is_some_and(|server| server.seeking.swap(false, Ordering::SeqCst));
+            let Some(player) = player_ref.upgrade().filter(|_| seeked) else {
+                return;
+            };
+            let position = player.get_position().map(to_micros).unwrap_or(0);
+            Self::notify(&server, move |_, ctxt| async move {
+                Player::seeked(&ctxt, position).await
+            });
+        })?;
+
+        let server = Arc::downgrade(self);
+        let player_ref = Arc::downgrade(player);
+        player.register_event_callback(MpvEventId::FileLoaded, move |_| {
+            let (Some(server), Some(player)) = (server.upgrade(), player_ref.upgrade()) else {
+                return;
+            };
+            let generation = server.generation.fetch_add(1, Ordering::SeqCst) + 1;
+            // Reading tags and art takes a while; the event thread must not wait
+            thread::spawn(move || {
+                let track_id = format!("{}{}", TRACK_PATH_PREFIX, generation);
+                let track = TrackMetadata::read(
+                    &player,
+                    track_id,
+                    &server.covers,
+                    &server.art_dir,
+                    &server.runtime,
+                );
+                server.set_track(generation, Some(track));
+            });
+        })?;
+
+        // Only cleared once playback stops: clearing it when each file ends
+        // would make widgets flicker between playlist entries
+        let server = Arc::downgrade(self);
+        player.on_property_change("idle-active", MpvFormat::Flag, move |value| {
+            let idle = matches!(value, PropertyValue::Flag(true));
+            if let Some(server) = server.upgrade().filter(|_| idle) {
+                let generation = server.generation.fetch_add(1, Ordering::SeqCst) + 1;
+                server.set_track(generation, None);
+            }
+        })?;
+
+        Ok(())
+    }
+
+    /// Publishes `track`, unless another file was loaded or playback stopped since
+    /// `generation` was taken.
+    fn set_track(self: &Arc<Self>, generation: u64, track: Option<TrackMetadata>) {
+        let server = self.clone();
+        self.runtime.spawn(async move {
+            let result = async {
+                let player = server
+                    .connection
+                    .object_server()
+                    .interface::<_, Player>(OBJECT_PATH)
+                    .await?;
+                let mut iface = player.get_mut().await;
+                // Checked with the interface locked, so an older track can't replace a newer one
+                if server.generation.load(Ordering::SeqCst) != generation {
+                    return Ok(());
+                }
+                iface.track = track;
+                iface.metadata_changed(player.signal_context()).await
+            };
+            if let Err(e) = result.await {
+                eprintln!("Failed to update MPRIS metadata: {}", e);
+            }
+        });
+    }
+
+    /// Runs `signal` with the `Player` interface on the runtime, since mpv's
+    /// callbacks can't wait for the bus.
+    fn notify<F, Fut>(server: &Weak<Self>, signal: F)
+    where
+        F: FnOnce(zbus::object_server::InterfaceRef<Player>, SignalContext<'static>) -> Fut
+            + Send
+            + 'static,
+        Fut: std::future::Future<Output = zbus::Result<()>> + Send,
+    {
+        let Some(server) = server.upgrade() else {
+            return;
+        };
+        let connection = server.connection.clone();
+        server.runtime.spawn(async move {
+            let result = async {
+                let player = connection
+                    .object_server()
+                    .interface::<_, Player>(OBJECT_PATH)
+                    .await?;
+                let ctxt = player.signal_context().clone();
+                signal(player, ctxt).await
+            };
+            if let Err(e) = result.await {
+                eprintln!("Failed to send MPRIS signal: {}", e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use std::process::{Child, Command, Stdio};
+    use std::time::{Duration, Instant};
+    use tokio::runtime::Runtime;
+    use zbus::names::InterfaceName;
+
+    const PLAYER_INTERFACE: &str = "org.mpris.MediaPlayer2.Player";
+
+    /// A private session bus, so tests don't show up in the desktop's media widgets.
+    struct TestBus {
+        daemon: Child,
+        address: String,
+    }
+
+    impl TestBus {
+        fn start() -> Option<Self> {
+            let daemon = Command::new("dbus-daemon")
+                .args(["--session", "--nofork", "--print-address"])
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null())
+                .spawn();
+            let mut daemon = match daemon {
+                Ok(daemon) => daemon,
+                Err(e) => {
+                    eprintln!("Skipping, dbus-daemon is not available: {}", e);
+                    return None;
+                }
+            };
+            let mut address = String::new();
+            BufReader::new(daemon.stdout.take().unwrap())
+                .read_line(&mut address)
+                .unwrap();
+            Some(TestBus {
+                daemon,
+                address: address.trim().to_string(),
+            })
+        }
+
+        fn connect(&self) -> connection::Builder<'_> {
+            connection::Builder::address(self.address.as_str()).unwrap()
+        }
+    }
+
+    impl Drop for TestBus {
+        fn drop(&mut self) {
+            let _ = self.daemon.kill();
+            let _ = self.daemon.wait();
+        }
+    }
+
+    fn get(runtime: &Runtime, client: &Connection, name: &str) -> OwnedValue {
+        runtime.block_on(async {
+            fdo::PropertiesProxy::builder(client)
+                .destination(BUS_NAME)
+                .unwrap()
+                .path(OBJECT_PATH)
+                .unwrap()
+                .build()
+                .await
+                .unwrap()
+                .get(
+                    InterfaceName::from_static_str_unchecked(PLAYER_INTERFACE),
+                    name,
+                )
+                .await
+                .unwrap()
+        })
+    }
+
+    fn metadata(runtime: &Runtime, client: &Connection) -> HashMap<String, OwnedValue> {
+        HashMap::try_from(get(runtime, client, "Metadata")).unwrap()
+    }
+
+    fn title(metadata: &HashMap<String, OwnedValue>) -> Option<String> {
+        metadata
+            .get("xesam:title")
+            .and_then(|title| String::try_from(title.try_clone().unwrap()).ok())
+    }
+
+    #[test]
+    fn converts_paths_to_urls() {
+        assert_eq!(
+            to_url("/music/a b#1.flac").as_deref(),
+            Some("file:///music/a%20b%231.flac")
+        );
+        assert_eq!(
+            to_url("https://example.com/stream").as_deref(),
+            Some("https://example.com/stream")
+        );
+        assert_eq!(to_url("music/a.flac"), None);
+    }
+
+    #[test]
+    fn publishes_the_player_and_only_the_latest_track() {
+        let Some(bus) = TestBus::start() else {
+            return;
+        };
+        let runtime = Runtime::new().unwrap();
+        let art_dir = tempfile::tempdir().unwrap();
+        let handle = PlayerHandle::new("", None);
+        let covers = CoverArtCache::new("", art_dir.path().join("covers"));
+        let server = MprisServer::start_on(
+            bus.connect(),
+            handle,
+            runtime.handle().clone(),
+            covers,
+            art_dir.path().to_path_buf(),
+        )
+        .unwrap();
+        let client = runtime.block_on(bus.connect().build()).unwrap();
+
+        let status = String::try_from(get(&runtime, &client, "PlaybackStatus")).unwrap();
+        assert_eq!(status, "Stopped");
+        let track_id = ObjectPath::try_from(
+            metadata(&runtime, &client)["mpris:trackid"]
+                .try_clone()
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(track_id.as_str(), NO_TRACK);
+
+        let track = |title: &str| TrackMetadata {
+            track_id: format!("{}{}", TRACK_PATH_PREFIX, title),
+            title: Some(title.to_string()),
+            ..Default::default()
+        };
+        let stale = server.generation.fetch_add(1, Ordering::SeqCst) + 1;
+        let current = server.generation.fetch_add(1, Ordering::SeqCst) + 1;
+        server.set_track(current, Some(track("current")));
+        server.set_track(stale, Some(track("stale")));
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while title(&metadata(&runtime, &client)).is_none() {
+            assert!(Instant::now() < deadline, "metadata was never published");
+            thread::sleep(Duration::from_millis(20));
+        }
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(
+            title(&metadata(&runtime, &client)).as_deref(),
+            Some("current")
+        );
+
+        let result = runtime.block_on(client.call_method(
+            Some(BUS_NAME),
+            OBJECT_PATH,
+            Some(PLAYER_INTERFACE),
+            "OpenUri",
+            &("not a uri",),
+        ));
+        assert!(matches!(
+            result,
+            Err(zbus::Error::MethodError(name, _, _))
+                if name.as_str() == "org.freedesktop.DBus.Error.InvalidArgs"
+        ));
+    }
+}
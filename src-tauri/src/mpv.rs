@@ -1,13 +1,16 @@
 use libloading::{Library, Symbol};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::ffi::{c_char, CStr, CString};
 use std::os::raw::{c_double, c_int, c_void};
 use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
 use std::sync::Arc;
 use std::sync::Mutex;
 use std::thread;
+use std::time::Duration;
 use thiserror::Error;
 
 // Thread-safe wrapper for the raw pointer
@@ -15,47 +18,244 @@ struct MpvHandle(*mut c_void);
 unsafe impl Send for MpvHandle {}
 unsafe impl Sync for MpvHandle {}
 
+/// Error codes copied straight from mpv's client.h file (`mpv_error`).
+/// See original client.h file for details.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+#[repr(i32)]
+pub enum MpvErrorCode {
+    Success = 0,
+    EventQueueFull = -1,
+    NoMem = -2,
+    Uninitialized = -3,
+    InvalidParameter = -4,
+    OptionNotFound = -5,
+    OptionFormat = -6,
+    OptionError = -7,
+    PropertyNotFound = -8,
+    PropertyFormat = -9,
+    PropertyUnavailable = -10,
+    PropertyError = -11,
+    Command = -12,
+    LoadingFailed = -13,
+    AoInitFailed = -14,
+    VoInitFailed = -15,
+    NothingToPlay = -16,
+    UnknownFormat = -17,
+    Unsupported = -18,
+    NotImplemented = -19,
+    Generic = -20,
+    /// A code this version of the bindings doesn't know about.
+    Unknown = i32::MIN,
+}
+
+impl From<c_int> for MpvErrorCode {
+    fn from(code: c_int) -> Self {
+        match code {
+            0 => Self::Success,
+            -1 => Self::EventQueueFull,
+            -2 => Self::NoMem,
+            -3 => Self::Uninitialized,
+            -4 => Self::InvalidParameter,
+            -5 => Self::OptionNotFound,
+            -6 => Self::OptionFormat,
+            -7 => Self::OptionError,
+            -8 => Self::PropertyNotFound,
+            -9 => Self::PropertyFormat,
+            -10 => Self::PropertyUnavailable,
+            -11 => Self::PropertyError,
+            -12 => Self::Command,
+            -13 => Self::LoadingFailed,
+            -14 => Self::AoInitFailed,
+            -15 => Self::VoInitFailed,
+            -16 => Self::NothingToPlay,
+            -17 => Self::UnknownFormat,
+            -18 => Self::Unsupported,
+            -19 => Self::NotImplemented,
+            -20 => Self::Generic,
+            _ => Self::Unknown,
+        }
+    }
+}
+
+/// Errors from the mpv bindings. Variants for failed libmpv calls carry the
+/// returned error code and its description from `mpv_error_string`; errors
+/// raised on our side before or after a libmpv call have no code.
 #[derive(Error, Debug)]
 pub enum MpvError {
     #[error("Library error: {0}")]
     LibraryError(#[from] libloading::Error),
 
-    #[error("Failed to set option: {name} = {value}")]
-    SetOptionError { name: String, value: String },
+    #[error("Failed to set option: {name} = {value} ({message})")]
+    SetOptionError {
+        name: String,
+        value: String,
+        code: MpvErrorCode,
+        message: String,
+    },
 
-    #[error("Failed to initialize MPV")]
-    InitializationError,
+    #[error("Failed to initialize MPV ({message})")]
+    InitializationError { code: MpvErrorCode, message: String },
 
-    #[error("Failed to execute command: {0}")]
-    CommandError(String),
+    #[error("Failed to execute command: {command} ({message})")]
+    CommandError {
+        command: String,
+        code: MpvErrorCode,
+        message: String,
+    },
 
     #[error("String conversion error: {0}")]
     StringConversionError(#[from] std::ffi::NulError),
 
-    #[error("Failed to get property: {0}")]
-    GetPropertyError(String),
+    #[error("Failed to get property: {name} ({message})")]
+    GetPropertyError {
+        name: String,
+        code: MpvErrorCode,
+        message: String,
+    },
 
-    #[error("Failed to set property: {0}")]
-    SetPropertyError(String),
+    #[error("Failed to set property: {name} ({message})")]
+    SetPropertyError {
+        name: String,
+        code: MpvErrorCode,
+        message: String,
+    },
 
     #[error("Failed to process events")]
     EventProcessingError, // TODO: actual use the error
+
+    /// A property or command the webview may not use, see `mpv_properties::MpvAllowlist`.
+    #[error("Not allowed: {name}")]
+    NotAllowedError { name: String },
+
+    /// An argument rejected before reaching libmpv, e.g. an out of range playlist position.
+    #[error("Invalid argument: {context} ({message})")]
+    InvalidArgumentError { context: String, message: String },
+
+    /// A file that couldn't be loaded or probed, e.g. because it timed out.
+    #[error("Failed to load: {path} ({message})")]
+    LoadError { path: String, message: String },
+
+    /// A property value libmpv returned that couldn't be parsed.
+    #[error("Unexpected value for property: {name} ({message})")]
+    PropertyValueError { name: String, message: String },
+
+    /// There is no core to talk to, e.g. because it failed to start.
+    #[error("mpv is not running ({message})")]
+    NotRunningError { message: String },
 }
 
-// Custom serializer for MpvError
+impl MpvError {
+    /// Stable name of the variant, e.g. `get_property`.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            MpvError::LibraryError(_) => "library",
+            MpvError::SetOptionError { .. } => "set_option",
+            MpvError::InitializationError { .. } => "initialization",
+            MpvError::CommandError { .. } => "command",
+            MpvError::StringConversionError(_) => "string_conversion",
+            MpvError::GetPropertyError { .. } => "get_property",
+            MpvError::SetPropertyError { .. } => "set_property",
+            MpvError::EventProcessingError => "event_processing",
+            MpvError::NotAllowedError { .. } => "not_allowed",
+            MpvError::InvalidArgumentError { .. } => "invalid_argument",
+            MpvError::LoadError { .. } => "load",
+            MpvError::PropertyValueError { .. } => "property_value",
+            MpvError::NotRunningError { .. } => "not_running",
+        }
+    }
+
+    /// The libmpv error code, if the error came from a libmpv call.
+    pub fn code(&self) -> Option<MpvErrorCode> {
+        match self {
+            MpvError::SetOptionError { code, .. }
+            | MpvError::InitializationError { code, .. }
+            | MpvError::CommandError { code, .. }
+            | MpvError::GetPropertyError { code, .. }
+            | MpvError::SetPropertyError { code, .. } => Some(*code),
+            _ => None,
+        }
+    }
+
+    /// Description of the error without the context, e.g. `property unavailable`
+    /// for libmpv errors.
+    pub fn message(&self) -> String {
+        match self {
+            MpvError::SetOptionError { message, .. }
+            | MpvError::InitializationError { message, .. }
+            | MpvError::CommandError { message, .. }
+            | MpvError::GetPropertyError { message, .. }
+            | MpvError::SetPropertyError { message, .. }
+            | MpvError::InvalidArgumentError { message, .. }
+            | MpvError::LoadError { message, .. }
+            | MpvError::PropertyValueError { message, .. }
+            | MpvError::NotRunningError { message } => message.clone(),
+            _ => self.to_string(),
+        }
+    }
+
+    /// What the failed call was about: the option, command or property name.
+    pub fn context(&self) -> Option<String> {
+        match self {
+            MpvError::SetOptionError { name, value, .. } => Some(format!("{}={}", name, value)),
+            MpvError::CommandError { command, .. } => Some(command.clone()),
+            MpvError::GetPropertyError { name, .. }
+            | MpvError::SetPropertyError { name, .. }
+            | MpvError::NotAllowedError { name }
+            | MpvError::PropertyValueError { name, .. } => Some(name.clone()),
+            MpvError::InvalidArgumentError { context, .. } => Some(context.clone()),
+            MpvError::LoadError { path, .. } => Some(path.clone()),
+            _ => None,
+        }
+    }
+
+    /// An argument rejected before reaching libmpv.
+    pub fn invalid_argument(context: impl Into<String>, message: impl Into<String>) -> Self {
+        MpvError::InvalidArgumentError {
+            context: context.into(),
+            message: message.into(),
+        }
+    }
+
+    /// A file that couldn't be loaded, for failures libmpv didn't report itself.
+    pub fn load(path: impl Into<String>, message: impl Into<String>) -> Self {
+        MpvError::LoadError {
+            path: path.into(),
+            message: message.into(),
+        }
+    }
+
+    /// A property value that couldn't be parsed.
+    pub fn property_format(name: impl Into<String>, message: impl Into<String>) -> Self {
+        MpvError::PropertyValueError {
+            name: name.into(),
+            message: message.into(),
+        }
+    }
+}
+
+// Serialized as `{ kind, code, message, context }` so the frontend can tell errors apart
 impl serde::Serialize for MpvError {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: serde::ser::Serializer,
     {
-        serializer.serialize_str(self.to_string().as_ref())
+        use serde::ser::SerializeStruct;
+
+        let mut state = serializer.serialize_struct("MpvError", 4)?;
+        state.serialize_field("kind", self.kind())?;
+        state.serialize_field("code", &self.code())?;
+        state.serialize_field("message", &self.message())?;
+        state.serialize_field("context", &self.context())?;
+        state.end()
     }
 }
 
 /// Mpv formats enum copied straight from mpv's client.h file.
 /// See original client.h file for details and usage.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(C)]
-enum MpvFormat {
+pub enum MpvFormat {
     None = 0,
     String = 1,
     OsdString = 2,
@@ -100,12 +300,316 @@ pub struct MpvEvent {
     data: *mut c_void,
 }
 
+/// `mpv_event_end_file` from client.h
+#[repr(C)]
+struct MpvEventEndFile {
+    reason: c_int,
+    error: c_int,
+    playlist_entry_id: i64,
+    playlist_insert_id: i64,
+    playlist_insert_num_entries: c_int,
+}
+
+/// `mpv_event_property` from client.h
+#[repr(C)]
+struct MpvEventProperty {
+    name: *const c_char,
+    format: c_int,
+    data: *mut c_void,
+}
+
+/// `mpv_node` from client.h
+#[repr(C)]
+struct MpvNode {
+    u: MpvNodeValue,
+    format: c_int,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+union MpvNodeValue {
+    string: *mut c_char,
+    flag: c_int,
+    int64: i64,
+    double: c_double,
+    list: *mut MpvNodeList,
+    ba: *mut c_void,
+}
+
+/// `mpv_node_list` from client.h. `keys` is only set for maps.
+#[repr(C)]
+struct MpvNodeList {
+    num: c_int,
+    values: *mut MpvNode,
+    keys: *mut *mut c_char,
+}
+
+impl MpvNode {
+    /// Converts a node (and everything below it) to JSON. Byte arrays become `null`.
+    ///
+    /// # Safety
+    /// `self` must be a valid node, e.g. as filled in by `mpv_get_property`.
+    unsafe fn to_json(&self) -> Value {
+        match self.format {
+            f if f == MpvFormat::String as c_int => {
+                Value::String(CStr::from_ptr(self.u.string).to_string_lossy().into_owned())
+            }
+            f if f == MpvFormat::Flag as c_int => Value::Bool(self.u.flag != 0),
+            f if f == MpvFormat::Int64 as c_int => Value::from(self.u.int64),
+            f if f == MpvFormat::Double as c_int => Value::from(self.u.double),
+            f if f == MpvFormat::NodeArray as c_int => {
+                let list = &*self.u.list;
+                let values = (0..list.num as usize).map(|i| (*list.values.add(i)).to_json());
+                Value::Array(values.collect())
+            }
+            f if f == MpvFormat::NodeMap as c_int => {
+                let list = &*self.u.list;
+                let entries = (0..list.num as usize).map(|i| {
+                    let key = CStr::from_ptr(*list.keys.add(i))
+                        .to_string_lossy()
+                        .into_owned();
+                    (key, (*list.values.add(i)).to_json())
+                });
+                Value::Object(entries.collect())
+            }
+            _ => Value::Null,
+        }
+    }
+}
+
+/// Builds `MpvNode`s from JSON and owns the memory they point to, which must
+/// stay alive until libmpv has copied the node.
+#[derive(Default)]
+struct NodeBuilder {
+    strings: Vec<CString>,
+    values: Vec<Vec<MpvNode>>,
+    keys: Vec<Vec<*mut c_char>>,
+    // Boxed, since nodes point to the lists and the `Vec` may reallocate
+    #[allow(clippy::vec_box)]
+    lists: Vec<Box<MpvNodeList>>,
+}
+
+impl NodeBuilder {
+    fn build(&mut self, value: &Value) -> Result<MpvNode, MpvError> {
+        let (u, format) = match value {
+            Value::Null => (MpvNodeValue { int64: 0 }, MpvFormat::None),
+            Value::Bool(b) => (MpvNodeValue { flag: *b as c_int }, MpvFormat::Flag),
+            Value::Number(n) => match n.as_i64() {
+                Some(i) => (MpvNodeValue { int64: i }, MpvFormat::Int64),
+                None => (
+                    MpvNodeValue {
+                        double: n.as_f64().unwrap_or_default(),
+                    },
+                    MpvFormat::Double,
+                ),
+            },
+            Value::String(string) => (
+                MpvNodeValue {
+                    string: self.string(string)?,
+                },
+                MpvFormat::String,
+            ),
+            Value::Array(items) => {
+                let values = items
+                    .iter()
+                    .map(|item| self.build(item))
+                    .collect::<Result<Vec<_>, _>>()?;
+                (
+                    MpvNodeValue {
+                        list: self.list(values, None),
+                    },
+                    MpvFormat::NodeArray,
+                )
+            }
+            Value::Object(map) => {
+                let mut keys = Vec::with_capacity(map.len());
+                let mut values = Vec::with_capacity(map.len());
+                for (key, item) in map {
+                    keys.push(self.string(key)?);
+                    values.push(self.build(item)?);
+                }
+                (
+                    MpvNodeValue {
+                        list: self.list(values, Some(keys)),
+                    },
+                    MpvFormat::NodeMap,
+                )
+            }
+        };
+
+        Ok(MpvNode {
+            u,
+            format: format as c_int,
+        })
+    }
+
+    fn string(&mut self, string: &str) -> Result<*mut c_char, MpvError> {
+        let string = CString::new(string)?;
+        let ptr = string.as_ptr() as *mut c_char;
+        self.strings.push(string);
+        Ok(ptr)
+    }
+
+    fn list(
+        &mut self,
+        mut values: Vec<MpvNode>,
+        keys: Option<Vec<*mut c_char>>,
+    ) -> *mut MpvNodeList {
+        // Moving the `Vec`s into `self` doesn't move their heap buffers
+        let mut list = Box::new(MpvNodeList {
+            num: values.len() as c_int,
+            values: values.as_mut_ptr(),
+            keys: std::ptr::null_mut(),
+        });
+        if let Some(mut keys) = keys {
+            list.keys = keys.as_mut_ptr();
+            self.keys.push(keys);
+        }
+        self.values.push(values);
+
+        let ptr = &mut *list as *mut MpvNodeList;
+        self.lists.push(list);
+        ptr
+    }
+}
+
+/// Why a file stopped playing (`mpv_end_file_reason` in client.h).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EndFileReason {
+    /// Reached the end of the file (or the `end` option).
+    Eof,
+    /// Stopped by a command, e.g. `stop`, `playlist-next` or loading another file.
+    Stop,
+    Quit,
+    Error,
+    Redirect,
+}
+
+/// The value carried by a `PropertyChange` event, in the format it was observed with.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PropertyValue {
+    /// The property is currently unavailable (e.g. `time-pos` while idle).
+    None,
+    Flag(bool),
+    Int64(i64),
+    Double(f64),
+    String(String),
+}
+
+impl PropertyValue {
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            PropertyValue::Double(v) => Some(*v),
+            PropertyValue::Int64(v) => Some(*v as f64),
+            _ => None,
+        }
+    }
+
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            PropertyValue::Int64(v) => Some(*v),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            PropertyValue::Flag(v) => Some(*v),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            PropertyValue::String(v) => Some(v),
+            _ => None,
+        }
+    }
+}
+
+impl MpvEvent {
+    pub fn event_id(&self) -> c_int {
+        self.event_id
+    }
+
+    /// Error code of the event; negative values are libmpv error codes.
+    pub fn error(&self) -> c_int {
+        self.error
+    }
+
+    /// The reason of an `EndFile` event, `None` for any other event.
+    pub fn end_file_reason(&self) -> Option<EndFileReason> {
+        if self.event_id != MpvEventId::EndFile as c_int || self.data.is_null() {
+            return None;
+        }
+
+        let end_file = unsafe { &*(self.data as *const MpvEventEndFile) };
+        match end_file.reason {
+            0 => Some(EndFileReason::Eof),
+            2 => Some(EndFileReason::Stop),
+            3 => Some(EndFileReason::Quit),
+            4 => Some(EndFileReason::Error),
+            5 => Some(EndFileReason::Redirect),
+            _ => None,
+        }
+    }
+
+    /// Name and new value of a `PropertyChange` event, `None` for any other event.
+    pub fn property_change(&self) -> Option<(String, PropertyValue)> {
+        if self.event_id != MpvEventId::PropertyChange as c_int || self.data.is_null() {
+            return None;
+        }
+
+        let property = unsafe { &*(self.data as *const MpvEventProperty) };
+        if property.name.is_null() {
+            return None;
+        }
+        let name = unsafe { CStr::from_ptr(property.name) }
+            .to_string_lossy()
+            .into_owned();
+
+        let value = if property.data.is_null() {
+            PropertyValue::None
+        } else {
+            unsafe {
+                match property.format {
+                    f if f == MpvFormat::Flag as c_int => {
+                        PropertyValue::Flag(*(property.data as *const c_int) != 0)
+                    }
+                    f if f == MpvFormat::Int64 as c_int => {
+                        PropertyValue::Int64(*(property.data as *const i64))
+                    }
+                    f if f == MpvFormat::Double as c_int => {
+                        PropertyValue::Double(*(property.data as *const c_double))
+                    }
+                    f if f == MpvFormat::String as c_int || f == MpvFormat::OsdString as c_int => {
+                        let string = *(property.data as *const *const c_char);
+                        if string.is_null() {
+                            PropertyValue::None
+                        } else {
+                            PropertyValue::String(
+                                CStr::from_ptr(string).to_string_lossy().into_owned(),
+                            )
+                        }
+                    }
+                    _ => PropertyValue::None,
+                }
+            }
+        };
+
+        Some((name, value))
+    }
+}
+
 pub type EventCallback = Box<dyn Fn(&MpvEvent) + Send + 'static>;
 
 struct Mpv {
     handle: MpvHandle,
     library: Arc<Library>,
     event_callbacks: Mutex<HashMap<c_int, Vec<EventCallback>>>,
+    /// Ends the event loop of a core that will never send `Shutdown`, e.g. one
+    /// that failed to initialize.
+    stop_events: AtomicBool,
 }
 
 impl Mpv {
@@ -121,9 +625,19 @@ impl Mpv {
             handle: MpvHandle(handle),
             library,
             event_callbacks: Mutex::new(HashMap::new()),
+            stop_events: AtomicBool::new(false),
         })
     }
 
+    /// Makes `mpv_wait_event` return right away, e.g. to notice `stop_events`.
+    fn wakeup(&self) -> Result<(), MpvError> {
+        let wakeup_fn: Symbol<unsafe extern "C" fn(*mut c_void)> =
+            unsafe { self.library.get(b"mpv_wakeup")? };
+
+        unsafe { wakeup_fn(self.handle.0) };
+        Ok(())
+    }
+
     fn destroy(&self) -> Result<(), MpvError> {
         let destroy_fn: Symbol<unsafe extern "C" fn(*mut c_void)> =
             unsafe { self.library.get(b"mpv_destroy")? };
@@ -149,6 +663,8 @@ impl Mpv {
             Err(MpvError::SetOptionError {
                 name: name.to_string(),
                 value: value.to_string(),
+                code: result.into(),
+                message: self.error_string(result),
             })
         }
     }
@@ -162,7 +678,10 @@ impl Mpv {
         if result == 0 {
             Ok(())
         } else {
-            Err(MpvError::InitializationError)
+            Err(MpvError::InitializationError {
+                code: result.into(),
+                message: self.error_string(result),
+            })
         }
     }
 
@@ -177,27 +696,41 @@ impl Mpv {
         if result == 0 {
             Ok(())
         } else {
-            Err(MpvError::CommandError(command.to_string()))
+            Err(MpvError::CommandError {
+                command: command.to_string(),
+                code: result.into(),
+                message: self.error_string(result),
+            })
         }
     }
 
     fn get_property_string(&self, name: &str) -> Result<String, MpvError> {
+        // `mpv_get_property_string` only returns NULL on failure, so go through
+        // `mpv_get_property` to get the error code
         let get_property_string_fn: Symbol<
-            unsafe extern "C" fn(*mut c_void, *const c_char) -> *mut c_char,
-        > = unsafe { self.library.get(b"mpv_get_property_string")? };
+            unsafe extern "C" fn(*mut c_void, *const c_char, c_int, *mut *mut c_char) -> c_int,
+        > = unsafe { self.library.get(b"mpv_get_property")? };
 
         let name_cstring = CString::new(name)?;
+        let mut value: *mut c_char = std::ptr::null_mut();
 
-        let result = unsafe { get_property_string_fn(self.handle.0, name_cstring.as_ptr()) };
+        let result = unsafe {
+            get_property_string_fn(
+                self.handle.0,
+                name_cstring.as_ptr(),
+                MpvFormat::String as c_int,
+                &mut value as *mut *mut c_char,
+            )
+        };
 
-        if result.is_null() {
-            return Err(MpvError::GetPropertyError(name.to_string()));
+        if result < 0 || value.is_null() {
+            return Err(self.get_property_error(name, result));
         }
 
-        let c_str = unsafe { CStr::from_ptr(result) };
+        let c_str = unsafe { CStr::from_ptr(value) };
         let string = c_str.to_str().unwrap_or("").to_string();
 
-        self.free(result as *mut c_void)?; // free string
+        self.free(value as *mut c_void)?; // free string
 
         Ok(string)
     }
@@ -222,7 +755,7 @@ impl Mpv {
         if result == 0 {
             Ok(value)
         } else {
-            Err(MpvError::GetPropertyError(name.to_string()))
+            Err(self.get_property_error(name, result))
         }
     }
 
@@ -246,7 +779,7 @@ impl Mpv {
         if result == 0 {
             Ok(value)
         } else {
-            Err(MpvError::GetPropertyError(name.to_string()))
+            Err(self.get_property_error(name, result))
         }
     }
 
@@ -268,7 +801,7 @@ impl Mpv {
         };
 
         if result < 0 {
-            return Err(MpvError::GetPropertyError(name.to_string()));
+            return Err(self.get_property_error(name, result));
         }
 
         Ok(value != 0)
@@ -293,7 +826,192 @@ impl Mpv {
         if result == 0 {
             Ok(())
         } else {
-            Err(MpvError::SetPropertyError(name.to_string()))
+            Err(MpvError::SetPropertyError {
+                name: name.to_string(),
+                code: result.into(),
+                message: self.error_string(result),
+            })
+        }
+    }
+
+    fn set_property_string(&self, name: &str, value: &str) -> Result<(), MpvError> {
+        let set_property_string_fn: Symbol<
+            unsafe extern "C" fn(*mut c_void, *const c_char, *const c_char) -> c_int,
+        > = unsafe { self.library.get(b"mpv_set_property_string")? };
+
+        let name_cstring = CString::new(name)?;
+        let value_cstring = CString::new(value)?;
+
+        let result = unsafe {
+            set_property_string_fn(self.handle.0, name_cstring.as_ptr(), value_cstring.as_ptr())
+        };
+
+        if result == 0 {
+            Ok(())
+        } else {
+            Err(MpvError::SetPropertyError {
+                name: name.to_string(),
+                code: result.into(),
+                message: self.error_string(result),
+            })
+        }
+    }
+
+    fn get_property_node(&self, name: &str) -> Result<Value, MpvError> {
+        let get_property_node_fn: Symbol<
+            unsafe extern "C" fn(*mut c_void, *const c_char, c_int, *mut MpvNode) -> c_int,
+        > = unsafe { self.library.get(b"mpv_get_property")? };
+        let free_node_contents_fn: Symbol<unsafe extern "C" fn(*mut MpvNode)> =
+            unsafe { self.library.get(b"mpv_free_node_contents")? };
+
+        let name_cstring = CString::new(name)?;
+        let mut node = MpvNode {
+            u: MpvNodeValue { int64: 0 },
+            format: MpvFormat::None as c_int,
+        };
+
+        let result = unsafe {
+            get_property_node_fn(
+                self.handle.0,
+                name_cstring.as_ptr(),
+                MpvFormat::Node as c_int,
+                &mut node as *mut MpvNode,
+            )
+        };
+
+        if result < 0 {
+            return Err(self.get_property_error(name, result));
+        }
+
+        let value = unsafe { node.to_json() };
+        unsafe { free_node_contents_fn(&mut node as *mut MpvNode) };
+
+        Ok(value)
+    }
+
+    fn set_property_node(&self, name: &str, value: &Value) -> Result<(), MpvError> {
+        let set_property_node_fn: Symbol<
+            unsafe extern "C" fn(*mut c_void, *const c_char, c_int, *const MpvNode) -> c_int,
+        > = unsafe { self.library.get(b"mpv_set_property")? };
+
+        let name_cstring = CString::new(name)?;
+        let mut builder = NodeBuilder::default();
+        let node = builder.build(value)?;
+
+        let result = unsafe {
+            set_property_node_fn(
+                self.handle.0,
+                name_cstring.as_ptr(),
+                MpvFormat::Node as c_int,
+                &node as *const MpvNode,
+            )
+        };
+
+        if result < 0 {
+            Err(MpvError::SetPropertyError {
+                name: name.to_string(),
+                code: result.into(),
+                message: self.error_string(result),
+            })
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Runs a command given as separate arguments, so no quoting is needed.
+    fn command(&self, args: &[&str]) -> Result<(), MpvError> {
+        let command_fn: Symbol<unsafe extern "C" fn(*mut c_void, *mut *const c_char) -> c_int> =
+            unsafe { self.library.get(b"mpv_command")? };
+
+        let args_cstrings = args
+            .iter()
+            .map(|arg| CString::new(*arg))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut argv: Vec<*const c_char> = args_cstrings.iter().map(|arg| arg.as_ptr()).collect();
+        argv.push(std::ptr::null()); // NULL-terminated
+
+        let result = unsafe { command_fn(self.handle.0, argv.as_mut_ptr()) };
+
+        if result < 0 {
+            Err(MpvError::CommandError {
+                command: args.join(" "),
+                code: result.into(),
+                message: self.error_string(result),
+            })
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Asks mpv to send a `PropertyChange` event whenever `name` changes.
+    /// `reply_userdata` identifies the observation for `unobserve_property`.
+    fn observe_property(
+        &self,
+        name: &str,
+        format: MpvFormat,
+        reply_userdata: u64,
+    ) -> Result<(), MpvError> {
+        let observe_property_fn: Symbol<
+            unsafe extern "C" fn(*mut c_void, u64, *const c_char, c_int) -> c_int,
+        > = unsafe { self.library.get(b"mpv_observe_property")? };
+
+        let name_cstring = CString::new(name)?;
+
+        let result = unsafe {
+            observe_property_fn(
+                self.handle.0,
+                reply_userdata,
+                name_cstring.as_ptr(),
+                format as c_int,
+            )
+        };
+
+        if result < 0 {
+            Err(self.get_property_error(name, result))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Stops the observations made with `reply_userdata`.
+    fn unobserve_property(&self, name: &str, reply_userdata: u64) -> Result<(), MpvError> {
+        let unobserve_property_fn: Symbol<unsafe extern "C" fn(*mut c_void, u64) -> c_int> =
+            unsafe { self.library.get(b"mpv_unobserve_property")? };
+
+        let result = unsafe { unobserve_property_fn(self.handle.0, reply_userdata) };
+
+        if result < 0 {
+            Err(self.get_property_error(name, result))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn get_property_error(&self, name: &str, code: c_int) -> MpvError {
+        MpvError::GetPropertyError {
+            name: name.to_string(),
+            code: code.into(),
+            message: self.error_string(code),
+        }
+    }
+
+    /// Describes a libmpv error code with `mpv_error_string`.
+    fn error_string(&self, code: c_int) -> String {
+        let error_string_fn: Result<Symbol<unsafe extern "C" fn(c_int) -> *const c_char>, _> =
+            unsafe { self.library.get(b"mpv_error_string") };
+
+        match error_string_fn {
+            // The returned string is static; it must not be freed
+            Ok(error_string_fn) => {
+                let result = unsafe { error_string_fn(code) };
+                if result.is_null() {
+                    return format!("error {}", code);
+                }
+                unsafe { CStr::from_ptr(result) }
+                    .to_string_lossy()
+                    .into_owned()
+            }
+            Err(_) => format!("error {}", code),
         }
     }
 
@@ -313,10 +1031,7 @@ impl Mpv {
     ) -> Result<(), MpvError> {
         let event_id = event_id as c_int;
         let mut callbacks = self.event_callbacks.lock().unwrap();
-        callbacks
-            .entry(event_id)
-            .or_insert_with(Vec::new)
-            .push(callback);
+        callbacks.entry(event_id).or_default().push(callback);
         Ok(())
     }
 
@@ -333,15 +1048,26 @@ impl Mpv {
 
             let event = unsafe { &*event };
             if event.event_id == MpvEventId::None as c_int {
+                if self.stop_events.load(Ordering::SeqCst) {
+                    self.event_callbacks.lock().unwrap().clear();
+                    break;
+                }
                 continue;
             }
 
-            let callbacks = self.event_callbacks.lock().unwrap();
+            let mut callbacks = self.event_callbacks.lock().unwrap();
             if let Some(event_callbacks) = callbacks.get(&event.event_id) {
                 for callback in event_callbacks {
                     callback(event);
                 }
             }
+
+            // The core is gone; no more events will arrive. Dropping the callbacks
+            // releases whatever they hold (often the player itself).
+            if event.event_id == MpvEventId::Shutdown as c_int {
+                callbacks.clear();
+                break;
+            }
         }
 
         Ok(())
@@ -361,7 +1087,7 @@ impl Drop for Mpv {
 
 /// Track struct according to documentation at
 /// https://mpv.io/manual/stable/#command-interface-track-list
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Track {
     pub id: i64,
     #[serde(rename = "type")]
@@ -433,7 +1159,7 @@ pub struct Track {
 }
 
 /// The current tracks of the media being played.
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
 pub struct CurrentTracks {
     pub video: Option<Track>,
     pub audio: Option<Track>,
@@ -452,8 +1178,9 @@ pub struct PlaylistEntry {
     pub id: Option<i64>,
 }
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
 pub enum LoadMode {
+    #[default]
     Replace,
     Append,
     AppendPlay,
@@ -463,24 +1190,171 @@ pub enum LoadMode {
     InsertAtPlay(usize),
 }
 
-impl Default for LoadMode {
+/// A range of the demuxer cache that can be seeked into without reading from the network.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SeekableRange {
+    pub start: f64,
+    pub end: f64,
+}
+
+/// The `demuxer-cache-state` property according to documentation at
+/// https://mpv.io/manual/stable/#command-interface-demuxer-cache-state
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct DemuxerCacheState {
+    pub cache_end: Option<f64>,
+    pub reader_pts: Option<f64>,
+    pub cache_duration: Option<f64>,
+    #[serde(default)]
+    pub eof: bool,
+    #[serde(default)]
+    pub underrun: bool,
+    #[serde(default)]
+    pub idle: bool,
+    pub total_bytes: Option<i64>,
+    pub fw_bytes: Option<i64>,
+    pub file_cache_bytes: Option<i64>,
+    pub raw_input_rate: Option<i64>,
+    #[serde(default)]
+    pub seekable_ranges: Vec<SeekableRange>,
+}
+
+/// Buffering state of the current file; mostly of interest for network streams.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StreamStatus {
+    /// Playback is paused because the cache ran dry.
+    pub paused_for_cache: bool,
+    /// How full the cache is (0-100) while buffering (`cache-buffering-state`).
+    pub buffering_percent: Option<i64>,
+    pub seekable: bool,
+    /// Seconds of media buffered ahead of the playback position.
+    pub cache_duration: Option<f64>,
+    pub cache_state: Option<DemuxerCacheState>,
+}
+
+/// Per-load options for network streams. Unset fields keep mpv's global settings.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StreamOptions {
+    /// Force the cache on (or off) regardless of whether mpv thinks the source is a network stream.
+    pub cache: Option<bool>,
+    /// How many seconds of media the cache may hold (`cache-secs`).
+    pub cache_secs: Option<f64>,
+    /// Upper bound of the forward cache in bytes (`demuxer-max-bytes`).
+    pub cache_max_bytes: Option<u64>,
+    /// How far ahead to read (`demuxer-readahead-secs`).
+    pub readahead_secs: Option<f64>,
+    /// Seconds to wait for the server before giving up (`network-timeout`).
+    pub network_timeout_secs: Option<f64>,
+    pub user_agent: Option<String>,
+    /// Extra HTTP request headers, e.g. `Authorization`.
+    #[serde(default)]
+    pub http_headers: std::collections::BTreeMap<String, String>,
+}
+
+impl StreamOptions {
+    /// Converts the options into per-file options for `load_file_with_options`.
+    pub fn to_load_options(&self) -> Vec<(&'static str, String)> {
+        let mut options = Vec::new();
+
+        if let Some(cache) = self.cache {
+            options.push(("cache", if cache { "yes" } else { "no" }.to_string()));
+        }
+        if let Some(cache_secs) = self.cache_secs {
+            options.push(("cache-secs", cache_secs.to_string()));
+        }
+        if let Some(max_bytes) = self.cache_max_bytes {
+            options.push(("demuxer-max-bytes", max_bytes.to_string()));
+        }
+        if let Some(readahead_secs) = self.readahead_secs {
+            options.push(("demuxer-readahead-secs", readahead_secs.to_string()));
+        }
+        if let Some(timeout) = self.network_timeout_secs {
+            options.push(("network-timeout", timeout.to_string()));
+        }
+        if let Some(user_agent) = &self.user_agent {
+            options.push(("user-agent", user_agent.clone()));
+        }
+        // One option per header, so header values may contain commas
+        for (name, value) in &self.http_headers {
+            options.push(("http-header-fields-append", format!("{}: {}", name, value)));
+        }
+
+        options
+    }
+}
+
+/// Values of mpv's `gapless-audio` option.
+/// See https://mpv.io/manual/stable/#options-gapless-audio
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum GaplessMode {
+    /// Always gapless; later files are resampled to the first file's format.
+    Yes,
+    No,
+    /// Gapless only while the audio format stays the same.
+    Weak,
+}
+
+impl GaplessMode {
+    fn as_mpv_str(&self) -> &'static str {
+        match self {
+            GaplessMode::Yes => "yes",
+            GaplessMode::No => "no",
+            GaplessMode::Weak => "weak",
+        }
+    }
+
+    fn from_mpv_str(value: &str) -> Self {
+        match value {
+            "yes" => GaplessMode::Yes,
+            "no" => GaplessMode::No,
+            _ => GaplessMode::Weak,
+        }
+    }
+}
+
+/// Settings that control how smoothly playback continues from one playlist entry to the next.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlaybackContinuity {
+    pub gapless: GaplessMode,
+    /// Open and demux the next playlist entry before the current one ends.
+    pub prefetch_playlist: bool,
+    /// Seconds of demuxer readahead, which also bounds how much of the next entry is prefetched.
+    pub readahead_secs: f64,
+}
+
+impl Default for PlaybackContinuity {
     fn default() -> Self {
-        LoadMode::Replace
+        PlaybackContinuity {
+            gapless: GaplessMode::Weak,
+            prefetch_playlist: true,
+            readahead_secs: 10.0,
+        }
     }
 }
 
 pub struct MpvPlayer {
     mpv: Arc<Mpv>,
+    observed_properties: Mutex<HashSet<String>>,
+    /// Ids of the observations made by `observe`, which `unobserve` can undo.
+    /// Those of `on_property_change` use 0.
+    observers: Mutex<HashMap<String, u64>>,
+    next_observer_id: AtomicU64,
+    /// Disconnected once the event thread has ended.
+    events_done: Mutex<Receiver<()>>,
 }
 
 impl MpvPlayer {
     pub fn new(lib_path: &str) -> Result<Arc<Self>, MpvError> {
         let mpv = Arc::new(Mpv::new(lib_path)?);
-        let player = Arc::new(Self { mpv });
-
-        player.start_event_processing();
-
-        Ok(player)
+        let events_done = Self::start_event_processing(mpv.clone());
+
+        Ok(Arc::new(Self {
+            mpv,
+            observed_properties: Mutex::new(HashSet::new()),
+            observers: Mutex::new(HashMap::new()),
+            next_observer_id: AtomicU64::new(1),
+            events_done: Mutex::new(events_done),
+        }))
     }
 
     fn escape_path(path: &str) -> String {
@@ -493,6 +1367,11 @@ impl MpvPlayer {
         self.mpv.destroy()
     }
 
+    /// Sets an option before `initialize` (e.g. `vid`, `ao`, `vo`).
+    pub fn set_option(&self, name: &str, value: &str) -> Result<(), MpvError> {
+        self.mpv.set_option(name, value)
+    }
+
     pub fn attach_to_window(&self, wid: usize) -> Result<(), MpvError> {
         self.mpv.set_option("wid", &wid.to_string())
     }
@@ -502,28 +1381,80 @@ impl MpvPlayer {
     }
 
     pub fn load_file(&self, path: &str, mode: Option<LoadMode>) -> Result<(), MpvError> {
+        self.load_file_with_options(path, mode, &[])
+    }
+
+    /// Loads a file with per-file options (e.g. `start`, `end`) that only apply
+    /// while this playlist entry is playing.
+    ///
+    /// See https://mpv.io/manual/stable/#command-interface-loadfile
+    pub fn load_file_with_options(
+        &self,
+        path: &str,
+        mode: Option<LoadMode>,
+        options: &[(&str, String)],
+    ) -> Result<(), MpvError> {
         let escaped_path = Self::escape_path(path);
         // println!("Loading file: {}", path);
         // println!("Loading file(escaped): {}", escaped_path);
 
-        let command = match mode.unwrap_or_default() {
-            LoadMode::Replace => format!("loadfile \"{}\"", escaped_path),
-            LoadMode::Append => format!("loadfile \"{}\" append", escaped_path),
-            LoadMode::AppendPlay => format!("loadfile \"{}\" append-play", escaped_path),
-            LoadMode::InsertNext => format!("loadfile \"{}\" insert-next", escaped_path),
-            LoadMode::InsertNextPlay => format!("loadfile \"{}\" insert-next-play", escaped_path),
+        // mpv before 0.38 has no index argument, so it is only passed for
+        // the modes that need one
+        let (flag, index) = match mode.unwrap_or_default() {
+            LoadMode::Replace => ("replace", None),
+            LoadMode::Append => ("append", None),
+            LoadMode::AppendPlay => ("append-play", None),
+            LoadMode::InsertNext => ("insert-next", None),
+            LoadMode::InsertNextPlay => ("insert-next-play", None),
             LoadMode::InsertAt(index) => {
                 // println!("Inserting at index: {}", index);
-                format!("loadfile \"{}\" insert-at {}", escaped_path, index)
-            }
-            LoadMode::InsertAtPlay(index) => {
-                format!("loadfile \"{}\" insert-at-play {}", escaped_path, index)
+                ("insert-at", Some(index as i64))
             }
+            LoadMode::InsertAtPlay(index) => ("insert-at-play", Some(index as i64)),
         };
 
+        let mut command = format!("loadfile \"{}\" {}", escaped_path, flag);
+        if let Some(index) = index {
+            command.push_str(&format!(" {}", index));
+        }
+        if !options.is_empty() {
+            let options = options
+                .iter()
+                .map(|(name, value)| format!("{}={}", name, Self::quote_option_value(value)))
+                .collect::<Vec<_>>()
+                .join(",");
+            command.push_str(&format!(" \"{}\"", Self::escape_path(&options)));
+        }
+
         self.mpv.command_string(&command)
     }
 
+    /// Loads a time range of a file as its own playlist entry. Playback ends
+    /// (and `EndFile` fires) at `end`, which is how CUE sheet tracks are played.
+    pub fn load_segment(
+        &self,
+        path: &str,
+        start: f64,
+        end: Option<f64>,
+        mode: Option<LoadMode>,
+    ) -> Result<(), MpvError> {
+        let mut options = vec![("start", format!("{:.3}", start))];
+        if let Some(end) = end {
+            options.push(("end", format!("{:.3}", end)));
+        }
+        self.load_file_with_options(path, mode, &options)
+    }
+
+    /// Quotes an option value with mpv's `%len%value` syntax when it contains
+    /// characters that would otherwise split the option list.
+    fn quote_option_value(value: &str) -> String {
+        if value.contains([',', '=', '%', '"', '\'', '[', ']']) {
+            format!("%{}%{}", value.len(), value)
+        } else {
+            value.to_string()
+        }
+    }
+
     pub fn play(&self) -> Result<(), MpvError> {
         self.mpv.command_string("set pause no")
     }
@@ -541,6 +1472,31 @@ impl MpvPlayer {
         self.mpv.command_string("stop")
     }
 
+    /// Shuts the core down. The event thread ends after the `Shutdown` event and
+    /// the handle is destroyed once the last reference to the player is dropped.
+    pub fn quit(&self) -> Result<(), MpvError> {
+        self.mpv.command_string("quit")
+    }
+
+    /// Waits for the event thread to end, which it does after the `Shutdown`
+    /// event. Returns `false` on timeout.
+    pub fn wait_for_shutdown(&self, timeout: Duration) -> bool {
+        !matches!(
+            self.events_done.lock().unwrap().recv_timeout(timeout),
+            Err(RecvTimeoutError::Timeout)
+        )
+    }
+
+    /// Ends the event thread of a core that won't shut down by itself, e.g. one
+    /// that failed to initialize, and drops its callbacks.
+    pub fn stop_event_processing(&self, timeout: Duration) -> bool {
+        self.mpv.stop_events.store(true, Ordering::SeqCst);
+        if let Err(e) = self.mpv.wakeup() {
+            eprintln!("Failed to wake up mpv: {}", e);
+        }
+        self.wait_for_shutdown(timeout)
+    }
+
     // BEYOND THIS IS UNTESTED
 
     pub fn get_position(&self) -> Result<f64, MpvError> {
@@ -571,6 +1527,53 @@ impl MpvPlayer {
         self.mpv.get_property_bool("pause")
     }
 
+    pub fn is_muted(&self) -> Result<bool, MpvError> {
+        self.mpv.get_property_bool("mute")
+    }
+
+    /// Whether no file is loaded (nothing loaded yet, or playback has ended).
+    pub fn is_idle(&self) -> Result<bool, MpvError> {
+        self.mpv.get_property_bool("idle-active")
+    }
+
+    pub fn get_demuxer_cache_state(&self) -> Result<DemuxerCacheState, MpvError> {
+        let cache_state_json = self.mpv.get_property_string("demuxer-cache-state")?;
+        serde_json::from_str(&cache_state_json).map_err(|e| {
+            MpvError::property_format(
+                "demuxer-cache-state",
+                format!("Failed to parse: {}. JSON: {}", e, cache_state_json),
+            )
+        })
+    }
+
+    pub fn is_paused_for_cache(&self) -> Result<bool, MpvError> {
+        self.mpv.get_property_bool("paused-for-cache")
+    }
+
+    pub fn get_cache_buffering_state(&self) -> Result<i64, MpvError> {
+        self.mpv.get_property_int("cache-buffering-state")
+    }
+
+    pub fn is_seekable(&self) -> Result<bool, MpvError> {
+        self.mpv.get_property_bool("seekable")
+    }
+
+    pub fn get_demuxer_cache_duration(&self) -> Result<f64, MpvError> {
+        self.mpv.get_property_double("demuxer-cache-duration")
+    }
+
+    /// Collects the buffering related properties. Those that are unavailable
+    /// (e.g. while idle or for local files without a cache) are left empty.
+    pub fn get_stream_status(&self) -> StreamStatus {
+        StreamStatus {
+            paused_for_cache: self.is_paused_for_cache().unwrap_or(false),
+            buffering_percent: self.get_cache_buffering_state().ok(),
+            seekable: self.is_seekable().unwrap_or(false),
+            cache_duration: self.get_demuxer_cache_duration().ok(),
+            cache_state: self.get_demuxer_cache_state().ok(),
+        }
+    }
+
     pub fn get_chapter(&self) -> Result<i64, MpvError> {
         self.mpv.get_property_double("chapter").map(|ch| ch as i64)
     }
@@ -579,6 +1582,31 @@ impl MpvPlayer {
         self.mpv.get_property_double("chapters").map(|ch| ch as i64)
     }
 
+    pub fn get_playback_continuity(&self) -> Result<PlaybackContinuity, MpvError> {
+        Ok(PlaybackContinuity {
+            gapless: GaplessMode::from_mpv_str(&self.mpv.get_property_string("gapless-audio")?),
+            prefetch_playlist: self.mpv.get_property_bool("prefetch-playlist")?,
+            readahead_secs: self.mpv.get_property_double("demuxer-readahead-secs")?,
+        })
+    }
+
+    pub fn set_playback_continuity(&self, continuity: &PlaybackContinuity) -> Result<(), MpvError> {
+        self.mpv
+            .set_property_string("gapless-audio", continuity.gapless.as_mpv_str())?;
+        self.mpv.set_property_string(
+            "prefetch-playlist",
+            if continuity.prefetch_playlist {
+                "yes"
+            } else {
+                "no"
+            },
+        )?;
+        self.mpv.set_property_string(
+            "demuxer-readahead-secs",
+            &continuity.readahead_secs.to_string(),
+        )
+    }
+
     pub fn disable_osd(&self) -> Result<(), MpvError> {
         self.mpv.command_string("set osd-level 0")
     }
@@ -586,10 +1614,10 @@ impl MpvPlayer {
     pub fn get_tracks(&self) -> Result<Vec<Track>, MpvError> {
         let tracks_json = self.mpv.get_property_string("track-list")?;
         let tracks: Vec<Track> = serde_json::from_str(&tracks_json).map_err(|e| {
-            MpvError::GetPropertyError(format!(
-                "Failed to parse track-list: {}. JSON: {}",
-                e, tracks_json
-            ))
+            MpvError::property_format(
+                "track-list",
+                format!("Failed to parse: {}. JSON: {}", e, tracks_json),
+            )
         })?;
         Ok(tracks)
     }
@@ -664,10 +1692,10 @@ impl MpvPlayer {
     pub fn get_playlist(&self) -> Result<Vec<PlaylistEntry>, MpvError> {
         let playlist_json = self.mpv.get_property_string("playlist")?;
         let playlist: Vec<PlaylistEntry> = serde_json::from_str(&playlist_json).map_err(|e| {
-            MpvError::GetPropertyError(format!(
-                "Failed to parse playlist: {}. JSON: {}",
-                e, playlist_json
-            ))
+            MpvError::property_format(
+                "playlist",
+                format!("Failed to parse: {}. JSON: {}", e, playlist_json),
+            )
         })?;
         Ok(playlist)
     }
@@ -676,6 +1704,24 @@ impl MpvPlayer {
         self.mpv.get_property_int("playlist-pos")
     }
 
+    pub fn get_playlist_count(&self) -> Result<i64, MpvError> {
+        self.mpv.get_property_int("playlist-count")
+    }
+
+    /// Reads any property as JSON, in mpv's native type (lists and maps included).
+    pub fn get_property_json(&self, name: &str) -> Result<Value, MpvError> {
+        self.mpv.get_property_node(name)
+    }
+
+    pub fn set_property_json(&self, name: &str, value: &Value) -> Result<(), MpvError> {
+        self.mpv.set_property_node(name, value)
+    }
+
+    /// Runs a command given as separate arguments, e.g. `["seek", "10", "relative"]`.
+    pub fn command(&self, args: &[&str]) -> Result<(), MpvError> {
+        self.mpv.command(args)
+    }
+
     pub fn set_playlist_pos(&self, pos: i64) -> Result<(), MpvError> {
         self.mpv.set_property_int("playlist-pos", pos)
     }
@@ -685,7 +1731,7 @@ impl MpvPlayer {
 
         for path in paths {
             let path_str = path.as_ref().to_str().ok_or_else(|| {
-                MpvError::CommandError("Failed to convert path to string".to_string())
+                MpvError::invalid_argument("loadfile", "Failed to convert path to string")
             })?;
             let escaped_path = Self::escape_path(path_str);
             self.mpv
@@ -739,12 +1785,162 @@ impl MpvPlayer {
             .register_event_callback(event_id, Box::new(callback))
     }
 
+    /// Makes mpv send `PropertyChange` events for `name`, for code that handles
+    /// those events itself. Does nothing if the property is already observed;
+    /// otherwise it is observed as a string, which later `on_property_change`
+    /// subscribers will then get too.
+    pub fn observe(&self, name: &str) -> Result<(), MpvError> {
+        let mut observed = self.observed_properties.lock().unwrap();
+        if observed.contains(name) {
+            return Ok(());
+        }
+
+        let id = self.next_observer_id.fetch_add(1, Ordering::Relaxed);
+        self.mpv.observe_property(name, MpvFormat::String, id)?;
+        observed.insert(name.to_string());
+        self.observers.lock().unwrap().insert(name.to_string(), id);
+        Ok(())
+    }
+
+    /// Undoes `observe`. Properties that `on_property_change` subscribers need
+    /// stay observed.
+    pub fn unobserve(&self, name: &str) -> Result<(), MpvError> {
+        let mut observed = self.observed_properties.lock().unwrap();
+        let Some(id) = self.observers.lock().unwrap().remove(name) else {
+            return Ok(());
+        };
+        observed.remove(name);
+        self.mpv.unobserve_property(name, id)
+    }
+
+    /// Observes `name` and calls `callback` with its value whenever it changes.
+    ///
+    /// A property is only observed once per player, in the format of the first
+    /// call, so every subscriber of a property must use the same format.
+    pub fn on_property_change(
+        &self,
+        name: &str,
+        format: MpvFormat,
+        callback: impl Fn(&PropertyValue) + Send + 'static,
+    ) -> Result<(), MpvError> {
+        let property_name = name.to_string();
+        self.register_event_callback(MpvEventId::PropertyChange, move |event| {
+            if let Some((name, value)) = event.property_change() {
+                if name == property_name {
+                    callback(&value);
+                }
+            }
+        })?;
+
+        let mut observed = self.observed_properties.lock().unwrap();
+        if observed.insert(name.to_string()) {
+            self.mpv.observe_property(name, format, 0)?;
+        } else {
+            // Keep it observed for this subscriber, even if `unobserve` is called
+            self.observers.lock().unwrap().remove(name);
+        }
+        Ok(())
+    }
+
     /// Starts an event processing thread. This is necessary to receive events
     /// from libmpv without blocking the current thread.
-    fn start_event_processing(&self) {
-        let mpv = self.mpv.clone();
+    fn start_event_processing(mpv: Arc<Mpv>) -> Receiver<()> {
+        let (done, events_done) = mpsc::channel();
         thread::spawn(move || {
             let _ = mpv.process_events();
+            drop(done);
         });
+        events_done
+    }
+}
+
+#[cfg(test)]
+pub mod test_support {
+    use super::{MpvError, MpvPlayer};
+    use std::f64::consts::TAU;
+    use std::sync::Arc;
+
+    pub const SINE_FREQUENCY: f64 = 441.0;
+    pub const SINE_AMPLITUDE: f64 = 16384.0;
+
+    /// The libmpv to test against: `MPV_TEST_LIB`, or the platform's library name.
+    pub fn lib_path() -> String {
+        std::env::var("MPV_TEST_LIB").unwrap_or_else(|_| {
+            if cfg!(windows) {
+                "./lib/mpv/libmpv-2.dll".to_string()
+            } else if cfg!(target_os = "macos") {
+                "libmpv.2.dylib".to_string()
+            } else {
+                "libmpv.so.2".to_string()
+            }
+        })
+    }
+
+    /// A new core, or `None` if libmpv can't be loaded, in which case the test
+    /// returns early.
+    pub fn player() -> Option<Arc<MpvPlayer>> {
+        match MpvPlayer::new(&lib_path()) {
+            Ok(player) => Some(player),
+            Err(MpvError::LibraryError(e)) => {
+                eprintln!("Skipping, libmpv is not available: {}", e);
+                None
+            }
+            Err(e) => panic!("Failed to create mpv core: {}", e),
+        }
+    }
+
+    /// Samples `offset..offset + count` of one continuous sine wave as a 16-bit
+    /// mono WAV file, so consecutive fixtures join without a jump.
+    pub fn sine_wav(sample_rate: u32, offset: usize, count: usize) -> Vec<u8> {
+        let data: Vec<u8> = (offset..offset + count)
+            .map(|i| {
+                let t = i as f64 / sample_rate as f64;
+                (SINE_AMPLITUDE * (TAU * SINE_FREQUENCY * t).sin()) as i16
+            })
+            .flat_map(i16::to_le_bytes)
+            .collect();
+
+        let mut wav = Vec::with_capacity(44 + data.len());
+        wav.extend(b"RIFF");
+        wav.extend((36 + data.len() as u32).to_le_bytes());
+        wav.extend(b"WAVEfmt ");
+        wav.extend(16u32.to_le_bytes());
+        wav.extend(1u16.to_le_bytes()); // PCM
+        wav.extend(1u16.to_le_bytes()); // mono
+        wav.extend(sample_rate.to_le_bytes());
+        wav.extend((sample_rate * 2).to_le_bytes());
+        wav.extend(2u16.to_le_bytes());
+        wav.extend(16u16.to_le_bytes());
+        wav.extend(b"data");
+        wav.extend((data.len() as u32).to_le_bytes());
+        wav.extend(data);
+        wav
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stops_the_event_thread_of_a_core_that_never_started() {
+        let Some(player) = test_support::player() else {
+            return;
+        };
+        assert!(!player.wait_for_shutdown(Duration::ZERO));
+        assert!(player.stop_event_processing(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn ends_the_event_thread_after_quitting() {
+        let Some(player) = test_support::player() else {
+            return;
+        };
+        player.set_option("vo", "null").unwrap();
+        player.set_option("ao", "null").unwrap();
+        player.initialize().unwrap();
+
+        player.quit().unwrap();
+        assert!(player.wait_for_shutdown(Duration::from_secs(5)));
     }
 }
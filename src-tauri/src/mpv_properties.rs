@@ -0,0 +1,395 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashSet};
+use std::path::Path;
+
+use crate::mpv::{MpvError, MpvErrorCode, MpvPlayer};
+
+/// Name of the file in the app config directory that replaces the default allowlist.
+pub const ALLOWLIST_FILE_NAME: &str = "mpv-allowlist.json";
+
+const DEFAULT_PROPERTIES: &[&str] = &[
+    "aid",
+    "audio-delay",
+    "audio-device",
+    "audio-device-list",
+    "audio-params",
+    "ab-loop-a",
+    "ab-loop-b",
+    "brightness",
+    "cache-buffering-state",
+    "chapter",
+    "chapter-list",
+    "chapters",
+    "container-fps",
+    "contrast",
+    "demuxer-cache-duration",
+    "demuxer-cache-state",
+    "duration",
+    "dwidth",
+    "dheight",
+    "eof-reached",
+    "estimated-vf-fps",
+    "file-size",
+    "filename",
+    "filtered-metadata",
+    "gamma",
+    "gapless-audio",
+    "height",
+    "hue",
+    "hwdec",
+    "hwdec-current",
+    "idle-active",
+    "loop-file",
+    "loop-playlist",
+    "media-title",
+    "metadata",
+    "mute",
+    "osd-level",
+    "panscan",
+    "path",
+    "pause",
+    "paused-for-cache",
+    "percent-pos",
+    "playlist",
+    "playlist-count",
+    "playlist-pos",
+    "prefetch-playlist",
+    "replaygain",
+    "saturation",
+    "secondary-sid",
+    "seekable",
+    "seeking",
+    "sid",
+    "speed",
+    "sub-delay",
+    "sub-font-size",
+    "sub-pos",
+    "sub-scale",
+    "sub-visibility",
+    "time-pos",
+    "time-remaining",
+    "track-list",
+    "vid",
+    "video-aspect-override",
+    "video-params",
+    "video-pan-x",
+    "video-pan-y",
+    "video-zoom",
+    "volume",
+    "volume-max",
+    "width",
+];
+
+const DEFAULT_COMMANDS: &[&str] = &[
+    "ab-loop",
+    "add",
+    "audio-reload",
+    "cycle",
+    "cycle-values",
+    "frame-back-step",
+    "frame-step",
+    "multiply",
+    "playlist-move",
+    "playlist-next",
+    "playlist-prev",
+    "playlist-remove",
+    "playlist-shuffle",
+    "playlist-unshuffle",
+    "revert-seek",
+    "seek",
+    "set",
+    "show-text",
+    "stop",
+    "sub-reload",
+    "sub-seek",
+    "sub-step",
+];
+
+/// Options that may be set before initialization through `mpv_recreate`.
+const DEFAULT_OPTIONS: &[&str] = &[
+    "audio-channels",
+    "audio-device",
+    "audio-exclusive",
+    "audio-spdif",
+    "cache",
+    "cache-secs",
+    "demuxer-max-back-bytes",
+    "demuxer-max-bytes",
+    "demuxer-readahead-secs",
+    "gapless-audio",
+    "gpu-api",
+    "gpu-context",
+    "hwdec",
+    "keep-open",
+    "prefetch-playlist",
+    "replaygain",
+    "volume-max",
+];
+
+/// Never allowed, whatever the allowlist file says: these load code, open IPC
+/// endpoints, write files or give access to arbitrary options.
+const ALWAYS_DENIED: &[&str] = &[
+    "ao-pcm-file",
+    "config",
+    "config-dir",
+    "delete-watch-later-config",
+    "dump-stats",
+    "file-local-options",
+    "include",
+    "input-conf",
+    "input-ipc-client",
+    "input-ipc-server",
+    "load-script",
+    "load-scripts",
+    "log-file",
+    "o",
+    "option-info",
+    "options",
+    "profile",
+    "quit",
+    "quit-watch-later",
+    "record-file",
+    "run",
+    "screenshot-directory",
+    "screenshot-template",
+    "script",
+    "script-opts",
+    "scripts",
+    "stream-dump",
+    "stream-record",
+    "subprocess",
+    "use-filedir-conf",
+    "vo-image-outdir",
+    "watch-later-directory",
+    "wid",
+    "write-watch-later-config",
+];
+
+/// Commands that change the property named by their argument, which has to be
+/// allowed too.
+const PROPERTY_COMMANDS: &[&str] = &["add", "cycle", "cycle-values", "multiply", "set"];
+
+/// The properties and commands the webview may use through the generic
+/// `mpv_get_properties`, `mpv_set_properties` and `mpv_batch` commands, and
+/// the options it may pass to `mpv_recreate`.
+///
+/// Properties are matched by their first path component, so allowing
+/// `metadata` also allows `metadata/by-key/Artist`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MpvAllowlist {
+    pub properties: HashSet<String>,
+    pub commands: HashSet<String>,
+    pub options: HashSet<String>,
+}
+
+impl Default for MpvAllowlist {
+    fn default() -> Self {
+        MpvAllowlist {
+            properties: DEFAULT_PROPERTIES.iter().map(|p| p.to_string()).collect(),
+            commands: DEFAULT_COMMANDS.iter().map(|c| c.to_string()).collect(),
+            options: DEFAULT_OPTIONS.iter().map(|o| o.to_string()).collect(),
+        }
+    }
+}
+
+impl MpvAllowlist {
+    /// Reads the allowlist from `path`, or uses the defaults if there is no such file.
+    pub fn load(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let json = std::fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&json)?)
+    }
+
+    pub fn check_property(&self, name: &str) -> Result<(), MpvError> {
+        let base = name.split('/').next().unwrap_or(name);
+        if ALWAYS_DENIED.contains(&base) || !self.properties.contains(base) {
+            return Err(MpvError::NotAllowedError {
+                name: name.to_string(),
+            });
+        }
+        Ok(())
+    }
+
+    pub fn check_option(&self, name: &str) -> Result<(), MpvError> {
+        if ALWAYS_DENIED.contains(&name) || !self.options.contains(name) {
+            return Err(MpvError::NotAllowedError {
+                name: name.to_string(),
+            });
+        }
+        Ok(())
+    }
+
+    pub fn check_command(&self, args: &[String]) -> Result<(), MpvError> {
+        let Some(name) = args.first() else {
+            return Err(MpvError::invalid_argument("", "Empty command"));
+        };
+        if ALWAYS_DENIED.contains(&name.as_str()) || !self.commands.contains(name) {
+            return Err(MpvError::NotAllowedError { name: name.clone() });
+        }
+
+        if PROPERTY_COMMANDS.contains(&name.as_str()) {
+            // `cycle-values` takes an optional `!reverse` before the property
+            let property = args[1..].iter().find(|arg| arg.as_str() != "!reverse");
+            match property {
+                Some(property) => self.check_property(property)?,
+                None => {
+                    return Err(MpvError::invalid_argument(
+                        name.clone(),
+                        "Missing property name",
+                    ))
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reads `names` in one go. Properties that are currently unavailable (e.g.
+/// `duration` while idle) are `null`.
+pub fn get_properties(
+    player: &MpvPlayer,
+    allowlist: &MpvAllowlist,
+    names: &[String],
+) -> Result<BTreeMap<String, Value>, MpvError> {
+    for name in names {
+        allowlist.check_property(name)?;
+    }
+
+    names
+        .iter()
+        .map(|name| Ok((name.clone(), get_property(player, name)?)))
+        .collect()
+}
+
+/// Sets every property in `values`. Nothing is set if any of them isn't allowed;
+/// otherwise they are set in order until one fails.
+pub fn set_properties(
+    player: &MpvPlayer,
+    allowlist: &MpvAllowlist,
+    values: &BTreeMap<String, Value>,
+) -> Result<(), MpvError> {
+    for name in values.keys() {
+        allowlist.check_property(name)?;
+    }
+
+    for (name, value) in values {
+        player.set_property_json(name, value)?;
+    }
+    Ok(())
+}
+
+fn get_property(player: &MpvPlayer, name: &str) -> Result<Value, MpvError> {
+    match player.get_property_json(name) {
+        Err(e) if e.code() == Some(MpvErrorCode::PropertyUnavailable) => Ok(Value::Null),
+        result => result,
+    }
+}
+
+/// One operation of `mpv_batch`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum BatchOp {
+    Get {
+        name: String,
+    },
+    Set {
+        name: String,
+        value: Value,
+    },
+    /// Arguments may be strings, numbers or booleans.
+    Command {
+        args: Vec<Value>,
+    },
+}
+
+/// Outcome of one `BatchOp`: the value read for `get` (`null` otherwise), or the error.
+#[derive(Debug, Serialize)]
+pub struct BatchResult {
+    pub value: Value,
+    pub error: Option<MpvError>,
+}
+
+/// Runs `ops` in order. A failed operation doesn't stop the ones after it.
+pub fn run_batch(
+    player: &MpvPlayer,
+    allowlist: &MpvAllowlist,
+    ops: &[BatchOp],
+) -> Vec<BatchResult> {
+    ops.iter()
+        .map(|op| {
+            let result = match op {
+                BatchOp::Get { name } => allowlist
+                    .check_property(name)
+                    .and_then(|_| get_property(player, name)),
+                BatchOp::Set { name, value } => allowlist
+                    .check_property(name)
+                    .and_then(|_| player.set_property_json(name, value))
+                    .map(|_| Value::Null),
+                BatchOp::Command { args } => {
+                    let args: Vec<String> = args
+                        .iter()
+                        .map(|arg| match arg {
+                            Value::String(s) => s.clone(),
+                            other => other.to_string(),
+                        })
+                        .collect();
+                    allowlist.check_command(&args).and_then(|_| {
+                        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+                        player.command(&args).map(|_| Value::Null)
+                    })
+                }
+            };
+
+            match result {
+                Ok(value) => BatchResult { value, error: None },
+                Err(e) => BatchResult {
+                    value: Value::Null,
+                    error: Some(e),
+                },
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allows_listed_options() {
+        let allowlist = MpvAllowlist::default();
+        assert!(allowlist.check_option("hwdec").is_ok());
+        assert!(matches!(
+            allowlist.check_option("vo"),
+            Err(MpvError::NotAllowedError { .. })
+        ));
+    }
+
+    #[test]
+    fn denies_dangerous_options_even_if_listed() {
+        let allowlist: MpvAllowlist =
+            serde_json::from_str(r#"{ "options": ["hwdec", "input-ipc-server", "script"] }"#)
+                .unwrap();
+        assert!(allowlist.check_option("hwdec").is_ok());
+        assert!(allowlist.check_option("input-ipc-server").is_err());
+        assert!(allowlist.check_option("script").is_err());
+        // Sections missing from the file keep their defaults
+        assert!(allowlist.check_property("pause").is_ok());
+    }
+
+    #[test]
+    fn checks_the_property_of_property_commands() {
+        let allowlist = MpvAllowlist::default();
+        let command = |args: &[&str]| {
+            allowlist.check_command(&args.iter().map(|a| a.to_string()).collect::<Vec<_>>())
+        };
+        assert!(command(&["cycle-values", "!reverse", "pause", "yes", "no"]).is_ok());
+        assert!(command(&["set", "input-ipc-server", "/tmp/socket"]).is_err());
+        assert!(command(&["set"]).is_err());
+        assert!(command(&["run", "sh"]).is_err());
+    }
+}
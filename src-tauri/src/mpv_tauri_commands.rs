@@ -1,53 +1,34 @@
+use crate::gapless::{self, GaplessBreak};
+use crate::metadata;
 use crate::mpv::{self};
+use crate::mpv_properties::{self, BatchOp, BatchResult, MpvAllowlist};
+use crate::player_handle::PlayerHandle;
+use crate::player_state::{PlayerState, PlayerStateTracker};
 
 use mpv::*;
 use once_cell::sync::Lazy;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
 use std::sync::{Arc, Mutex};
-use winapi::shared::windef::HWND;
+use tauri::State;
 
 #[derive(Clone, serde::Serialize)]
 struct MpvEventPayload {
     event_id: u32,
 }
 
-// Global instance of mpv
-static MPV_PLAYER: Lazy<Mutex<Arc<MpvPlayer>>> =
-    Lazy::new(|| Mutex::new(MpvPlayer::new("./lib/mpv/libmpv-2.dll").unwrap()));
+pub const MPV_LIB_PATH: &str = "./lib/mpv/libmpv-2.dll";
 
-pub fn init_mpv(win_to_attach_to: HWND) {
-    let player = MPV_PLAYER.lock().unwrap();
-
-    player
-        .attach_to_window(win_to_attach_to as usize)
-        .expect("Failed to attach to window");
-    player.initialize().expect("Failed to initialize MPV");
-
-    // player.load_file("E:/Users/Administrator/Downloads/test.mkv").expect("Failed to load file");
-
-    // thread::sleep(std::time::Duration::from_millis(1000));
-
-    // match player.get_current_tracks() {
-    //     Ok(tracks) => {
-    //         println!("Tracks: {:?}", tracks);
-    //     }
-    //     Err(e) => {
-    //         println!("Failed to get tracks: {:?}", e);
-    //     }
-    // }
-
-    // player
-    //     .register_event_callback(MpvEventId::FileLoaded, |event| {
-    //         println!("Received event: FileLoaded");
-    //     })
-    //     .expect("Failed to register event callback");
-
-    #[cfg(not(dev))]
-    player.disable_osd().expect("Failed to disable OSD");
-}
+// Where `mpv-event`s go. The frontend registers again whenever it reloads, but
+// the hook forwarding them is only added once.
+static EVENTS_WINDOW: Lazy<Mutex<Option<tauri::Window>>> = Lazy::new(|| Mutex::new(None));
 
 #[tauri::command]
-pub fn mpv_register_events_callback(window: tauri::Window) {
-    let player = MPV_PLAYER.lock().unwrap();
+pub fn mpv_register_events_callback(handle: State<'_, PlayerHandle>, window: tauri::Window) {
+    if EVENTS_WINDOW.lock().unwrap().replace(window).is_some() {
+        return;
+    }
 
     let events = [
         MpvEventId::None,
@@ -70,19 +51,29 @@ pub fn mpv_register_events_callback(window: tauri::Window) {
     ]; // register all the events we want
 
     // HACK: this is kinda a mess and should be refactored but i don't have time right now
-    for event in events.iter() {
-        let event_window = window.clone();
-        let event_id = event.clone(); // Clone the event_id here
-        match player.register_event_callback(event_id.clone(), move |_| {
-            emit_event(event_window.clone(), event_id.clone()); // Clone again here if necessary
-        }) {
-            Ok(_) => (),
-            Err(e) => eprintln!("Failed to register event callback: {}", e), // TODO: Handle error properly
-        };
+    // Registered again on every new core
+    let result = handle.on_create(move |player| {
+        for event in events.iter() {
+            let event_id = event.clone(); // Clone the event_id here
+            match player.register_event_callback(event_id.clone(), move |_| {
+                emit_event(event_id.clone()); // Clone again here if necessary
+            }) {
+                Ok(_) => (),
+                Err(e) => eprintln!("Failed to register event callback: {}", e), // TODO: Handle error properly
+            };
+        }
+        Ok(())
+    });
+
+    if let Err(e) = result {
+        eprintln!("Failed to register event callback: {}", e);
     }
 }
 
-fn emit_event(window: tauri::Window, event_id: MpvEventId) {
+fn emit_event(event_id: MpvEventId) {
+    let Some(window) = EVENTS_WINDOW.lock().unwrap().clone() else {
+        return;
+    };
     window
         .emit(
             "mpv-event",
@@ -94,137 +85,327 @@ fn emit_event(window: tauri::Window, event_id: MpvEventId) {
 }
 
 #[tauri::command]
-pub fn mpv_get_duration() -> Result<f64, MpvError> {
-    let player = MPV_PLAYER.lock().unwrap();
+pub fn mpv_get_duration(handle: State<'_, PlayerHandle>) -> Result<f64, MpvError> {
+    let player = handle.get()?;
     player.get_duration()
 }
 
 #[tauri::command]
-pub fn mpv_get_position() -> Result<f64, MpvError> {
-    let player = MPV_PLAYER.lock().unwrap();
+pub fn mpv_get_position(handle: State<'_, PlayerHandle>) -> Result<f64, MpvError> {
+    let player = handle.get()?;
     player.get_position()
 }
 
 #[tauri::command]
-pub fn mpv_seek(position: f64) -> Result<(), MpvError> {
-    let player = MPV_PLAYER.lock().unwrap();
+pub fn mpv_seek(handle: State<'_, PlayerHandle>, position: f64) -> Result<(), MpvError> {
+    let player = handle.get()?;
     player.seek(position)
 }
 
 #[tauri::command]
-pub fn mpv_get_volume() -> Result<f64, MpvError> {
-    let player = MPV_PLAYER.lock().unwrap();
+pub fn mpv_get_volume(handle: State<'_, PlayerHandle>) -> Result<f64, MpvError> {
+    let player = handle.get()?;
     player.get_volume()
 }
 
 #[tauri::command]
-pub fn mpv_set_volume(volume: f64) -> Result<(), MpvError> {
-    let player = MPV_PLAYER.lock().unwrap();
+pub fn mpv_set_volume(handle: State<'_, PlayerHandle>, volume: f64) -> Result<(), MpvError> {
+    let player = handle.get()?;
     player.set_volume(volume)
 }
 
 #[tauri::command]
-pub fn mpv_is_paused() -> Result<bool, MpvError> {
-    let player = MPV_PLAYER.lock().unwrap();
+pub fn mpv_is_paused(handle: State<'_, PlayerHandle>) -> Result<bool, MpvError> {
+    let player = handle.get()?;
     player.is_paused()
 }
 
 #[tauri::command]
-pub fn mpv_play() -> Result<(), MpvError> {
-    let player = MPV_PLAYER.lock().unwrap();
+pub fn mpv_play(handle: State<'_, PlayerHandle>) -> Result<(), MpvError> {
+    let player = handle.get()?;
     player.play()
 }
 
 #[tauri::command]
-pub fn mpv_pause() -> Result<(), MpvError> {
-    let player = MPV_PLAYER.lock().unwrap();
+pub fn mpv_pause(handle: State<'_, PlayerHandle>) -> Result<(), MpvError> {
+    let player = handle.get()?;
     player.pause()
 }
 
 #[tauri::command]
-pub fn mpv_stop() -> Result<(), MpvError> {
-    let player = MPV_PLAYER.lock().unwrap();
+pub fn mpv_stop(handle: State<'_, PlayerHandle>) -> Result<(), MpvError> {
+    let player = handle.get()?;
     player.stop()
 }
 
 #[tauri::command]
-pub fn mpv_load_file(path: &str, mode: Option<LoadMode>) -> Result<(), MpvError> {
-    let player = MPV_PLAYER.lock().unwrap();
+pub fn mpv_load_file(
+    handle: State<'_, PlayerHandle>,
+    path: &str,
+    mode: Option<LoadMode>,
+) -> Result<(), MpvError> {
+    let player = handle.get()?;
     player.load_file(path, mode)
 }
 
 #[tauri::command]
-pub fn mpv_get_path() -> Result<String, MpvError> {
-    let player = MPV_PLAYER.lock().unwrap();
+pub fn mpv_get_path(handle: State<'_, PlayerHandle>) -> Result<String, MpvError> {
+    let player = handle.get()?;
     player.get_path()
 }
 
 #[tauri::command]
-pub fn mpv_get_filename() -> Result<String, MpvError> {
-    let player = MPV_PLAYER.lock().unwrap();
+pub fn mpv_get_filename(handle: State<'_, PlayerHandle>) -> Result<String, MpvError> {
+    let player = handle.get()?;
     player.get_filename()
 }
 
 #[tauri::command]
-pub fn mpv_get_tracks() -> Result<Vec<Track>, MpvError> {
-    let player = MPV_PLAYER.lock().unwrap();
+pub fn mpv_get_tracks(handle: State<'_, PlayerHandle>) -> Result<Vec<Track>, MpvError> {
+    let player = handle.get()?;
     player.get_tracks()
 }
 
 #[tauri::command]
-pub fn mpv_get_current_tracks() -> Result<CurrentTracks, MpvError> {
-    let player = MPV_PLAYER.lock().unwrap();
+pub fn mpv_get_current_tracks(handle: State<'_, PlayerHandle>) -> Result<CurrentTracks, MpvError> {
+    let player = handle.get()?;
     player.get_current_tracks()
 }
 
 #[tauri::command]
 pub fn mpv_set_tracks(
+    handle: State<'_, PlayerHandle>,
     video: Option<i64>,
     audio: Option<i64>,
     subtitle: Option<i64>,
 ) -> Result<(), MpvError> {
-    let player = MPV_PLAYER.lock().unwrap();
+    let player = handle.get()?;
     player.set_tracks(video, audio, subtitle)
 }
 
 #[tauri::command]
-pub fn mpv_playlist_next() -> Result<(), MpvError> {
-    let player = MPV_PLAYER.lock().unwrap();
+pub fn mpv_playlist_next(handle: State<'_, PlayerHandle>) -> Result<(), MpvError> {
+    let player = handle.get()?;
     player.playlist_next()
 }
 
 #[tauri::command]
-pub fn mpv_playlist_prev() -> Result<(), MpvError> {
-    let player = MPV_PLAYER.lock().unwrap();
+pub fn mpv_playlist_prev(handle: State<'_, PlayerHandle>) -> Result<(), MpvError> {
+    let player = handle.get()?;
     player.playlist_prev()
 }
 
 #[tauri::command]
-pub fn mpv_get_playlist() -> Result<Vec<PlaylistEntry>, MpvError> {
-    let player = MPV_PLAYER.lock().unwrap();
+pub fn mpv_get_playlist(handle: State<'_, PlayerHandle>) -> Result<Vec<PlaylistEntry>, MpvError> {
+    let player = handle.get()?;
     player.get_playlist()
 }
 
 #[tauri::command]
-pub fn mpv_get_playlist_pos() -> Result<i64, MpvError> {
-    let player = MPV_PLAYER.lock().unwrap();
+pub fn mpv_get_playlist_pos(handle: State<'_, PlayerHandle>) -> Result<i64, MpvError> {
+    let player = handle.get()?;
     player.get_playlist_pos()
 }
 
 #[tauri::command]
-pub fn mpv_set_playlist_pos(pos: i64) -> Result<(), MpvError> {
-    let player = MPV_PLAYER.lock().unwrap();
+pub fn mpv_set_playlist_pos(handle: State<'_, PlayerHandle>, pos: i64) -> Result<(), MpvError> {
+    let player = handle.get()?;
     player.set_playlist_pos(pos)
 }
 
+/// Replaces the playlist with `paths`. Cue sheets, and files described by a
+/// sidecar or embedded sheet, are expanded into one entry per track.
 #[tauri::command]
-pub fn mpv_set_playlist_from_paths(paths: Vec<String>) -> Result<(), MpvError> {
-    let player = MPV_PLAYER.lock().unwrap();
-    player.set_playlist_from_paths(&paths)
+pub async fn mpv_set_playlist_from_paths(
+    handle: State<'_, PlayerHandle>,
+    paths: Vec<String>,
+) -> Result<(), MpvError> {
+    let sheet_paths = paths.clone();
+    let entries = tauri::async_runtime::spawn_blocking(move || {
+        sheet_paths
+            .into_iter()
+            .map(|path| {
+                let virtual_tracks = metadata::read_virtual_tracks(Path::new(&path))
+                    .map_err(|e| MpvError::load(&path, e.to_string()))?;
+                Ok((path, virtual_tracks))
+            })
+            .collect::<Result<Vec<_>, MpvError>>()
+    })
+    .await
+    .map_err(|e| MpvError::load("playlist", e.to_string()))??;
+
+    let player = handle.get()?;
+    if entries.iter().all(|(_, tracks)| tracks.is_none()) {
+        return player.set_playlist_from_paths(&paths);
+    }
+
+    player.clear_playlist()?;
+    for (path, virtual_tracks) in entries {
+        match virtual_tracks {
+            Some(tracks) => {
+                for track in tracks {
+                    player.load_segment(
+                        &track.path,
+                        track.start,
+                        track.end,
+                        Some(LoadMode::Append),
+                    )?;
+                }
+            }
+            None => player.load_file(&path, Some(LoadMode::Append))?,
+        }
+    }
+
+    Ok(())
 }
 
+/// Loads a single track of a CUE sheet. `path` is either the `.cue` file or the
+/// media file the sheet describes.
 #[tauri::command]
-pub fn mpv_clear_playlist() -> Result<(), MpvError> {
-    let player = MPV_PLAYER.lock().unwrap();
+pub async fn mpv_load_virtual_track(
+    handle: State<'_, PlayerHandle>,
+    path: String,
+    track: u32,
+    mode: Option<LoadMode>,
+) -> Result<(), MpvError> {
+    let metadata = metadata::parse_metadata(&path)
+        .await
+        .map_err(|e| MpvError::load(&path, e.to_string()))?;
+
+    let virtual_track = metadata
+        .virtual_tracks
+        .unwrap_or_default()
+        .into_iter()
+        .find(|t| t.track == track)
+        .ok_or_else(|| MpvError::invalid_argument(&path, format!("no track {}", track)))?;
+
+    let player = handle.get()?;
+    player.load_segment(
+        &virtual_track.path,
+        virtual_track.start,
+        virtual_track.end,
+        mode,
+    )
+}
+
+#[tauri::command]
+pub fn mpv_clear_playlist(handle: State<'_, PlayerHandle>) -> Result<(), MpvError> {
+    let player = handle.get()?;
     player.clear_playlist()
 }
+
+#[tauri::command]
+pub fn mpv_get_playback_continuity(
+    handle: State<'_, PlayerHandle>,
+) -> Result<PlaybackContinuity, MpvError> {
+    let player = handle.get()?;
+    player.get_playback_continuity()
+}
+
+#[tauri::command]
+pub fn mpv_set_playback_continuity(
+    handle: State<'_, PlayerHandle>,
+    continuity: PlaybackContinuity,
+) -> Result<(), MpvError> {
+    let player = handle.get()?;
+    player.set_playback_continuity(&continuity)
+}
+
+/// Reports the transitions in `paths` that will have an audible gap because the
+/// audio format (sample rate, channels, bit depth) changes between entries.
+#[tauri::command]
+pub async fn mpv_detect_gapless_breaks(paths: Vec<String>) -> Vec<GaplessBreak> {
+    gapless::detect_gapless_breaks(&paths).await
+}
+
+/// Loads a network stream (HTTP, HLS, ...) with per-load cache, timeout and header options.
+#[tauri::command]
+pub fn mpv_load_stream(
+    handle: State<'_, PlayerHandle>,
+    url: &str,
+    mode: Option<LoadMode>,
+    options: Option<StreamOptions>,
+) -> Result<(), MpvError> {
+    let player = handle.get()?;
+    let options = options.unwrap_or_default().to_load_options();
+    player.load_file_with_options(url, mode, &options)
+}
+
+#[tauri::command]
+pub fn mpv_get_stream_status(handle: State<'_, PlayerHandle>) -> Result<StreamStatus, MpvError> {
+    let player = handle.get()?;
+    Ok(player.get_stream_status())
+}
+
+/// The latest player state. Changes are pushed as `mpv-state` events.
+#[tauri::command]
+pub fn mpv_get_state(tracker: State<'_, Arc<PlayerStateTracker>>) -> PlayerState {
+    tracker.state()
+}
+
+/// Succeeds if the mpv core is running; otherwise returns why it couldn't be started.
+#[tauri::command]
+pub fn mpv_check_core(handle: State<'_, PlayerHandle>) -> Result<(), MpvError> {
+    handle.get().map(|_| ())
+}
+
+/// Replaces the mpv core with a new one, e.g. to apply options that can only be
+/// set before initialization. `options` are kept for later restarts; `None`
+/// reuses the previous ones. Nothing happens if any option isn't allowed.
+///
+/// Waiting for the old core to shut down can take seconds, so it runs on a
+/// blocking thread.
+#[tauri::command]
+pub async fn mpv_recreate(
+    handle: State<'_, PlayerHandle>,
+    allowlist: State<'_, MpvAllowlist>,
+    options: Option<HashMap<String, String>>,
+) -> Result<(), MpvError> {
+    if let Some(options) = &options {
+        for name in options.keys() {
+            allowlist.check_option(name)?;
+        }
+    }
+
+    let handle = handle.inner().clone();
+    tauri::async_runtime::spawn_blocking(move || {
+        handle.recreate(options.map(|options| options.into_iter().collect()))
+    })
+    .await
+    .map_err(|e| MpvError::NotRunningError {
+        message: e.to_string(),
+    })?
+}
+
+/// Reads several properties at once, as JSON. Unavailable properties are `null`.
+#[tauri::command]
+pub fn mpv_get_properties(
+    handle: State<'_, PlayerHandle>,
+    allowlist: State<'_, MpvAllowlist>,
+    names: Vec<String>,
+) -> Result<BTreeMap<String, Value>, MpvError> {
+    let player = handle.get()?;
+    mpv_properties::get_properties(&player, &allowlist, &names)
+}
+
+#[tauri::command]
+pub fn mpv_set_properties(
+    handle: State<'_, PlayerHandle>,
+    allowlist: State<'_, MpvAllowlist>,
+    values: BTreeMap<String, Value>,
+) -> Result<(), MpvError> {
+    let player = handle.get()?;
+    mpv_properties::set_properties(&player, &allowlist, &values)
+}
+
+/// Runs property reads, writes and commands in one round-trip. Each operation
+/// gets its own result.
+#[tauri::command]
+pub fn mpv_batch(
+    handle: State<'_, PlayerHandle>,
+    allowlist: State<'_, MpvAllowlist>,
+    ops: Vec<BatchOp>,
+) -> Result<Vec<BatchResult>, MpvError> {
+    let player = handle.get()?;
+    Ok(mpv_properties::run_batch(&player, &allowlist, &ops))
+}
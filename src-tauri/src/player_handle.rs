@@ -0,0 +1,311 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::thread;
+use std::time::Duration;
+
+use crate::mpv::{MpvError, MpvErrorCode, MpvEventId, MpvPlayer, PlaybackContinuity};
+
+/// How long to wait for a core to shut down before giving up on it.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+type CreateHook = Box<dyn Fn(&Arc<MpvPlayer>) -> Result<(), MpvError> + Send + Sync + 'static>;
+
+/// Owns the mpv core used for playback and lets it be replaced at runtime,
+/// e.g. after it shut down unexpectedly or to apply options that only take
+/// effect before initialization. Clones share the same core.
+///
+/// libmpv handles are thread-safe, so the player is handed out as a plain
+/// `Arc` instead of being locked for the duration of each call.
+#[derive(Clone)]
+pub struct PlayerHandle {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    lib_path: String,
+    /// Window the video is rendered into; mpv opens its own if `None`.
+    wid: Option<usize>,
+    /// Set on every new core before it is initialized.
+    options: Mutex<Vec<(String, String)>>,
+    player: RwLock<Option<Arc<MpvPlayer>>>,
+    /// libmpv code, if any, and message of the last failed attempt to create the core.
+    error: Mutex<Option<(Option<MpvErrorCode>, String)>>,
+    /// Bumped for every new core, so a core that shuts down after being
+    /// replaced isn't mistaken for a crash.
+    generation: AtomicU64,
+    hooks: Mutex<Vec<CreateHook>>,
+    /// Held while a core is replaced, so only one is created at a time.
+    recreating: Mutex<()>,
+}
+
+impl PlayerHandle {
+    /// Creates a handle without a core; call `recreate` to start one.
+    pub fn new(lib_path: &str, wid: Option<usize>) -> Self {
+        PlayerHandle {
+            inner: Arc::new(Inner {
+                lib_path: lib_path.to_string(),
+                wid,
+                options: Mutex::new(Vec::new()),
+                player: RwLock::new(None),
+                error: Mutex::new(None),
+                generation: AtomicU64::new(0),
+                hooks: Mutex::new(Vec::new()),
+                recreating: Mutex::new(()),
+            }),
+        }
+    }
+
+    /// The running core, or the reason there is none.
+    pub fn get(&self) -> Result<Arc<MpvPlayer>, MpvError> {
+        if let Some(player) = self.inner.player.read().unwrap().as_ref() {
+            return Ok(player.clone());
+        }
+
+        match self.inner.error.lock().unwrap().clone() {
+            Some((Some(code), message)) => Err(MpvError::InitializationError { code, message }),
+            Some((None, message)) => Err(MpvError::NotRunningError { message }),
+            None => Err(MpvError::NotRunningError {
+                message: "not started".to_string(),
+            }),
+        }
+    }
+
+    /// Calls `hook` with the current core and again with every core created
+    /// later. Used by everything that registers callbacks on the player.
+    ///
+    /// Hooks must not call `on_create` themselves.
+    pub fn on_create(
+        &self,
+        hook: impl Fn(&Arc<MpvPlayer>) -> Result<(), MpvError> + Send + Sync + 'static,
+    ) -> Result<(), MpvError> {
+        let player = self.inner.player.read().unwrap().clone();
+        if let Some(player) = player {
+            hook(&player)?;
+        }
+
+        self.inner.hooks.lock().unwrap().push(Box::new(hook));
+        Ok(())
+    }
+
+    /// Shuts down the current core, if any, and creates a new one. `options`
+    /// replace the options set before initialization; `None` keeps the previous ones.
+    pub fn recreate(&self, options: Option<Vec<(String, String)>>) -> Result<(), MpvError> {
+        if let Some(options) = options {
+            *self.inner.options.lock().unwrap() = options;
+        }
+
+        let _recreating = self.inner.recreating.lock().unwrap();
+        let generation = self.inner.generation.fetch_add(1, Ordering::SeqCst) + 1;
+
+        // Shut down first and wait for it; two cores can't render into the same
+        // window. The lock isn't held meanwhile, since the old core's callbacks
+        // may still call `get`.
+        let old = self.inner.player.write().unwrap().take();
+        if let Some(old) = old {
+            let stopped = match old.quit() {
+                Ok(()) => old.wait_for_shutdown(SHUTDOWN_TIMEOUT),
+                Err(e) => {
+                    eprintln!("Failed to shut down mpv: {}", e);
+                    false
+                }
+            };
+            if !stopped && !old.stop_event_processing(SHUTDOWN_TIMEOUT) {
+                eprintln!("mpv did not shut down in time");
+            }
+        }
+
+        let player = match self.inner.create_core() {
+            Ok(player) => player,
+            Err(e) => {
+                *self.inner.error.lock().unwrap() = Some((e.code(), e.to_string()));
+                return Err(e);
+            }
+        };
+
+        Self::watch_shutdown(Arc::downgrade(&self.inner), &player, generation)?;
+        *self.inner.player.write().unwrap() = Some(player.clone());
+        *self.inner.error.lock().unwrap() = None;
+
+        for hook in self.inner.hooks.lock().unwrap().iter() {
+            if let Err(e) = hook(&player) {
+                eprintln!("Failed to set up new mpv core: {}", e);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Replaces the core if it shuts down on its own (e.g. it crashed or was
+    /// told to quit from its window).
+    fn watch_shutdown(
+        inner: Weak<Inner>,
+        player: &Arc<MpvPlayer>,
+        generation: u64,
+    ) -> Result<(), MpvError> {
+        player.register_event_callback(MpvEventId::Shutdown, move |_| {
+            let Some(inner) = inner.upgrade() else {
+                return;
+            };
+            if inner.generation.load(Ordering::SeqCst) != generation {
+                return;
+            }
+
+            // Event callbacks run on the old core's event thread, which must
+            // not wait for the new core
+            thread::spawn(move || {
+                eprintln!("mpv shut down unexpectedly, restarting");
+                if let Err(e) = (PlayerHandle { inner }).recreate(None) {
+                    eprintln!("Failed to restart mpv: {}", e);
+                }
+            });
+        })
+    }
+}
+
+/// The player a tracker follows. A tracker can be attached to several players
+/// (both crossfade decks) and their callbacks stay registered, so callbacks of
+/// any player but the followed one return early.
+#[derive(Default)]
+pub struct FollowedPlayer {
+    followed: Mutex<Weak<MpvPlayer>>,
+    attached: Mutex<Vec<Weak<MpvPlayer>>>,
+}
+
+impl FollowedPlayer {
+    /// Follows `player` from now on. Returns whether the tracker wasn't attached
+    /// to it before, i.e. its callbacks still have to be registered.
+    pub fn follow(&self, player: &Arc<MpvPlayer>) -> bool {
+        let player = Arc::downgrade(player);
+        *self.followed.lock().unwrap() = player.clone();
+
+        let mut attached = self.attached.lock().unwrap();
+        attached.retain(|attached| attached.strong_count() > 0);
+        if attached.iter().any(|attached| attached.ptr_eq(&player)) {
+            return false;
+        }
+        attached.push(player);
+        true
+    }
+
+    /// The followed player, unless it is gone.
+    pub fn get(&self) -> Option<Arc<MpvPlayer>> {
+        self.followed.lock().unwrap().upgrade()
+    }
+
+    /// Whether the events of `player` are for the tracker.
+    pub fn is_followed(&self, player: &Weak<MpvPlayer>) -> bool {
+        self.followed.lock().unwrap().ptr_eq(player)
+    }
+}
+
+impl Inner {
+    fn create_core(&self) -> Result<Arc<MpvPlayer>, MpvError> {
+        let player = MpvPlayer::new(&self.lib_path)?;
+        if let Err(e) = self.set_up_core(&player) {
+            // It never started, so no Shutdown event will end its event thread
+            player.stop_event_processing(SHUTDOWN_TIMEOUT);
+            return Err(e);
+        }
+        Ok(player)
+    }
+
+    fn set_up_core(&self, player: &MpvPlayer) -> Result<(), MpvError> {
+        if let Some(wid) = self.wid {
+            player.attach_to_window(wid)?;
+        }
+        for (name, value) in self.options.lock().unwrap().iter() {
+            player.set_option(name, value)?;
+        }
+        player.initialize()?;
+        player.set_playback_continuity(&PlaybackContinuity::default())?;
+
+        #[cfg(not(dev))]
+        player.disable_osd()?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mpv::test_support;
+    use std::sync::atomic::AtomicUsize;
+
+    fn null_output() -> Option<Vec<(String, String)>> {
+        Some(vec![
+            ("vo".to_string(), "null".to_string()),
+            ("ao".to_string(), "null".to_string()),
+        ])
+    }
+
+    #[test]
+    fn reports_that_no_core_was_started() {
+        let handle = PlayerHandle::new(&test_support::lib_path(), None);
+        assert!(matches!(
+            handle.get(),
+            Err(MpvError::NotRunningError { .. })
+        ));
+    }
+
+    #[test]
+    fn shuts_down_the_old_core_before_creating_a_new_one() {
+        if test_support::player().is_none() {
+            return;
+        }
+        let handle = PlayerHandle::new(&test_support::lib_path(), None);
+        let created = Arc::new(AtomicUsize::new(0));
+        let counter = created.clone();
+        handle
+            .on_create(move |_| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                Ok(())
+            })
+            .unwrap();
+
+        handle.recreate(null_output()).unwrap();
+        let old = handle.get().unwrap();
+        handle.recreate(None).unwrap();
+
+        assert!(old.wait_for_shutdown(Duration::ZERO));
+        assert!(!Arc::ptr_eq(&old, &handle.get().unwrap()));
+        assert_eq!(created.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn follows_one_player_at_a_time() {
+        let (Some(first), Some(second)) = (test_support::player(), test_support::player()) else {
+            return;
+        };
+        let followed = FollowedPlayer::default();
+
+        assert!(followed.follow(&first));
+        assert!(followed.is_followed(&Arc::downgrade(&first)));
+        assert!(followed.follow(&second));
+        assert!(!followed.is_followed(&Arc::downgrade(&first)));
+        // Its callbacks are still registered
+        assert!(!followed.follow(&first));
+        assert!(followed.is_followed(&Arc::downgrade(&first)));
+
+        for player in [first, second] {
+            player.stop_event_processing(SHUTDOWN_TIMEOUT);
+        }
+    }
+
+    #[test]
+    fn keeps_the_error_of_a_core_that_failed_to_start() {
+        if test_support::player().is_none() {
+            return;
+        }
+        let handle = PlayerHandle::new(&test_support::lib_path(), None);
+        let options = vec![("no-such-option".to_string(), "yes".to_string())];
+
+        assert!(handle.recreate(Some(options)).is_err());
+        match handle.get() {
+            Err(MpvError::InitializationError { code, .. }) => {
+                assert_eq!(code, MpvErrorCode::OptionNotFound)
+            }
+            other => panic!("unexpected result: {:?}", other.map(|_| ())),
+        }
+    }
+}
@@ -0,0 +1,326 @@
+use serde::Serialize;
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::mpv::{CurrentTracks, MpvError, MpvFormat, MpvPlayer, PropertyValue, Track};
+use crate::player_handle::FollowedPlayer;
+
+/// Position updates are sent at most this often (10 Hz).
+const POSITION_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Other changes are sent right away, but changes that arrive together (e.g.
+/// everything that changes when a file loads) are collected into one update.
+const COALESCE_WINDOW: Duration = Duration::from_millis(15);
+
+/// Everything the player controls need to draw, kept current from observed properties.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct PlayerState {
+    pub path: Option<String>,
+    pub filename: Option<String>,
+    pub position: Option<f64>,
+    pub duration: Option<f64>,
+    pub paused: bool,
+    pub volume: Option<f64>,
+    pub muted: bool,
+    /// No file is loaded.
+    pub idle: bool,
+    pub playlist_pos: Option<i64>,
+    pub playlist_count: i64,
+    pub tracks: Vec<Track>,
+    pub current_tracks: CurrentTracks,
+}
+
+impl PlayerState {
+    /// Reads the complete state from `player`; used to seed the snapshot.
+    fn read(player: &MpvPlayer) -> Self {
+        PlayerState {
+            path: player.get_path().ok(),
+            filename: player.get_filename().ok(),
+            position: player.get_position().ok(),
+            duration: player.get_duration().ok(),
+            paused: player.is_paused().unwrap_or(true),
+            volume: player.get_volume().ok(),
+            muted: player.is_muted().unwrap_or(false),
+            idle: player.is_idle().unwrap_or(true),
+            playlist_pos: player.get_playlist_pos().ok().filter(|pos| *pos >= 0),
+            playlist_count: player.get_playlist_count().unwrap_or(0),
+            tracks: player.get_tracks().unwrap_or_default(),
+            current_tracks: player.get_current_tracks().unwrap_or_default(),
+        }
+    }
+
+    /// Applies a property change. Returns `false` for properties that aren't part of the state.
+    fn apply(&mut self, name: &str, value: &PropertyValue) -> bool {
+        match name {
+            "path" => self.path = value.as_str().map(String::from),
+            "filename" => self.filename = value.as_str().map(String::from),
+            "time-pos" => self.position = value.as_f64(),
+            "duration" => self.duration = value.as_f64(),
+            "pause" => self.paused = value.as_bool().unwrap_or(self.paused),
+            "volume" => self.volume = value.as_f64(),
+            "mute" => self.muted = value.as_bool().unwrap_or(false),
+            "idle-active" => self.idle = value.as_bool().unwrap_or(false),
+            "playlist-pos" => self.playlist_pos = value.as_i64().filter(|pos| *pos >= 0),
+            "playlist-count" => self.playlist_count = value.as_i64().unwrap_or(0),
+            "track-list" => {
+                self.tracks = value
+                    .as_str()
+                    .and_then(|json| serde_json::from_str(json).ok())
+                    .unwrap_or_default();
+                self.update_current_tracks();
+            }
+            // Selected tracks are resolved from `tracks` by the `selected` flag
+            "vid" | "aid" | "sid" => self.update_current_tracks(),
+            _ => return false,
+        }
+        true
+    }
+
+    fn update_current_tracks(&mut self) {
+        let selected = |type_: &str| {
+            self.tracks
+                .iter()
+                .find(|t| t.type_ == type_ && t.selected)
+                .cloned()
+        };
+
+        self.current_tracks = CurrentTracks {
+            video: selected("video"),
+            audio: selected("audio"),
+            subtitle: selected("sub"),
+        };
+    }
+}
+
+/// Properties that make up `PlayerState`, with the format they are observed in.
+const WATCHED_PROPERTIES: [(&str, MpvFormat); 14] = [
+    ("path", MpvFormat::String),
+    ("filename", MpvFormat::String),
+    ("time-pos", MpvFormat::Double),
+    ("duration", MpvFormat::Double),
+    ("pause", MpvFormat::Flag),
+    ("volume", MpvFormat::Double),
+    ("mute", MpvFormat::Flag),
+    ("idle-active", MpvFormat::Flag),
+    ("playlist-pos", MpvFormat::Int64),
+    ("playlist-count", MpvFormat::Int64),
+    ("track-list", MpvFormat::String),
+    ("vid", MpvFormat::Int64),
+    ("aid", MpvFormat::Int64),
+    ("sid", MpvFormat::Int64),
+];
+
+struct Shared {
+    state: PlayerState,
+    /// A change other than the position is waiting to be sent.
+    changed: bool,
+    /// Only the position changed since the last update.
+    position_changed: bool,
+    sent_at: Instant,
+}
+
+/// How long to wait before sending the pending update, `since_sent` after the
+/// last one: other changes are forced out after the coalescing window, while
+/// position-only changes wait for the rest of the position interval. `None` if
+/// nothing is pending.
+fn send_delay(changed: bool, position_changed: bool, since_sent: Duration) -> Option<Duration> {
+    if changed {
+        Some(COALESCE_WINDOW)
+    } else if position_changed {
+        Some(POSITION_INTERVAL.saturating_sub(since_sent))
+    } else {
+        None
+    }
+}
+
+pub type StateCallback = Box<dyn Fn(&PlayerState) + Send + Sync + 'static>;
+
+/// Keeps a `PlayerState` snapshot up to date and sends it to a callback,
+/// rate limited as described on `POSITION_INTERVAL` and `COALESCE_WINDOW`.
+pub struct PlayerStateTracker {
+    shared: Mutex<Shared>,
+    wakeup: Condvar,
+    on_change: StateCallback,
+    /// Added with `subscribe`; get the same updates as `on_change`.
+    subscribers: Mutex<Vec<StateCallback>>,
+    followed: FollowedPlayer,
+}
+
+impl PlayerStateTracker {
+    /// Starts the thread that sends updates. The state stays empty until `attach`.
+    pub fn new(on_change: impl Fn(&PlayerState) + Send + Sync + 'static) -> Arc<Self> {
+        let tracker = Arc::new(Self {
+            shared: Mutex::new(Shared {
+                state: PlayerState::default(),
+                changed: false,
+                position_changed: false,
+                sent_at: Instant::now(),
+            }),
+            wakeup: Condvar::new(),
+            on_change: Box::new(on_change),
+            subscribers: Mutex::new(Vec::new()),
+            followed: FollowedPlayer::default(),
+        });
+
+        Self::start_sending(Arc::downgrade(&tracker));
+        tracker
+    }
+
+    /// Follows `player` from now on. Called again for every new core and
+    /// whenever a crossfade moves playback to the other deck.
+    pub fn attach(self: &Arc<Self>, player: &Arc<MpvPlayer>) -> Result<(), MpvError> {
+        let new = self.followed.follow(player);
+        {
+            let mut shared = self.shared.lock().unwrap();
+            shared.state = PlayerState::read(player);
+            shared.changed = true;
+            self.wakeup.notify_one();
+        }
+
+        if !new {
+            return Ok(());
+        }
+
+        for (name, format) in WATCHED_PROPERTIES {
+            let (t, p) = (self.clone(), Arc::downgrade(player));
+            player.on_property_change(name, format, move |value| {
+                if t.followed.is_followed(&p) {
+                    t.update(name, value)
+                }
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// Sends every later update to `callback` too.
+    pub fn subscribe(&self, callback: impl Fn(&PlayerState) + Send + Sync + 'static) {
+        self.subscribers.lock().unwrap().push(Box::new(callback));
+    }
+
+    pub fn state(&self) -> PlayerState {
+        self.shared.lock().unwrap().state.clone()
+    }
+
+    fn update(&self, name: &str, value: &PropertyValue) {
+        let mut shared = self.shared.lock().unwrap();
+        let previous = shared.state.clone();
+        if !shared.state.apply(name, value) || shared.state == previous {
+            return;
+        }
+
+        if name == "time-pos" {
+            shared.position_changed = true;
+        } else {
+            shared.changed = true;
+        }
+        self.wakeup.notify_one();
+    }
+
+    /// Sends pending updates from a separate thread, so mpv's event thread never
+    /// waits on the rate limit.
+    fn start_sending(tracker: Weak<Self>) {
+        thread::spawn(move || loop {
+            let Some(tracker) = tracker.upgrade() else {
+                break;
+            };
+
+            let shared = tracker.shared.lock().unwrap();
+            let (mut shared, _) = tracker
+                .wakeup
+                .wait_timeout_while(shared, Duration::from_secs(1), |s| {
+                    !s.changed && !s.position_changed
+                })
+                .unwrap();
+
+            let Some(delay) = send_delay(
+                shared.changed,
+                shared.position_changed,
+                shared.sent_at.elapsed(),
+            ) else {
+                continue;
+            };
+            if !delay.is_zero() {
+                drop(shared);
+                thread::sleep(delay);
+                shared = tracker.shared.lock().unwrap();
+            }
+
+            let state = shared.state.clone();
+            shared.changed = false;
+            shared.position_changed = false;
+            shared.sent_at = Instant::now();
+            drop(shared);
+
+            (tracker.on_change)(&state);
+            for subscriber in tracker.subscribers.lock().unwrap().iter() {
+                subscriber(&state);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    #[test]
+    fn sends_nothing_without_changes() {
+        assert_eq!(send_delay(false, false, Duration::ZERO), None);
+        assert_eq!(send_delay(false, false, Duration::from_secs(10)), None);
+    }
+
+    #[test]
+    fn throttles_position_updates() {
+        let ms = Duration::from_millis;
+        assert_eq!(send_delay(false, true, ms(0)), Some(ms(100)));
+        assert_eq!(send_delay(false, true, ms(30)), Some(ms(70)));
+        assert_eq!(send_delay(false, true, ms(100)), Some(Duration::ZERO));
+        assert_eq!(send_delay(false, true, ms(250)), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn forces_other_changes_out_after_the_coalescing_window() {
+        let ms = Duration::from_millis;
+        assert_eq!(send_delay(true, false, ms(0)), Some(COALESCE_WINDOW));
+        assert_eq!(send_delay(true, false, ms(250)), Some(COALESCE_WINDOW));
+        // The position goes along without waiting for its interval
+        assert_eq!(send_delay(true, true, ms(0)), Some(COALESCE_WINDOW));
+    }
+
+    #[test]
+    fn collects_changes_that_arrive_together() {
+        let (sender, receiver) = mpsc::channel();
+        let sender = Mutex::new(sender);
+        let tracker = PlayerStateTracker::new(move |state| {
+            sender.lock().unwrap().send(state.clone()).unwrap();
+        });
+
+        tracker.update("pause", &PropertyValue::Flag(true));
+        tracker.update("volume", &PropertyValue::Double(50.0));
+        tracker.update("time-pos", &PropertyValue::Double(1.5));
+
+        let state = receiver.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert!(state.paused);
+        assert_eq!(state.volume, Some(50.0));
+        assert_eq!(state.position, Some(1.5));
+        assert!(receiver.recv_timeout(Duration::from_millis(200)).is_err());
+    }
+
+    #[test]
+    fn ignores_properties_that_did_not_change() {
+        let (sender, receiver) = mpsc::channel();
+        let sender = Mutex::new(sender);
+        let tracker = PlayerStateTracker::new(move |state| {
+            sender.lock().unwrap().send(state.clone()).unwrap();
+        });
+
+        tracker.update("volume", &PropertyValue::Double(50.0));
+        receiver.recv_timeout(Duration::from_secs(1)).unwrap();
+        tracker.update("volume", &PropertyValue::Double(50.0));
+        tracker.update("no-such-property", &PropertyValue::Flag(true));
+        assert!(receiver.recv_timeout(Duration::from_millis(200)).is_err());
+    }
+}
@@ -0,0 +1,818 @@
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sqlx::SqlitePool;
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+use tiny_http::{Header, Method, Request, Response, Server};
+use tokio::runtime::Handle;
+use tungstenite::handshake::derive_accept_key;
+use tungstenite::protocol::Role;
+use tungstenite::{Message, WebSocket};
+use uuid::Uuid;
+
+use crate::database;
+use crate::mpv::{MpvError, MpvEventId, PlaylistEntry};
+use crate::player_handle::PlayerHandle;
+use crate::player_state::PlayerStateTracker;
+
+/// Key of the remote control settings in the `app_setting` table.
+pub const CONFIG_KEY: &str = "remote_control";
+
+/// How long a pairing code shown in the app can be used.
+pub const PAIRING_CODE_LIFETIME: Duration = Duration::from_secs(120);
+
+/// Wrong codes allowed before the pairing code is thrown away.
+const MAX_PAIRING_ATTEMPTS: u32 = 5;
+
+/// Threads answering requests, so a slow request doesn't hold up the others.
+const WORKER_THREADS: usize = 4;
+
+/// Events streamed to WebSocket clients.
+const STREAMED_EVENTS: [MpvEventId; 8] = [
+    MpvEventId::StartFile,
+    MpvEventId::EndFile,
+    MpvEventId::FileLoaded,
+    MpvEventId::Seek,
+    MpvEventId::PlaybackRestart,
+    MpvEventId::VideoReconfig,
+    MpvEventId::AudioReconfig,
+    MpvEventId::Shutdown,
+];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RemoteConfig {
+    pub enabled: bool,
+    /// Use `0.0.0.0` to accept connections from the LAN.
+    pub bind_address: String,
+    pub port: u16,
+}
+
+impl Default for RemoteConfig {
+    fn default() -> Self {
+        RemoteConfig {
+            enabled: false,
+            bind_address: "127.0.0.1".to_string(),
+            port: 8765,
+        }
+    }
+}
+
+/// A device paired through `POST /api/pair`. Its token authorizes all other requests.
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct RemoteDevice {
+    pub id: i64,
+    pub name: String,
+    #[serde(skip)]
+    pub token: String,
+    pub paired_at: i64,
+}
+
+/// The remote control settings and paired devices, persisted in the app database.
+pub struct RemoteStore {
+    pool: SqlitePool,
+    config: Mutex<RemoteConfig>,
+    /// Cached, since every request is checked against them.
+    devices: Mutex<Vec<RemoteDevice>>,
+}
+
+impl RemoteStore {
+    pub async fn new(pool: SqlitePool) -> Result<Self, sqlx::Error> {
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS remote_device (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                name TEXT NOT NULL,
+                token TEXT NOT NULL UNIQUE,
+                paired_at INTEGER NOT NULL
+            )",
+        )
+        .execute(&pool)
+        .await?;
+
+        let config = database::get_setting(&pool, CONFIG_KEY)
+            .await?
+            .unwrap_or_default();
+        let devices = sqlx::query_as::<_, RemoteDevice>("SELECT * FROM remote_device ORDER BY id")
+            .fetch_all(&pool)
+            .await?;
+
+        Ok(Self {
+            pool,
+            config: Mutex::new(config),
+            devices: Mutex::new(devices),
+        })
+    }
+
+    pub fn config(&self) -> RemoteConfig {
+        self.config.lock().unwrap().clone()
+    }
+
+    pub async fn set_config(&self, config: RemoteConfig) -> Result<(), sqlx::Error> {
+        database::set_setting(&self.pool, CONFIG_KEY, &config).await?;
+        *self.config.lock().unwrap() = config;
+        Ok(())
+    }
+
+    pub fn devices(&self) -> Vec<RemoteDevice> {
+        self.devices.lock().unwrap().clone()
+    }
+
+    /// Pairs a new device and returns it with its freshly generated token.
+    pub async fn add_device(&self, name: &str) -> Result<RemoteDevice, sqlx::Error> {
+        let token = Uuid::new_v4().to_simple().to_string();
+        let paired_at = database::unix_now();
+        let id = sqlx::query("INSERT INTO remote_device (name, token, paired_at) VALUES (?, ?, ?)")
+            .bind(name)
+            .bind(&token)
+            .bind(paired_at)
+            .execute(&self.pool)
+            .await?
+            .last_insert_rowid();
+
+        let device = RemoteDevice {
+            id,
+            name: name.to_string(),
+            token,
+            paired_at,
+        };
+        self.devices.lock().unwrap().push(device.clone());
+        Ok(device)
+    }
+
+    /// Unpairs a device; its token stops working right away. Use
+    /// `RemoteControl::remove_device` to also close its open WebSockets.
+    pub async fn remove_device(&self, id: i64) -> Result<(), sqlx::Error> {
+        sqlx::query("DELETE FROM remote_device WHERE id = ?")
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        self.devices
+            .lock()
+            .unwrap()
+            .retain(|device| device.id != id);
+        Ok(())
+    }
+
+    /// The id of the device `token` belongs to.
+    fn authorize(&self, token: &str) -> Option<i64> {
+        self.devices
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|device| constant_time_eq(device.token.as_bytes(), token.as_bytes()))
+            .map(|device| device.id)
+    }
+}
+
+/// Compares without returning early, so the time taken doesn't tell how much
+/// of a guessed token was right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// A code shown in the app that a device sends to `POST /api/pair` to get a token.
+#[derive(Debug, Clone, Serialize)]
+pub struct PairingCode {
+    pub code: String,
+    pub expires_in_secs: u64,
+}
+
+struct Pairing {
+    code: String,
+    expires_at: Instant,
+    attempts: u32,
+}
+
+enum ApiError {
+    BadRequest(String),
+    Unauthorized,
+    Forbidden(String),
+    NotFound,
+    Mpv(MpvError),
+    Internal(String),
+}
+
+impl From<MpvError> for ApiError {
+    fn from(e: MpvError) -> Self {
+        ApiError::Mpv(e)
+    }
+}
+
+impl ApiError {
+    fn status(&self) -> u16 {
+        match self {
+            ApiError::BadRequest(_) => 400,
+            ApiError::Unauthorized => 401,
+            ApiError::Forbidden(_) => 403,
+            ApiError::NotFound => 404,
+            ApiError::Mpv(_) | ApiError::Internal(_) => 500,
+        }
+    }
+
+    /// mpv errors keep the structure the webview gets; the others only have a message.
+    fn body(&self) -> Value {
+        match self {
+            ApiError::BadRequest(message)
+            | ApiError::Forbidden(message)
+            | ApiError::Internal(message) => json!({ "message": message }),
+            ApiError::Unauthorized => json!({ "message": "Missing or unknown token" }),
+            ApiError::NotFound => json!({ "message": "Not found" }),
+            ApiError::Mpv(e) => json!(e),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct PairRequest {
+    code: String,
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct VolumeRequest {
+    volume: f64,
+}
+
+#[derive(Deserialize)]
+struct SeekRequest {
+    position: f64,
+}
+
+#[derive(Deserialize)]
+struct PlaylistRequest {
+    paths: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct PlaylistPosRequest {
+    pos: i64,
+}
+
+#[derive(Deserialize)]
+struct TracksRequest {
+    vid: Option<i64>,
+    aid: Option<i64>,
+    sid: Option<i64>,
+}
+
+type PlaylistCallback = Box<dyn Fn(&[PlaylistEntry]) + Send + Sync + 'static>;
+
+/// An optional HTTP server for controlling the player from other devices.
+///
+/// REST endpoints under `/api` wrap the `MpvPlayer` methods, and `/api/ws` is a
+/// WebSocket that streams the `PlayerState` and mpv's events. Every request
+/// except `POST /api/pair` needs a device token, either as
+/// `Authorization: Bearer <token>` or as a `token` query parameter (browsers
+/// can't set headers on WebSockets).
+pub struct RemoteControl {
+    store: Arc<RemoteStore>,
+    handle: PlayerHandle,
+    state: Arc<PlayerStateTracker>,
+    runtime: Handle,
+    on_playlist_change: PlaylistCallback,
+    server: Mutex<Option<Arc<Server>>>,
+    /// Outgoing messages of the connected WebSockets, with the id of their device.
+    clients: Mutex<Vec<(i64, Sender<String>)>>,
+    pairing: Mutex<Option<Pairing>>,
+}
+
+impl RemoteControl {
+    /// Sets up streaming to WebSocket clients. The server itself is started by `apply_config`.
+    /// `on_playlist_change` is called with mpv's playlist after a device replaced it.
+    pub fn new(
+        store: Arc<RemoteStore>,
+        handle: PlayerHandle,
+        state: Arc<PlayerStateTracker>,
+        runtime: Handle,
+        on_playlist_change: impl Fn(&[PlaylistEntry]) + Send + Sync + 'static,
+    ) -> Result<Arc<Self>, MpvError> {
+        let control = Arc::new(Self {
+            store,
+            handle: handle.clone(),
+            state: state.clone(),
+            runtime,
+            on_playlist_change: Box::new(on_playlist_change),
+            server: Mutex::new(None),
+            clients: Mutex::new(Vec::new()),
+            pairing: Mutex::new(None),
+        });
+
+        let control_ref = Arc::downgrade(&control);
+        state.subscribe(move |state| {
+            if let Some(control) = control_ref.upgrade() {
+                control.broadcast(json!({ "type": "state", "state": state }));
+            }
+        });
+
+        let control_ref = Arc::downgrade(&control);
+        handle.on_create(move |player| {
+            for event_id in STREAMED_EVENTS {
+                let control_ref = control_ref.clone();
+                let id = event_id.clone() as u32;
+                player.register_event_callback(event_id, move |event| {
+                    if let Some(control) = control_ref.upgrade() {
+                        control.broadcast(json!({
+                            "type": "event",
+                            "event_id": id,
+                            "reason": event.end_file_reason(),
+                        }));
+                    }
+                })?;
+            }
+            Ok(())
+        })?;
+
+        Ok(control)
+    }
+
+    /// Starts, restarts or stops the server to match the stored config.
+    pub fn apply_config(self: &Arc<Self>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        if let Some(server) = self.server.lock().unwrap().take() {
+            stop(&server);
+        }
+        // Ends the WebSocket threads
+        self.clients.lock().unwrap().clear();
+
+        let config = self.store.config();
+        if !config.enabled {
+            return Ok(());
+        }
+
+        let server = Arc::new(Server::http((config.bind_address.as_str(), config.port))?);
+        *self.server.lock().unwrap() = Some(server.clone());
+
+        for _ in 0..WORKER_THREADS {
+            let control_ref = Arc::downgrade(self);
+            let server = server.clone();
+            thread::spawn(move || Self::serve(control_ref, server));
+        }
+        Ok(())
+    }
+
+    /// Unpairs a device and closes its WebSockets.
+    pub async fn remove_device(&self, id: i64) -> Result<(), sqlx::Error> {
+        self.store.remove_device(id).await?;
+        // Dropping the sender ends the socket's thread
+        self.clients
+            .lock()
+            .unwrap()
+            .retain(|(device_id, _)| *device_id != id);
+        Ok(())
+    }
+
+    /// Replaces the current pairing code with a new one.
+    pub fn start_pairing(&self) -> PairingCode {
+        let code = format!("{:06}", Uuid::new_v4().as_u128() % 1_000_000);
+        *self.pairing.lock().unwrap() = Some(Pairing {
+            code: code.clone(),
+            expires_at: Instant::now() + PAIRING_CODE_LIFETIME,
+            attempts: 0,
+        });
+        PairingCode {
+            code,
+            expires_in_secs: PAIRING_CODE_LIFETIME.as_secs(),
+        }
+    }
+
+    fn serve(control: Weak<Self>, server: Arc<Server>) {
+        for request in server.incoming_requests() {
+            let Some(control) = control.upgrade() else {
+                break;
+            };
+            control.handle_request(request);
+        }
+    }
+
+    fn handle_request(&self, mut request: Request) {
+        // Preflight of web pages on other origins; the token, not the origin, is checked
+        if *request.method() == Method::Options {
+            respond(request, Response::empty(204));
+            return;
+        }
+
+        let url = request.url().to_string();
+        let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+        let method = request.method().clone();
+
+        if method == Method::Post && path == "/api/pair" {
+            match read_body(&mut request).and_then(|body| self.pair(&body)) {
+                Ok(value) => respond_json(request, 200, &value),
+                Err(e) => respond_error(request, e),
+            }
+            return;
+        }
+
+        let Some(device_id) = token(&request, query).and_then(|t| self.store.authorize(&t)) else {
+            respond_error(request, ApiError::Unauthorized);
+            return;
+        };
+
+        // Before reading a body: the upgraded connection has none, and reading
+        // would wait for the client to hang up
+        if path == "/api/ws" {
+            self.accept_websocket(request, device_id);
+            return;
+        }
+
+        match read_body(&mut request).and_then(|body| self.route(&method, path, &body)) {
+            Ok(value) => respond_json(request, 200, &value),
+            Err(e) => respond_error(request, e),
+        }
+    }
+
+    fn route(&self, method: &Method, path: &str, body: &[u8]) -> Result<Value, ApiError> {
+        if (method, path) == (&Method::Get, "/api/state") {
+            return Ok(json!(self.state.state()));
+        }
+
+        let player = self.handle.get()?;
+        match (method, path) {
+            (Method::Post, "/api/play") => player.play()?,
+            (Method::Post, "/api/pause") => player.pause()?,
+            (Method::Post, "/api/toggle") => {
+                if player.is_paused()? {
+                    player.play()?
+                } else {
+                    player.pause()?
+                }
+            }
+            (Method::Post, "/api/stop") => player.stop()?,
+            (Method::Post, "/api/next") => player.playlist_next()?,
+            (Method::Post, "/api/prev") => player.playlist_prev()?,
+            (Method::Post, "/api/seek") => {
+                let request: SeekRequest = parse(body)?;
+                player.seek(request.position)?
+            }
+            (Method::Get, "/api/volume") => return Ok(json!({ "volume": player.get_volume()? })),
+            (Method::Put, "/api/volume") => {
+                let request: VolumeRequest = parse(body)?;
+                player.set_volume(request.volume)?
+            }
+            (Method::Get, "/api/playlist") => {
+                return Ok(json!({
+                    "entries": player.get_playlist()?,
+                    "pos": player.get_playlist_pos()?,
+                }))
+            }
+            (Method::Put, "/api/playlist") => {
+                let request: PlaylistRequest = parse(body)?;
+                player.set_playlist_from_paths(&request.paths)?;
+                (self.on_playlist_change)(&player.get_playlist()?);
+            }
+            (Method::Put, "/api/playlist/pos") => {
+                let request: PlaylistPosRequest = parse(body)?;
+                player.set_playlist_pos(request.pos)?
+            }
+            (Method::Get, "/api/tracks") => {
+                return Ok(json!({
+                    "tracks": player.get_tracks()?,
+                    "current": player.get_current_tracks()?,
+                }))
+            }
+            (Method::Put, "/api/tracks") => {
+                let request: TracksRequest = parse(body)?;
+                player.set_tracks(request.vid, request.aid, request.sid)?
+            }
+            _ => return Err(ApiError::NotFound),
+        }
+        Ok(json!({}))
+    }
+
+    fn pair(&self, body: &[u8]) -> Result<Value, ApiError> {
+        let request: PairRequest = parse(body)?;
+
+        {
+            let mut pairing = self.pairing.lock().unwrap();
+            let Some(current) = pairing.as_mut().filter(|p| p.expires_at > Instant::now()) else {
+                *pairing = None;
+                return Err(ApiError::Forbidden("No pairing in progress".to_string()));
+            };
+            if current.code != request.code {
+                current.attempts += 1;
+                if current.attempts >= MAX_PAIRING_ATTEMPTS {
+                    *pairing = None;
+                }
+                return Err(ApiError::Forbidden("Wrong pairing code".to_string()));
+            }
+            // A code pairs one device
+            *pairing = None;
+        }
+
+        let device = self
+            .runtime
+            .block_on(self.store.add_device(&request.name))
+            .map_err(|e| ApiError::Internal(e.to_string()))?;
+        Ok(json!({ "id": device.id, "token": device.token }))
+    }
+
+    fn accept_websocket(&self, request: Request, device_id: i64) {
+        let key = request
+            .headers()
+            .iter()
+            .find(|h| h.field.equiv("Sec-WebSocket-Key"))
+            .map(|h| h.value.to_string());
+        let Some(key) = key else {
+            respond_error(
+                request,
+                ApiError::BadRequest("Expected a WebSocket handshake".to_string()),
+            );
+            return;
+        };
+
+        let response = Response::empty(101).with_header(header(
+            "Sec-WebSocket-Accept",
+            &derive_accept_key(key.as_bytes()),
+        ));
+        let stream = request.upgrade("websocket", response);
+        let mut socket = WebSocket::from_raw_socket(stream, Role::Server, None);
+
+        let (sender, receiver) = mpsc::channel::<String>();
+        let initial = json!({ "type": "state", "state": self.state.state() }).to_string();
+        self.clients.lock().unwrap().push((device_id, sender));
+
+        // Only writes; the client is dropped once a write fails, the server stops
+        // or the device is removed
+        thread::spawn(move || {
+            for message in std::iter::once(initial).chain(receiver) {
+                if socket.send(Message::Text(message)).is_err() {
+                    break;
+                }
+            }
+            let _ = socket.close(None);
+        });
+    }
+
+    fn broadcast(&self, message: Value) {
+        let message = message.to_string();
+        self.clients
+            .lock()
+            .unwrap()
+            .retain(|(_, client)| client.send(message.clone()).is_ok());
+    }
+}
+
+impl Drop for RemoteControl {
+    fn drop(&mut self) {
+        if let Some(server) = self.server.lock().unwrap().take() {
+            stop(&server);
+        }
+    }
+}
+
+/// Ends the worker threads; `unblock` wakes one waiting thread per call.
+fn stop(server: &Server) {
+    for _ in 0..WORKER_THREADS {
+        server.unblock();
+    }
+}
+
+/// The device token from the `Authorization` header or the `token` query parameter.
+fn token(request: &Request, query: &str) -> Option<String> {
+    let from_header = request
+        .headers()
+        .iter()
+        .find(|h| h.field.equiv("Authorization"))
+        .and_then(|h| h.value.as_str().strip_prefix("Bearer "))
+        .map(String::from);
+
+    from_header.or_else(|| {
+        query
+            .split('&')
+            .find_map(|pair| pair.strip_prefix("token="))
+            .map(String::from)
+    })
+}
+
+fn read_body(request: &mut Request) -> Result<Vec<u8>, ApiError> {
+    let mut body = Vec::new();
+    request
+        .as_reader()
+        .read_to_end(&mut body)
+        .map_err(|e| ApiError::BadRequest(e.to_string()))?;
+    Ok(body)
+}
+
+fn parse<T: DeserializeOwned>(body: &[u8]) -> Result<T, ApiError> {
+    serde_json::from_slice(body).map_err(|e| ApiError::BadRequest(e.to_string()))
+}
+
+fn header(name: &str, value: &str) -> Header {
+    Header::from_bytes(name.as_bytes(), value.as_bytes()).unwrap()
+}
+
+fn respond<R: std::io::Read>(request: Request, response: Response<R>) {
+    let response = response
+        .with_header(header("Access-Control-Allow-Origin", "*"))
+        .with_header(header(
+            "Access-Control-Allow-Headers",
+            "Authorization, Content-Type",
+        ))
+        .with_header(header(
+            "Access-Control-Allow-Methods",
+            "GET, POST, PUT, OPTIONS",
+        ));
+    if let Err(e) = request.respond(response) {
+        eprintln!("Failed to respond to remote control request: {}", e);
+    }
+}
+
+fn respond_json(request: Request, status: u16, value: &Value) {
+    let response = Response::from_string(value.to_string())
+        .with_status_code(status)
+        .with_header(header("Content-Type", "application/json"));
+    respond(request, response);
+}
+
+fn respond_error(request: Request, error: ApiError) {
+    respond_json(request, error.status(), &error.body());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpStream};
+    use tokio::runtime::Runtime;
+
+    struct TestServer {
+        control: Arc<RemoteControl>,
+        addr: SocketAddr,
+        runtime: Runtime,
+        _dir: tempfile::TempDir,
+    }
+
+    impl TestServer {
+        fn start() -> Self {
+            let dir = tempfile::tempdir().unwrap();
+            let runtime = Runtime::new().unwrap();
+            let store = runtime.block_on(async {
+                let pool = database::connect(&dir.path().join("app.db")).await.unwrap();
+                let store = RemoteStore::new(pool).await.unwrap();
+                let config = RemoteConfig {
+                    enabled: true,
+                    bind_address: "127.0.0.1".to_string(),
+                    port: 0,
+                };
+                store.set_config(config).await.unwrap();
+                Arc::new(store)
+            });
+
+            let control = RemoteControl::new(
+                store,
+                PlayerHandle::new("", None),
+                PlayerStateTracker::new(|_| ()),
+                runtime.handle().clone(),
+                |_| (),
+            )
+            .unwrap();
+            control.apply_config().unwrap();
+            let addr = control
+                .server
+                .lock()
+                .unwrap()
+                .as_ref()
+                .unwrap()
+                .server_addr();
+
+            TestServer {
+                control,
+                addr: addr.to_ip().unwrap(),
+                runtime,
+                _dir: dir,
+            }
+        }
+
+        /// Sends a request and returns the status and JSON body of the response.
+        fn request(
+            &self,
+            method: &str,
+            path: &str,
+            token: Option<&str>,
+            body: &str,
+        ) -> (u16, Value) {
+            let mut stream = TcpStream::connect(self.addr).unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            let authorization = token
+                .map(|token| format!("Authorization: Bearer {}\r\n", token))
+                .unwrap_or_default();
+            write!(
+                stream,
+                "{} {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n{}Content-Length: {}\r\n\r\n{}",
+                method,
+                path,
+                authorization,
+                body.len(),
+                body
+            )
+            .unwrap();
+
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            let status = response[9..12].parse().unwrap();
+            let (_, body) = response.split_once("\r\n\r\n").unwrap();
+            (status, serde_json::from_str(body).unwrap_or(Value::Null))
+        }
+
+        /// Pairs a device and returns its id and token.
+        fn pair(&self) -> (i64, String) {
+            let code = self.control.start_pairing().code;
+            let body = json!({ "code": code, "name": "Phone" }).to_string();
+            let (status, reply) = self.request("POST", "/api/pair", None, &body);
+            assert_eq!(status, 200);
+            (
+                reply["id"].as_i64().unwrap(),
+                reply["token"].as_str().unwrap().to_string(),
+            )
+        }
+    }
+
+    #[test]
+    fn compares_tokens_in_full() {
+        assert!(constant_time_eq(b"abcdef", b"abcdef"));
+        assert!(!constant_time_eq(b"abcdef", b"abcdeg"));
+        assert!(!constant_time_eq(b"abcdef", b"abc"));
+        assert!(!constant_time_eq(b"", b"a"));
+    }
+
+    #[test]
+    fn pairs_devices_and_checks_their_tokens() {
+        let server = TestServer::start();
+
+        assert_eq!(server.request("GET", "/api/state", None, "").0, 401);
+        assert_eq!(
+            server.request("GET", "/api/state", Some("guessed"), "").0,
+            401
+        );
+
+        server.control.start_pairing();
+        let wrong = json!({ "code": "not a code", "name": "Phone" }).to_string();
+        assert_eq!(server.request("POST", "/api/pair", None, &wrong).0, 403);
+
+        let (_, token) = server.pair();
+        assert_eq!(server.request("GET", "/api/state", Some(&token), "").0, 200);
+        let path = format!("/api/state?token={}", token);
+        assert_eq!(server.request("GET", &path, None, "").0, 200);
+        // There is no core, so player requests fail with the structured mpv error
+        let (status, error) = server.request("POST", "/api/play", Some(&token), "");
+        assert_eq!(status, 500);
+        assert_eq!(error["kind"], "not_running");
+    }
+
+    #[test]
+    fn answers_while_another_request_is_waiting_for_its_body() {
+        let server = TestServer::start();
+        let (_, token) = server.pair();
+
+        // Promises a body it never sends
+        let mut slow = TcpStream::connect(server.addr).unwrap();
+        write!(
+            slow,
+            "PUT /api/volume HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer {}\r\nContent-Length: 100\r\n\r\n{{",
+            token
+        )
+        .unwrap();
+        thread::sleep(Duration::from_millis(100));
+
+        assert_eq!(server.request("GET", "/api/state", Some(&token), "").0, 200);
+    }
+
+    #[test]
+    fn closes_the_websockets_of_removed_devices() {
+        let server = TestServer::start();
+        let (id, token) = server.pair();
+        let (other_id, other_token) = server.pair();
+
+        let connect = |token: &str| {
+            let stream = TcpStream::connect(server.addr).unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            let url = format!("ws://{}/api/ws?token={}", server.addr, token);
+            let (mut socket, _) = tungstenite::client(url.as_str(), stream).unwrap();
+            let initial: Value =
+                serde_json::from_str(socket.read().unwrap().to_text().unwrap()).unwrap();
+            assert_eq!(initial["type"], "state");
+            socket
+        };
+        let mut removed = connect(&token);
+        let _kept = connect(&other_token);
+
+        server
+            .runtime
+            .block_on(server.control.remove_device(id))
+            .unwrap();
+
+        assert!(matches!(
+            removed.read(),
+            Ok(Message::Close(_)) | Err(tungstenite::Error::ConnectionClosed)
+        ));
+        assert_eq!(server.control.clients.lock().unwrap().len(), 1);
+        assert_eq!(server.control.clients.lock().unwrap()[0].0, other_id);
+        assert_eq!(server.request("GET", "/api/state", Some(&token), "").0, 401);
+    }
+}
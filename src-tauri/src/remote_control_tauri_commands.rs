@@ -0,0 +1,39 @@
+use crate::remote_control::{PairingCode, RemoteConfig, RemoteControl, RemoteDevice, RemoteStore};
+
+use std::sync::Arc;
+use tauri::State;
+
+#[tauri::command]
+pub fn remote_get_config(store: State<'_, Arc<RemoteStore>>) -> RemoteConfig {
+    store.config()
+}
+
+/// Saves the config and starts, restarts or stops the server to match it.
+#[tauri::command]
+pub async fn remote_set_config(
+    store: State<'_, Arc<RemoteStore>>,
+    control: State<'_, Arc<RemoteControl>>,
+    config: RemoteConfig,
+) -> Result<(), String> {
+    store.set_config(config).await.map_err(|e| e.to_string())?;
+    control.apply_config().map_err(|e| e.to_string())
+}
+
+/// Creates a code to show to the user, which pairs one device within `expires_in_secs`.
+#[tauri::command]
+pub fn remote_start_pairing(control: State<'_, Arc<RemoteControl>>) -> PairingCode {
+    control.start_pairing()
+}
+
+#[tauri::command]
+pub fn remote_list_devices(store: State<'_, Arc<RemoteStore>>) -> Vec<RemoteDevice> {
+    store.devices()
+}
+
+#[tauri::command]
+pub async fn remote_remove_device(
+    control: State<'_, Arc<RemoteControl>>,
+    id: i64,
+) -> Result<(), String> {
+    control.remove_device(id).await.map_err(|e| e.to_string())
+}
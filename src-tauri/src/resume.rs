@@ -0,0 +1,624 @@
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use std::sync::{Arc, Mutex};
+use tokio::runtime::Handle;
+
+use crate::database;
+use crate::mpv::{EndFileReason, MpvError, MpvEventId, MpvFormat, MpvPlayer};
+use crate::player_handle::FollowedPlayer;
+
+const CONFIG_KEY: &str = "resume";
+
+/// Positions are kept per time range of a file, so each track of a CUE sheet
+/// has its own. Whole files have a `segment_start` of 0.
+const COLUMNS: &str = "path, segment_start, position, duration, aid, sid, volume, updated_at";
+
+const CREATE_TABLE: &str = "CREATE TABLE IF NOT EXISTS playback_position (
+    path TEXT NOT NULL,
+    segment_start REAL NOT NULL DEFAULT 0,
+    position REAL NOT NULL,
+    duration REAL,
+    aid INTEGER,
+    sid INTEGER,
+    volume REAL,
+    updated_at INTEGER NOT NULL,
+    PRIMARY KEY (path, segment_start)
+)";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResumeConfig {
+    pub enabled: bool,
+    /// Positions this close to the start are not worth resuming from.
+    pub skip_first_secs: f64,
+    /// Positions this close to the end count as finished and are discarded.
+    pub skip_last_secs: f64,
+}
+
+impl Default for ResumeConfig {
+    fn default() -> Self {
+        ResumeConfig {
+            enabled: true,
+            skip_first_secs: 10.0,
+            skip_last_secs: 10.0,
+        }
+    }
+}
+
+impl ResumeConfig {
+    /// `position` and `duration` are relative to the start of the segment.
+    fn should_resume(&self, position: f64, duration: Option<f64>) -> bool {
+        position >= self.skip_first_secs
+            && !duration.is_some_and(|duration| position > duration - self.skip_last_secs)
+    }
+}
+
+/// A saved playback position, along with the track selection and volume at the time.
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct SavedPosition {
+    pub path: String,
+    /// Where the played range of the file starts; 0 unless it is a CUE sheet track.
+    pub segment_start: f64,
+    /// In seconds from the start of the file, not of the segment.
+    pub position: f64,
+    /// Of the segment.
+    pub duration: Option<f64>,
+    pub aid: Option<i64>,
+    pub sid: Option<i64>,
+    pub volume: Option<f64>,
+    pub updated_at: i64,
+}
+
+/// Playback positions persisted in the `playback_position` table of the app database.
+pub struct ResumeStore {
+    pool: SqlitePool,
+    config: Mutex<ResumeConfig>,
+}
+
+impl ResumeStore {
+    pub async fn new(pool: SqlitePool) -> Result<Self, sqlx::Error> {
+        sqlx::query(CREATE_TABLE).execute(&pool).await?;
+
+        let config = database::get_setting(&pool, CONFIG_KEY)
+            .await?
+            .unwrap_or_default();
+
+        Ok(Self {
+            pool,
+            config: Mutex::new(config),
+        })
+    }
+
+    pub fn config(&self) -> ResumeConfig {
+        self.config.lock().unwrap().clone()
+    }
+
+    pub async fn set_config(&self, config: ResumeConfig) -> Result<(), sqlx::Error> {
+        database::set_setting(&self.pool, CONFIG_KEY, &config).await?;
+        *self.config.lock().unwrap() = config;
+        Ok(())
+    }
+
+    /// Saves `saved`, or forgets the segment's position if it is outside the
+    /// resume thresholds.
+    pub async fn save(&self, saved: &SavedPosition) -> Result<(), sqlx::Error> {
+        let position = saved.position - saved.segment_start;
+        if !self.config().should_resume(position, saved.duration) {
+            return self.clear_segment(&saved.path, saved.segment_start).await;
+        }
+
+        sqlx::query(
+            "INSERT INTO playback_position
+                (path, segment_start, position, duration, aid, sid, volume, updated_at)
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?)
+             ON CONFLICT(path, segment_start) DO UPDATE SET
+                position = excluded.position,
+                duration = excluded.duration,
+                aid = excluded.aid,
+                sid = excluded.sid,
+                volume = excluded.volume,
+                updated_at = excluded.updated_at",
+        )
+        .bind(&saved.path)
+        .bind(saved.segment_start)
+        .bind(saved.position)
+        .bind(saved.duration)
+        .bind(saved.aid)
+        .bind(saved.sid)
+        .bind(saved.volume)
+        .bind(saved.updated_at)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn get(
+        &self,
+        path: &str,
+        segment_start: f64,
+    ) -> Result<Option<SavedPosition>, sqlx::Error> {
+        sqlx::query_as::<_, SavedPosition>(&format!(
+            "SELECT {} FROM playback_position WHERE path = ? AND segment_start = ?",
+            COLUMNS
+        ))
+        .bind(path)
+        .bind(segment_start)
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    /// All saved positions, most recently updated first.
+    pub async fn list(&self) -> Result<Vec<SavedPosition>, sqlx::Error> {
+        sqlx::query_as::<_, SavedPosition>(&format!(
+            "SELECT {} FROM playback_position ORDER BY updated_at DESC",
+            COLUMNS
+        ))
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    async fn clear_segment(&self, path: &str, segment_start: f64) -> Result<(), sqlx::Error> {
+        sqlx::query("DELETE FROM playback_position WHERE path = ? AND segment_start = ?")
+            .bind(path)
+            .bind(segment_start)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Forgets the positions of `path` (all of its segments), or of every file
+    /// if `path` is `None`.
+    pub async fn clear(&self, path: Option<&str>) -> Result<(), sqlx::Error> {
+        match path {
+            Some(path) => {
+                sqlx::query("DELETE FROM playback_position WHERE path = ?")
+                    .bind(path)
+                    .execute(&self.pool)
+                    .await?
+            }
+            None => {
+                sqlx::query("DELETE FROM playback_position")
+                    .execute(&self.pool)
+                    .await?
+            }
+        };
+        Ok(())
+    }
+}
+
+/// Last known state of the current file. Properties are gone by the time
+/// `EndFile` arrives, so they are cached as they change.
+#[derive(Default)]
+struct CurrentFile {
+    path: String,
+    /// The `start` and `end` options of the playlist entry, for CUE sheet tracks.
+    start: Option<f64>,
+    end: Option<f64>,
+    position: Option<f64>,
+    duration: Option<f64>,
+    aid: Option<i64>,
+    sid: Option<i64>,
+    volume: Option<f64>,
+}
+
+impl CurrentFile {
+    fn to_saved(&self) -> Option<SavedPosition> {
+        let segment_start = self.start.unwrap_or(0.0);
+        Some(SavedPosition {
+            path: self.path.clone(),
+            segment_start,
+            position: self.position?,
+            duration: self.end.or(self.duration).map(|end| end - segment_start),
+            aid: self.aid,
+            sid: self.sid,
+            volume: self.volume,
+            updated_at: database::unix_now(),
+        })
+    }
+}
+
+/// Saves the position of the playing file on pause, on stop and on shutdown, and
+/// restores it when the file is loaded again. Entries loaded with a `start`
+/// option (CUE sheet tracks) restore the position saved for that segment.
+pub struct ResumeTracker {
+    store: Arc<ResumeStore>,
+    runtime: Handle,
+    current: Mutex<Option<CurrentFile>>,
+    followed: FollowedPlayer,
+}
+
+impl ResumeTracker {
+    /// `runtime` runs the database queries, since the callbacks are called from
+    /// mpv's event thread.
+    pub fn new(store: Arc<ResumeStore>, runtime: Handle) -> Arc<Self> {
+        Arc::new(Self {
+            store,
+            runtime,
+            current: Mutex::new(None),
+            followed: FollowedPlayer::default(),
+        })
+    }
+
+    /// Hooks the tracker into `player`'s events and follows it from now on.
+    /// Called again for every new core and whenever a crossfade moves playback
+    /// to the other deck; a file already playing there is picked up where it is.
+    pub fn attach(self: &Arc<Self>, player: &Arc<MpvPlayer>) -> Result<(), MpvError> {
+        let new = self.followed.follow(player);
+
+        // The previously followed player stopped playing for the tracker
+        let previous = self.current.lock().unwrap().take();
+        self.persist(previous.and_then(|current| current.to_saved()));
+        if !player.is_idle().unwrap_or(true) {
+            *self.current.lock().unwrap() = Self::current_file(player);
+        }
+
+        if !new {
+            return Ok(());
+        }
+
+        let t = self.clone();
+        // Weak, since the player keeps its callbacks alive
+        let player_ref = Arc::downgrade(player);
+        player.register_event_callback(MpvEventId::FileLoaded, move |_| {
+            if !t.followed.is_followed(&player_ref) {
+                return;
+            }
+            if let Some(player) = player_ref.upgrade() {
+                t.on_file_loaded(&player);
+            }
+        })?;
+
+        let (t, p) = (self.clone(), Arc::downgrade(player));
+        player.register_event_callback(MpvEventId::EndFile, move |event| {
+            if t.followed.is_followed(&p) {
+                t.on_end_file(event.end_file_reason())
+            }
+        })?;
+
+        let (t, p) = (self.clone(), Arc::downgrade(player));
+        player.on_property_change("time-pos", MpvFormat::Double, move |value| {
+            if t.followed.is_followed(&p) {
+                t.update(|current| current.position = value.as_f64().or(current.position))
+            }
+        })?;
+
+        let (t, p) = (self.clone(), Arc::downgrade(player));
+        player.on_property_change("duration", MpvFormat::Double, move |value| {
+            if t.followed.is_followed(&p) {
+                t.update(|current| current.duration = value.as_f64())
+            }
+        })?;
+
+        let (t, p) = (self.clone(), Arc::downgrade(player));
+        player.on_property_change("aid", MpvFormat::Int64, move |value| {
+            if t.followed.is_followed(&p) {
+                t.update(|current| current.aid = value.as_i64())
+            }
+        })?;
+
+        let (t, p) = (self.clone(), Arc::downgrade(player));
+        player.on_property_change("sid", MpvFormat::Int64, move |value| {
+            if t.followed.is_followed(&p) {
+                t.update(|current| current.sid = value.as_i64())
+            }
+        })?;
+
+        let (t, p) = (self.clone(), Arc::downgrade(player));
+        player.on_property_change("volume", MpvFormat::Double, move |value| {
+            if t.followed.is_followed(&p) {
+                t.update(|current| current.volume = value.as_f64())
+            }
+        })?;
+
+        let (t, p) = (self.clone(), Arc::downgrade(player));
+        player.on_property_change("pause", MpvFormat::Flag, move |value| {
+            if t.followed.is_followed(&p) && value.as_bool() == Some(true) {
+                t.save_current();
+            }
+        })?;
+
+        Ok(())
+    }
+
+    fn update(&self, f: impl FnOnce(&mut CurrentFile)) {
+        if let Some(current) = self.current.lock().unwrap().as_mut() {
+            f(current);
+        }
+    }
+
+    /// The file `player` is playing, as far as it is known yet.
+    fn current_file(player: &MpvPlayer) -> Option<CurrentFile> {
+        Some(CurrentFile {
+            path: player.get_path().ok()?,
+            start: time_option(player, "start"),
+            end: time_option(player, "end"),
+            position: player.get_position().ok(),
+            duration: player.get_duration().ok(),
+            volume: player.get_volume().ok(),
+            ..Default::default()
+        })
+    }
+
+    /// Looks the position of the loaded file up on `runtime` and restores it
+    /// from there, so mpv's event thread doesn't wait for the database.
+    fn on_file_loaded(&self, player: &Arc<MpvPlayer>) {
+        let Some(current) = Self::current_file(player) else {
+            return;
+        };
+        let path = current.path.clone();
+        let segment_start = current.start.unwrap_or(0.0);
+        let segment_duration = current
+            .end
+            .or(current.duration)
+            .map(|end| end - segment_start);
+        *self.current.lock().unwrap() = Some(current);
+
+        let config = self.store.config();
+        if !config.enabled {
+            return;
+        }
+
+        let store = self.store.clone();
+        let player = Arc::downgrade(player);
+        self.runtime.spawn(async move {
+            let saved = match store.get(&path, segment_start).await {
+                Ok(Some(saved)) => saved,
+                Ok(None) => return,
+                Err(e) => {
+                    eprintln!("Failed to read saved position of {}: {}", path, e);
+                    return;
+                }
+            };
+
+            let duration = segment_duration.or(saved.duration);
+            if !config.should_resume(saved.position - segment_start, duration) {
+                return;
+            }
+            // Another file may have been loaded meanwhile
+            let Some(player) = player.upgrade() else {
+                return;
+            };
+            let loaded = Self::current_file(&player);
+            if !loaded.is_some_and(|l| l.path == path && l.start.unwrap_or(0.0) == segment_start) {
+                return;
+            }
+
+            if let Err(e) = Self::restore(&player, &saved) {
+                eprintln!("Failed to resume {}: {}", path, e);
+            }
+        });
+    }
+
+    fn restore(player: &MpvPlayer, saved: &SavedPosition) -> Result<(), MpvError> {
+        if let Some(aid) = saved.aid {
+            player.set_audio_track(aid)?;
+        }
+        if let Some(sid) = saved.sid {
+            player.set_subtitle_track(sid)?;
+        }
+        if let Some(volume) = saved.volume {
+            player.set_volume(volume)?;
+        }
+        player.seek(saved.position)
+    }
+
+    fn on_end_file(&self, reason: Option<EndFileReason>) {
+        let Some(current) = self.current.lock().unwrap().take() else {
+            return;
+        };
+
+        match reason {
+            Some(EndFileReason::Stop) | Some(EndFileReason::Quit) => {
+                self.persist(current.to_saved());
+            }
+            // Played to the end; there is nothing left to resume
+            Some(EndFileReason::Eof) => {
+                let store = self.store.clone();
+                let segment_start = current.start.unwrap_or(0.0);
+                self.runtime.spawn(async move {
+                    if let Err(e) = store.clear_segment(&current.path, segment_start).await {
+                        eprintln!("Failed to clear saved position: {}", e);
+                    }
+                });
+            }
+            _ => {}
+        }
+    }
+
+    fn save_current(&self) {
+        let saved = self
+            .current
+            .lock()
+            .unwrap()
+            .as_ref()
+            .and_then(|c| c.to_saved());
+        self.persist(saved);
+    }
+
+    fn persist(&self, saved: Option<SavedPosition>) {
+        let Some(saved) = saved else {
+            return;
+        };
+        if !self.store.config().enabled {
+            return;
+        }
+
+        let store = self.store.clone();
+        self.runtime.spawn(async move {
+            if let Err(e) = store.save(&saved).await {
+                eprintln!("Failed to save position of {}: {}", saved.path, e);
+            }
+        });
+    }
+
+    /// Saves the current position and waits for the write to finish. Called on
+    /// app exit, where a spawned task would not get to run.
+    pub fn save_now(&self) {
+        let saved = self
+            .current
+            .lock()
+            .unwrap()
+            .as_ref()
+            .and_then(|c| c.to_saved());
+        if let (Some(saved), true) = (saved, self.store.config().enabled) {
+            if let Err(e) = self.runtime.block_on(self.store.save(&saved)) {
+                eprintln!("Failed to save position of {}: {}", saved.path, e);
+            }
+        }
+    }
+}
+
+/// A time option of the playing entry, e.g. the `start` that `load_segment`
+/// sets. `None` if it isn't set (`none`) or is relative (`-10`, `50%`).
+fn time_option(player: &MpvPlayer, name: &str) -> Option<f64> {
+    player
+        .get_property_json(name)
+        .ok()?
+        .as_str()?
+        .trim_start_matches('+')
+        .parse()
+        .ok()
+        .filter(|time: &f64| *time >= 0.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::runtime::Runtime;
+
+    fn saved(path: &str, segment_start: f64, position: f64, duration: f64) -> SavedPosition {
+        SavedPosition {
+            path: path.to_string(),
+            segment_start,
+            position,
+            duration: Some(duration),
+            aid: Some(2),
+            sid: None,
+            volume: Some(80.0),
+            updated_at: database::unix_now(),
+        }
+    }
+
+    #[test]
+    fn resumes_only_between_the_skipped_start_and_end() {
+        let config = ResumeConfig::default();
+
+        assert!(!config.should_resume(9.9, Some(300.0)));
+        assert!(config.should_resume(10.0, Some(300.0)));
+        assert!(config.should_resume(290.0, Some(300.0)));
+        assert!(!config.should_resume(290.1, Some(300.0)));
+        // Without a duration only the start is checked
+        assert!(config.should_resume(1000.0, None));
+    }
+
+    #[test]
+    fn saves_the_duration_of_the_segment() {
+        let current = CurrentFile {
+            path: "album.flac".to_string(),
+            start: Some(120.0),
+            end: Some(300.0),
+            position: Some(150.0),
+            duration: Some(2400.0),
+            ..Default::default()
+        };
+        let saved = current.to_saved().unwrap();
+        assert_eq!(saved.segment_start, 120.0);
+        assert_eq!(saved.position, 150.0);
+        assert_eq!(saved.duration, Some(180.0));
+
+        // Nothing to save before the first position update
+        let current = CurrentFile {
+            position: None,
+            ..current
+        };
+        assert!(current.to_saved().is_none());
+    }
+
+    #[test]
+    fn keeps_a_position_per_segment() {
+        let dir = tempfile::tempdir().unwrap();
+        Runtime::new().unwrap().block_on(async {
+            let pool = database::connect(&dir.path().join("app.db")).await.unwrap();
+            let store = ResumeStore::new(pool).await.unwrap();
+
+            store
+                .save(&saved("album.flac", 0.0, 60.0, 120.0))
+                .await
+                .unwrap();
+            store
+                .save(&saved("album.flac", 120.0, 150.0, 180.0))
+                .await
+                .unwrap();
+
+            let first = store.get("album.flac", 0.0).await.unwrap().unwrap();
+            assert_eq!(first.position, 60.0);
+            assert_eq!(first.aid, Some(2));
+            assert_eq!(first.volume, Some(80.0));
+            let second = store.get("album.flac", 120.0).await.unwrap().unwrap();
+            assert_eq!(second.position, 150.0);
+            assert!(store.get("album.flac", 300.0).await.unwrap().is_none());
+
+            store.clear(Some("album.flac")).await.unwrap();
+            assert!(store.list().await.unwrap().is_empty());
+        });
+    }
+
+    #[test]
+    fn forgets_positions_outside_the_thresholds() {
+        let dir = tempfile::tempdir().unwrap();
+        Runtime::new().unwrap().block_on(async {
+            let pool = database::connect(&dir.path().join("app.db")).await.unwrap();
+            let store = ResumeStore::new(pool).await.unwrap();
+
+            store.save(&saved("a.mp3", 0.0, 60.0, 120.0)).await.unwrap();
+            // Near the end of the segment: counts as finished
+            store
+                .save(&saved("a.mp3", 0.0, 115.0, 120.0))
+                .await
+                .unwrap();
+            assert!(store.get("a.mp3", 0.0).await.unwrap().is_none());
+
+            // Relative to the start of the segment, 125 s into the file is only 5 s in
+            store
+                .save(&saved("b.flac", 120.0, 125.0, 180.0))
+                .await
+                .unwrap();
+            assert!(store.get("b.flac", 120.0).await.unwrap().is_none());
+            store
+                .save(&saved("b.flac", 120.0, 135.0, 180.0))
+                .await
+                .unwrap();
+            assert!(store.get("b.flac", 120.0).await.unwrap().is_some());
+        });
+    }
+
+    #[test]
+    fn keeps_the_config() {
+        let dir = tempfile::tempdir().unwrap();
+        Runtime::new().unwrap().block_on(async {
+            let db_path = dir.path().join("app.db");
+            let store = ResumeStore::new(database::connect(&db_path).await.unwrap())
+                .await
+                .unwrap();
+            let config = ResumeConfig {
+                enabled: true,
+                skip_first_secs: 30.0,
+                skip_last_secs: 0.0,
+            };
+            store.set_config(config).await.unwrap();
+
+            store.save(&saved("a.mp3", 0.0, 20.0, 120.0)).await.unwrap();
+            assert!(store.get("a.mp3", 0.0).await.unwrap().is_none());
+            store
+                .save(&saved("a.mp3", 0.0, 119.0, 120.0))
+                .await
+                .unwrap();
+            assert!(store.get("a.mp3", 0.0).await.unwrap().is_some());
+
+            let store = ResumeStore::new(database::connect(&db_path).await.unwrap())
+                .await
+                .unwrap();
+            assert_eq!(store.config().skip_first_secs, 30.0);
+        });
+    }
+}
@@ -0,0 +1,36 @@
+use crate::resume::{ResumeConfig, ResumeStore, SavedPosition};
+
+use std::sync::Arc;
+use tauri::State;
+
+#[tauri::command]
+pub fn resume_get_config(store: State<'_, Arc<ResumeStore>>) -> ResumeConfig {
+    store.config()
+}
+
+#[tauri::command]
+pub async fn resume_set_config(
+    store: State<'_, Arc<ResumeStore>>,
+    config: ResumeConfig,
+) -> Result<(), String> {
+    store.set_config(config).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub async fn resume_list_positions(
+    store: State<'_, Arc<ResumeStore>>,
+) -> Result<Vec<SavedPosition>, String> {
+    store.list().await.map_err(|e| e.to_string())
+}
+
+/// Clears the saved position of `path`, or all saved positions if no path is given.
+#[tauri::command]
+pub async fn resume_clear_positions(
+    store: State<'_, Arc<ResumeStore>>,
+    path: Option<String>,
+) -> Result<(), String> {
+    store
+        .clear(path.as_deref())
+        .await
+        .map_err(|e| e.to_string())
+}
@@ -0,0 +1,622 @@
+use serde::Serialize;
+use sqlx::SqlitePool;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+use tokio::runtime::Handle;
+use tokio::sync::mpsc;
+use walkdir::WalkDir;
+
+use crate::media_probe::ProbePool;
+use crate::metadata::{self, SimplifiedMetadata};
+
+/// Extensions of the files picked up by a scan.
+const AUDIO_EXTENSIONS: &[&str] = &[
+    "aac", "aif", "aifc", "aiff", "ape", "dff", "dsf", "flac", "m4a", "m4b", "mka", "mp2", "mp3",
+    "mpc", "oga", "ogg", "opus", "spx", "wav", "wma", "wv",
+];
+const VIDEO_EXTENSIONS: &[&str] = &[
+    "3gp", "avi", "flv", "m2ts", "m4v", "mkv", "mov", "mp4", "mpeg", "mpg", "mts", "ogv", "ts",
+    "webm", "wmv",
+];
+
+/// Results are sent once this many are pending...
+const BATCH_SIZE: usize = 50;
+/// ...or once this long has passed since the last event.
+const BATCH_INTERVAL: Duration = Duration::from_millis(250);
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScanStatus {
+    /// Looking for media files.
+    Walking,
+    /// Reading the metadata of the files found.
+    Parsing,
+    Done,
+    Cancelled,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ScanEntry {
+    pub path: String,
+    /// `None` if the file was skipped or couldn't be read.
+    pub metadata: Option<SimplifiedMetadata>,
+    /// The file is unchanged since it was last scanned; its `media_info` row is
+    /// up to date.
+    pub cached: bool,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ScanProgress {
+    pub scan_id: u64,
+    pub status: ScanStatus,
+    /// Media files found so far.
+    pub found: usize,
+    /// Files parsed or skipped so far.
+    pub done: usize,
+    /// Entries finished since the previous event.
+    pub entries: Vec<ScanEntry>,
+}
+
+/// Size and modification time of a file, as stored in `scan_cache` and `library_track`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FileStamp {
+    /// Unix timestamp (milliseconds).
+    pub modified: i64,
+    pub size: i64,
+}
+
+impl FileStamp {
+    pub fn read(path: &Path) -> std::io::Result<Self> {
+        let metadata = std::fs::metadata(path)?;
+        let modified = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as i64)
+            .unwrap_or(0);
+        Ok(Self {
+            modified,
+            size: metadata.len() as i64,
+        })
+    }
+}
+
+/// The stamps of scanned files in the `scan_cache` table of the app database,
+/// next to their `media_info` rows.
+pub struct ScanCache {
+    pool: SqlitePool,
+}
+
+impl ScanCache {
+    pub async fn new(pool: SqlitePool) -> Result<Self, sqlx::Error> {
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS scan_cache (
+                path TEXT PRIMARY KEY NOT NULL,
+                modified INTEGER NOT NULL,
+                size INTEGER NOT NULL
+            )",
+        )
+        .execute(&pool)
+        .await?;
+
+        Ok(Self { pool })
+    }
+
+    /// Whether `path` has a `media_info` row and is unchanged since it was scanned.
+    async fn is_fresh(&self, path: &str, stamp: FileStamp) -> Result<bool, sqlx::Error> {
+        let fresh: Option<i64> = sqlx::query_scalar(
+            "SELECT 1 FROM scan_cache
+             JOIN media_info ON media_info.path = scan_cache.path
+             WHERE scan_cache.path = ? AND scan_cache.modified = ? AND scan_cache.size = ?",
+        )
+        .bind(path)
+        .bind(stamp.modified)
+        .bind(stamp.size)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(fresh.is_some())
+    }
+
+    /// Writes the `media_info` row of `path` and remembers its stamp.
+    async fn save(
+        &self,
+        path: &str,
+        stamp: FileStamp,
+        metadata: &SimplifiedMetadata,
+    ) -> Result<(), sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(
+            "INSERT INTO media_info (path, title, artist, album, year, track, total_tracks,
+                disc, total_discs, genre, album_artist, duration, bitrate, sample_rate, channels,
+                bit_depth, is_video)
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+             ON CONFLICT(path) DO UPDATE SET
+                title = excluded.title,
+                artist = excluded.artist,
+                album = excluded.album,
+                year = excluded.year,
+                track = excluded.track,
+                total_tracks = excluded.total_tracks,
+                disc = excluded.disc,
+                total_discs = excluded.total_discs,
+                genre = excluded.genre,
+                album_artist = excluded.album_artist,
+                duration = excluded.duration,
+                bitrate = excluded.bitrate,
+                sample_rate = excluded.sample_rate,
+                channels = excluded.channels,
+                bit_depth = excluded.bit_depth,
+                is_video = excluded.is_video",
+        )
+        .bind(path)
+        .bind(display_title(path, metadata))
+        .bind(&metadata.artist)
+        .bind(&metadata.album)
+        .bind(metadata.year)
+        .bind(metadata.track)
+        .bind(metadata.total_tracks)
+        .bind(metadata.disc)
+        .bind(metadata.total_discs)
+        .bind(&metadata.genre)
+        .bind(metadata.album_artist())
+        .bind(metadata.duration)
+        .bind(metadata.bitrate)
+        .bind(metadata.sample_rate)
+        .bind(metadata.channels)
+        .bind(metadata.bit_depth)
+        .bind(metadata.is_video)
+        .execute(&mut tx)
+        .await?;
+
+        sqlx::query(
+            "INSERT INTO scan_cache (path, modified, size) VALUES (?, ?, ?)
+             ON CONFLICT(path) DO UPDATE SET
+                modified = excluded.modified,
+                size = excluded.size",
+        )
+        .bind(path)
+        .bind(stamp.modified)
+        .bind(stamp.size)
+        .execute(&mut tx)
+        .await?;
+
+        tx.commit().await
+    }
+}
+
+/// The title shown for `path`, as `getMediaInfo` picks it: the file name for
+/// videos, unless the container has a title, and the tag title for audio.
+fn display_title(path: &str, metadata: &SimplifiedMetadata) -> String {
+    let title = if metadata.is_video && metadata.container.is_none() {
+        None
+    } else {
+        metadata.title.clone()
+    };
+    title.unwrap_or_else(|| {
+        Path::new(path)
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| path.to_string())
+    })
+}
+
+/// Whether the extension is that of a video format. Audio-only MP4 files are
+/// expected to use `.m4a`.
+pub fn is_video_file(path: &Path) -> bool {
+    path.extension().is_some_and(|extension| {
+        VIDEO_EXTENSIONS.contains(&extension.to_string_lossy().to_lowercase().as_str())
+    })
+}
+
+/// Whether `path` has the extension of an audio or video format.
+pub fn is_media_file(path: &Path) -> bool {
+    path.extension().is_some_and(|extension| {
+        let extension = extension.to_string_lossy().to_lowercase();
+        AUDIO_EXTENSIONS.contains(&extension.as_str())
+            || VIDEO_EXTENSIONS.contains(&extension.as_str())
+    })
+}
+
+/// The media files in `paths`, which may be files or folders. Folders are only
+/// walked one level deep unless `recursive` is set. Sorted, without duplicates.
+pub fn collect_media_files(
+    paths: &[String],
+    recursive: bool,
+    cancelled: &AtomicBool,
+) -> Vec<PathBuf> {
+    let mut seen = HashSet::new();
+    let mut files = Vec::new();
+    for path in paths {
+        let walker = WalkDir::new(path).follow_links(true);
+        let walker = if recursive {
+            walker
+        } else {
+            walker.max_depth(1)
+        };
+        for entry in walker {
+            if cancelled.load(Ordering::SeqCst) {
+                return files;
+            }
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    eprintln!("Failed to read {}: {}", path, e);
+                    continue;
+                }
+            };
+            if entry.file_type().is_file()
+                && is_media_file(entry.path())
+                && seen.insert(entry.path().to_path_buf())
+            {
+                files.push(entry.into_path());
+            }
+        }
+    }
+    files.sort();
+    files
+}
+
+/// Walks folders for media files and reads their metadata on the blocking
+/// thread pool, several files at a time. Results are written to `media_info`
+/// and reported in batches; files unchanged since the last scan are not read again.
+pub struct Scanner {
+    probes: Arc<ProbePool>,
+    cache: Arc<ScanCache>,
+    runtime: Handle,
+    next_id: AtomicU64,
+    /// Cancellation flags of the running scans.
+    scans: Mutex<HashMap<u64, Arc<AtomicBool>>>,
+    on_progress: Box<dyn Fn(ScanProgress) + Send + Sync + 'static>,
+}
+
+impl Scanner {
+    /// `probes` are used for files lofty can't read.
+    pub fn new(
+        probes: Arc<ProbePool>,
+        cache: Arc<ScanCache>,
+        runtime: Handle,
+        on_progress: impl Fn(ScanProgress) + Send + Sync + 'static,
+    ) -> Arc<Self> {
+        Arc::new(Self {
+            probes,
+            cache,
+            runtime,
+            next_id: AtomicU64::new(1),
+            scans: Mutex::new(HashMap::new()),
+            on_progress: Box::new(on_progress),
+        })
+    }
+
+    /// Starts scanning `paths` and returns the id that its progress updates carry.
+    pub fn start(self: &Arc<Self>, paths: Vec<String>, recursive: bool) -> u64 {
+        let scan_id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let cancelled = Arc::new(AtomicBool::new(false));
+        self.scans
+            .lock()
+            .unwrap()
+            .insert(scan_id, cancelled.clone());
+
+        let scanner = self.clone();
+        self.runtime.spawn(async move {
+            scanner.run(scan_id, paths, recursive, cancelled).await;
+            scanner.scans.lock().unwrap().remove(&scan_id);
+        });
+        scan_id
+    }
+
+    /// Cancels the scan `scan_id`, or every running scan if it's not given.
+    pub fn cancel(&self, scan_id: Option<u64>) {
+        let scans = self.scans.lock().unwrap();
+        for (id, cancelled) in scans.iter() {
+            if scan_id.is_none_or(|scan_id| scan_id == *id) {
+                cancelled.store(true, Ordering::SeqCst);
+            }
+        }
+    }
+
+    async fn run(
+        &self,
+        scan_id: u64,
+        paths: Vec<String>,
+        recursive: bool,
+        cancelled: Arc<AtomicBool>,
+    ) {
+        let progress = |status, found, done, entries| {
+            (self.on_progress)(ScanProgress {
+                scan_id,
+                status,
+                found,
+                done,
+                entries,
+            })
+        };
+        progress(ScanStatus::Walking, 0, 0, Vec::new());
+
+        let flag = cancelled.clone();
+        let files =
+            tokio::task::spawn_blocking(move || collect_media_files(&paths, recursive, &flag))
+                .await
+                .unwrap_or_else(|e| {
+                    eprintln!("Failed to walk scan paths: {}", e);
+                    Vec::new()
+                });
+        let found = files.len();
+        if cancelled.load(Ordering::SeqCst) {
+            progress(ScanStatus::Cancelled, found, 0, Vec::new());
+            return;
+        }
+        progress(ScanStatus::Parsing, found, 0, Vec::new());
+
+        // Each worker takes the next file until none are left
+        let queue = Arc::new(Mutex::new(files.into_iter()));
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let workers = std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(4)
+            .min(found.max(1));
+        for _ in 0..workers {
+            let (queue, sender, cancelled) = (queue.clone(), sender.clone(), cancelled.clone());
+            let (cache, runtime, probes) = (
+                self.cache.clone(),
+                self.runtime.clone(),
+                self.probes.clone(),
+            );
+            tokio::task::spawn_blocking(move || {
+                while !cancelled.load(Ordering::SeqCst) {
+                    let Some(path) = queue.lock().unwrap().next() else {
+                        break;
+                    };
+                    let entry = scan_file(&cache, &runtime, &probes, &path);
+                    if sender.send(entry).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        // The channel closes once every worker is done
+        drop(sender);
+
+        let mut done = 0;
+        let mut batch = Vec::new();
+        let mut last_sent = Instant::now();
+        while let Some(entry) = receiver.recv().await {
+            done += 1;
+            batch.push(entry);
+            if batch.len() >= BATCH_SIZE || last_sent.elapsed() >= BATCH_INTERVAL {
+                progress(ScanStatus::Parsing, found, done, std::mem::take(&mut batch));
+                last_sent = Instant::now();
+            }
+        }
+
+        let status = if cancelled.load(Ordering::SeqCst) {
+            ScanStatus::Cancelled
+        } else {
+            ScanStatus::Done
+        };
+        progress(status, found, done, batch);
+    }
+}
+
+/// Reads the metadata of `path` unless it is cached, and saves it. Runs on a
+/// blocking thread.
+fn scan_file(
+    cache: &ScanCache,
+    runtime: &Handle,
+    probes: &Arc<ProbePool>,
+    path: &Path,
+) -> ScanEntry {
+    let path = path.to_string_lossy().into_owned();
+    let entry = |metadata, cached, error| ScanEntry {
+        path: path.clone(),
+        metadata,
+        cached,
+        error,
+    };
+
+    let stamp = match FileStamp::read(Path::new(&path)) {
+        Ok(stamp) => stamp,
+        Err(e) => return entry(None, false, Some(e.to_string())),
+    };
+    match runtime.block_on(cache.is_fresh(&path, stamp)) {
+        Ok(true) => return entry(None, true, None),
+        Ok(false) => {}
+        Err(e) => eprintln!("Failed to read scan cache of {}: {}", path, e),
+    }
+
+    let metadata = runtime.block_on(async {
+        metadata::parse_media_info(probes, &path)
+            .await
+            .map_err(|e| e.to_string())
+    });
+    let metadata = match metadata {
+        Ok(metadata) => metadata,
+        Err(e) => return entry(None, false, Some(e)),
+    };
+    if let Err(e) = runtime.block_on(cache.save(&path, stamp, &metadata)) {
+        eprintln!("Failed to save media info of {}: {}", path, e);
+    }
+    entry(Some(metadata), false, None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database;
+    use crate::mpv::test_support;
+    use std::fs;
+    use tokio::runtime::Runtime;
+
+    const MIGRATIONS: [&str; 2] = [
+        include_str!("../migrations/0000_secret_lucky_pierre.sql"),
+        include_str!("../migrations/0001_quiet_stardust.sql"),
+    ];
+
+    async fn cache(dir: &Path) -> ScanCache {
+        let pool = database::connect(&dir.join("app.db")).await.unwrap();
+        for migration in MIGRATIONS {
+            for statement in migration.split("--> statement-breakpoint") {
+                sqlx::query(statement).execute(&pool).await.unwrap();
+            }
+        }
+        ScanCache::new(pool).await.unwrap()
+    }
+
+    /// `music` with `a.mp3`, `B.FLAC`, `notes.txt` and `live/c.mkv`.
+    fn music(dir: &Path) -> PathBuf {
+        let music = dir.join("music");
+        fs::create_dir_all(music.join("live")).unwrap();
+        for name in ["a.mp3", "B.FLAC", "notes.txt", "live/c.mkv"] {
+            fs::write(music.join(name), b"").unwrap();
+        }
+        music
+    }
+
+    fn collect(paths: &[&Path], recursive: bool) -> Vec<PathBuf> {
+        let paths: Vec<String> = paths
+            .iter()
+            .map(|path| path.to_string_lossy().into_owned())
+            .collect();
+        collect_media_files(&paths, recursive, &AtomicBool::new(false))
+    }
+
+    #[test]
+    fn tells_media_files_by_extension() {
+        assert!(is_media_file(Path::new("/music/a.FLAC")));
+        assert!(is_media_file(Path::new("/videos/a.mkv")));
+        assert!(!is_media_file(Path::new("/music/cover.jpg")));
+        assert!(!is_media_file(Path::new("/music/flac")));
+        assert!(is_video_file(Path::new("/videos/a.MP4")));
+        assert!(!is_video_file(Path::new("/music/a.m4a")));
+    }
+
+    #[test]
+    fn collects_subfolders_only_when_recursive() {
+        let dir = tempfile::tempdir().unwrap();
+        let music = music(dir.path());
+
+        assert_eq!(
+            collect(&[&music], false),
+            [music.join("B.FLAC"), music.join("a.mp3")]
+        );
+        assert_eq!(
+            collect(&[&music], true),
+            [
+                music.join("B.FLAC"),
+                music.join("a.mp3"),
+                music.join("live/c.mkv")
+            ]
+        );
+    }
+
+    #[test]
+    fn collects_each_file_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let music = music(dir.path());
+        let missing = dir.path().join("missing");
+
+        let files = collect(
+            &[
+                &music.join("live/c.mkv"),
+                &missing,
+                &music,
+                &music.join("a.mp3"),
+            ],
+            true,
+        );
+        assert_eq!(
+            files,
+            [
+                music.join("B.FLAC"),
+                music.join("a.mp3"),
+                music.join("live/c.mkv")
+            ]
+        );
+        assert!(collect(&[&music.join("notes.txt")], true).is_empty());
+    }
+
+    #[test]
+    fn stops_collecting_when_cancelled() {
+        let dir = tempfile::tempdir().unwrap();
+        let music = music(dir.path());
+
+        let paths = [music.to_string_lossy().into_owned()];
+        assert!(collect_media_files(&paths, true, &AtomicBool::new(true)).is_empty());
+    }
+
+    #[test]
+    fn knows_which_files_are_unchanged() {
+        let runtime = Runtime::new().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let cache = runtime.block_on(cache(dir.path()));
+        let stamp = FileStamp {
+            modified: 1000,
+            size: 10,
+        };
+        let metadata = SimplifiedMetadata {
+            title: Some("Title".to_string()),
+            album_artists: Some(vec!["Band".to_string()]),
+            ..Default::default()
+        };
+
+        runtime.block_on(async {
+            assert!(!cache.is_fresh("a.mp3", stamp).await.unwrap());
+            cache.save("a.mp3", stamp, &metadata).await.unwrap();
+            assert!(cache.is_fresh("a.mp3", stamp).await.unwrap());
+
+            let touched = FileStamp {
+                modified: 2000,
+                ..stamp
+            };
+            let resized = FileStamp { size: 11, ..stamp };
+            assert!(!cache.is_fresh("a.mp3", touched).await.unwrap());
+            assert!(!cache.is_fresh("a.mp3", resized).await.unwrap());
+            assert!(!cache.is_fresh("b.mp3", stamp).await.unwrap());
+
+            // Saving again replaces the row and the stamp
+            cache.save("a.mp3", touched, &metadata).await.unwrap();
+            assert!(cache.is_fresh("a.mp3", touched).await.unwrap());
+            assert!(!cache.is_fresh("a.mp3", stamp).await.unwrap());
+            let row: (String, Option<String>) =
+                sqlx::query_as("SELECT title, album_artist FROM media_info WHERE path = ?")
+                    .bind("a.mp3")
+                    .fetch_one(&cache.pool)
+                    .await
+                    .unwrap();
+            assert_eq!(row, ("Title".to_string(), Some("Band".to_string())));
+
+            // A stamp without its row doesn't count
+            sqlx::query("DELETE FROM media_info")
+                .execute(&cache.pool)
+                .await
+                .unwrap();
+            assert!(!cache.is_fresh("a.mp3", touched).await.unwrap());
+        });
+    }
+
+    #[test]
+    fn skips_files_scanned_before() {
+        let runtime = Runtime::new().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let cache = runtime.block_on(cache(dir.path()));
+        let probes = ProbePool::new("");
+        let song = dir.path().join("song.wav");
+        fs::write(&song, test_support::sine_wav(8_000, 0, 800)).unwrap();
+
+        let first = scan_file(&cache, runtime.handle(), &probes, &song);
+        assert!(!first.cached);
+        assert_eq!(first.error, None);
+        assert!(first.metadata.is_some());
+
+        let second = scan_file(&cache, runtime.handle(), &probes, &song);
+        assert!(second.cached);
+        assert!(second.metadata.is_none());
+
+        fs::write(&song, test_support::sine_wav(8_000, 0, 1600)).unwrap();
+        let changed = scan_file(&cache, runtime.handle(), &probes, &song);
+        assert!(!changed.cached);
+        assert!(changed.metadata.is_some());
+    }
+}
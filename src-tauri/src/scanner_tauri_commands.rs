@@ -0,0 +1,22 @@
+use crate::scanner::Scanner;
+
+use std::sync::Arc;
+use tauri::State;
+
+/// Starts scanning `paths` (files or folders) for media files and returns the
+/// scan id. Folders are only searched one level deep unless `recursive` is set.
+/// Progress and metadata follow in batches in `scan-progress` events.
+#[tauri::command]
+pub fn scan_paths(
+    scanner: State<'_, Arc<Scanner>>,
+    paths: Vec<String>,
+    recursive: Option<bool>,
+) -> u64 {
+    scanner.start(paths, recursive.unwrap_or(false))
+}
+
+/// Cancels the scan `scan_id`, or every running scan if it's not given.
+#[tauri::command]
+pub fn scan_cancel(scanner: State<'_, Arc<Scanner>>, scan_id: Option<u64>) {
+    scanner.cancel(scan_id);
+}
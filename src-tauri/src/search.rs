@@ -0,0 +1,541 @@
+use serde::{Deserialize, Serialize};
+use sqlx::{Sqlite, SqlitePool, Transaction};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Tokens are folded to lower case without diacritics, so "beyonce" finds
+/// "Beyoncé". Prefix indexes make short prefix queries fast. `location` is the
+/// searchable path, while the unindexed `path` ties each row to its `media_info` row.
+const CREATE_INDEX: &str = "CREATE VIRTUAL TABLE IF NOT EXISTS media_search USING fts5(
+    title, artist, album, album_artist, genre, filename, location, path UNINDEXED,
+    tokenize = 'unicode61 remove_diacritics 2',
+    prefix = '2 3'
+)";
+
+/// Makes the `rank` column weigh the columns above, in order. Unlike calling
+/// `bm25()` directly, `rank` can be used in aggregates.
+const CONFIGURE_RANK: &str = "INSERT INTO media_search (media_search, rank)
+    VALUES ('rank', 'bm25(10.0, 6.0, 4.0, 4.0, 2.0, 3.0, 1.0, 0.0)')";
+
+pub const DEFAULT_LIMIT: u32 = 50;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct SearchFilters {
+    pub is_video: Option<bool>,
+    pub genre: Option<String>,
+    pub year: Option<i64>,
+    /// Only files in this folder or its subfolders.
+    pub folder: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct SearchTrack {
+    pub path: String,
+    pub title: String,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub album_artist: Option<String>,
+    pub genre: Option<String>,
+    pub year: Option<i64>,
+    pub duration: Option<f64>,
+    pub is_video: Option<bool>,
+    /// Lower is better.
+    pub rank: f64,
+}
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct SearchAlbum {
+    pub title: String,
+    /// The album artist, or the artist of the tracks if they don't name one.
+    pub artist: Option<String>,
+    pub year: Option<i64>,
+    pub tracks: i64,
+    /// A track of the album, to get its cover from.
+    pub cover_path: String,
+    pub rank: f64,
+}
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct SearchArtist {
+    pub name: String,
+    pub tracks: i64,
+    pub rank: f64,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SearchResults {
+    pub tracks: Vec<SearchTrack>,
+    /// Albums whose title or album artist matches.
+    pub albums: Vec<SearchAlbum>,
+    /// Artists whose name matches.
+    pub artists: Vec<SearchArtist>,
+}
+
+/// The last component of the path in `column`, in SQL. `media_info` paths may use
+/// either separator.
+fn file_name_sql(column: &str) -> String {
+    let path = format!("replace({}, '\\', '/')", column);
+    format!(
+        "replace({path}, rtrim({path}, replace({path}, '/', '')), '')",
+        path = path
+    )
+}
+
+/// A full-text index of `media_info` in the `media_search` FTS5 table.
+///
+/// Triggers on `media_info` keep it in step with every write to that table,
+/// whether from the webview, the scanner, the tag editor or the library watcher.
+/// Rows are matched to `media_info` by path rather than rowid, which VACUUM may
+/// renumber.
+pub struct SearchIndex {
+    pool: SqlitePool,
+    /// Whether the triggers exist.
+    ready: AtomicBool,
+}
+
+impl SearchIndex {
+    pub async fn new(pool: SqlitePool) -> Result<Self, sqlx::Error> {
+        drop_rowid_index(&pool).await?;
+        sqlx::query(CREATE_INDEX).execute(&pool).await?;
+        sqlx::query(CONFIGURE_RANK).execute(&pool).await?;
+        let index = Self {
+            pool,
+            ready: AtomicBool::new(false),
+        };
+        index.ensure_triggers().await?;
+        Ok(index)
+    }
+
+    /// Creates the triggers, and fills the index if they are new. `media_info`
+    /// and its `album_artist` column come from the Drizzle migrations, which the
+    /// webview runs after the backend has started, so on a first run this only
+    /// succeeds once a search is made. Returns whether the index is usable.
+    async fn ensure_triggers(&self) -> Result<bool, sqlx::Error> {
+        if self.ready.load(Ordering::SeqCst) {
+            return Ok(true);
+        }
+
+        let migrated: i64 = sqlx::query_scalar(
+            "SELECT COUNT(*) FROM pragma_table_info('media_info') WHERE name = 'album_artist'",
+        )
+        .fetch_one(&self.pool)
+        .await?;
+        if migrated == 0 {
+            return Ok(false);
+        }
+
+        let mut tx = self.pool.begin().await?;
+        let existing: i64 = sqlx::query_scalar(
+            "SELECT COUNT(*) FROM sqlite_master
+             WHERE type = 'trigger' AND name = 'media_search_insert'",
+        )
+        .fetch_one(&mut tx)
+        .await?;
+        if existing == 0 {
+            create_triggers(&mut tx).await?;
+            fill(&mut tx).await?;
+        }
+        tx.commit().await?;
+
+        self.ready.store(true, Ordering::SeqCst);
+        Ok(true)
+    }
+
+    /// Rebuilds the index from `media_info`.
+    pub async fn rebuild(&self) -> Result<(), sqlx::Error> {
+        if !self.ensure_triggers().await? {
+            return Ok(());
+        }
+        let mut tx = self.pool.begin().await?;
+        fill(&mut tx).await?;
+        tx.commit().await
+    }
+
+    /// Finds the tracks, albums and artists matching every word of `query`, as
+    /// prefixes, best first. At most `limit` of each are returned.
+    pub async fn search(
+        &self,
+        query: &str,
+        filters: &SearchFilters,
+        limit: u32,
+    ) -> Result<SearchResults, sqlx::Error> {
+        let Some(expression) = match_expression(query) else {
+            return Ok(SearchResults::default());
+        };
+        if !self.ensure_triggers().await? {
+            return Ok(SearchResults::default());
+        }
+
+        // Compared with forward slashes, as `media_info` paths may use either separator
+        let folder = filters
+            .folder
+            .as_deref()
+            .map(|folder| format!("{}/", folder.replace('\\', "/").trim_end_matches('/')));
+        // ?1 is the query, ?2 to ?5 the filters and ?6 the limit
+        let filter_sql = "(?2 IS NULL OR media_info.is_video = ?2)
+            AND (?3 IS NULL OR media_info.genre = ?3 COLLATE NOCASE)
+            AND (?4 IS NULL OR media_info.year = ?4)
+            AND (?5 IS NULL OR substr(replace(media_info.path, '\\', '/'), 1, length(?5)) = ?5)";
+
+        let tracks_sql = format!(
+            "SELECT media_info.path, media_info.title, media_info.artist, media_info.album,
+                media_info.album_artist, media_info.genre, media_info.year,
+                CAST(media_info.duration AS REAL) AS duration, media_info.is_video,
+                media_search.rank AS rank
+             FROM media_search
+             JOIN media_info ON media_info.path = media_search.path
+             WHERE media_search MATCH ?1 AND {filter_sql}
+             ORDER BY media_search.rank
+             LIMIT ?6",
+            filter_sql = filter_sql,
+        );
+        let albums_sql = format!(
+            "SELECT media_info.album AS title,
+                COALESCE(media_info.album_artist, media_info.artist) AS artist,
+                MIN(media_info.year) AS year, COUNT(*) AS tracks,
+                MIN(media_info.path) AS cover_path, MIN(media_search.rank) AS rank
+             FROM media_search
+             JOIN media_info ON media_info.path = media_search.path
+             WHERE media_search MATCH ?1 AND media_info.album IS NOT NULL AND {filter_sql}
+             GROUP BY media_info.album COLLATE NOCASE,
+                COALESCE(media_info.album_artist, media_info.artist) COLLATE NOCASE
+             ORDER BY rank
+             LIMIT ?6",
+            filter_sql = filter_sql,
+        );
+        let artists_sql = format!(
+            "SELECT media_info.artist AS name, COUNT(*) AS tracks,
+                MIN(media_search.rank) AS rank
+             FROM media_search
+             JOIN media_info ON media_info.path = media_search.path
+             WHERE media_search MATCH ?1 AND media_info.artist IS NOT NULL AND {filter_sql}
+             GROUP BY media_info.artist COLLATE NOCASE
+             ORDER BY rank
+             LIMIT ?6",
+            filter_sql = filter_sql,
+        );
+
+        let tracks = sqlx::query_as::<_, SearchTrack>(&tracks_sql)
+            .bind(&expression)
+            .bind(filters.is_video)
+            .bind(&filters.genre)
+            .bind(filters.year)
+            .bind(&folder)
+            .bind(limit)
+            .fetch_all(&self.pool)
+            .await?;
+        let albums = sqlx::query_as::<_, SearchAlbum>(&albums_sql)
+            .bind(format!("{{album album_artist}} : ({})", expression))
+            .bind(filters.is_video)
+            .bind(&filters.genre)
+            .bind(filters.year)
+            .bind(&folder)
+            .bind(limit)
+            .fetch_all(&self.pool)
+            .await?;
+        let artists = sqlx::query_as::<_, SearchArtist>(&artists_sql)
+            .bind(format!("{{artist}} : ({})", expression))
+            .bind(filters.is_video)
+            .bind(&filters.genre)
+            .bind(filters.year)
+            .bind(&folder)
+            .bind(limit)
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(SearchResults {
+            tracks,
+            albums,
+            artists,
+        })
+    }
+}
+
+/// Every word of `query` as a prefix, all required. Punctuation separates words,
+/// as it does for the tokenizer, and can't be used for FTS5 syntax.
+fn match_expression(query: &str) -> Option<String> {
+    let terms: Vec<String> = query
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| format!("\"{}\"*", word))
+        .collect();
+    (!terms.is_empty()).then(|| terms.join(" "))
+}
+
+const COLUMNS: &str = "title, artist, album, album_artist, genre, filename, location, path";
+
+/// Drops an index made before rows were keyed on the path, along with its
+/// triggers, so that it is created and filled again.
+async fn drop_rowid_index(pool: &SqlitePool) -> Result<(), sqlx::Error> {
+    let sql: Option<String> = sqlx::query_scalar(
+        "SELECT sql FROM sqlite_master WHERE type = 'table' AND name = 'media_search'",
+    )
+    .fetch_optional(pool)
+    .await?;
+    if sql.is_none_or(|sql| sql.contains("UNINDEXED")) {
+        return Ok(());
+    }
+
+    let mut tx = pool.begin().await?;
+    for statement in [
+        "DROP TRIGGER IF EXISTS media_search_insert",
+        "DROP TRIGGER IF EXISTS media_search_update",
+        "DROP TRIGGER IF EXISTS media_search_delete",
+        "DROP TABLE media_search",
+    ] {
+        sqlx::query(statement).execute(&mut tx).await?;
+    }
+    tx.commit().await
+}
+
+async fn create_triggers(tx: &mut Transaction<'_, Sqlite>) -> Result<(), sqlx::Error> {
+    let values = |row: &str| {
+        format!(
+            "{row}.title, {row}.artist, {row}.album, {row}.album_artist,
+             {row}.genre, {file_name}, {row}.path, {row}.path",
+            row = row,
+            file_name = file_name_sql(&format!("{}.path", row)),
+        )
+    };
+    // The delete before each insert also clears rows left behind by
+    // `INSERT OR REPLACE`, which doesn't fire delete triggers
+    let statements = [
+        format!(
+            "CREATE TRIGGER IF NOT EXISTS media_search_insert AFTER INSERT ON media_info BEGIN
+                DELETE FROM media_search WHERE path = new.path;
+                INSERT INTO media_search ({columns}) VALUES ({values});
+            END",
+            columns = COLUMNS,
+            values = values("new"),
+        ),
+        format!(
+            "CREATE TRIGGER IF NOT EXISTS media_search_update AFTER UPDATE ON media_info BEGIN
+                DELETE FROM media_search WHERE path = old.path;
+                DELETE FROM media_search WHERE path = new.path;
+                INSERT INTO media_search ({columns}) VALUES ({values});
+            END",
+            columns = COLUMNS,
+            values = values("new"),
+        ),
+        "CREATE TRIGGER IF NOT EXISTS media_search_delete AFTER DELETE ON media_info BEGIN
+            DELETE FROM media_search WHERE path = old.path;
+        END"
+        .to_string(),
+    ];
+    for statement in statements {
+        sqlx::query(&statement).execute(&mut *tx).await?;
+    }
+    Ok(())
+}
+
+async fn fill(tx: &mut Transaction<'_, Sqlite>) -> Result<(), sqlx::Error> {
+    sqlx::query("DELETE FROM media_search")
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query(&format!(
+        "INSERT INTO media_search ({columns})
+         SELECT title, artist, album, album_artist, genre, {file_name}, path, path
+         FROM media_info",
+        columns = COLUMNS,
+        file_name = file_name_sql("path"),
+    ))
+    .execute(&mut *tx)
+    .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database;
+    use tokio::runtime::Runtime;
+
+    const MIGRATIONS: [&str; 2] = [
+        include_str!("../migrations/0000_secret_lucky_pierre.sql"),
+        include_str!("../migrations/0001_quiet_stardust.sql"),
+    ];
+
+    async fn migrated_pool(dir: &std::path::Path) -> SqlitePool {
+        let pool = database::connect(&dir.join("app.db")).await.unwrap();
+        for migration in MIGRATIONS {
+            for statement in migration.split("--> statement-breakpoint") {
+                sqlx::query(statement).execute(&pool).await.unwrap();
+            }
+        }
+        pool
+    }
+
+    async fn add_media(pool: &SqlitePool, path: &str, title: &str) {
+        sqlx::query("INSERT INTO media_info (path, title) VALUES (?, ?)")
+            .bind(path)
+            .bind(title)
+            .execute(pool)
+            .await
+            .unwrap();
+    }
+
+    fn paths(results: &SearchResults) -> Vec<&str> {
+        let mut paths: Vec<&str> = results
+            .tracks
+            .iter()
+            .map(|track| track.path.as_str())
+            .collect();
+        paths.sort();
+        paths
+    }
+
+    #[test]
+    fn match_expression_requires_every_word_as_a_prefix() {
+        assert_eq!(
+            match_expression("daft  punk").as_deref(),
+            Some("\"daft\"* \"punk\"*")
+        );
+        assert_eq!(match_expression("Beyoncé").as_deref(), Some("\"Beyoncé\"*"));
+    }
+
+    #[test]
+    fn match_expression_splits_on_punctuation() {
+        assert_eq!(
+            match_expression("AC/DC \"back\" OR (black)*").as_deref(),
+            Some("\"AC\"* \"DC\"* \"back\"* \"OR\"* \"black\"*")
+        );
+        assert_eq!(match_expression(""), None);
+        assert_eq!(match_expression(" -*\"() "), None);
+    }
+
+    #[test]
+    fn file_name_sql_takes_the_last_component_of_either_separator() {
+        let runtime = Runtime::new().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        runtime.block_on(async {
+            let pool = database::connect(&dir.path().join("app.db")).await.unwrap();
+            let sql = format!("SELECT {}", file_name_sql("?1"));
+            for (path, file_name) in [
+                ("/music/album/01 intro.flac", "01 intro.flac"),
+                ("C:\\Music\\Album\\02.mp3", "02.mp3"),
+                ("D:\\Music/mixed\\03.ogg", "03.ogg"),
+                ("track.wav", "track.wav"),
+                ("/music/album/", ""),
+            ] {
+                let result: String = sqlx::query_scalar(&sql)
+                    .bind(path)
+                    .fetch_one(&pool)
+                    .await
+                    .unwrap();
+                assert_eq!(result, file_name, "{}", path);
+            }
+        });
+    }
+
+    #[test]
+    fn folder_filter_matches_either_separator() {
+        let runtime = Runtime::new().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        runtime.block_on(async {
+            let pool = migrated_pool(dir.path()).await;
+            let index = SearchIndex::new(pool.clone()).await.unwrap();
+            add_media(&pool, "C:\\Music\\Live\\song.mp3", "Song").await;
+            add_media(&pool, "C:/Music/Studio/song.mp3", "Song").await;
+            add_media(&pool, "C:\\Musical\\song.mp3", "Song").await;
+
+            for folder in ["C:\\Music", "C:/Music/", "C:\\Music\\"] {
+                let filters = SearchFilters {
+                    folder: Some(folder.to_string()),
+                    ..Default::default()
+                };
+                let results = index.search("song", &filters, DEFAULT_LIMIT).await.unwrap();
+                assert_eq!(
+                    paths(&results),
+                    vec!["C:/Music/Studio/song.mp3", "C:\\Music\\Live\\song.mp3"],
+                    "{}",
+                    folder
+                );
+            }
+        });
+    }
+
+    #[test]
+    fn removed_media_is_not_found() {
+        let runtime = Runtime::new().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        runtime.block_on(async {
+            let pool = migrated_pool(dir.path()).await;
+            let index = SearchIndex::new(pool.clone()).await.unwrap();
+            add_media(&pool, "/music/kept.flac", "Nocturne").await;
+            add_media(&pool, "/music/gone.flac", "Nocturne").await;
+            sqlx::query("DELETE FROM media_info WHERE path = '/music/gone.flac'")
+                .execute(&pool)
+                .await
+                .unwrap();
+
+            let results = index
+                .search("noct", &SearchFilters::default(), DEFAULT_LIMIT)
+                .await
+                .unwrap();
+            assert_eq!(paths(&results), vec!["/music/kept.flac"]);
+        });
+    }
+
+    #[test]
+    fn finds_media_after_vacuum_renumbers_rows() {
+        let runtime = Runtime::new().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        runtime.block_on(async {
+            let pool = migrated_pool(dir.path()).await;
+            let index = SearchIndex::new(pool.clone()).await.unwrap();
+            add_media(&pool, "/music/first.flac", "Prelude").await;
+            add_media(&pool, "/music/second.flac", "Nocturne").await;
+            add_media(&pool, "/music/third.flac", "Nocturne").await;
+            for statement in [
+                "DELETE FROM media_info WHERE path = '/music/first.flac'",
+                "VACUUM",
+                "UPDATE media_info SET title = 'Etude' WHERE path = '/music/second.flac'",
+            ] {
+                sqlx::query(statement).execute(&pool).await.unwrap();
+            }
+
+            let search = |query: &'static str| {
+                let index = &index;
+                async move {
+                    index
+                        .search(query, &SearchFilters::default(), DEFAULT_LIMIT)
+                        .await
+                        .unwrap()
+                }
+            };
+            assert_eq!(paths(&search("noct").await), vec!["/music/third.flac"]);
+            assert_eq!(paths(&search("etude").await), vec!["/music/second.flac"]);
+            assert!(search("prelude").await.tracks.is_empty());
+        });
+    }
+
+    #[test]
+    fn replaces_an_index_keyed_on_rowids() {
+        let runtime = Runtime::new().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        runtime.block_on(async {
+            let pool = migrated_pool(dir.path()).await;
+            sqlx::query("CREATE VIRTUAL TABLE media_search USING fts5(title, path)")
+                .execute(&pool)
+                .await
+                .unwrap();
+            sqlx::query(
+                "CREATE TRIGGER media_search_insert AFTER INSERT ON media_info BEGIN
+                    INSERT INTO media_search (rowid, title, path) VALUES (new.rowid, new.title, new.path);
+                END",
+            )
+            .execute(&pool)
+            .await
+            .unwrap();
+            add_media(&pool, "/music/old.flac", "Nocturne").await;
+
+            let index = SearchIndex::new(pool.clone()).await.unwrap();
+            add_media(&pool, "/music/new.flac", "Nocturne").await;
+            let results = index
+                .search("noct", &SearchFilters::default(), DEFAULT_LIMIT)
+                .await
+                .unwrap();
+            assert_eq!(paths(&results), vec!["/music/new.flac", "/music/old.flac"]);
+        });
+    }
+}
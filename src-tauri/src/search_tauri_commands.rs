@@ -0,0 +1,29 @@
+use crate::search::{SearchFilters, SearchIndex, SearchResults, DEFAULT_LIMIT};
+
+use std::sync::Arc;
+use tauri::State;
+
+/// Finds the tracks, albums and artists matching every word of `query` as a
+/// prefix, best first, at most `limit` (50 by default) of each.
+#[tauri::command]
+pub async fn search_library(
+    index: State<'_, Arc<SearchIndex>>,
+    query: String,
+    filters: Option<SearchFilters>,
+    limit: Option<u32>,
+) -> Result<SearchResults, String> {
+    index
+        .search(
+            &query,
+            &filters.unwrap_or_default(),
+            limit.unwrap_or(DEFAULT_LIMIT),
+        )
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// Rebuilds the search index from `media_info`.
+#[tauri::command]
+pub async fn search_rebuild(index: State<'_, Arc<SearchIndex>>) -> Result<(), String> {
+    index.rebuild().await.map_err(|e| e.to_string())
+}
@@ -0,0 +1,536 @@
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+use crate::mpv::{EndFileReason, MpvError, MpvEventId, MpvFormat, MpvPlayer};
+use crate::player_handle::FollowedPlayer;
+
+/// Longest gap between position updates that still counts as playback time;
+/// longer gaps mean mpv was stalled (e.g. buffering) rather than playing.
+const MAX_TICK: Duration = Duration::from_secs(1);
+
+/// Smallest volume step applied while fading, to avoid setting it on every frame.
+const FADE_STEP: f64 = 0.5;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum SleepTimerError {
+    #[error("No sleep timer is running")]
+    NotRunning,
+
+    #[error("A timer in minutes is extended by minutes")]
+    MinutesExpected,
+
+    #[error("A timer at the end of a file is extended by entries")]
+    EntriesExpected,
+}
+
+impl SleepTimerError {
+    /// Stable name of the variant, e.g. `not_running`.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            SleepTimerError::NotRunning => "not_running",
+            SleepTimerError::MinutesExpected => "minutes_expected",
+            SleepTimerError::EntriesExpected => "entries_expected",
+        }
+    }
+}
+
+// Serialized as `{ kind, message }`, like `MpvError`
+impl Serialize for SleepTimerError {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::ser::Serializer,
+    {
+        use serde::ser::SerializeStruct;
+
+        let mut state = serializer.serialize_struct("SleepTimerError", 2)?;
+        state.serialize_field("kind", self.kind())?;
+        state.serialize_field("message", &self.to_string())?;
+        state.end()
+    }
+}
+
+/// When the sleep timer pauses playback.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum SleepTimerMode {
+    /// After this many minutes of playback. Time spent paused doesn't count.
+    Minutes { minutes: f64 },
+    /// When the current file ends.
+    EndOfFile,
+    /// When the current file and `count` more entries have ended.
+    Entries { count: u32 },
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SleepTimerStatus {
+    pub mode: SleepTimerMode,
+    /// Playback time until the timer fires, if known: always for `Minutes`,
+    /// otherwise once the last file is playing.
+    pub remaining_secs: Option<f64>,
+    /// Entries to play after the current one, for `EndOfFile` and `Entries`.
+    pub remaining_entries: Option<u32>,
+    pub fade_secs: f64,
+    pub fading: bool,
+}
+
+struct Timer {
+    mode: SleepTimerMode,
+    /// Playback time left, for `Minutes`.
+    remaining_secs: f64,
+    /// Files that have to end, counting the current one, for the other modes.
+    files_left: u32,
+    fade_secs: f64,
+    last_tick: Option<Instant>,
+    /// Volume before the fade started, restored once the timer fires or is cancelled.
+    fade_from: Option<f64>,
+    /// Volume last set by the fade.
+    fade_volume: Option<f64>,
+}
+
+impl Timer {
+    /// The volume to set `remaining` seconds before the timer fires, in
+    /// proportion to the time left in the fade, or `None` to leave it be.
+    /// `volume` reads the current one when the fade starts.
+    fn fade_step(&mut self, remaining: f64, volume: impl FnOnce() -> Option<f64>) -> Option<f64> {
+        if self.fade_secs <= 0.0 || remaining > self.fade_secs {
+            return None;
+        }
+        let from = match self.fade_from {
+            Some(from) => from,
+            None => *self.fade_from.insert(volume()?),
+        };
+        let volume = from * (remaining / self.fade_secs).clamp(0.0, 1.0);
+        if self
+            .fade_volume
+            .is_some_and(|last| (last - volume).abs() < FADE_STEP)
+        {
+            return None;
+        }
+        self.fade_volume = Some(volume);
+        Some(volume)
+    }
+}
+
+#[derive(Default)]
+struct Shared {
+    timer: Option<Timer>,
+    paused: bool,
+    position: Option<f64>,
+    duration: Option<f64>,
+}
+
+impl Shared {
+    fn remaining_secs(&self) -> Option<f64> {
+        let timer = self.timer.as_ref()?;
+        match timer.mode {
+            SleepTimerMode::Minutes { .. } => Some(timer.remaining_secs.max(0.0)),
+            _ if timer.files_left == 1 => self
+                .duration
+                .zip(self.position)
+                .map(|(duration, position)| (duration - position).max(0.0)),
+            _ => None,
+        }
+    }
+
+    fn status(&self) -> Option<SleepTimerStatus> {
+        let timer = self.timer.as_ref()?;
+        Some(SleepTimerStatus {
+            mode: timer.mode.clone(),
+            remaining_secs: self.remaining_secs(),
+            remaining_entries: match timer.mode {
+                SleepTimerMode::Minutes { .. } => None,
+                _ => Some(timer.files_left.saturating_sub(1)),
+            },
+            fade_secs: timer.fade_secs,
+            fading: timer.fade_from.is_some(),
+        })
+    }
+}
+
+/// Pauses playback after a while, optionally fading the volume out over the
+/// final seconds. Driven by the position and `EndFile` events of the player.
+pub struct SleepTimer {
+    shared: Mutex<Shared>,
+    player: FollowedPlayer,
+    on_fire: Box<dyn Fn() + Send + Sync + 'static>,
+}
+
+impl SleepTimer {
+    /// `on_fire` is called after the timer paused playback.
+    pub fn new(on_fire: impl Fn() + Send + Sync + 'static) -> Arc<Self> {
+        Arc::new(Self {
+            shared: Mutex::new(Shared::default()),
+            player: FollowedPlayer::default(),
+            on_fire: Box::new(on_fire),
+        })
+    }
+
+    /// Follows `player` from now on. Called again for every new core and
+    /// whenever a crossfade moves playback to the other deck.
+    pub fn attach(self: &Arc<Self>, player: &Arc<MpvPlayer>) -> Result<(), MpvError> {
+        let new = self.player.follow(player);
+        {
+            let mut shared = self.shared.lock().unwrap();
+            shared.paused = player.is_paused().unwrap_or(true);
+            shared.position = player.get_position().ok();
+            shared.duration = player.get_duration().ok();
+        }
+
+        if !new {
+            return Ok(());
+        }
+
+        // Same formats as `PlayerStateTracker`, which observes these too
+        let (timer, p) = (Arc::downgrade(self), Arc::downgrade(player));
+        player.on_property_change("pause", MpvFormat::Flag, move |value| {
+            let Some(timer) = timer.upgrade().filter(|t| t.player.is_followed(&p)) else {
+                return;
+            };
+            let mut shared = timer.shared.lock().unwrap();
+            shared.paused = value.as_bool().unwrap_or(shared.paused);
+            if let Some(t) = shared.timer.as_mut() {
+                t.last_tick = None;
+            }
+        })?;
+
+        let (timer, p) = (Arc::downgrade(self), Arc::downgrade(player));
+        player.on_property_change("duration", MpvFormat::Double, move |value| {
+            if let Some(timer) = timer.upgrade().filter(|t| t.player.is_followed(&p)) {
+                timer.shared.lock().unwrap().duration = value.as_f64();
+            }
+        })?;
+
+        let (timer, p) = (Arc::downgrade(self), Arc::downgrade(player));
+        player.on_property_change("time-pos", MpvFormat::Double, move |value| {
+            if let Some(timer) = timer.upgrade().filter(|t| t.player.is_followed(&p)) {
+                timer.on_position(value.as_f64());
+            }
+        })?;
+
+        let (timer, p) = (Arc::downgrade(self), Arc::downgrade(player));
+        player.register_event_callback(MpvEventId::EndFile, move |event| {
+            if let Some(timer) = timer.upgrade().filter(|t| t.player.is_followed(&p)) {
+                timer.on_end_file(event.end_file_reason());
+            }
+        })?;
+
+        Ok(())
+    }
+
+    /// Starts the timer, replacing a running one.
+    pub fn set(&self, mode: SleepTimerMode, fade_secs: f64) -> SleepTimerStatus {
+        self.cancel();
+
+        let mut shared = self.shared.lock().unwrap();
+        shared.timer = Some(Timer {
+            remaining_secs: match mode {
+                SleepTimerMode::Minutes { minutes } => minutes.max(0.0) * 60.0,
+                _ => 0.0,
+            },
+            files_left: match mode {
+                SleepTimerMode::Minutes { .. } => 0,
+                SleepTimerMode::EndOfFile => 1,
+                SleepTimerMode::Entries { count } => count + 1,
+            },
+            mode,
+            fade_secs: fade_secs.max(0.0),
+            last_tick: None,
+            fade_from: None,
+            fade_volume: None,
+        });
+        shared.status().unwrap()
+    }
+
+    /// Adds `minutes` to a `Minutes` timer or `entries` to the others. A fade
+    /// in progress is undone if the timer now ends later than the fade.
+    pub fn extend(
+        &self,
+        minutes: Option<f64>,
+        entries: Option<u32>,
+    ) -> Result<SleepTimerStatus, SleepTimerError> {
+        let mut shared = self.shared.lock().unwrap();
+        let Some(timer) = shared.timer.as_mut() else {
+            return Err(SleepTimerError::NotRunning);
+        };
+
+        timer.mode = match (timer.mode.clone(), minutes, entries) {
+            (SleepTimerMode::Minutes { minutes: total }, Some(minutes), _) => {
+                timer.remaining_secs += minutes * 60.0;
+                SleepTimerMode::Minutes {
+                    minutes: total + minutes,
+                }
+            }
+            (SleepTimerMode::EndOfFile, _, Some(entries)) => {
+                timer.files_left += entries;
+                SleepTimerMode::Entries { count: entries }
+            }
+            (SleepTimerMode::Entries { count }, _, Some(entries)) => {
+                timer.files_left += entries;
+                SleepTimerMode::Entries {
+                    count: count + entries,
+                }
+            }
+            (SleepTimerMode::Minutes { .. }, ..) => return Err(SleepTimerError::MinutesExpected),
+            _ => return Err(SleepTimerError::EntriesExpected),
+        };
+
+        let fading = shared
+            .remaining_secs()
+            .zip(shared.timer.as_ref().map(|t| t.fade_secs))
+            .is_some_and(|(remaining, fade_secs)| remaining <= fade_secs);
+        if !fading {
+            self.restore_volume(&mut shared);
+        }
+        Ok(shared.status().unwrap())
+    }
+
+    /// Stops the timer and restores the volume if it was fading.
+    pub fn cancel(&self) {
+        let mut shared = self.shared.lock().unwrap();
+        self.restore_volume(&mut shared);
+        shared.timer = None;
+    }
+
+    pub fn status(&self) -> Option<SleepTimerStatus> {
+        self.shared.lock().unwrap().status()
+    }
+
+    fn on_position(&self, position: Option<f64>) {
+        let mut shared = self.shared.lock().unwrap();
+        shared.position = position;
+
+        let paused = shared.paused;
+        let Some(timer) = shared.timer.as_mut() else {
+            return;
+        };
+        if let SleepTimerMode::Minutes { .. } = timer.mode {
+            if !paused {
+                let now = Instant::now();
+                if let Some(last_tick) = timer.last_tick {
+                    timer.remaining_secs -=
+                        now.duration_since(last_tick).min(MAX_TICK).as_secs_f64();
+                }
+                timer.last_tick = Some(now);
+            }
+        }
+
+        let Some(remaining) = shared.remaining_secs() else {
+            return;
+        };
+        let is_minutes = matches!(
+            shared.timer.as_ref().map(|t| &t.mode),
+            Some(SleepTimerMode::Minutes { .. })
+        );
+        // File based timers fire on `EndFile`, so the file plays to its end
+        if is_minutes && remaining <= 0.0 {
+            drop(shared);
+            self.fire();
+            return;
+        }
+        self.fade(&mut shared, remaining);
+    }
+
+    fn on_end_file(&self, reason: Option<EndFileReason>) {
+        let mut shared = self.shared.lock().unwrap();
+        shared.position = None;
+        shared.duration = None;
+
+        // Files that were skipped or failed don't count
+        if reason != Some(EndFileReason::Eof) {
+            return;
+        }
+        let Some(timer) = shared.timer.as_mut() else {
+            return;
+        };
+        if let SleepTimerMode::Minutes { .. } = timer.mode {
+            return;
+        }
+
+        timer.files_left = timer.files_left.saturating_sub(1);
+        if timer.files_left == 0 {
+            drop(shared);
+            self.fire();
+        }
+    }
+
+    /// Lowers the volume in proportion to the time left in the fade.
+    fn fade(&self, shared: &mut Shared, remaining: f64) {
+        let (Some(timer), Some(player)) = (shared.timer.as_mut(), self.player.get()) else {
+            return;
+        };
+        let Some(volume) = timer.fade_step(remaining, || player.get_volume().ok()) else {
+            return;
+        };
+        if let Err(e) = player.set_volume(volume) {
+            eprintln!("Failed to fade out volume: {}", e);
+        }
+    }
+
+    fn restore_volume(&self, shared: &mut Shared) {
+        let Some(timer) = shared.timer.as_mut() else {
+            return;
+        };
+        timer.fade_volume = None;
+        let Some(volume) = timer.fade_from.take() else {
+            return;
+        };
+        if let Some(player) = self.player.get() {
+            if let Err(e) = player.set_volume(volume) {
+                eprintln!("Failed to restore volume: {}", e);
+            }
+        }
+    }
+
+    /// Pauses, restores the volume and ends the timer.
+    fn fire(&self) {
+        {
+            let mut shared = self.shared.lock().unwrap();
+            if shared.timer.is_none() {
+                return;
+            }
+            if let Some(player) = self.player.get() {
+                if let Err(e) = player.pause() {
+                    eprintln!("Failed to pause for sleep timer: {}", e);
+                }
+            }
+            self.restore_volume(&mut shared);
+            shared.timer = None;
+        }
+        (self.on_fire)();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn timer() -> (Arc<SleepTimer>, Arc<AtomicUsize>) {
+        let fired = Arc::new(AtomicUsize::new(0));
+        let counter = fired.clone();
+        let timer = SleepTimer::new(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+        (timer, fired)
+    }
+
+    #[test]
+    fn extends_each_mode_by_its_unit() {
+        let (timer, _) = timer();
+        assert_eq!(
+            timer.extend(Some(5.0), None).unwrap_err(),
+            SleepTimerError::NotRunning
+        );
+
+        timer.set(SleepTimerMode::Minutes { minutes: 10.0 }, 0.0);
+        let status = timer.extend(Some(5.0), None).unwrap();
+        assert_eq!(status.mode, SleepTimerMode::Minutes { minutes: 15.0 });
+        assert_eq!(status.remaining_secs, Some(900.0));
+        assert_eq!(status.remaining_entries, None);
+        assert_eq!(
+            timer.extend(None, Some(1)).unwrap_err(),
+            SleepTimerError::MinutesExpected
+        );
+
+        timer.set(SleepTimerMode::EndOfFile, 0.0);
+        assert_eq!(timer.status().unwrap().remaining_entries, Some(0));
+        assert_eq!(
+            timer.extend(Some(5.0), None).unwrap_err(),
+            SleepTimerError::EntriesExpected
+        );
+        let status = timer.extend(None, Some(2)).unwrap();
+        assert_eq!(status.mode, SleepTimerMode::Entries { count: 2 });
+        assert_eq!(status.remaining_entries, Some(2));
+        let status = timer.extend(None, Some(1)).unwrap();
+        assert_eq!(status.mode, SleepTimerMode::Entries { count: 3 });
+        assert_eq!(status.remaining_entries, Some(3));
+
+        timer.cancel();
+        assert!(timer.status().is_none());
+    }
+
+    #[test]
+    fn knows_the_remaining_time() {
+        let (timer, _) = timer();
+        timer.set(SleepTimerMode::Minutes { minutes: 1.0 }, 0.0);
+        {
+            let mut shared = timer.shared.lock().unwrap();
+            assert_eq!(shared.remaining_secs(), Some(60.0));
+            shared.timer.as_mut().unwrap().remaining_secs = -2.0;
+            assert_eq!(shared.remaining_secs(), Some(0.0));
+        }
+
+        timer.set(SleepTimerMode::Entries { count: 1 }, 0.0);
+        let mut shared = timer.shared.lock().unwrap();
+        shared.position = Some(30.0);
+        shared.duration = Some(100.0);
+        // Unknown until the last file plays
+        assert_eq!(shared.remaining_secs(), None);
+        shared.timer.as_mut().unwrap().files_left = 1;
+        assert_eq!(shared.remaining_secs(), Some(70.0));
+        shared.duration = None;
+        assert_eq!(shared.remaining_secs(), None);
+    }
+
+    #[test]
+    fn counts_only_files_that_played_to_their_end() {
+        let (timer, fired) = timer();
+        timer.set(SleepTimerMode::Entries { count: 1 }, 0.0);
+
+        timer.on_end_file(Some(EndFileReason::Stop));
+        timer.on_end_file(Some(EndFileReason::Error));
+        timer.on_end_file(None);
+        assert_eq!(timer.status().unwrap().remaining_entries, Some(1));
+
+        timer.on_end_file(Some(EndFileReason::Eof));
+        assert_eq!(timer.status().unwrap().remaining_entries, Some(0));
+        assert_eq!(fired.load(Ordering::SeqCst), 0);
+
+        timer.on_end_file(Some(EndFileReason::Eof));
+        assert!(timer.status().is_none());
+        assert_eq!(fired.load(Ordering::SeqCst), 1);
+
+        // A timer in minutes doesn't count files
+        timer.set(SleepTimerMode::Minutes { minutes: 1.0 }, 0.0);
+        timer.on_end_file(Some(EndFileReason::Eof));
+        assert!(timer.status().is_some());
+        assert_eq!(fired.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn fades_the_volume_in_steps() {
+        let (timer, _) = timer();
+        timer.set(SleepTimerMode::Minutes { minutes: 1.0 }, 10.0);
+        let mut shared = timer.shared.lock().unwrap();
+        let timer = shared.timer.as_mut().unwrap();
+
+        // Nothing before the fade
+        assert_eq!(timer.fade_step(11.0, || Some(80.0)), None);
+        assert_eq!(timer.fade_from, None);
+
+        assert_eq!(timer.fade_step(10.0, || Some(80.0)), Some(80.0));
+        // The volume is only read when the fade starts
+        assert_eq!(timer.fade_step(5.0, || Some(20.0)), Some(40.0));
+        // Changes smaller than a step are skipped
+        assert_eq!(timer.fade_step(4.99, || None), None);
+        assert_eq!(timer.fade_step(2.5, || None), Some(20.0));
+        assert_eq!(timer.fade_step(0.0, || None), Some(0.0));
+        assert_eq!(timer.fade_step(-1.0, || None), None);
+        assert_eq!(timer.fade_from, Some(80.0));
+    }
+
+    #[test]
+    fn doesnt_fade_without_a_fade_time_or_volume() {
+        let (timer, _) = timer();
+        timer.set(SleepTimerMode::EndOfFile, 0.0);
+        let mut shared = timer.shared.lock().unwrap();
+        let t = shared.timer.as_mut().unwrap();
+        assert_eq!(t.fade_step(0.0, || Some(80.0)), None);
+
+        t.fade_secs = 5.0;
+        assert_eq!(t.fade_step(1.0, || None), None);
+        assert_eq!(t.fade_from, None);
+    }
+}
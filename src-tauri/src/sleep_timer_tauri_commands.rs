@@ -0,0 +1,35 @@
+use crate::sleep_timer::{SleepTimer, SleepTimerError, SleepTimerMode, SleepTimerStatus};
+
+use std::sync::Arc;
+use tauri::State;
+
+/// Starts the sleep timer, replacing a running one. The volume fades out over
+/// the last `fade_secs` seconds, if given.
+#[tauri::command]
+pub fn sleep_timer_set(
+    timer: State<'_, Arc<SleepTimer>>,
+    mode: SleepTimerMode,
+    fade_secs: Option<f64>,
+) -> SleepTimerStatus {
+    timer.set(mode, fade_secs.unwrap_or(0.0))
+}
+
+#[tauri::command]
+pub fn sleep_timer_extend(
+    timer: State<'_, Arc<SleepTimer>>,
+    minutes: Option<f64>,
+    entries: Option<u32>,
+) -> Result<SleepTimerStatus, SleepTimerError> {
+    timer.extend(minutes, entries)
+}
+
+#[tauri::command]
+pub fn sleep_timer_cancel(timer: State<'_, Arc<SleepTimer>>) {
+    timer.cancel();
+}
+
+/// The running timer, or `None` if there is none.
+#[tauri::command]
+pub fn sleep_timer_get(timer: State<'_, Arc<SleepTimer>>) -> Option<SleepTimerStatus> {
+    timer.status()
+}
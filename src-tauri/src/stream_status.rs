@@ -0,0 +1,309 @@
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::mpv::{MpvError, MpvFormat, MpvPlayer, StreamStatus};
+
+/// `demuxer-cache-state` changes many times per second while a stream is
+/// playing; updates that only change the cache figures are sent at most this often.
+const MIN_UPDATE_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Properties that make up `StreamStatus`, with the format they are observed in.
+const WATCHED_PROPERTIES: [(&str, MpvFormat); 5] = [
+    ("paused-for-cache", MpvFormat::Flag),
+    ("cache-buffering-state", MpvFormat::Int64),
+    ("seekable", MpvFormat::Flag),
+    ("demuxer-cache-duration", MpvFormat::Double),
+    ("demuxer-cache-state", MpvFormat::String),
+];
+
+struct LastUpdate {
+    status: Option<StreamStatus>,
+    sent_at: Instant,
+    /// The latest status held back by the throttle, sent when the interval is over.
+    pending: Option<StreamStatus>,
+    flush_scheduled: bool,
+}
+
+/// Passes statuses on to a callback, throttling those that only change the
+/// cache figures. The last status held back is always sent in the end, so the
+/// callback ends up with the final state.
+struct Throttle<F> {
+    callback: F,
+    interval: Duration,
+    last: Mutex<LastUpdate>,
+}
+
+impl<F: Fn(&StreamStatus) + Send + Sync + 'static> Throttle<F> {
+    fn new(callback: F, interval: Duration) -> Arc<Self> {
+        Arc::new(Self {
+            callback,
+            interval,
+            last: Mutex::new(LastUpdate {
+                status: None,
+                sent_at: Instant::now(),
+                pending: None,
+                flush_scheduled: false,
+            }),
+        })
+    }
+
+    fn update(self: &Arc<Self>, status: StreamStatus) {
+        let mut last = self.last.lock().unwrap();
+
+        let urgent = match &last.status {
+            Some(previous) if *previous == status => {
+                // Back to what was last sent; nothing is left to report
+                last.pending = None;
+                return;
+            }
+            Some(previous) => {
+                previous.paused_for_cache != status.paused_for_cache
+                    || previous.seekable != status.seekable
+            }
+            None => true,
+        };
+
+        let elapsed = last.sent_at.elapsed();
+        if urgent || elapsed >= self.interval {
+            self.send(&mut last, status);
+            return;
+        }
+
+        last.pending = Some(status);
+        if !last.flush_scheduled {
+            last.flush_scheduled = true;
+            let throttle = self.clone();
+            let delay = self.interval - elapsed;
+            thread::spawn(move || {
+                thread::sleep(delay);
+                throttle.flush();
+            });
+        }
+    }
+
+    fn flush(&self) {
+        let mut last = self.last.lock().unwrap();
+        last.flush_scheduled = false;
+        if let Some(status) = last.pending.take() {
+            self.send(&mut last, status);
+        }
+    }
+
+    fn send(&self, last: &mut LastUpdate, status: StreamStatus) {
+        (self.callback)(&status);
+        last.status = Some(status);
+        last.sent_at = Instant::now();
+        last.pending = None;
+    }
+}
+
+/// Calls `callback` with the new `StreamStatus` whenever buffering changes.
+/// Changes of `paused_for_cache` and `seekable` are reported right away; cache
+/// progress is throttled to `MIN_UPDATE_INTERVAL`, with the latest status sent
+/// once the interval is over.
+pub fn watch_stream_status(
+    player: &Arc<MpvPlayer>,
+    callback: impl Fn(&StreamStatus) + Send + Sync + 'static,
+) -> Result<(), MpvError> {
+    let throttle = Throttle::new(callback, MIN_UPDATE_INTERVAL);
+
+    for (name, format) in WATCHED_PROPERTIES {
+        // Weak, since the player keeps its callbacks alive
+        let player_ref = Arc::downgrade(player);
+        let throttle = throttle.clone();
+
+        player.on_property_change(name, format, move |_| {
+            if let Some(player) = player_ref.upgrade() {
+                throttle.update(player.get_stream_status());
+            }
+        })?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mpv::{test_support, LoadMode, MpvEventId, StreamOptions};
+    use std::io::{Cursor, Read};
+    use std::sync::mpsc;
+
+    fn status(cache_duration: f64, paused_for_cache: bool) -> StreamStatus {
+        StreamStatus {
+            paused_for_cache,
+            buffering_percent: None,
+            seekable: true,
+            cache_duration: Some(cache_duration),
+            cache_state: None,
+        }
+    }
+
+    type Recorded = Arc<Mutex<Vec<StreamStatus>>>;
+
+    fn recording_throttle(
+        interval: Duration,
+    ) -> (
+        Arc<Throttle<impl Fn(&StreamStatus) + Send + Sync>>,
+        Recorded,
+    ) {
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let recorded = sent.clone();
+        let throttle = Throttle::new(
+            move |status: &StreamStatus| recorded.lock().unwrap().push(status.clone()),
+            interval,
+        );
+        (throttle, sent)
+    }
+
+    #[test]
+    fn sends_the_last_throttled_status_when_the_interval_is_over() {
+        let (throttle, sent) = recording_throttle(Duration::from_millis(100));
+        throttle.update(status(1.0, false));
+        throttle.update(status(2.0, false));
+        throttle.update(status(3.0, false));
+        assert_eq!(*sent.lock().unwrap(), [status(1.0, false)]);
+
+        thread::sleep(Duration::from_millis(300));
+        assert_eq!(
+            *sent.lock().unwrap(),
+            [status(1.0, false), status(3.0, false)]
+        );
+    }
+
+    #[test]
+    fn sends_cache_stalls_right_away() {
+        let (throttle, sent) = recording_throttle(Duration::from_secs(60));
+        throttle.update(status(1.0, false));
+        throttle.update(status(0.0, true));
+        throttle.update(status(0.0, true));
+        throttle.update(status(0.5, false));
+        assert_eq!(
+            *sent.lock().unwrap(),
+            [status(1.0, false), status(0.0, true), status(0.5, false)]
+        );
+    }
+
+    #[test]
+    fn drops_a_held_back_status_that_was_reverted() {
+        let (throttle, sent) = recording_throttle(Duration::from_millis(100));
+        throttle.update(status(1.0, false));
+        throttle.update(status(2.0, false));
+        throttle.update(status(1.0, false));
+        thread::sleep(Duration::from_millis(300));
+        assert_eq!(*sent.lock().unwrap(), [status(1.0, false)]);
+    }
+
+    /// Hands out its data a chunk at a time, like a slow connection.
+    struct SlowReader {
+        data: Cursor<Vec<u8>>,
+        chunk_size: usize,
+        delay: Duration,
+    }
+
+    impl Read for SlowReader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            thread::sleep(self.delay);
+            let len = buf.len().min(self.chunk_size);
+            self.data.read(&mut buf[..len])
+        }
+    }
+
+    #[test]
+    fn reports_buffering_of_a_throttled_http_stream() {
+        let Some(player) = test_support::player() else {
+            return;
+        };
+
+        // Two seconds of audio, served at about three quarters of its bitrate
+        let fixture = test_support::sine_wav(44_100, 0, 88_200);
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let url = format!(
+            "http://127.0.0.1:{}/sine.wav",
+            server.server_addr().to_ip().unwrap().port()
+        );
+        let (headers_sender, headers) = mpsc::channel();
+        thread::spawn(move || {
+            for request in server.incoming_requests() {
+                let _ = headers_sender.send(
+                    request
+                        .headers()
+                        .iter()
+                        .map(|h| (h.field.to_string().to_lowercase(), h.value.to_string()))
+                        .collect::<Vec<_>>(),
+                );
+                let len = fixture.len();
+                let reader = SlowReader {
+                    data: Cursor::new(fixture.clone()),
+                    chunk_size: 4096,
+                    delay: Duration::from_millis(60),
+                };
+                let header = "Content-Type: audio/wav"
+                    .parse::<tiny_http::Header>()
+                    .unwrap();
+                let response =
+                    tiny_http::Response::new(200.into(), vec![header], reader, Some(len), None);
+                thread::spawn(move || {
+                    let _ = request.respond(response);
+                });
+            }
+        });
+
+        for (name, value) in [
+            ("config", "no"),
+            ("load-scripts", "no"),
+            ("ytdl", "no"),
+            ("vo", "null"),
+            ("ao", "null"),
+            ("idle", "yes"),
+        ] {
+            player.set_option(name, value).unwrap();
+        }
+        player.initialize().unwrap();
+
+        let (sender, statuses) = mpsc::channel();
+        watch_stream_status(&player, move |status| {
+            let _ = sender.send(status.clone());
+        })
+        .unwrap();
+        let (sender, ended) = mpsc::channel();
+        player
+            .register_event_callback(MpvEventId::EndFile, move |event| {
+                let _ = sender.send(event.end_file_reason());
+            })
+            .unwrap();
+
+        let options = StreamOptions {
+            cache: Some(true),
+            network_timeout_secs: Some(10.0),
+            user_agent: Some("media-player-test".to_string()),
+            http_headers: [("X-Test".to_string(), "a, b".to_string())].into(),
+            ..Default::default()
+        };
+        player
+            .load_file_with_options(&url, Some(LoadMode::Replace), &options.to_load_options())
+            .unwrap();
+
+        let headers = headers.recv_timeout(Duration::from_secs(10)).unwrap();
+        let header = |name: &str| {
+            headers
+                .iter()
+                .find(|(field, _)| field == name)
+                .map(|(_, value)| value.as_str())
+        };
+        assert_eq!(header("user-agent"), Some("media-player-test"));
+        assert_eq!(header("x-test"), Some("a, b"));
+
+        let reason = ended.recv_timeout(Duration::from_secs(30)).unwrap();
+        assert_eq!(reason, Some(crate::mpv::EndFileReason::Eof));
+
+        // Whatever was held back by the throttle has been sent by now, so the
+        // last status reported is the player's current one
+        thread::sleep(MIN_UPDATE_INTERVAL * 2);
+        let reported: Vec<StreamStatus> = statuses.try_iter().collect();
+        assert!(reported.iter().any(|status| status.cache_state.is_some()));
+        assert_eq!(reported.last(), Some(&player.get_stream_status()));
+        let _ = player.quit();
+    }
+}
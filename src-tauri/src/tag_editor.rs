@@ -0,0 +1,560 @@
+use lofty::config::{ParseOptions, WriteOptions};
+use lofty::error::LoftyError;
+use lofty::file::{AudioFile, FileType};
+use lofty::ogg::OggPictureStorage;
+use lofty::picture::{MimeType, Picture, PictureType};
+use lofty::probe::Probe;
+use lofty::tag::{Accessor, ItemKey, MergeTag, SplitTag, Tag};
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use std::fs::{File, OpenOptions};
+use std::io::Seek;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+use crate::metadata::{self, SimplifiedMetadata};
+
+/// Appended to the file name of the copy made before the first edit.
+const BACKUP_SUFFIX: &str = ".bak";
+
+#[derive(Error, Debug)]
+pub enum TagError {
+    #[error("Failed to access file: {0}")]
+    IoError(#[from] std::io::Error),
+
+    #[error("Failed to write tags: {0}")]
+    LoftyError(#[from] LoftyError),
+
+    #[error("Tags can't be written to this file format")]
+    UnsupportedFormat,
+
+    #[error("Invalid {field}: {message}")]
+    InvalidValue {
+        field: &'static str,
+        message: String,
+    },
+
+    #[error("'{path}' doesn't match the pattern")]
+    PatternMismatch { path: String },
+}
+
+/// A field that can be cleared with `TagChanges::clear`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TagField {
+    Title,
+    Artist,
+    Album,
+    AlbumArtist,
+    Genre,
+    Composer,
+    Comment,
+    Lyrics,
+    Year,
+    Track,
+    TotalTracks,
+    Disc,
+    TotalDiscs,
+}
+
+/// Edits to the tag of a file. Fields that are `None` are left as they are.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct TagChanges {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub album_artist: Option<String>,
+    pub genre: Option<String>,
+    pub composer: Option<String>,
+    pub comment: Option<String>,
+    pub lyrics: Option<String>,
+    pub year: Option<u32>,
+    pub track: Option<u32>,
+    pub total_tracks: Option<u32>,
+    pub disc: Option<u32>,
+    pub total_discs: Option<u32>,
+    /// Fields to remove, applied before the values above.
+    #[serde(default)]
+    pub clear: Vec<TagField>,
+    /// Replaces the front cover.
+    pub front_cover: Option<metadata::Picture>,
+    /// Removes all embedded pictures, before adding `front_cover`.
+    #[serde(default)]
+    pub remove_pictures: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+pub struct TagWriteOptions {
+    /// Copies the file to `<name>.bak` before the first edit. An existing
+    /// backup is kept, so it always holds the original.
+    #[serde(default)]
+    pub backup: bool,
+}
+
+/// The changes for one file of a batch edit.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TagEdit {
+    pub path: String,
+    pub changes: TagChanges,
+}
+
+impl TagChanges {
+    /// Rejects numbers that no tag format can hold or that contradict each other.
+    pub fn validate(&self) -> Result<(), TagError> {
+        let invalid = |field, message: &str| TagError::InvalidValue {
+            field,
+            message: message.to_string(),
+        };
+
+        if let Some(year) = self.year {
+            if !(1..=9999).contains(&year) {
+                return Err(invalid("year", "must be between 1 and 9999"));
+            }
+        }
+        for (field, value) in [
+            ("track", self.track),
+            ("total tracks", self.total_tracks),
+            ("disc", self.disc),
+            ("total discs", self.total_discs),
+        ] {
+            if value == Some(0) {
+                return Err(invalid(field, "must be at least 1"));
+            }
+        }
+        if let (Some(track), Some(total)) = (self.track, self.total_tracks) {
+            if track > total {
+                return Err(invalid("track", "is greater than the total tracks"));
+            }
+        }
+        if let (Some(disc), Some(total)) = (self.disc, self.total_discs) {
+            if disc > total {
+                return Err(invalid("disc", "is greater than the total discs"));
+            }
+        }
+        Ok(())
+    }
+
+    fn apply(&self, tag: &mut Tag) {
+        for field in &self.clear {
+            match field {
+                TagField::Title => tag.remove_title(),
+                TagField::Artist => tag.remove_artist(),
+                TagField::Album => tag.remove_album(),
+                TagField::AlbumArtist => tag.remove_key(&ItemKey::AlbumArtist),
+                TagField::Genre => tag.remove_genre(),
+                TagField::Composer => tag.remove_key(&ItemKey::Composer),
+                TagField::Comment => tag.remove_comment(),
+                TagField::Lyrics => tag.remove_key(&ItemKey::Lyrics),
+                TagField::Year => tag.remove_year(),
+                TagField::Track => tag.remove_track(),
+                TagField::TotalTracks => tag.remove_track_total(),
+                TagField::Disc => tag.remove_disk(),
+                TagField::TotalDiscs => tag.remove_disk_total(),
+            }
+        }
+
+        if let Some(title) = &self.title {
+            tag.set_title(title.clone());
+        }
+        if let Some(artist) = &self.artist {
+            tag.set_artist(artist.clone());
+        }
+        if let Some(album) = &self.album {
+            tag.set_album(album.clone());
+        }
+        if let Some(genre) = &self.genre {
+            tag.set_genre(genre.clone());
+        }
+        if let Some(comment) = &self.comment {
+            tag.set_comment(comment.clone());
+        }
+        for (key, value) in [
+            (ItemKey::AlbumArtist, &self.album_artist),
+            (ItemKey::Composer, &self.composer),
+            (ItemKey::Lyrics, &self.lyrics),
+        ] {
+            if let Some(value) = value {
+                tag.insert_text(key, value.clone());
+            }
+        }
+        if let Some(year) = self.year {
+            tag.set_year(year);
+        }
+        if let Some(track) = self.track {
+            tag.set_track(track);
+        }
+        if let Some(total) = self.total_tracks {
+            tag.set_track_total(total);
+        }
+        if let Some(disc) = self.disc {
+            tag.set_disk(disc);
+        }
+        if let Some(total) = self.total_discs {
+            tag.set_disk_total(total);
+        }
+
+        if self.remove_pictures {
+            while !tag.pictures().is_empty() {
+                tag.remove_picture(0);
+            }
+        }
+        if let Some(cover) = &self.front_cover {
+            tag.remove_picture_type(PictureType::CoverFront);
+            tag.push_picture(Picture::new_unchecked(
+                PictureType::CoverFront,
+                Some(MimeType::from_str(&cover.mime_type)),
+                None,
+                cover.data.clone(),
+            ));
+        }
+    }
+}
+
+/// Splits the native tag of `$file`, edits its generic part and merges it back,
+/// so frames and atoms that have no generic equivalent are written unchanged.
+macro_rules! edit_native_tag {
+    ($file:ident, $changes:ident, $get_mut:ident, $set:ident) => {{
+        let native = $file.$get_mut().map(std::mem::take).unwrap_or_default();
+        let (remainder, mut tag) = native.split_tag();
+        $changes.apply(&mut tag);
+        $file.$set(remainder.merge_tag(tag));
+    }};
+}
+
+/// Writes `changes` into the tag lofty considers native for the format
+/// (ID3v2 for MP3, Vorbis comments for FLAC and Ogg, ilst for MP4, APE for
+/// Monkey's Audio, WavPack and Musepack), creating it if needed.
+pub fn write_tags(
+    path: &Path,
+    changes: &TagChanges,
+    options: TagWriteOptions,
+) -> Result<(), TagError> {
+    changes.validate()?;
+    let file_type = Probe::open(path)?
+        .guess_file_type()?
+        .file_type()
+        .ok_or(TagError::UnsupportedFormat)?;
+
+    if options.backup {
+        let backup_path = backup_path(path);
+        if !backup_path.exists() {
+            std::fs::copy(path, backup_path)?;
+        }
+    }
+
+    let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+    match file_type {
+        FileType::Mpeg => {
+            let mut audio = read::<lofty::mpeg::MpegFile>(&mut file)?;
+            edit_native_tag!(audio, changes, id3v2_mut, set_id3v2);
+            save(&audio, &mut file)
+        }
+        FileType::Aac => {
+            let mut audio = read::<lofty::aac::AacFile>(&mut file)?;
+            edit_native_tag!(audio, changes, id3v2_mut, set_id3v2);
+            save(&audio, &mut file)
+        }
+        FileType::Aiff => {
+            let mut audio = read::<lofty::iff::aiff::AiffFile>(&mut file)?;
+            edit_native_tag!(audio, changes, id3v2_mut, set_id3v2);
+            save(&audio, &mut file)
+        }
+        FileType::Wav => {
+            let mut audio = read::<lofty::iff::wav::WavFile>(&mut file)?;
+            edit_native_tag!(audio, changes, id3v2_mut, set_id3v2);
+            save(&audio, &mut file)
+        }
+        FileType::Flac => {
+            let mut audio = read::<lofty::flac::FlacFile>(&mut file)?;
+            // FLAC keeps pictures in their own blocks; move them into the comments
+            // so they can be edited, like `TaggedFile` does when reading
+            let mut comments = audio.remove_vorbis_comments().unwrap_or_default();
+            for (picture, info) in audio.remove_pictures() {
+                comments.insert_picture(picture, Some(info))?;
+            }
+            audio.set_vorbis_comments(comments);
+            edit_native_tag!(audio, changes, vorbis_comments_mut, set_vorbis_comments);
+            save(&audio, &mut file)
+        }
+        FileType::Vorbis => {
+            let mut audio = read::<lofty::ogg::VorbisFile>(&mut file)?;
+            edit_ogg_tag(audio.vorbis_comments_mut(), changes);
+            save(&audio, &mut file)
+        }
+        FileType::Opus => {
+            let mut audio = read::<lofty::ogg::OpusFile>(&mut file)?;
+            edit_ogg_tag(audio.vorbis_comments_mut(), changes);
+            save(&audio, &mut file)
+        }
+        FileType::Speex => {
+            let mut audio = read::<lofty::ogg::SpeexFile>(&mut file)?;
+            edit_ogg_tag(audio.vorbis_comments_mut(), changes);
+            save(&audio, &mut file)
+        }
+        FileType::Mp4 => {
+            let mut audio = read::<lofty::mp4::Mp4File>(&mut file)?;
+            edit_native_tag!(audio, changes, ilst_mut, set_ilst);
+            save(&audio, &mut file)
+        }
+        FileType::Ape => {
+            let mut audio = read::<lofty::ape::ApeFile>(&mut file)?;
+            edit_native_tag!(audio, changes, ape_mut, set_ape);
+            save(&audio, &mut file)
+        }
+        FileType::WavPack => {
+            let mut audio = read::<lofty::wavpack::WavPackFile>(&mut file)?;
+            edit_native_tag!(audio, changes, ape_mut, set_ape);
+            save(&audio, &mut file)
+        }
+        FileType::Mpc => {
+            let mut audio = read::<lofty::musepack::MpcFile>(&mut file)?;
+            edit_native_tag!(audio, changes, ape_mut, set_ape);
+            save(&audio, &mut file)
+        }
+        _ => Err(TagError::UnsupportedFormat),
+    }
+}
+
+/// Ogg files always have a comment header, so there is no tag to create.
+fn edit_ogg_tag(comments: &mut lofty::ogg::VorbisComments, changes: &TagChanges) {
+    let (remainder, mut tag) = std::mem::take(comments).split_tag();
+    changes.apply(&mut tag);
+    *comments = remainder.merge_tag(tag);
+}
+
+fn read<F: AudioFile>(file: &mut File) -> Result<F, TagError> {
+    Ok(F::read_from(file, ParseOptions::new())?)
+}
+
+fn save<F: AudioFile>(audio: &F, file: &mut File) -> Result<(), TagError> {
+    file.rewind()?;
+    audio.save_to(file, WriteOptions::default())?;
+    Ok(())
+}
+
+fn backup_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(BACKUP_SUFFIX);
+    path.with_file_name(name)
+}
+
+/// Fills fields from the end of `path`, following `pattern`: `%field%`
+/// placeholders separated by literal text, e.g. `%artist% - %title%` for the
+/// file name or `%artist%/%album%/%track%. %title%` for the folders too.
+/// `%ignore%` matches text that isn't kept. The extension is never matched.
+pub fn changes_from_filename(path: &str, pattern: &str) -> Result<TagChanges, TagError> {
+    let mismatch = || TagError::PatternMismatch {
+        path: path.to_string(),
+    };
+
+    // As many trailing components of the path as the pattern has
+    let depth = pattern.matches('/').count() + 1;
+    let without_extension = Path::new(path).with_extension("");
+    let components: Vec<String> = without_extension
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy().into_owned())
+        .collect();
+    if components.len() < depth {
+        return Err(mismatch());
+    }
+    let text = components[components.len() - depth..].join("/");
+
+    let mut changes = TagChanges::default();
+    let mut rest = text.as_str();
+    let mut parts = pattern.split('%').enumerate().peekable();
+    while let Some((i, part)) = parts.next() {
+        // Even parts are literals, odd ones are field names
+        if i % 2 == 0 {
+            rest = rest.strip_prefix(part).ok_or_else(mismatch)?;
+            continue;
+        }
+
+        let literal = parts.peek().map(|(_, literal)| *literal).unwrap_or("");
+        let value = if literal.is_empty() {
+            std::mem::take(&mut rest)
+        } else {
+            let end = rest.find(literal).ok_or_else(mismatch)?;
+            let (value, remaining) = rest.split_at(end);
+            rest = remaining;
+            value
+        };
+        set_field(&mut changes, part, value.trim()).ok_or_else(mismatch)?;
+    }
+    if !rest.is_empty() {
+        return Err(mismatch());
+    }
+
+    Ok(changes)
+}
+
+/// Returns `None` for unknown fields or numbers that don't parse.
+fn set_field(changes: &mut TagChanges, field: &str, value: &str) -> Option<()> {
+    let number = || value.parse::<u32>().ok();
+    match field {
+        "title" => changes.title = Some(value.to_string()),
+        "artist" => changes.artist = Some(value.to_string()),
+        "album" => changes.album = Some(value.to_string()),
+        "album_artist" => changes.album_artist = Some(value.to_string()),
+        "genre" => changes.genre = Some(value.to_string()),
+        "composer" => changes.composer = Some(value.to_string()),
+        "comment" => changes.comment = Some(value.to_string()),
+        "year" => changes.year = Some(number()?),
+        "track" => changes.track = Some(number()?),
+        "total_tracks" => changes.total_tracks = Some(number()?),
+        "disc" => changes.disc = Some(number()?),
+        "total_discs" => changes.total_discs = Some(number()?),
+        "ignore" => (),
+        _ => return None,
+    }
+    Some(())
+}
+
+/// Updates the row the webview cached for `path` in `media_info`, if there is one.
+/// The title is kept if the tag has none, since the webview falls back to the file name.
+pub async fn update_cached_media_info(
+    pool: &SqlitePool,
+    path: &str,
+    metadata: &SimplifiedMetadata,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "UPDATE media_info SET
+            title = COALESCE(?, title),
+            artist = ?,
+            album = ?,
+            year = ?,
+            track = ?,
+            total_tracks = ?,
+            disc = ?,
+            total_discs = ?,
+            genre = ?,
+            album_artist = ?
+         WHERE path = ?",
+    )
+    .bind(&metadata.title)
+    .bind(&metadata.artist)
+    .bind(&metadata.album)
+    .bind(metadata.year)
+    .bind(metadata.track)
+    .bind(metadata.total_tracks)
+    .bind(metadata.disc)
+    .bind(metadata.total_discs)
+    .bind(&metadata.genre)
+    .bind(metadata.album_artist())
+    .bind(path)
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fills_fields_from_the_file_name() {
+        let changes =
+            changes_from_filename("/music/Artist - Some Title.flac", "%artist% - %title%").unwrap();
+        assert_eq!(changes.artist.as_deref(), Some("Artist"));
+        assert_eq!(changes.title.as_deref(), Some("Some Title"));
+        assert_eq!(changes.album, None);
+    }
+
+    #[test]
+    fn fills_fields_from_the_folders() {
+        let changes = changes_from_filename(
+            "/music/The Band/Greatest Hits (2001)/07. A Song.mp3",
+            "%artist%/%album% (%year%)/%track%. %title%",
+        )
+        .unwrap();
+        assert_eq!(changes.artist.as_deref(), Some("The Band"));
+        assert_eq!(changes.album.as_deref(), Some("Greatest Hits"));
+        assert_eq!(changes.year, Some(2001));
+        assert_eq!(changes.track, Some(7));
+        assert_eq!(changes.title.as_deref(), Some("A Song"));
+    }
+
+    #[test]
+    fn skips_ignored_text_and_trims_values() {
+        let changes = changes_from_filename(
+            "/music/[web] 03 -  Title .ogg",
+            "[%ignore%] %track% -%title%",
+        )
+        .unwrap();
+        assert_eq!(changes.track, Some(3));
+        assert_eq!(changes.title.as_deref(), Some("Title"));
+    }
+
+    #[test]
+    fn rejects_names_that_dont_match() {
+        for (path, pattern) in [
+            // Missing separator
+            ("/music/Artist Title.mp3", "%artist% - %title%"),
+            // Not a number
+            ("/music/One. Title.mp3", "%track%. %title%"),
+            // Unknown field
+            ("/music/A - B.mp3", "%artist% - %mood%"),
+            // Text left over
+            ("/music/A - B.mp3", "%artist% -"),
+            // Deeper than the path
+            ("Title.mp3", "%album%/%title%"),
+        ] {
+            assert!(
+                matches!(
+                    changes_from_filename(path, pattern),
+                    Err(TagError::PatternMismatch { .. })
+                ),
+                "{} matched {}",
+                path,
+                pattern
+            );
+        }
+    }
+
+    #[test]
+    fn accepts_consistent_numbers() {
+        let changes = TagChanges {
+            year: Some(1999),
+            track: Some(12),
+            total_tracks: Some(12),
+            disc: Some(1),
+            total_discs: Some(2),
+            ..Default::default()
+        };
+        assert!(changes.validate().is_ok());
+        assert!(TagChanges::default().validate().is_ok());
+    }
+
+    #[test]
+    fn rejects_invalid_numbers() {
+        let invalid = |changes: TagChanges| match changes.validate() {
+            Err(TagError::InvalidValue { field, .. }) => field,
+            other => panic!("unexpected result: {:?}", other),
+        };
+
+        for year in [0, 10_000] {
+            let changes = TagChanges {
+                year: Some(year),
+                ..Default::default()
+            };
+            assert_eq!(invalid(changes), "year");
+        }
+        let changes = TagChanges {
+            total_discs: Some(0),
+            ..Default::default()
+        };
+        assert_eq!(invalid(changes), "total discs");
+        let changes = TagChanges {
+            track: Some(5),
+            total_tracks: Some(4),
+            ..Default::default()
+        };
+        assert_eq!(invalid(changes), "track");
+        let changes = TagChanges {
+            disc: Some(3),
+            total_discs: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(invalid(changes), "disc");
+    }
+}
@@ -0,0 +1,105 @@
+use crate::metadata::{self, SimplifiedMetadata};
+use crate::tag_editor::{self, TagChanges, TagEdit, TagWriteOptions};
+
+use serde::Serialize;
+use sqlx::SqlitePool;
+use std::path::PathBuf;
+use tauri::State;
+
+#[derive(Serialize)]
+pub struct FilenameMatch {
+    pub path: String,
+    pub changes: Option<TagChanges>,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct TagEditResult {
+    pub path: String,
+    /// The metadata as read back after the write.
+    pub metadata: Option<SimplifiedMetadata>,
+    pub error: Option<String>,
+}
+
+/// Writes `changes` to one file, then refreshes its cached `media_info` row.
+async fn write_and_refresh(
+    pool: &SqlitePool,
+    path: String,
+    changes: TagChanges,
+    options: TagWriteOptions,
+) -> Result<SimplifiedMetadata, String> {
+    let file_path = PathBuf::from(&path);
+    tauri::async_runtime::spawn_blocking(move || {
+        tag_editor::write_tags(&file_path, &changes, options)
+    })
+    .await
+    .map_err(|e| e.to_string())?
+    .map_err(|e| e.to_string())?;
+
+    let metadata = metadata::parse_metadata(&path)
+        .await
+        .map_err(|e| e.to_string())?;
+    tag_editor::update_cached_media_info(pool, &path, &metadata)
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(metadata)
+}
+
+/// Writes tag fields and pictures to `path` and returns the new metadata.
+#[tauri::command]
+pub async fn set_media_info(
+    pool: State<'_, SqlitePool>,
+    path: String,
+    changes: TagChanges,
+    options: Option<TagWriteOptions>,
+) -> Result<SimplifiedMetadata, String> {
+    write_and_refresh(&pool, path, changes, options.unwrap_or_default()).await
+}
+
+/// Applies each edit in turn. A failed edit doesn't stop the others.
+#[tauri::command]
+pub async fn set_media_info_batch(
+    pool: State<'_, SqlitePool>,
+    edits: Vec<TagEdit>,
+    options: Option<TagWriteOptions>,
+) -> Result<Vec<TagEditResult>, String> {
+    let options = options.unwrap_or_default();
+    let mut results = Vec::with_capacity(edits.len());
+    for edit in edits {
+        let (metadata, error) =
+            match write_and_refresh(&pool, edit.path.clone(), edit.changes, options).await {
+                Ok(metadata) => (Some(metadata), None),
+                Err(e) => (None, Some(e)),
+            };
+        results.push(TagEditResult {
+            path: edit.path,
+            metadata,
+            error,
+        });
+    }
+    Ok(results)
+}
+
+/// Fills fields of each of `paths` from its path, following `pattern` (see
+/// `tag_editor::changes_from_filename`). Nothing is written: the edits are
+/// meant to be reviewed, then passed to `set_media_info_batch`.
+#[tauri::command]
+pub fn tags_from_filename(paths: Vec<String>, pattern: String) -> Vec<FilenameMatch> {
+    paths
+        .into_iter()
+        .map(
+            |path| match tag_editor::changes_from_filename(&path, &pattern) {
+                Ok(changes) => FilenameMatch {
+                    path,
+                    changes: Some(changes),
+                    error: None,
+                },
+                Err(e) => FilenameMatch {
+                    path,
+                    changes: None,
+                    error: Some(e.to_string()),
+                },
+            },
+        )
+        .collect()
+}
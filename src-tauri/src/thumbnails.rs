@@ -0,0 +1,736 @@
+use serde::Serialize;
+use std::collections::hash_map::DefaultHasher;
+use std::fmt::Write as _;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+
+use crate::mpv::{EndFileReason, MpvError, MpvEventId, MpvFormat, MpvPlayer};
+
+/// Directory in the app cache directory that holds one directory of sprites per video.
+pub const CACHE_DIR_NAME: &str = "thumbnails";
+
+/// Scheme of the protocol that serves the sprites and their index.
+pub const PROTOCOL: &str = "thumbnail";
+
+const INDEX_FILE_NAME: &str = "thumbnails.vtt";
+const PARTIAL_SUFFIX: &str = ".partial";
+
+const THUMBNAIL_WIDTH: u32 = 160;
+const THUMBNAIL_HEIGHT: u32 = 90;
+const SPRITE_COLUMNS: u32 = 10;
+const SPRITE_ROWS: u32 = 10;
+
+/// Thumbnails are at least this far apart...
+const MIN_INTERVAL_SECS: f64 = 2.0;
+/// ...and long videos get at most this many, spread further apart.
+const MAX_THUMBNAILS: f64 = 500.0;
+
+/// The cache is trimmed to this size, least recently used videos first...
+const MAX_CACHE_BYTES: u64 = 512 * 1024 * 1024;
+/// ...and videos not used for this long are dropped.
+const MAX_CACHE_AGE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+#[derive(Error, Debug)]
+pub enum ThumbnailError {
+    #[error("Failed to access thumbnail cache: {0}")]
+    IoError(#[from] std::io::Error),
+
+    #[error(transparent)]
+    MpvError(#[from] MpvError),
+
+    #[error("Thumbnails can only be generated for local files")]
+    NotLocalFile,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ThumbnailStatus {
+    Running,
+    Done,
+    Failed,
+    Cancelled,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ThumbnailProgress {
+    pub path: String,
+    pub status: ThumbnailStatus,
+    /// From 0 to 1.
+    pub progress: f64,
+    /// URL of the WebVTT index, once done.
+    pub index_url: Option<String>,
+}
+
+/// A generation in progress, on its own headless mpv core.
+struct Job {
+    path: String,
+    key: String,
+    /// Where the sprites are written, unique to the job so a cancelled job
+    /// can't clean up after a later one for the same file.
+    partial_dir: PathBuf,
+    player: Arc<MpvPlayer>,
+    cancelled: AtomicBool,
+    finished: AtomicBool,
+    duration: Mutex<Option<f64>>,
+    interval: Mutex<f64>,
+    /// Last reported progress in percent, to report each step once.
+    percent: AtomicI64,
+}
+
+/// Generates seek bar previews in the background: frames taken at regular
+/// intervals, packed into sprite sheets and indexed by a WebVTT file whose cues
+/// point into the sheets with `#xywh=` fragments.
+///
+/// Results are cached by path and modification time, and served through the
+/// `thumbnail` protocol. Only one video is processed at a time. The cache is
+/// limited by `MAX_CACHE_BYTES` and `MAX_CACHE_AGE`.
+pub struct ThumbnailGenerator {
+    lib_path: String,
+    cache_dir: PathBuf,
+    job: Mutex<Option<Arc<Job>>>,
+    /// Replaced jobs, kept until their core ends so they are cleaned up and reported.
+    stopping: Mutex<Vec<Arc<Job>>>,
+    next_job_id: AtomicU64,
+    on_progress: Box<dyn Fn(&ThumbnailProgress) + Send + Sync + 'static>,
+}
+
+impl ThumbnailGenerator {
+    pub fn new(
+        lib_path: &str,
+        cache_dir: PathBuf,
+        on_progress: impl Fn(&ThumbnailProgress) + Send + Sync + 'static,
+    ) -> Arc<Self> {
+        let generator = Arc::new(Self {
+            lib_path: lib_path.to_string(),
+            cache_dir,
+            job: Mutex::new(None),
+            stopping: Mutex::new(Vec::new()),
+            next_job_id: AtomicU64::new(0),
+            on_progress: Box::new(on_progress),
+        });
+
+        // Also drops what earlier runs left half done
+        let generator_ref = Arc::downgrade(&generator);
+        thread::spawn(move || {
+            if let Some(generator) = generator_ref.upgrade() {
+                generator.evict();
+            }
+        });
+        generator
+    }
+
+    /// Cancels generation for the previous file when `player` starts another one.
+    /// Called again for every new core.
+    pub fn attach(self: &Arc<Self>, player: &Arc<MpvPlayer>) -> Result<(), MpvError> {
+        let generator = Arc::downgrade(self);
+        let player_ref = Arc::downgrade(player);
+        player.register_event_callback(MpvEventId::StartFile, move |_| {
+            let (Some(generator), Some(player)) = (generator.upgrade(), player_ref.upgrade())
+            else {
+                return;
+            };
+            let path = player.get_path().ok();
+            let running = generator.job.lock().unwrap().clone();
+            if let Some(job) = running {
+                if path.as_deref() != Some(job.path.as_str()) {
+                    generator.cancel_job(&job);
+                }
+            }
+        })
+    }
+
+    /// Returns the index URL if the thumbnails of `path` are cached. Otherwise
+    /// starts generating them, replacing the current job, and returns `None`;
+    /// the URL then arrives with the `Done` progress update.
+    pub fn request(self: &Arc<Self>, path: &str) -> Result<Option<String>, ThumbnailError> {
+        if path.contains("://") {
+            return Err(ThumbnailError::NotLocalFile);
+        }
+        let key = cache_key(Path::new(path))?;
+        let index = self.cache_dir.join(&key).join(INDEX_FILE_NAME);
+        if index.exists() {
+            // Marks it as recently used for `evict`
+            let _ = fs::File::options()
+                .append(true)
+                .open(&index)
+                .and_then(|file| file.set_modified(SystemTime::now()));
+            return Ok(Some(url(&key, INDEX_FILE_NAME)));
+        }
+
+        let mut current = self.job.lock().unwrap();
+        if current.as_ref().is_some_and(|job| job.key == key) {
+            return Ok(None);
+        }
+        if let Some(job) = current.take() {
+            // `finish` reports it once its core has ended
+            self.cancel_job(&job);
+            self.stopping.lock().unwrap().push(job);
+        }
+
+        let job = self.start_job(path, key)?;
+        *current = Some(job);
+        Ok(None)
+    }
+
+    /// Cancels generation for `path`, or whatever is running if `path` is `None`.
+    pub fn cancel(&self, path: Option<&str>) {
+        let running = self.job.lock().unwrap().clone();
+        if let Some(job) = running {
+            if path.is_none_or(|path| path == job.path) {
+                self.cancel_job(&job);
+            }
+        }
+    }
+
+    /// Reads a file served by the protocol, given the path part of its URL.
+    pub fn read(&self, url_path: &str) -> Option<(Vec<u8>, &'static str)> {
+        let (key, file) = url_path.trim_start_matches('/').split_once('/')?;
+        let is_name = |s: &str| {
+            !s.is_empty()
+                && s.chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-')
+                && !s.starts_with('.')
+        };
+        if !is_name(key) || !is_name(file) || key.ends_with(PARTIAL_SUFFIX) {
+            return None;
+        }
+
+        let data = fs::read(self.cache_dir.join(key).join(file)).ok()?;
+        let mime_type = if file.ends_with(".vtt") {
+            "text/vtt"
+        } else {
+            "image/jpeg"
+        };
+        Some((data, mime_type))
+    }
+
+    fn cancel_job(&self, job: &Arc<Job>) {
+        job.cancelled.store(true, Ordering::SeqCst);
+        if let Err(e) = job.player.quit() {
+            eprintln!("Failed to stop thumbnail generation: {}", e);
+        }
+    }
+
+    fn start_job(self: &Arc<Self>, path: &str, key: String) -> Result<Arc<Job>, ThumbnailError> {
+        let id = self.next_job_id.fetch_add(1, Ordering::Relaxed);
+        let out_dir = self
+            .cache_dir
+            .join(format!("{}-{}{}", key, id, PARTIAL_SUFFIX));
+        if out_dir.exists() {
+            fs::remove_dir_all(&out_dir)?;
+        }
+        fs::create_dir_all(&out_dir)?;
+
+        let player = MpvPlayer::new(&self.lib_path).inspect_err(|_| {
+            let _ = fs::remove_dir_all(&out_dir);
+        })?;
+        let job = Arc::new(Job {
+            path: path.to_string(),
+            key,
+            partial_dir: out_dir,
+            player: player.clone(),
+            cancelled: AtomicBool::new(false),
+            finished: AtomicBool::new(false),
+            duration: Mutex::new(None),
+            interval: Mutex::new(MIN_INTERVAL_SECS),
+            percent: AtomicI64::new(-1),
+        });
+        if let Err(e) = self.run_job(&job) {
+            // It never started, so no Shutdown event will end its event thread
+            job.player.stop_event_processing(Duration::from_secs(5));
+            let _ = fs::remove_dir_all(&job.partial_dir);
+            return Err(e.into());
+        }
+        Ok(job)
+    }
+
+    fn run_job(self: &Arc<Self>, job: &Arc<Job>) -> Result<(), MpvError> {
+        // Frames are written as they come out of the filters, as fast as they
+        // decode. Every frame is decoded, not just keyframes, so each thumbnail
+        // is taken at the time its cue in the index starts; skipping the loop
+        // filter keeps that fast, and the artifacts don't show at this size
+        let player = &job.player;
+        for (name, value) in [
+            ("config", "no"),
+            ("load-scripts", "no"),
+            ("ytdl", "no"),
+            ("vo", "image"),
+            ("vo-image-format", "jpg"),
+            ("vo-image-jpeg-quality", "80"),
+            ("ao", "null"),
+            ("aid", "no"),
+            ("sid", "no"),
+            ("hwdec", "no"),
+            ("untimed", "yes"),
+            ("vd-lavc-skiploopfilter", "all"),
+            ("pause", "yes"),
+        ] {
+            player.set_option(name, value)?;
+        }
+        player.set_option("vo-image-outdir", &job.partial_dir.to_string_lossy())?;
+        player.initialize()?;
+
+        self.watch_job(job)?;
+        player.load_file(&job.path, None)
+    }
+
+    fn watch_job(self: &Arc<Self>, job: &Arc<Job>) -> Result<(), MpvError> {
+        let player = &job.player;
+
+        // The interval depends on the duration, so the filters are added once
+        // the file is loaded (still paused) and playback starts after
+        let generator = Arc::downgrade(self);
+        let job_ref = Arc::downgrade(job);
+        player.register_event_callback(MpvEventId::FileLoaded, move |_| {
+            let (Some(generator), Some(job)) = (generator.upgrade(), job_ref.upgrade()) else {
+                return;
+            };
+            if let Err(e) = job.start_filters() {
+                eprintln!("Failed to generate thumbnails for {}: {}", job.path, e);
+                generator.finish(&job, ThumbnailStatus::Failed);
+            }
+        })?;
+
+        let generator = Arc::downgrade(self);
+        let job_ref = Arc::downgrade(job);
+        player.on_property_change("time-pos", MpvFormat::Double, move |value| {
+            let (Some(generator), Some(job)) = (generator.upgrade(), job_ref.upgrade()) else {
+                return;
+            };
+            let (Some(position), Some(duration)) = (value.as_f64(), *job.duration.lock().unwrap())
+            else {
+                return;
+            };
+            if duration <= 0.0 {
+                return;
+            }
+            let progress = (position / duration).clamp(0.0, 1.0);
+            let percent = (progress * 100.0) as i64;
+            if job.percent.swap(percent, Ordering::Relaxed) != percent {
+                generator.report(&job, ThumbnailStatus::Running, progress, None);
+            }
+        })?;
+
+        let generator = Arc::downgrade(self);
+        let job_ref = Arc::downgrade(job);
+        player.register_event_callback(MpvEventId::EndFile, move |event| {
+            let (Some(generator), Some(job)) = (generator.upgrade(), job_ref.upgrade()) else {
+                return;
+            };
+            let status = if job.cancelled.load(Ordering::SeqCst) {
+                ThumbnailStatus::Cancelled
+            } else if event.end_file_reason() == Some(EndFileReason::Eof) {
+                ThumbnailStatus::Done
+            } else {
+                ThumbnailStatus::Failed
+            };
+            generator.finish(&job, status);
+        })?;
+
+        // In case the core ends before the file did, e.g. when quit while loading
+        let generator = Arc::downgrade(self);
+        let job_ref = Arc::downgrade(job);
+        player.register_event_callback(MpvEventId::Shutdown, move |_| {
+            let (Some(generator), Some(job)) = (generator.upgrade(), job_ref.upgrade()) else {
+                return;
+            };
+            let status = if job.cancelled.load(Ordering::SeqCst) {
+                ThumbnailStatus::Cancelled
+            } else {
+                ThumbnailStatus::Failed
+            };
+            generator.finish(&job, status);
+        })?;
+
+        Ok(())
+    }
+
+    /// Writes the index and publishes the sprites (or throws them away), then
+    /// shuts down the job's core. Only the first call for a job does anything.
+    fn finish(&self, job: &Arc<Job>, status: ThumbnailStatus) {
+        if job.finished.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        {
+            let mut current = self.job.lock().unwrap();
+            if current.as_ref().is_some_and(|j| Arc::ptr_eq(j, job)) {
+                *current = None;
+            }
+        }
+        self.stopping
+            .lock()
+            .unwrap()
+            .retain(|j| !Arc::ptr_eq(j, job));
+        // Fails if the core already ended, which is fine
+        let _ = job.player.quit();
+
+        let mut status = status;
+        if status == ThumbnailStatus::Done {
+            if let Err(e) = self.publish(job) {
+                eprintln!("Failed to save thumbnails for {}: {}", job.path, e);
+                status = ThumbnailStatus::Failed;
+            }
+        }
+        if status == ThumbnailStatus::Done {
+            self.evict();
+        } else {
+            let _ = fs::remove_dir_all(&job.partial_dir);
+        }
+
+        let index_url = (status == ThumbnailStatus::Done).then(|| url(&job.key, INDEX_FILE_NAME));
+        let progress = if status == ThumbnailStatus::Done {
+            1.0
+        } else {
+            0.0
+        };
+        self.report(job, status, progress, index_url);
+    }
+
+    fn publish(&self, job: &Job) -> std::io::Result<()> {
+        let partial_dir = &job.partial_dir;
+        let mut sheets: Vec<String> = fs::read_dir(partial_dir)?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.file_name().to_string_lossy().into_owned())
+            .filter(|name| name.ends_with(".jpg"))
+            .collect();
+        sheets.sort();
+
+        let duration = job.duration.lock().unwrap().unwrap_or(0.0);
+        let interval = *job.interval.lock().unwrap();
+        fs::write(
+            partial_dir.join(INDEX_FILE_NAME),
+            build_index(&sheets, duration, interval),
+        )?;
+
+        let dir = self.cache_dir.join(&job.key);
+        if dir.exists() {
+            fs::remove_dir_all(&dir)?;
+        }
+        fs::rename(partial_dir, dir)
+    }
+
+    fn report(&self, job: &Job, status: ThumbnailStatus, progress: f64, index_url: Option<String>) {
+        (self.on_progress)(&ThumbnailProgress {
+            path: job.path.clone(),
+            status,
+            progress,
+            index_url,
+        });
+    }
+
+    /// Trims the cache and removes sprites left behind by jobs that are gone.
+    fn evict(&self) {
+        // Held throughout, since jobs are started with it held
+        let current = self.job.lock().unwrap();
+        let running: Vec<PathBuf> = current
+            .iter()
+            .chain(self.stopping.lock().unwrap().iter())
+            .map(|job| job.partial_dir.clone())
+            .collect();
+        if let Err(e) = trim_cache(&self.cache_dir, &running, MAX_CACHE_BYTES, MAX_CACHE_AGE) {
+            eprintln!("Failed to clean up thumbnail cache: {}", e);
+        }
+    }
+}
+
+impl Job {
+    fn start_filters(&self) -> Result<(), MpvError> {
+        let duration = self.player.get_duration()?;
+        let interval = (duration / MAX_THUMBNAILS).max(MIN_INTERVAL_SECS);
+        *self.duration.lock().unwrap() = Some(duration);
+        *self.interval.lock().unwrap() = interval;
+
+        // Takes the first frame at or after every multiple of `interval` from
+        // the first one, which is where `build_index` puts the cells, fits it
+        // into the thumbnail size and packs the thumbnails into sheets
+        let filters = format!(
+            "lavfi=[select='gte(t-start_t,selected_n*{interval})',\
+             scale={w}:{h}:force_original_aspect_ratio=decrease,\
+             pad={w}:{h}:(ow-iw)/2:(oh-ih)/2,setsar=1,tile={columns}x{rows}]",
+            interval = interval,
+            w = THUMBNAIL_WIDTH,
+            h = THUMBNAIL_HEIGHT,
+            columns = SPRITE_COLUMNS,
+            rows = SPRITE_ROWS,
+        );
+        self.player.command(&["set", "vf", &filters])?;
+        self.player.play()
+    }
+}
+
+/// Identifies a version of a file: edits change the modification time.
+fn cache_key(path: &Path) -> std::io::Result<String> {
+    let modified = fs::metadata(path)?
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+
+    let mut hasher = DefaultHasher::new();
+    path.hash(&mut hasher);
+    modified.hash(&mut hasher);
+    Ok(format!("{:016x}", hasher.finish()))
+}
+
+/// Removes videos not used for `max_age`, then the least recently used ones
+/// until the rest fit in `max_bytes`, and partial directories not in `running`.
+/// A video's last use is the modification time of its index.
+fn trim_cache(
+    cache_dir: &Path,
+    running: &[PathBuf],
+    max_bytes: u64,
+    max_age: Duration,
+) -> std::io::Result<()> {
+    let mut videos = Vec::new();
+    for entry in fs::read_dir(cache_dir)? {
+        let path = entry?.path();
+        if !path.is_dir() {
+            continue;
+        }
+        if path.to_string_lossy().ends_with(PARTIAL_SUFFIX) {
+            if !running.contains(&path) {
+                let _ = fs::remove_dir_all(&path);
+            }
+            continue;
+        }
+
+        let last_used = fs::metadata(path.join(INDEX_FILE_NAME))
+            .or_else(|_| fs::metadata(&path))
+            .and_then(|metadata| metadata.modified())
+            .unwrap_or(UNIX_EPOCH);
+        let size: u64 = fs::read_dir(&path)?
+            .filter_map(|entry| entry.ok()?.metadata().ok())
+            .map(|metadata| metadata.len())
+            .sum();
+        videos.push((last_used, size, path));
+    }
+
+    videos.sort_by_key(|(last_used, _, _)| std::cmp::Reverse(*last_used));
+    let now = SystemTime::now();
+    let mut total = 0;
+    for (last_used, size, path) in videos {
+        total += size;
+        let expired = now.duration_since(last_used).unwrap_or_default() > max_age;
+        if expired || total > max_bytes {
+            fs::remove_dir_all(&path)?;
+            total -= size;
+        }
+    }
+    Ok(())
+}
+
+/// URL of a cached file, in the form the webview loads custom protocols.
+fn url(key: &str, file: &str) -> String {
+    if cfg!(windows) {
+        format!("https://{}.localhost/{}/{}", PROTOCOL, key, file)
+    } else {
+        format!("{}://localhost/{}/{}", PROTOCOL, key, file)
+    }
+}
+
+/// One cue per thumbnail, pointing at its cell in the sheet. Sprite URLs are
+/// relative to the index.
+fn build_index(sheets: &[String], duration: f64, interval: f64) -> String {
+    let per_sheet = (SPRITE_COLUMNS * SPRITE_ROWS) as usize;
+    let count = (duration / interval).ceil().max(1.0) as usize;
+
+    let mut index = String::from("WEBVTT\n");
+    for i in 0..count.min(sheets.len() * per_sheet) {
+        let cell = (i % per_sheet) as u32;
+        let start = i as f64 * interval;
+        let end = ((i + 1) as f64 * interval).min(duration.max(start));
+        let _ = write!(
+            index,
+            "\n{} --> {}\n{}#xywh={},{},{},{}\n",
+            timestamp(start),
+            timestamp(end),
+            sheets[i / per_sheet],
+            (cell % SPRITE_COLUMNS) * THUMBNAIL_WIDTH,
+            (cell / SPRITE_COLUMNS) * THUMBNAIL_HEIGHT,
+            THUMBNAIL_WIDTH,
+            THUMBNAIL_HEIGHT,
+        );
+    }
+    index
+}
+
+fn timestamp(seconds: f64) -> String {
+    let millis = (seconds * 1000.0).round() as u64;
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        millis / 3_600_000,
+        millis / 60_000 % 60,
+        millis / 1000 % 60,
+        millis % 1000
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mpv::test_support;
+    use std::sync::mpsc;
+
+    /// A gray `width`x`height` video of `frames` frames at 25 fps.
+    fn gray_y4m(width: usize, height: usize, frames: usize) -> Vec<u8> {
+        let mut data =
+            format!("YUV4MPEG2 W{} H{} F25:1 Ip A1:1 C420jpeg\n", width, height).into_bytes();
+        for _ in 0..frames {
+            data.extend_from_slice(b"FRAME\n");
+            data.resize(data.len() + width * height * 3 / 2, 128);
+        }
+        data
+    }
+
+    fn write_video(dir: &Path, name: &str, size: usize) {
+        let path = dir.join(name);
+        fs::create_dir_all(&path).unwrap();
+        fs::write(path.join(INDEX_FILE_NAME), vec![0; size]).unwrap();
+    }
+
+    fn set_last_used(dir: &Path, name: &str, ago: Duration) {
+        fs::File::options()
+            .append(true)
+            .open(dir.join(name).join(INDEX_FILE_NAME))
+            .unwrap()
+            .set_modified(SystemTime::now() - ago)
+            .unwrap();
+    }
+
+    #[test]
+    fn indexes_cells_of_the_sheets() {
+        let sheets = vec!["00000001.jpg".to_string(), "00000002.jpg".to_string()];
+        let index = build_index(&sheets, 205.0, 2.0);
+
+        assert!(index.starts_with("WEBVTT\n"));
+        assert!(index.contains("\n00:00:00.000 --> 00:00:02.000\n00000001.jpg#xywh=0,0,160,90\n"));
+        assert!(
+            index.contains("\n00:00:22.000 --> 00:00:24.000\n00000001.jpg#xywh=160,90,160,90\n")
+        );
+        assert!(index.contains("\n00:03:20.000 --> 00:03:22.000\n00000002.jpg#xywh=0,0,160,90\n"));
+        assert!(
+            index.ends_with("\n00:03:24.000 --> 00:03:25.000\n00000002.jpg#xywh=320,0,160,90\n")
+        );
+    }
+
+    #[test]
+    fn formats_timestamps() {
+        assert_eq!(timestamp(0.0), "00:00:00.000");
+        assert_eq!(timestamp(3723.4567), "01:02:03.457");
+    }
+
+    #[test]
+    fn only_serves_finished_cache_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let generator = ThumbnailGenerator::new("", dir.path().to_path_buf(), |_| {});
+        write_video(dir.path(), "0123abcd", 3);
+        fs::create_dir_all(dir.path().join("0123abcd-0.partial")).unwrap();
+        fs::write(dir.path().join("0123abcd-0.partial").join("a.jpg"), "x").unwrap();
+
+        let (data, mime_type) = generator.read("/0123abcd/thumbnails.vtt").unwrap();
+        assert_eq!((data.len(), mime_type), (3, "text/vtt"));
+        assert!(generator.read("/0123abcd-0.partial/a.jpg").is_none());
+        assert!(generator
+            .read("/0123abcd/../0123abcd/thumbnails.vtt")
+            .is_none());
+        assert!(generator.read("/0123abcd/.hidden").is_none());
+        assert!(generator.read("/0123abcd").is_none());
+    }
+
+    #[test]
+    fn evicts_old_and_least_recently_used_videos() {
+        let dir = tempfile::tempdir().unwrap();
+        let day = Duration::from_secs(24 * 60 * 60);
+        for (name, ago) in [("old", 40), ("older", 3), ("newer", 2), ("newest", 1)] {
+            write_video(dir.path(), name, 100);
+            set_last_used(dir.path(), name, day * ago);
+        }
+
+        trim_cache(dir.path(), &[], 250, day * 30).unwrap();
+
+        let mut left: Vec<String> = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        left.sort();
+        assert_eq!(left, ["newer", "newest"]);
+    }
+
+    #[test]
+    fn removes_partial_directories_of_jobs_that_are_gone() {
+        let dir = tempfile::tempdir().unwrap();
+        let running = dir.path().join("abc-1.partial");
+        fs::create_dir_all(&running).unwrap();
+        fs::create_dir_all(dir.path().join("abc-0.partial")).unwrap();
+
+        trim_cache(
+            dir.path(),
+            std::slice::from_ref(&running),
+            u64::MAX,
+            MAX_CACHE_AGE,
+        )
+        .unwrap();
+
+        assert!(running.exists());
+        assert!(!dir.path().join("abc-0.partial").exists());
+    }
+
+    #[test]
+    fn reports_and_cleans_up_replaced_jobs() {
+        if test_support::player().is_none() {
+            return;
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let cache_dir = dir.path().join("cache");
+        let first = dir.path().join("first.y4m");
+        let second = dir.path().join("second.y4m");
+        fs::write(&first, gray_y4m(64, 36, 1500)).unwrap();
+        fs::write(&second, gray_y4m(64, 36, 50)).unwrap();
+
+        let (sender, receiver) = mpsc::channel();
+        let sender = Mutex::new(sender);
+        let generator = ThumbnailGenerator::new(
+            &test_support::lib_path(),
+            cache_dir.clone(),
+            move |progress| {
+                if progress.status != ThumbnailStatus::Running {
+                    let _ = sender.lock().unwrap().send(progress.clone());
+                }
+            },
+        );
+
+        let first = first.to_string_lossy().into_owned();
+        let second = second.to_string_lossy().into_owned();
+        assert!(generator.request(&first).unwrap().is_none());
+        assert!(generator.request(&second).unwrap().is_none());
+
+        let mut ended = Vec::new();
+        while ended.len() < 2 {
+            let progress = receiver.recv_timeout(Duration::from_secs(30)).unwrap();
+            ended.push((progress.path, progress.status));
+        }
+        ended.sort_by(|a, b| a.0.cmp(&b.0));
+        // The first one may have been quick enough to finish anyway
+        assert!(matches!(
+            ended[0].1,
+            ThumbnailStatus::Cancelled | ThumbnailStatus::Done
+        ));
+        assert_eq!(ended[1], (second.clone(), ThumbnailStatus::Done));
+
+        assert!(generator.stopping.lock().unwrap().is_empty());
+        assert!(generator.request(&second).unwrap().is_some());
+        let partial = fs::read_dir(&cache_dir)
+            .unwrap()
+            .filter(|entry| {
+                let name = entry.as_ref().unwrap().file_name();
+                name.to_string_lossy().ends_with(PARTIAL_SUFFIX)
+            })
+            .count();
+        assert_eq!(partial, 0);
+    }
+}
@@ -0,0 +1,21 @@
+use crate::thumbnails::ThumbnailGenerator;
+
+use std::sync::Arc;
+use tauri::State;
+
+/// Returns the URL of the WebVTT thumbnail index of `path` if it is cached.
+/// Otherwise starts generating it and returns `None`; progress and the URL
+/// follow in `thumbnail-progress` events.
+#[tauri::command]
+pub fn thumbnails_request(
+    generator: State<'_, Arc<ThumbnailGenerator>>,
+    path: String,
+) -> Result<Option<String>, String> {
+    generator.request(&path).map_err(|e| e.to_string())
+}
+
+/// Cancels generation for `path`, or whatever is running if it's not given.
+#[tauri::command]
+pub fn thumbnails_cancel(generator: State<'_, Arc<ThumbnailGenerator>>, path: Option<String>) {
+    generator.cancel(path.as_deref());
+}
@@ -38,6 +38,7 @@ export const mediaInfo = sqliteTable("media_info", {
     disc: integer("disc"),
     totalDiscs: integer("total_discs"),
     genre: text("genre"),
+    albumArtist: text("album_artist"),
     // pictures: text("pictures"), // TODO: handle pictures later
     duration: integer("duration"),
     bitrate: integer("bitrate"),
@@ -14,7 +14,7 @@ export function useMpvPlayer({}: MpvPlayerHookOptions = {}) {
         seek(position: number) {
             setPartialInfo({ position }); // optimistic update
             MpvPlayer.seek(position).then(() =>
-                MpvPlayer.getDuration().then((duration) => setPartialInfo({ duration }))
+                MpvPlayer.getDuration().then((duration) => setPartialInfo({ duration: duration ?? 0 }))
             ); // actual update
         },
         play() {
@@ -4,6 +4,55 @@ import { mediaInfo as MediaInfoTable } from "@/db/schema";
 import { db } from "@/db/database";
 import { basename } from "@tauri-apps/api/path";
 
+// A track of a CUE sheet; a time range within a single media file
+export type VirtualTrack = {
+    path: string;
+    track: number;
+    totalTracks: number;
+    title?: string;
+    artist?: string;
+    album?: string;
+    start: number;
+    end?: number;
+    duration?: number;
+};
+
+export type MusicBrainzIds = {
+    recordingId?: string;
+    trackId?: string;
+    releaseId?: string;
+    releaseGroupId?: string;
+    workId?: string;
+    artistIds: string[];
+    releaseArtistIds: string[];
+};
+
+// Gains in dB, peaks as a fraction of full scale
+export type ReplayGain = {
+    trackGain?: number;
+    trackPeak?: number;
+    albumGain?: number;
+    albumPeak?: number;
+};
+
+export type MediaStream = {
+    kind: "video" | "audio" | "subtitle";
+    codec?: string;
+    language?: string;
+    title?: string;
+    albumArt: boolean;
+    width?: number;
+    height?: number;
+    frameRate?: number;
+    channels?: number;
+    sampleRate?: number;
+};
+
+export type Chapter = {
+    title?: string;
+    start: number;
+};
+
 // What Tauri backend returns
 type TauriMediaMetadata = {
     title?: string;
@@ -15,12 +64,38 @@ type TauriMediaMetadata = {
     disc?: number;
     totalDiscs?: number;
     genre?: string;
+    // All values of multi-value fields; `artist` and `genre` hold the first one
+    artists?: string[];
+    genres?: string[];
+    albumArtists?: string[];
+    composers?: string[];
+    conductors?: string[];
+    comments?: string[];
+    lyrics?: string;
+    bpm?: number;
+    isrc?: string;
+    labels?: string[];
+    catalogNumber?: string;
+    originalDate?: string;
+    musicbrainz?: MusicBrainzIds;
+    replayGain?: ReplayGain;
     // pictures?: Array<Array<number>>;
     duration: number;
     bitrate?: number;
     sampleRate?: number;
     channels?: number;
     bitDepth?: number;
+    virtualTracks?: VirtualTrack[];
+    // Decided from the streams in the file, not its extension
+    isVideo: boolean;
+    container?: string;
+    width?: number;
+    height?: number;
+    frameRate?: number;
+    videoCodec?: string;
+    audioCodec?: string;
+    streams?: MediaStream[];
+    chapters?: Chapter[];
 };
 
 // The DB metadata schema
@@ -38,16 +113,21 @@ export async function getMediaInfo(path: string): Promise<MediaMetadata | undefi
 
     if (mediaInfo) return mediaInfo;
 
-    const isVideo = isVideoFileByFileExtension(path);
     const tauriMetadata = await getMetadataFromFile(path).catch(() => undefined);
+    const isVideo = tauriMetadata?.isVideo ?? isVideoFileByFileExtension(path);
     const altName = await basename(path);
 
-    // Video files should use file name instead of media info title.
-    const title = isVideo ? altName : tauriMetadata?.title ?? altName;
+    // Video files should use file name instead of media info title, unless the container has one.
+    const title = isVideo
+        ? tauriMetadata?.container && tauriMetadata.title
+            ? tauriMetadata.title
+            : altName
+        : tauriMetadata?.title ?? altName;
 
     const insertionValues: typeof MediaInfoTable.$inferInsert = {
         ...tauriMetadata,
         title,
+        albumArtist: tauriMetadata?.albumArtists?.[0],
         path: path,
         isVideo,
     };
@@ -72,3 +152,41 @@ export async function getPictures(path: string): Promise<Picture[]> {
     const pictures: any = await invoke("get_pictures", { path });
     return pictures.map(objectKeysToCamelCase) as Picture[];
 }
+
+/**
+ * URL of the embedded cover of `path`, for use in `<img src>`. With `size`, the
+ * image is scaled down so its longest side fits; with `format`, it is
+ * re-encoded (JPEG if only `size` is given). Without either, the image is
+ * served as found. Resolves once the image is cached on disk.
+ */
+export async function getCoverUrl(
+    path: string,
+    size?: number,
+    format?: "jpeg" | "webp"
+): Promise<string> {
+    return await invoke("cover_get_url", { path, size, format });
+}
+
+export type CoverSource =
+    | { kind: "embedded"; pictureType: string }
+    | { kind: "sidecar"; path: string }
+    | { kind: "video_frame" };
+
+export type ResolvedCover = {
+    /** Like `getCoverUrl` without a size or format. */
+    url: string;
+    source: CoverSource;
+    mimeType: string;
+    width?: number;
+    height?: number;
+};
+
+/**
+ * The cover of `path`: its front cover, else an image in its folder, else any
+ * other embedded picture, else the first frame of the video. The image itself
+ * is loaded from `url`.
+ */
+export async function resolveCover(path: string): Promise<ResolvedCover | null> {
+    const cover: any = await invoke("cover_resolve", { path });
+    return cover ? (objectKeysToCamelCase(cover) as ResolvedCover) : null;
+}
@@ -1,7 +1,8 @@
 import { objectKeysToCamelCase } from "@/lib/utils";
 import { invoke } from "@tauri-apps/api";
 import { listen, type Event } from "@tauri-apps/api/event";
-import { IPlaylist } from "./PlaylistSvc";
+import { createPlaylistEntry, deletePlaylistEntriesByPlaylistId } from "./PlaylistEntrySvc";
+import { getPlaylistById, IPlaylist } from "./PlaylistSvc";
 
 export enum MpvEventId {
     None = 0,
@@ -84,6 +85,118 @@ export type PlaylistEntry = {
     id?: number;
 };
 
+export type PlaybackContinuity = {
+    gapless: "Yes" | "No" | "Weak";
+    prefetch_playlist: boolean;
+    readahead_secs: number;
+};
+
+export type GaplessBreak = {
+    index: number;
+    from: string;
+    to: string;
+    changes: { field: string; from?: number; to?: number }[];
+};
+
+export type StreamOptions = {
+    cache?: boolean;
+    cache_secs?: number;
+    cache_max_bytes?: number;
+    readahead_secs?: number;
+    network_timeout_secs?: number;
+    user_agent?: string;
+    http_headers?: Record<string, string>;
+};
+
+export type StreamStatus = {
+    pausedForCache: boolean;
+    bufferingPercent?: number;
+    seekable: boolean;
+    cacheDuration?: number;
+    cacheState?: {
+        cacheEnd?: number;
+        readerPts?: number;
+        cacheDuration?: number;
+        eof: boolean;
+        underrun: boolean;
+        idle: boolean;
+        totalBytes?: number;
+        fwBytes?: number;
+        fileCacheBytes?: number;
+        rawInputRate?: number;
+        seekableRanges: { start: number; end: number }[];
+    };
+};
+
+export type MpvErrorCode =
+    | "success"
+    | "event_queue_full"
+    | "no_mem"
+    | "uninitialized"
+    | "invalid_parameter"
+    | "option_not_found"
+    | "option_format"
+    | "option_error"
+    | "property_not_found"
+    | "property_format"
+    | "property_unavailable"
+    | "property_error"
+    | "command"
+    | "loading_failed"
+    | "ao_init_failed"
+    | "vo_init_failed"
+    | "nothing_to_play"
+    | "unknown_format"
+    | "unsupported"
+    | "not_implemented"
+    | "generic"
+    | "unknown";
+
+/** Error returned by the mpv commands. */
+export type MpvError = {
+    kind: string;
+    code: MpvErrorCode | null;
+    message: string;
+    /** The option, command or property the error is about. */
+    context: string | null;
+};
+
+export function isMpvError(error: unknown, code?: MpvErrorCode): error is MpvError {
+    return (
+        typeof error === "object" &&
+        error !== null &&
+        "kind" in error &&
+        "message" in error &&
+        (code === undefined || (error as MpvError).code === code)
+    );
+}
+
+export type BatchOp =
+    | { op: "get"; name: string }
+    | { op: "set"; name: string; value: unknown }
+    | { op: "command"; args: (string | number | boolean)[] };
+
+export type BatchResult = {
+    /** The value read by a `get`; `null` for other operations and on error. */
+    value: unknown;
+    error: MpvError | null;
+};
+
+export type PlayerState = {
+    path?: string;
+    filename?: string;
+    position?: number;
+    duration?: number;
+    paused: boolean;
+    volume?: number;
+    muted: boolean;
+    idle: boolean;
+    playlistPos?: number;
+    playlistCount: number;
+    tracks: Track[];
+    currentTracks: CurrentTracks;
+};
+
 type LoadMode =
     | "Replace"
     | "Append"
@@ -108,6 +221,13 @@ export default class MpvPlayer {
                 listeners.forEach((callback) => callback(event));
             }
         });
+
+        // An IPC client or a remote device changed mpv's playlist; rebuild ours from it
+        listen("mpv-external-playlist-change", (event: Event<PlaylistEntry[]>) => {
+            MpvPlayer.syncing = MpvPlayer.syncing
+                .then(() => MpvPlayer.syncPlaylist(event.payload))
+                .catch((e) => console.error("Failed to sync the playlist with mpv:", e));
+        });
     }
 
     public static on(event: MpvEventId, callback: MpvEventCallback) {
@@ -127,8 +247,14 @@ export default class MpvPlayer {
         }
     }
 
-    public static async getDuration(): Promise<number> {
-        return await invoke("mpv_get_duration");
+    /** Duration in seconds, or `null` if no media is loaded. */
+    public static async getDuration(): Promise<number | null> {
+        try {
+            return await invoke("mpv_get_duration");
+        } catch (e) {
+            if (isMpvError(e, "property_unavailable")) return null;
+            throw e;
+        }
     }
 
     public static async getPosition(): Promise<number> {
@@ -164,6 +290,68 @@ export default class MpvPlayer {
         // console.log(await MpvPlayer._getPlaylist());
     }
 
+    public static async loadStream(url: string, mode?: LoadMode, options?: StreamOptions) {
+        await invoke("mpv_load_stream", { url, mode, options });
+    }
+
+    public static async getStreamStatus(): Promise<StreamStatus> {
+        const status: any = await invoke("mpv_get_stream_status");
+        return objectKeysToCamelCase(status) as StreamStatus;
+    }
+
+    public static onStreamStatus(callback: (status: StreamStatus) => void) {
+        return listen("mpv-stream-status", (event: Event<any>) =>
+            callback(objectKeysToCamelCase(event.payload) as StreamStatus)
+        );
+    }
+
+    /** Resolves if the mpv core is running; otherwise rejects with the `MpvError` that stopped it. */
+    public static async checkCore() {
+        await invoke("mpv_check_core");
+    }
+
+    /**
+     * Restarts the mpv core, e.g. to apply options that mpv only reads on startup.
+     * `options` are kept for later restarts; omit them to reuse the previous ones.
+     * Rejects with a `not_allowed` error, without restarting, if an option isn't
+     * in the `options` of the mpv allowlist.
+     */
+    public static async recreate(options?: Record<string, string>) {
+        await invoke("mpv_recreate", { options });
+    }
+
+    /**
+     * Reads several properties in one call. Only allowlisted properties can be read;
+     * unavailable ones (e.g. `duration` while idle) are `null`.
+     */
+    public static async getProperties(names: string[]): Promise<Record<string, unknown>> {
+        return await invoke("mpv_get_properties", { names });
+    }
+
+    public static async setProperties(values: Record<string, unknown>) {
+        await invoke("mpv_set_properties", { values });
+    }
+
+    /** Runs several operations in one call; a failed operation doesn't stop the others. */
+    public static async batch(ops: BatchOp[]): Promise<BatchResult[]> {
+        return await invoke("mpv_batch", { ops });
+    }
+
+    public static async getState(): Promise<PlayerState> {
+        const state: any = await invoke("mpv_get_state");
+        return objectKeysToCamelCase(state) as PlayerState;
+    }
+
+    /**
+     * Calls `callback` whenever the player state changes. Position-only
+     * changes are sent at most 10 times per second.
+     */
+    public static onState(callback: (state: PlayerState) => void) {
+        return listen("mpv-state", (event: Event<any>) =>
+            callback(objectKeysToCamelCase(event.payload) as PlayerState)
+        );
+    }
+
     public static async getPath(): Promise<string> {
         return await invoke("mpv_get_path");
     }
@@ -217,14 +405,50 @@ export default class MpvPlayer {
         });
     }
 
+    public static async getPlaybackContinuity(): Promise<PlaybackContinuity> {
+        return await invoke("mpv_get_playback_continuity");
+    }
+
+    public static async setPlaybackContinuity(continuity: PlaybackContinuity) {
+        return await invoke("mpv_set_playback_continuity", { continuity });
+    }
+
+    public static async detectGaplessBreaks(paths: string[]): Promise<GaplessBreak[]> {
+        return await invoke("mpv_detect_gapless_breaks", { paths });
+    }
+
     /*
      * Playlist are managed by the TS MpvPlayer class.
      * In theory, if MPV backend is not controlled by any other
      * service, the MpvPlayer class's playlist state should be kept in sync.
+     * When a client of the mpv IPC server or a remote device edits mpv's
+     * playlist, the entries of ours are rebuilt from it.
      */
 
     private static playlist: Playlist | null = null;
 
+    /** The last sync with mpv's playlist, so syncs run one after the other. */
+    private static syncing: Promise<void> = Promise.resolve();
+
+    /**
+     * Replaces the entries of the loaded playlist, and its `playlist_entry` rows,
+     * with `entries` from mpv's playlist.
+     */
+    private static async syncPlaylist(entries: PlaylistEntry[]) {
+        const playlistId = MpvPlayer.playlist?.id;
+        if (playlistId === undefined) return;
+
+        await deletePlaylistEntriesByPlaylistId(playlistId);
+        for (const entry of entries) {
+            await createPlaylistEntry(entry.filename, playlistId);
+        }
+
+        const playlist = await getPlaylistById(playlistId);
+        // Another playlist may have been loaded meanwhile
+        if (MpvPlayer.playlist?.id !== playlistId) return;
+        MpvPlayer.playlist = playlist ? (JSON.parse(JSON.stringify(playlist)) as Playlist) : null;
+    }
+
     public static async setPlaylist(playlist: Playlist) {
         MpvPlayer.playlist = JSON.parse(JSON.stringify(playlist)) as Playlist; // deep copy
 
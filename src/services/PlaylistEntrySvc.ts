@@ -64,6 +64,10 @@ export async function deletePlaylistEntryById(id: number): Promise<void> {
     await db.delete(PlaylistEntryTable).where(eq(PlaylistEntryTable.id, id));
 }
 
+export async function deletePlaylistEntriesByPlaylistId(playlistId: number): Promise<void> {
+    await db.delete(PlaylistEntryTable).where(eq(PlaylistEntryTable.playlistId, playlistId));
+}
+
 export async function updatePlaylistEntrySortIndex(
     id: number,
     sortIndex: number
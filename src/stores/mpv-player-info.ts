@@ -1,4 +1,4 @@
-import MpvPlayer, { MpvEventId, Track } from "@/services/MpvPlayer";
+import MpvPlayer, { PlayerState, Track } from "@/services/MpvPlayer";
 import { IPlaylistEntry } from "@/services/PlaylistEntrySvc";
 import { IPlaylist } from "@/services/PlaylistSvc";
 import { atom } from "nanostores";
@@ -34,51 +34,33 @@ export function setPartialMpvPlayerInfo(partialInfo: Partial<PlayerInfo>) {
     });
 }
 
-// Initial setup for polling data
+// Keep in sync with the state pushed by the backend
 (async function init() {
-    const fetchInfo = async () => {
-        // Get state and use default value if there is an error
-        const duration = await MpvPlayer.getDuration().catch(() => 0);
-        const position = await MpvPlayer.getPosition().catch(() => 0);
-        const volume = await MpvPlayer.getVolume().catch(() => 0);
-        const isPaused = await MpvPlayer.isPaused().catch(() => true);
-        const path = await MpvPlayer.getPath().catch(() => "");
-        const filename = await MpvPlayer.getFilename().catch(() => "");
-        const tracks = await MpvPlayer.getTracks().catch(() => []);
+    const applyState = (state: PlayerState) => {
         const currentPlaylist = MpvPlayer.getPlaylist();
-        const currentPlaylistEntrySortIndex = await MpvPlayer.getPlaylistPos().catch(() => -1);
         const currentPlaylistEntry =
-            currentPlaylist?.entries.find((e) => e.sortIndex === currentPlaylistEntrySortIndex) ??
-            null;
-        
-        // console.log("currentPlaylistEntrySortIndex", currentPlaylistEntrySortIndex);
-        // console.log("currentPlaylistEntry", currentPlaylistEntry);
+            currentPlaylist?.entries.find((e) => e.sortIndex === state.playlistPos) ?? null;
 
         setPartialMpvPlayerInfo({
-            duration,
-            position,
-            volume,
-            isPaused,
-            path,
-            filename,
-            tracks,
+            duration: state.duration ?? 0,
+            position: state.position ?? 0,
+            volume: state.volume ?? 0,
+            isPaused: state.paused,
+            path: state.path ?? "",
+            filename: state.filename ?? "",
+            tracks: state.tracks,
             currentPlaylist,
             currentPlaylistEntry,
         });
     };
 
-    let intId = setInterval(fetchInfo, 1000);
-    MpvPlayer.on(MpvEventId.FileLoaded, fetchInfo);
-    MpvPlayer.on(MpvEventId.StartFile, fetchInfo);
-    MpvPlayer.on(MpvEventId.EndFile, fetchInfo);
+    const unlisten = await MpvPlayer.onState(applyState);
 
-    fetchInfo(); // initial fetch
+    // Initial state; later changes arrive as events
+    MpvPlayer.getState()
+        .then(applyState)
+        .catch((e) => console.error("Failed to get player state", e));
 
     // Cleanup func
-    return () => {
-        MpvPlayer.off(MpvEventId.FileLoaded, fetchInfo);
-        MpvPlayer.off(MpvEventId.StartFile, fetchInfo);
-        MpvPlayer.off(MpvEventId.EndFile, fetchInfo);
-        clearInterval(intId);
-    };
+    return unlisten;
 })();